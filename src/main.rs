@@ -16,541 +16,12963 @@
  *  - Uses serde for JSON serialization and prettytable for display
  */
 
-use chrono::{DateTime, Utc};
-use clap::{Parser, Subcommand, ValueEnum, error::ErrorKind};
-use prettytable::{Table, cell, row};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Utc, Weekday};
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use fuzzy_matcher::FuzzyMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use prettytable::{Cell, Row, Table};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+#[cfg(test)]
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 const NOTES_PATH: &str = "notes.json";
 
-type NoteResult<T> = Result<T, Box<dyn std::error::Error>>;
+/// Set by `--verbose`, for details reported far from the command line
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--recover`, read where the notes file is loaded
+static RECOVER: AtomicBool = AtomicBool::new(false);
+
+/// File holding `free_ids` when notes are stored one file per note
+const DIR_META_FILE: &str = "meta.json";
+
+/// Value of the `layout` key marking the index file of yearly shards
+const SHARDED_LAYOUT: &str = "sharded";
+
+/// Location of the config file below the user config directory
+const CONFIG_FILE: &str = "note-cli/config.toml";
+
+/// Location of the `new` cursors below the user state directory
+const CURSORS_FILE: &str = "note-cli/cursors.json";
+
+/// Directory of the notebooks below the user data directory
+const NOTEBOOKS_DIR: &str = "note-cli";
+
+/// Environment variable giving the notes file when --file isn't
+const FILE_ENV: &str = "NOTE_CLI_FILE";
+
+/// Notebook used when neither a notebook nor a file is given
+const DEFAULT_NOTEBOOK: &str = "notes";
+
+/// Exit status when a command asked to fail on an empty result found nothing
+const EXIT_EMPTY: u8 = 3;
+
+/// Exit status when `add --unique` found an identical note instead of creating one
+const EXIT_EXISTS: u8 = 4;
+
+/// Exit status when `show --grep` found no matching line
+const EXIT_NO_MATCH: u8 = 5;
+
+/// Exit status when `status` counted overdue notes
+const EXIT_OVERDUE: u8 = 6;
+
+/// Exit status when a note ID given to a command doesn't exist
+const EXIT_NOT_FOUND: u8 = 7;
+
+/// Exit status when a notes file is not valid JSON
+const EXIT_CORRUPT: u8 = 8;
+
+/// Exit status when `doctor` found problems and `--fix` wasn't given
+const EXIT_PROBLEMS: u8 = 9;
+
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  0  Success
+  1  Error while reading, writing or updating notes
+  2  Invalid command line usage
+  3  No note selected and --fail-if-empty was given, or pick was cancelled
+  4  add --unique found an identical note, its ID is printed
+  5  show --grep found no matching line
+  6  status counted overdue notes
+  7  A note ID given doesn't exist
+  8  A notes file is not valid JSON
+  9  doctor found problems and --fix wasn't given";
+
+type NoteResult<T> = Result<T, NoteError>;
 
 #[derive(Parser)]
 #[command(name = "note-cli")]
 #[command(about="Minimal note manager in Rust", long_about=None)]
+#[command(after_long_help = EXIT_STATUS_HELP)]
 struct Cli {
-    #[arg(long, default_value = "notes.json")]
+    /// Notes file or directory, also read from `NOTE_CLI_FILE` [default: the
+    /// `file` of the config, else $XDG_DATA_HOME/note-cli/notes.json]
+    #[arg(id = "file", long = "file", value_name = "FILE", value_parser = expand_home)]
+    file_arg: Option<String>,
+
+    /// Use the notebook of this name, `<data dir>/note-cli/<name>.json`
+    #[arg(long, conflicts_with = "file", value_parser = parse_notebook_name)]
+    notebook: Option<String>,
+
+    /// Notes file in use, from --notebook, --file, `NOTE_CLI_FILE`, the
+    /// config or the default
+    #[arg(skip)]
     file: String,
 
+    /// Config file [default: $XDG_CONFIG_HOME/note-cli/config.toml]
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Print more details, such as the underlying cause of errors
+    #[arg(long, global = true)]
+    verbose: bool,
+
+    /// Use plain text instead of emoji and other symbols
+    #[arg(long, global = true)]
+    no_emoji: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_parser = parse_date_format,
+        help = format!(
+            "strftime format of dates in tables and show, e.g. \"%Y-%m-%d\" [default: \"{}\"]",
+            DEFAULT_DATE_FORMAT
+        )
+    )]
+    date_format: Option<String>,
+
+    /// When to color the output, auto colors a terminal unless NO_COLOR is set
+    /// [default: auto]
+    #[arg(long, global = true, value_enum)]
+    color: Option<ColorChoice>,
+
+    /// Don't ask for confirmation before removing or discarding anything,
+    /// required when not run in a terminal
+    #[arg(short, long, global = true)]
+    yes: bool,
+
+    /// Report the changes a command would make to the notes without saving
+    /// them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Don't keep a rotating backup of the notes file before changing it
+    #[arg(long, global = true)]
+    no_backup: bool,
+
+    /// When the notes file is not valid JSON, move it aside to
+    /// `<file>.corrupt.<time>` and start with no notes
+    #[arg(long, global = true)]
+    recover: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+impl Cli {
+    /// File given with --file, else with `NOTE_CLI_FILE`, where an empty
+    /// `NOTE_CLI_FILE=` counts as unset. The variable is read here rather than
+    /// by clap so that --notebook wins over it instead of conflicting.
+    fn file_given(&self) -> NoteResult<Option<String>> {
+        if let Some(file) = self.file_arg.as_ref().filter(|file| !file.is_empty()) {
+            return Ok(Some(file.clone()));
+        }
+        match std::env::var(FILE_ENV) {
+            Ok(file) if !file.is_empty() => Ok(Some(expand_home(&file)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Notebook holding the notes: --notebook, else the `notebook` of the
+    /// config when no file is given at all
+    fn notebook<'a>(&'a self, config: &'a Config) -> NoteResult<Option<&'a str>> {
+        if self.notebook.is_some() {
+            return Ok(self.notebook.as_deref());
+        }
+        if self.file_given()?.is_some() || config.file.is_some() {
+            return Ok(None);
+        }
+        Ok(config.notebook.as_deref())
+    }
+
+    /// Notes file in use: --notebook, else --file, else `NOTE_CLI_FILE`, else
+    /// the `file` of the config, else its `notebook`, else the default file.
+    ///
+    /// # Parameters
+    /// - `config: &Config` - Settings of the config file
+    ///
+    /// # Returns
+    /// `NoteResult<String>` - The path or error if a notebook has no data
+    /// directory
+    fn notes_file(&self, config: &Config) -> NoteResult<String> {
+        if let Some(name) = self.notebook(config)? {
+            return notebook_path(name);
+        }
+        Ok(self
+            .file_given()?
+            .or_else(|| config.file.clone())
+            .unwrap_or_else(default_notes_file))
+    }
+
+    /// Checks the argument combinations clap can't express, the message is
+    /// reported as a usage error
+    fn validate(&self) -> Result<(), String> {
+        match &self.command {
+            Commands::Backup {
+                action: BackupAction::Create,
+            } if self.dry_run => Err("backup create doesn't support --dry-run".to_string()),
+            Commands::Migrate { .. } if self.dry_run => {
+                Err("migrate doesn't support --dry-run".to_string())
+            }
+            Commands::Compact { .. } if self.dry_run => {
+                Err("compact doesn't support --dry-run".to_string())
+            }
+            Commands::New {
+                since_cursor: Some(CursorAction::Reset),
+                ..
+            } if self.dry_run => {
+                Err("new --since-cursor reset doesn't support --dry-run".to_string())
+            }
+            Commands::List { filter, .. }
+            | Commands::Search { filter, .. }
+            | Commands::Count { filter, .. }
+            | Commands::Timeline { filter, .. } => filter.validate(),
+            Commands::Show {
+                ids, grep: Some(_), ..
+            } if ids.len() > 1 => Err("show --grep takes a single ID".to_string()),
+            Commands::Add { edit: true, .. } if !interactive() => {
+                Err("add --edit needs a terminal to run the editor in".to_string())
+            }
+            Commands::Export {
+                format,
+                format_flag,
+                dir: Some(_),
+                ..
+            } if format.or(*format_flag) != Some(ExportFormat::Markdown) => {
+                Err("export --dir only works with the markdown format".to_string())
+            }
+            Commands::Edit { editor: true, .. } if !interactive() => {
+                Err("edit --editor needs a terminal to run the editor in".to_string())
+            }
+            Commands::Edit { id: None, .. } if !interactive() => Err(
+                "edit needs an ID and --content or --rating when not run in a terminal".to_string(),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    // Add new note
+    /// Add new note
     Add {
-        // Note content
-        content: String,
+        /// Note content, `-` reads it from stdin
+        #[arg(required_unless_present_any = ["stdin", "from_file"])]
+        content: Option<String>,
+
+        /// Read the content from stdin
+        #[arg(long, conflicts_with = "content")]
+        stdin: bool,
+
+        /// Read the content from this UTF-8 file
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["content", "stdin"])]
+        from_file: Option<PathBuf>,
 
-        // Associated tags (repeatable)
+        /// Short title shown next to the content
+        #[arg(long)]
+        title: Option<String>,
+
+        /// Associated tags (repeatable)
         #[arg(long = "tag")]
         tags: Vec<String>,
+
+        /// Give the note this ID instead of an automatic one
+        #[arg(long)]
+        id: Option<u32>,
+
+        /// Skip creation when a note with the same trimmed content exists
+        #[arg(long)]
+        unique: bool,
+
+        /// Ignore case when looking for an identical note
+        #[arg(long, requires = "unique")]
+        unique_ignore_case: bool,
+
+        /// Only compare with notes created within this duration (e.g. 1h, 2d)
+        #[arg(long, requires = "unique", value_parser = parse_duration)]
+        unique_window: Option<Duration>,
+
+        /// Hide the note once this duration has passed (e.g. 2d)
+        #[arg(long, value_parser = parse_duration)]
+        expires: Option<Duration>,
+
+        /// Due date (YYYY-MM-DD or RFC 3339, UTC)
+        #[arg(long, value_parser = parse_date)]
+        due: Option<DateTime<Utc>>,
+
+        /// Metadata entry KEY=VALUE (repeatable)
+        #[arg(long = "meta", value_parser = parse_meta_entry)]
+        meta: Vec<(String, String)>,
+
+        /// Rating from 1 to 5
+        #[arg(long, value_parser = parse_rating())]
+        rating: Option<u8>,
+
+        /// Open the new note in $EDITOR to write the rest of it
+        #[arg(long)]
+        edit: bool,
     },
-    // List all notes
+    /// List all notes
     List {
-        #[arg(long = "sort", value_enum, default_value = "id")]
-        method: SortMethod,
+        #[command(flatten)]
+        sort: SortArgs,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[command(flatten)]
+        sample: SampleArgs,
+
+        #[command(flatten)]
+        display: DisplayArgs,
     },
-    // Remove a Note
+    /// Remove a Note
     Remove {
-        // Note to remove id
+        /// IDs of the notes to remove
+        #[arg(required = true)]
+        ids: Vec<u32>,
+
+        /// Delete the note for good instead of moving it to the trash
+        #[arg(long)]
+        permanent: bool,
+    },
+
+    /// Undo the last change to the notes, run again to redo it
+    Undo,
+
+    /// Put a removed note back from the trash
+    Restore {
+        /// ID the note had when it was removed
+        id: u32,
+    },
+
+    /// Look at the removed notes
+    Trash {
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+
+    /// Add text at the end of a note, on a new line
+    Append {
+        /// note id
         id: u32,
+
+        /// Text to add
+        text: String,
+
+        /// Join the text to the last line instead of starting a new one
+        #[arg(long)]
+        no_newline: bool,
     },
 
-    // Add a tag to an existing note
+    /// Add a tag to an existing note
     AddTag {
-        // note id
+        /// note id
         id: u32,
 
-        // tag to add
+        /// tag to add
         #[arg(long = "tag")]
         tags: Vec<String>,
     },
-    // Edit the content of an
+    /// Remove tags from a note
+    RemoveTag {
+        /// note id
+        id: u32,
+
+        /// tag to remove
+        #[arg(long = "tag", required = true)]
+        tags: Vec<String>,
+    },
+    /// Rename a tag on every note carrying it
+    RenameTag {
+        /// Current name of the tag
+        old: String,
+
+        /// New name of the tag
+        new: String,
+    },
+    /// Edit the content, tags or rating of a note, without arguments pick
+    /// the note and type its content interactively
+    #[command(group(ArgGroup::new("change").multiple(true)))]
     Edit {
-        // note id
+        /// note id
+        #[arg(requires = "change")]
+        id: Option<u32>,
+
+        /// content
+        #[arg(long = "content", group = "change", requires = "id")]
+        content: Option<String>,
+
+        /// New title, an empty one removes it
+        #[arg(long, group = "change", requires = "id")]
+        title: Option<String>,
+
+        /// Rating from 1 to 5
+        #[arg(long, group = "change", requires = "id", value_parser = parse_rating())]
+        rating: Option<u8>,
+
+        /// New due date (YYYY-MM-DD or RFC 3339, UTC)
+        #[arg(long, group = "change", requires = "id", value_parser = parse_date)]
+        due: Option<DateTime<Utc>>,
+
+        /// Tag to add, can be repeated
+        #[arg(
+            long = "add-tag",
+            value_name = "TAG",
+            group = "change",
+            requires = "id"
+        )]
+        add_tags: Vec<String>,
+
+        /// Tag to remove, can be repeated
+        #[arg(
+            long = "remove-tag",
+            value_name = "TAG",
+            group = "change",
+            requires = "id"
+        )]
+        remove_tags: Vec<String>,
+
+        /// Replace all the tags, comma separated
+        #[arg(
+            long,
+            value_name = "TAGS",
+            value_delimiter = ',',
+            group = "change",
+            requires = "id",
+            conflicts_with_all = ["add_tags", "remove_tags"]
+        )]
+        set_tags: Option<Vec<String>>,
+
+        /// Change the content in $VISUAL or $EDITOR (vi by default)
+        #[arg(
+            long,
+            group = "change",
+            requires = "id",
+            conflicts_with_all = ["content", "title", "rating", "due", "add_tags", "remove_tags", "set_tags"]
+        )]
+        editor: bool,
+    },
+
+    /// Show the previous contents of a note
+    History {
+        /// note id
+        id: u32,
+    },
+
+    /// Put back a previous content of a note
+    Revert {
+        /// note id
+        id: u32,
+
+        /// Revision number, as shown by `history`
+        revision: usize,
+    },
+
+    /// Rate a note from 1 to 5
+    Rate {
+        /// note id
+        id: u32,
+        #[arg(value_parser = parse_rating())]
+        rating: u8,
+    },
+
+    /// Mark a note as done
+    Done {
+        /// note id
+        id: u32,
+    },
+
+    /// Mark a done note as pending again
+    Undone {
+        /// note id
+        id: u32,
+    },
+
+    /// Pin a note to the top of the list
+    Pin {
+        /// note id
+        id: u32,
+    },
+
+    /// Unpin a note
+    Unpin {
+        /// note id
+        id: u32,
+    },
+
+    /// Copy a note into another notes file
+    Copy {
+        /// note id
+        id: u32,
+
+        /// Notes file (or directory) receiving the copy
+        #[arg(long)]
+        to: String,
+
+        /// Set the update date of the copy to now
+        #[arg(long)]
+        touch: bool,
+    },
+
+    /// Move a note into another notes file or notebook
+    Move {
+        /// note id
+        id: u32,
+
+        /// Notes file (or directory) receiving the note, or a notebook name
+        /// such as `work`
+        #[arg(long)]
+        to: String,
+    },
+
+    /// Print the content of a note, nothing else
+    Cat {
+        /// ID of the note
         id: u32,
+    },
+
+    /// Choose a note with fzf, or a numbered list without it, and print its ID
+    Pick {
+        /// Don't show the content of the highlighted note next to the list
+        #[arg(long)]
+        no_preview: bool,
+    },
+
+    /// Show a note in full
+    Show {
+        /// IDs of the notes, shown in this order
+        #[arg(required = true, num_args = 1..)]
+        ids: Vec<u32>,
+
+        /// Only print the lines matching this pattern, case-insensitive
+        #[arg(long)]
+        grep: Option<String>,
 
-        // content
-        #[arg(long = "content")]
-        content: String,
+        /// Treat the --grep pattern as a regular expression
+        #[arg(long, requires = "grep")]
+        regex: bool,
+
+        /// Lines of context around each matching line
+        #[arg(short = 'C', long, default_value_t = 0, requires = "grep")]
+        context: usize,
+
+        /// Show dates in UTC instead of the time zone the note was written in
+        #[arg(long, conflicts_with = "local")]
+        utc: bool,
+
+        /// Show dates in the local time zone
+        #[arg(long)]
+        local: bool,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ShowFormat::Text, conflicts_with = "grep")]
+        format: ShowFormat,
     },
 
+    /// Search notes by keyword
     Search {
-        keyword: String,
-        #[arg(long = "sort", value_enum, default_value = "id")]
-        method: SortMethod,
+        /// Keywords to look for
+        #[arg(required = true)]
+        keywords: Vec<String>,
+
+        /// Fields the keyword is looked for in
+        #[arg(long = "in", value_enum, default_value_t = SearchScope::All)]
+        scope: SearchScope,
+
+        #[command(flatten)]
+        matching: MatchArgs,
+
+        #[command(flatten)]
+        sort: SortArgs,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+
+        #[command(flatten)]
+        display: DisplayArgs,
+    },
+
+    /// Print the number of notes, nothing else
+    Count {
+        /// Only count the notes containing this keyword, as search does
+        #[arg(long)]
+        keyword: Option<String>,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// Export all notes to another format
+    Export {
+        /// Format to write
+        #[arg(value_enum, required_unless_present = "format_flag")]
+        format: Option<ExportFormat>,
+
+        /// Same as the positional format, kept for older scripts
+        #[arg(long = "format", value_enum, hide = true, conflicts_with = "format")]
+        format_flag: Option<ExportFormat>,
+
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+
+        /// Write one file per note in this directory (markdown only)
+        #[arg(long, conflicts_with = "output")]
+        dir: Option<PathBuf>,
+
+        /// Overwrite existing files in --dir
+        #[arg(long, requires = "dir")]
+        force: bool,
+
+        /// Only notes with this tag (repeatable, all must match)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+    },
+
+    /// Merge another copy of the notes file, such as a sync conflict copy
+    Merge {
+        /// Notes file (or directory) to merge into --file
+        other: String,
+
+        /// When both files changed a note, also keep the older version as a
+        /// new note instead of dropping it
+        #[arg(long)]
+        keep_both: bool,
+    },
+
+    /// Import notes from another notes file, or a CSV or JSON export of another tool
+    Import {
+        /// File to import
+        input: PathBuf,
+
+        /// Input format, guessed from the file extension by default
+        #[arg(long, value_enum)]
+        format: Option<ImportFormat>,
+
+        /// Source column of each note field, e.g. "content=body,tags=labels"
+        #[arg(long, value_parser = parse_field_map)]
+        map: Option<FieldMap>,
+
+        /// TOML file mapping note fields to source columns
+        #[arg(long, conflicts_with = "map")]
+        map_file: Option<PathBuf>,
+
+        /// Separator between tags in a text tag column
+        #[arg(long, default_value = ",")]
+        tag_separator: String,
+
+        /// Show the first converted notes without importing anything, as
+        /// --dry-run does
+        #[arg(long)]
+        preview: bool,
+
+        /// Skip notes whose content is exactly that of an existing note
+        #[arg(long)]
+        skip_duplicates: bool,
+    },
+
+    /// Create a note from each Markdown or text file of a directory
+    ImportFiles {
+        /// Directory holding the files
+        dir: PathBuf,
+
+        /// Also import the files of subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+    },
+
+    /// Archive a note, or the notes older than a date, hiding them from
+    /// list and search
+    Archive {
+        /// Note to archive
+        #[arg(conflicts_with = "before")]
+        id: Option<u32>,
+
+        /// Archive the notes dated before this (YYYY-MM-DD or RFC 3339)
+        #[arg(long, value_parser = parse_date, required_unless_present = "id")]
+        before: Option<DateTime<Utc>>,
+
+        /// Date compared with --before
+        #[arg(long, value_enum, default_value_t = DateField::Created)]
+        field: DateField,
+    },
+
+    /// Bring an archived note back in list and search
+    Unarchive {
+        /// note id
+        id: u32,
+    },
+
+    /// Delete all the notes
+    Clear,
+
+    /// List expired notes, or delete them with --purge
+    Expire {
+        /// Delete the expired notes and free their IDs
+        #[arg(long)]
+        purge: bool,
+    },
+
+    /// Change the metadata of a note
+    Meta {
+        #[command(subcommand)]
+        action: MetaAction,
+    },
+
+    /// Save, list and restore copies of the notes
+    Backup {
+        #[command(subcommand)]
+        action: BackupAction,
+    },
+
+    /// Show notes grouped by day, newest first
+    Timeline {
+        /// Date used to group and order the notes
+        #[arg(long, value_enum, default_value = "created")]
+        field: DateField,
+
+        /// Show the whole content instead of its first line
+        #[arg(long)]
+        full: bool,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    /// Show the notes created or changed since the last run of new on this machine
+    New {
+        /// Show the notes without moving the cursor
+        #[arg(long)]
+        peek: bool,
+
+        /// Clear the cursor, the next run shows every note
+        #[arg(long, value_enum)]
+        since_cursor: Option<CursorAction>,
+
+        #[command(flatten)]
+        display: DisplayArgs,
+    },
+
+    /// Print a one-line summary of the notes for status bars
+    Status {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = StatusFormat::Text)]
+        format: StatusFormat,
+    },
+
+    /// Show a heatmap of notes per day over the last 12 months
+    Calendar {
+        /// Show this calendar year instead of the last 12 months
+        #[arg(long)]
+        year: Option<i32>,
+
+        /// Date counted for each note
+        #[arg(long, value_enum, default_value = "created")]
+        field: DateField,
+
+        #[arg(long, value_enum, default_value = "grid")]
+        format: CalendarFormat,
+    },
+
+    /// List the tags in use with the number of notes carrying them
+    Tags {
+        /// Order of the tags
+        #[arg(long, value_enum, default_value_t = TagSortMethod::Count)]
+        sort: TagSortMethod,
+
+        /// Show the color of each tag and whether it comes from the config
+        #[arg(long)]
+        show_colors: bool,
+    },
+
+    /// Convert the notes file to another storage layout
+    Migrate {
+        /// Split the notes into one file per creation year
+        #[arg(long, required = true)]
+        shard: bool,
+    },
+
+    /// Rewrite the notes file in canonical form and report its size
+    Compact {
+        /// Write the JSON without whitespace instead of pretty-printing it
+        #[arg(long)]
+        minify: bool,
+    },
+
+    /// List the free IDs given to new notes, or discard some of them
+    Ids {
+        /// Discard every free ID, new notes then get the highest ID plus one
+        #[arg(long, conflicts_with = "cap")]
+        clear_free: bool,
+
+        /// Keep only the N smallest free IDs
+        #[arg(long, value_name = "N")]
+        cap: Option<usize>,
+    },
+
+    /// Check the notes for duplicate IDs, wrong free IDs or dates and empty
+    /// notes
+    Doctor {
+        /// Repair what can be: renumber duplicates, clean the free IDs and
+        /// move update dates back to the creation date
+        #[arg(long)]
+        fix: bool,
+    },
+
+    /// List the notebooks with their number of notes
+    Notebooks,
+
+    /// Write man pages for note-cli and its subcommands
+    #[command(hide = true)]
+    Man {
+        /// Directory where the pages are written
+        #[arg(long, default_value = ".")]
+        out_dir: PathBuf,
     },
 }
 
-#[derive(Clone, ValueEnum)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
 enum SortMethod {
     Id,
     Date,
     Update,
     Content,
+    /// Highest rating first, unrated notes last
+    Rating,
+    /// Most tags first
+    TagCount,
+    /// Fewest tags first, to find the notes needing more
+    Tags,
+    /// Alphabetical by title, untitled notes last
+    Title,
+    /// Soonest due date first, notes without one last
+    Due,
 }
 
-// Struct for a single note
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
-struct Note {
-    id: u32,
-    content: String,
-    tags: Vec<String>,
-    created_at: DateTime<Utc>,
-    updated_at: DateTime<Utc>,
+// How search compares the keyword with the notes
+#[derive(Args, Clone, Copy, Debug, Default)]
+struct MatchArgs {
+    /// Treat the keyword as a regular expression
+    #[arg(long)]
+    regex: bool,
+
+    /// Only match text with the same case as the keyword
+    #[arg(long)]
+    case_sensitive: bool,
+
+    /// Tolerate typos: the letters of the keyword must appear in order, but
+    /// not next to each other
+    #[arg(long, conflicts_with_all = ["regex", "case_sensitive"])]
+    fuzzy: bool,
+
+    /// Only notes matching every keyword, the default
+    #[arg(long, conflicts_with = "any")]
+    all_keywords: bool,
+
+    /// Notes matching any of the keywords
+    #[arg(long)]
+    any: bool,
 }
 
-// Struct of json file
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
-struct NoteData {
-    notes: Vec<Note>,
-    free_ids: Vec<u32>, // to give ids to new note and keep clear
+impl MatchArgs {
+    /// Builds the matcher of search keywords.
+    ///
+    /// # Parameters
+    /// - `keywords: &[String]` - Keywords, at least one
+    ///
+    /// # Returns
+    /// `NoteResult<Matcher>` - Matcher or error for an invalid regex
+    fn matcher(&self, keywords: &[String]) -> NoteResult<Matcher> {
+        let mut matchers = keywords
+            .iter()
+            .map(|keyword| {
+                if self.fuzzy {
+                    Ok(Matcher::fuzzy(keyword))
+                } else {
+                    Matcher::with_case(keyword, self.regex, self.case_sensitive)
+                }
+            })
+            .collect::<NoteResult<Vec<Matcher>>>()?;
+        Ok(match (matchers.len(), self.any) {
+            (1, _) => matchers.remove(0),
+            (_, true) => Matcher::Any(matchers),
+            (_, false) => Matcher::All(matchers),
+        })
+    }
 }
 
-/// Cleans all notes by writing an empty JSON array to the file.
-///
-/// # Parameters
-/// - `path: &str` - File path to clean
-///
-/// # Returns
-/// `std::io::Result<()>` - Success or I/O error
-fn clean_notes(path: &str) -> std::io::Result<()> {
-    fs::write(path, "[]")
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SearchScope {
+    Content,
+    /// A tag matches when it contains the keyword
+    Tags,
+    /// Content and tags
+    All,
 }
 
-/// Loads note data from a JSON file, returns empty data if file doesn't exist.
-///
-/// # Parameters
-/// - `path: &str` - File path to read from
-///
-/// # Returns
-/// `NoteResult<NoteData>` - Loaded note data or error
-fn load_notes(path: &str) -> NoteResult<NoteData> {
-    use std::io::ErrorKind;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum TagSortMethod {
+    /// Most used first
+    Count,
+    /// Alphabetical
+    Name,
+}
 
-    match fs::read_to_string(path) {
-        Ok(content) => {
-            if content.trim().is_empty() {
-                Ok(NoteData {
-                    notes: vec![],
-                    free_ids: vec![],
-                }) // empty file = no notes
-            } else {
-                Ok(serde_json::from_str(&content)?)
-            }
+// Sort options shared by list and search
+#[derive(Args, Clone, Debug)]
+struct SortArgs {
+    /// Sort keys, comma separated: later keys order the notes equal on the
+    /// first ones. ID when not given (best match first for search --fuzzy)
+    #[arg(long = "sort", value_name = "KEYS", value_enum, value_delimiter = ',')]
+    methods: Vec<SortMethod>,
+
+    /// Reverse the order, notes with equal keys stay sorted by ID
+    #[arg(long)]
+    reverse: bool,
+}
+
+impl From<SortMethod> for SortArgs {
+    fn from(method: SortMethod) -> Self {
+        SortArgs {
+            methods: vec![method],
+            reverse: false,
+        }
+    }
+}
+
+// Random selection options of list
+#[derive(Args, Clone, Copy, Debug, Default)]
+struct SampleArgs {
+    /// Show this many notes picked at random among the selected ones, still
+    /// ordered by --sort
+    #[arg(long, value_name = "COUNT")]
+    sample: Option<usize>,
+
+    /// Seed of the random picks, for reproducible samples
+    #[arg(long, hide = true, requires = "sample")]
+    seed: Option<u64>,
+}
+
+impl SampleArgs {
+    /// Keeps `sample` notes chosen uniformly at random, without repeats, or
+    /// all of them when there are fewer. Kept notes stay in their order.
+    ///
+    /// # Parameters
+    /// - `notes: &mut Vec<&Note>` - Notes to sample from
+    fn apply(&self, notes: &mut Vec<&Note>) {
+        let Some(count) = self.sample else {
+            return;
+        };
+        let mut rng = match self.seed {
+            Some(seed) => fastrand::Rng::with_seed(seed),
+            None => fastrand::Rng::new(),
+        };
+        let mut picks: Vec<usize> = (0..notes.len()).collect();
+        let count = count.min(picks.len());
+        for i in 0..count {
+            let j = rng.usize(i..picks.len());
+            picks.swap(i, j);
+        }
+        let mut picks = picks[..count].to_vec();
+        picks.sort_unstable();
+        *notes = picks.into_iter().map(|i| notes[i]).collect();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Bordered table for reading in a terminal
+    Table,
+    /// Tab-separated values with a header row
+    Tsv,
+    /// Headerless records with configurable delimiters
+    Plain,
+    /// JSON array with one object per note
+    Json,
+    /// Headerless `ID<TAB>first line<TAB>tags` lines for fzf, ignoring --columns
+    Fzf,
+    /// Stable format for scripts, see --porcelain
+    Porcelain,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Column {
+    Id,
+    Title,
+    Content,
+    Tags,
+    Created,
+    Updated,
+    Meta,
+    Rating,
+    Due,
+    Status,
+}
+
+impl Column {
+    /// Header used in the table output
+    fn title(self) -> &'static str {
+        match self {
+            Column::Id => "ID",
+            Column::Title => "Title",
+            Column::Content => "Content",
+            Column::Tags => "Tags",
+            Column::Created => "Created at",
+            Column::Updated => "Update at",
+            Column::Meta => "Meta",
+            Column::Rating => "Rating",
+            Column::Due => "Due",
+            Column::Status => "Status",
+        }
+    }
+
+    /// Header used in machine-readable outputs
+    fn key(self) -> &'static str {
+        match self {
+            Column::Id => "id",
+            Column::Title => "title",
+            Column::Content => "content",
+            Column::Tags => "tags",
+            Column::Created => "created_at",
+            Column::Updated => "updated_at",
+            Column::Meta => "meta",
+            Column::Rating => "rating",
+            Column::Due => "due",
+            Column::Status => "status",
+        }
+    }
+}
+
+/// Columns shown when --columns isn't given
+const DEFAULT_COLUMNS: [Column; 5] = [
+    Column::Id,
+    Column::Content,
+    Column::Tags,
+    Column::Created,
+    Column::Updated,
+];
+
+// Output options shared by list and search
+#[derive(Args, Clone, Debug)]
+struct DisplayArgs {
+    /// Output format. In tsv, tabs, newlines, carriage returns and
+    /// backslashes inside fields are written as `\t`, `\n`, `\r` and `\\`
+    /// so every line is exactly one note
+    #[arg(long, value_enum, default_value = "table")]
+    format: OutputFormat,
+
+    /// Columns to display, comma separated, id,content,tags,created,updated
+    /// when not given. Without it, json prints the notes as stored
+    #[arg(long, value_enum, value_delimiter = ',')]
+    columns: Option<Vec<Column>>,
+
+    /// Field separator for the plain format. Accepts `\0`, `\t`, `\n`, `\r`,
+    /// `\\` or any literal string
+    #[arg(long, default_value = "\\t", value_parser = parse_delimiter)]
+    delimiter: String,
+
+    /// Separator written after each note in the plain format, with the same
+    /// escapes as --delimiter (use `\0` for `xargs -0`)
+    #[arg(long, default_value = "\\n", value_parser = parse_delimiter)]
+    record_delimiter: String,
+
+    /// Print only the IDs of the selected notes, one per line
+    #[arg(long)]
+    ids_only: bool,
+
+    /// Print one note per line as `id<TAB>content<TAB>tag1,tag2<TAB>created<TAB>updated`,
+    /// dates in RFC 3339 and fields escaped as in tsv. This format is stable
+    /// for scripts
+    #[arg(long, visible_alias = "plain", conflicts_with_all = ["format", "columns", "ids_only"])]
+    porcelain: bool,
+
+    /// Cut content lines in the table to this many columns
+    #[arg(long, default_value_t = DEFAULT_CONTENT_WIDTH, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    width: usize,
+
+    /// Show the whole content in the table instead of cutting long lines
+    #[arg(long, conflicts_with = "width")]
+    full: bool,
+
+    /// Wrap content in the table at this many columns instead of cutting it
+    #[arg(long, value_name = "WIDTH", conflicts_with_all = ["width", "full"], value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    wrap: Option<usize>,
+
+    /// Show dates in the table in UTC instead of the local time zone
+    #[arg(long)]
+    utc: bool,
+
+    /// Show dates in the table relative to now, e.g. "3 days ago"
+    #[arg(long, conflicts_with = "utc")]
+    relative: bool,
+
+    /// Exit with a non-zero status when no note is selected
+    #[arg(long)]
+    fail_if_empty: bool,
+
+    /// Show at most this many notes, 0 shows them all
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Skip this many notes first
+    #[arg(long, default_value_t = 0)]
+    offset: usize,
+
+    /// In a terminal, show the table this many notes at a time
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    page_size: Option<usize>,
+
+    /// Show this page of --per-page notes, counting from 1
+    #[arg(
+        long,
+        requires = "per_page",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    page: Option<usize>,
+
+    /// Notes per page for --page, which defaults to the first page
+    #[arg(
+        long,
+        conflicts_with_all = ["limit", "offset"],
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    per_page: Option<usize>,
+
+    /// Colors of tags in the table, `None` when output isn't colored
+    #[arg(skip)]
+    palette: Option<TagPalette>,
+
+    /// Replace emoji and symbols with plain text
+    #[arg(skip)]
+    no_emoji: bool,
+
+    /// strftime format of the dates in the table
+    #[arg(skip = DEFAULT_DATE_FORMAT.to_string())]
+    date_format: String,
+}
+
+impl DisplayArgs {
+    /// Columns to show, the default ones unless --columns was given
+    fn columns(&self) -> &[Column] {
+        self.columns.as_deref().unwrap_or(&DEFAULT_COLUMNS)
+    }
+
+    /// Formats a date for the table, in local time unless --utc or
+    /// --relative was given
+    fn format_date(&self, date: DateTime<Utc>) -> String {
+        if self.relative {
+            humanize_duration(Utc::now() - date)
+        } else if self.utc {
+            format_date_in(date, &Utc, &self.date_format)
+        } else {
+            format_date_in(date, &Local, &self.date_format)
+        }
+    }
+}
+
+impl Default for DisplayArgs {
+    fn default() -> Self {
+        DisplayArgs {
+            format: OutputFormat::Table,
+            columns: None,
+            delimiter: "\t".to_string(),
+            record_delimiter: "\n".to_string(),
+            ids_only: false,
+            porcelain: false,
+            width: DEFAULT_CONTENT_WIDTH,
+            full: false,
+            wrap: None,
+            utc: false,
+            relative: false,
+            fail_if_empty: false,
+            limit: None,
+            offset: 0,
+            page_size: None,
+            page: None,
+            per_page: None,
+            palette: None,
+            no_emoji: false,
+            date_format: DEFAULT_DATE_FORMAT.to_string(),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum MetaAction {
+    /// Set a metadata entry
+    Set {
+        /// note id
+        id: u32,
+        #[arg(value_parser = parse_meta_key)]
+        key: String,
+        value: String,
+    },
+    /// Remove a metadata entry
+    Unset {
+        /// note id
+        id: u32,
+        key: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum TrashAction {
+    /// List the notes in the trash, oldest removal first
+    List,
+    /// Delete the notes in the trash for good
+    Purge {
+        /// Only the notes removed longer ago than this (e.g. 30d, 12h)
+        #[arg(long, value_parser = parse_duration)]
+        older_than: Option<Duration>,
+    },
+}
+
+#[derive(Subcommand)]
+enum BackupAction {
+    /// Copy the notes to `<file>.bak.<timestamp>.json`
+    Create,
+    /// List the backups of the notes file, oldest first
+    List,
+    /// Replace the notes with a backup
+    Restore {
+        /// File name of the backup, as shown by `backup list`, or its path
+        name: String,
+
+        /// Restore even if the notes changed after the backup was made
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ShowFormat {
+    /// Every field of the notes, separated by a rule
+    Text,
+    /// JSON array of the stored notes
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum StatusFormat {
+    /// The summary line
+    Text,
+    /// A waybar custom module object with text, tooltip and class
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum CursorAction {
+    Reset,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum DateField {
+    Created,
+    Updated,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum CalendarFormat {
+    /// One shaded cell per day, weeks as columns
+    Grid,
+    /// Number of notes of each day as a JSON object
+    Json,
+}
+
+/// Format of dates in tables and show unless --date-format is given
+const DEFAULT_DATE_FORMAT: &str = "%d/%m/%Y - %H:%M";
+
+/// Width of the content column in the table unless --width or --full is given
+const DEFAULT_CONTENT_WIDTH: usize = 60;
+
+/// Age in days after which dates are dimmed in a colored table
+const RECENT_DAYS: i64 = 7;
+
+/// Shades of the calendar cells, from no note to the busiest days
+const CALENDAR_SHADES: [char; 5] = ['·', '░', '▒', '▓', '█'];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ExportFormat {
+    /// Emacs Org-mode, one heading per note
+    Org,
+    /// One row per note: id, content, tags joined with `;`, created_at, updated_at
+    Csv,
+    /// A `## Note <id>` section per note, or one file per note with --dir
+    Markdown,
+}
+
+/// Number of words of the first line used in Markdown file names
+const SLUG_WORDS: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ImportFormat {
+    Csv,
+    Json,
+    /// Notes file of note-cli, keeping IDs that are free and every field.
+    /// Guessed for `.json` files holding an object
+    Notes,
+    /// Output of `task export`
+    Taskwarrior,
+}
+
+/// Note fields an import can fill, the first one is required
+const IMPORT_FIELDS: [&str; 4] = ["content", "tags", "created_at", "updated_at"];
+
+/// Number of notes shown by `import --preview`
+const IMPORT_PREVIEW_COUNT: usize = 5;
+
+// Source column of each note field, fields not listed keep their own name
+#[derive(Clone, Debug, Default, Deserialize)]
+struct FieldMap(BTreeMap<String, String>);
+
+impl FieldMap {
+    /// Source column holding a note field
+    fn source<'a>(&'a self, field: &'a str) -> &'a str {
+        self.0.get(field).map_or(field, String::as_str)
+    }
+
+    /// Checks that only known note fields are mapped
+    fn validate(self) -> Result<Self, String> {
+        match self
+            .0
+            .keys()
+            .find(|field| !IMPORT_FIELDS.contains(&field.as_str()))
+        {
+            Some(field) => Err(format!(
+                "unknown note field '{}', expected one of: {}",
+                field,
+                IMPORT_FIELDS.join(", ")
+            )),
+            None => Ok(self),
+        }
+    }
+}
+
+/// Parses a field mapping such as `content=body,tags=labels`.
+///
+/// # Parameters
+/// - `raw: &str` - Mapping as typed by the user
+///
+/// # Returns
+/// `Result<FieldMap, String>` - Parsed mapping or a message for clap
+fn parse_field_map(raw: &str) -> Result<FieldMap, String> {
+    let mut map = BTreeMap::new();
+    for pair in raw.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let (field, source) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("expected field=column, got '{}'", pair))?;
+        map.insert(field.trim().to_string(), source.trim().to_string());
+    }
+    FieldMap(map).validate()
+}
+
+/// Base directory from an XDG variable, falling back to a directory below
+/// `$HOME`.
+///
+/// # Parameters
+/// - `var: &str` - Variable such as `XDG_CONFIG_HOME`
+/// - `fallback: &str` - Path relative to `$HOME` used when it isn't set
+///
+/// # Returns
+/// `Option<PathBuf>` - The directory, `None` without `$HOME`
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(fallback)))
+}
+
+/// Directory of the notebooks: `$XDG_DATA_HOME/note-cli`, falling back to
+/// `~/.local/share`, or to the application data directory of macOS and
+/// Windows.
+///
+/// # Returns
+/// `Option<PathBuf>` - The directory, `None` without a home directory
+fn notebooks_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| directories::BaseDirs::new().map(|dirs| dirs.data_dir().to_path_buf()))
+        .map(|dir| dir.join(NOTEBOOKS_DIR))
+}
+
+/// Default notes file, the `notes` notebook:
+/// `$XDG_DATA_HOME/note-cli/notes.json`.
+///
+/// # Returns
+/// `Option<PathBuf>` - The path, `None` without a home directory
+fn default_notes_path() -> Option<PathBuf> {
+    notebooks_dir().map(|dir| dir.join(format!("{}.json", DEFAULT_NOTEBOOK)))
+}
+
+/// File of a notebook in the notebooks directory.
+///
+/// # Parameters
+/// - `name: &str` - Notebook name, checked by [`parse_notebook_name`]
+///
+/// # Returns
+/// `NoteResult<String>` - The path or error without a data directory
+fn notebook_path(name: &str) -> NoteResult<String> {
+    let dir = notebooks_dir()
+        .ok_or_else(|| NoteError::from("cannot locate the data directory, set XDG_DATA_HOME"))?;
+    Ok(dir
+        .join(format!("{}.json", name))
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Checks a notebook name, which becomes a file name: letters, digits, `-`
+/// and `_`.
+///
+/// # Parameters
+/// - `raw: &str` - Name as given
+///
+/// # Returns
+/// `Result<String, String>` - The name or a message for clap
+fn parse_notebook_name(raw: &str) -> Result<String, String> {
+    let valid = raw
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if raw.is_empty() || raw.starts_with('-') || !valid {
+        return Err(format!(
+            "invalid notebook name '{}', use letters, digits, '-' and '_'",
+            raw
+        ));
+    }
+    Ok(raw.to_string())
+}
+
+/// Finds the notebooks of a directory with their number of notes, by name.
+/// Backups and the yearly shards of a notebook are left out.
+///
+/// # Parameters
+/// - `dir: &Path` - Notebooks directory
+///
+/// # Returns
+/// `NoteResult<Vec<(String, usize)>>` - Names and note counts
+fn list_notebooks(dir: &Path) -> NoteResult<Vec<(String, usize)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(StorageError::new(StorageOp::Read, dir, e).into()),
+    };
+
+    let mut notebooks = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| StorageError::new(StorageOp::Read, dir, e))?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(name) = name
+            .strip_suffix(".json")
+            .filter(|name| parse_notebook_name(name).is_ok() && path.is_file())
+        else {
+            continue;
+        };
+        let shard_of = name
+            .rsplit_once('-')
+            .filter(|(_, year)| year.len() == 4 && year.chars().all(|c| c.is_ascii_digit()))
+            .map(|(notebook, _)| dir.join(format!("{}.json", notebook)));
+        if shard_of.is_some_and(|index| is_sharded(&index.to_string_lossy())) {
+            continue;
+        }
+        let count = open_store(&path.to_string_lossy()).load()?.notes.len();
+        notebooks.push((name.to_string(), count));
+    }
+    notebooks.sort();
+    Ok(notebooks)
+}
+
+/// `--file` when it isn't given, `notes.json` in the current directory when
+/// there is no home directory.
+fn default_notes_file() -> String {
+    default_notes_path()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|| NOTES_PATH.to_string())
+}
+
+/// Expands a leading `~` of a path given on the command line, in
+/// `NOTE_CLI_FILE` or in the config, which the shell leaves alone when quoted.
+///
+/// # Parameters
+/// - `raw: &str` - Path as given
+///
+/// # Returns
+/// `Result<String, String>` - The path or a message for clap
+fn expand_home(raw: &str) -> Result<String, String> {
+    let Some(rest) = raw
+        .strip_prefix('~')
+        .filter(|rest| rest.is_empty() || rest.starts_with(['/', std::path::MAIN_SEPARATOR]))
+    else {
+        return Ok(raw.to_string());
+    };
+    let home = directories::BaseDirs::new()
+        .ok_or_else(|| format!("cannot expand '{}', no home directory found", raw))?;
+    Ok(format!("{}{}", home.home_dir().display(), rest))
+}
+
+/// Creates the directory holding `path` if it doesn't exist yet.
+///
+/// # Parameters
+/// - `path: &Path` - File about to be written
+///
+/// # Returns
+/// `NoteResult<()>` - Success or error if the directory cannot be created
+fn create_parent_dir(path: &Path) -> NoteResult<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.exists() => {
+            fs::create_dir_all(dir).map_err(|e| StorageError::new(StorageOp::Create, dir, e).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Previous contents kept per note when the config doesn't say
+const DEFAULT_HISTORY_LIMIT: usize = 10;
+
+// User settings read from the config file
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// Delete expired notes whenever the notes are opened
+    purge_expired: bool,
+
+    /// First day of the week for `--this-week`, e.g. "monday" or "sun"
+    week_start: Weekday,
+
+    /// Colors of tags, keyed by tag name or hierarchical prefix
+    tag_colors: BTreeMap<String, TagColor>,
+
+    /// Hide the notes marked as done from `list` unless `--done` is given
+    hide_done: bool,
+
+    /// Previous contents kept per note, 0 keeps none
+    history_limit: usize,
+
+    /// Rotating backups of the notes file kept, 0 keeps none
+    backups: usize,
+
+    /// Age after which `compact` deletes removed notes, e.g. "30d"
+    #[serde(deserialize_with = "deserialize_duration")]
+    trash_retention: Duration,
+
+    /// Notes file used when neither --file nor `NOTE_CLI_FILE` is given
+    #[serde(deserialize_with = "deserialize_notes_file")]
+    file: Option<String>,
+
+    /// Sort keys of `list` and `search` without --sort, e.g. "update,id"
+    #[serde(deserialize_with = "deserialize_sort_keys")]
+    default_sort: Vec<SortMethod>,
+
+    /// strftime format of dates without --date-format
+    #[serde(deserialize_with = "deserialize_date_format")]
+    date_format: Option<String>,
+
+    /// When to color the output without --color
+    color: Option<ColorChoice>,
+
+    /// Ask before removing notes, `false` removes them as with --yes
+    confirm_remove: bool,
+
+    /// Notebook used when neither --notebook nor a file is given
+    #[serde(deserialize_with = "deserialize_notebook")]
+    notebook: Option<String>,
+}
+
+/// Reads the `notebook` of the config, checked as --notebook.
+fn deserialize_notebook<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    parse_notebook_name(&raw)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Reads a duration written as for --older-than, e.g. "12h" or "30d".
+fn deserialize_duration<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    parse_duration(&raw).map_err(serde::de::Error::custom)
+}
+
+/// Reads the `file` of the config, expanding a leading `~`.
+fn deserialize_notes_file<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    expand_home(&raw)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Reads comma separated sort keys, written as for --sort.
+fn deserialize_sort_keys<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SortMethod>, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    raw.split(',')
+        .map(|key| {
+            SortMethod::from_str(key.trim(), true).map_err(|_| {
+                let names: Vec<_> = SortMethod::value_variants()
+                    .iter()
+                    .filter_map(|method| method.to_possible_value())
+                    .map(|value| value.get_name().to_string())
+                    .collect();
+                serde::de::Error::custom(format!(
+                    "unknown sort key '{}', expected {}",
+                    key.trim(),
+                    names.join(", ")
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Reads a strftime format, checked as --date-format.
+fn deserialize_date_format<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    parse_date_format(&raw)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            purge_expired: false,
+            week_start: Weekday::Mon,
+            tag_colors: BTreeMap::new(),
+            hide_done: false,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            backups: DEFAULT_BACKUPS,
+            trash_retention: Duration::days(DEFAULT_TRASH_RETENTION_DAYS),
+            file: None,
+            default_sort: Vec::new(),
+            date_format: None,
+            color: None,
+            confirm_remove: true,
+            notebook: None,
+        }
+    }
+}
+
+impl Config {
+    /// Path of the config file: `$XDG_CONFIG_HOME/note-cli/config.toml`,
+    /// falling back to `~/.config`
+    fn path() -> Option<PathBuf> {
+        xdg_dir("XDG_CONFIG_HOME", ".config").map(|dir| dir.join(CONFIG_FILE))
+    }
+
+    /// Loads the config file, a missing file gives the default settings
+    /// unless it was given with --config.
+    ///
+    /// # Parameters
+    /// - `given: Option<&Path>` - Value of --config
+    ///
+    /// # Returns
+    /// `NoteResult<Config>` - Settings or error if the file is invalid
+    fn load_or_default(given: Option<&Path>) -> NoteResult<Config> {
+        let Some(path) = given.map(Path::to_path_buf).or_else(Config::path) else {
+            return Ok(Config::default());
+        };
+        match fs::read_to_string(&path) {
+            Ok(content) => Config::parse(&path, &content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && given.is_none() => {
+                Ok(Config::default())
+            }
+            Err(e) => Err(StorageError::new(StorageOp::Read, &path, e).into()),
+        }
+    }
+
+    /// Parses the content of a config file. Errors name the line and the
+    /// key they were found at.
+    ///
+    /// # Parameters
+    /// - `path: &Path` - Config file, for the error message
+    /// - `content: &str` - TOML text
+    ///
+    /// # Returns
+    /// `NoteResult<Config>` - Settings or error if the content is invalid
+    fn parse(path: &Path, content: &str) -> NoteResult<Config> {
+        let e = match toml::from_str(content) {
+            Ok(config) => return Ok(config),
+            Err(e) => e,
+        };
+        let Some(span) = e.span() else {
+            return Err(format!("invalid config {}: {}", path.display(), e.message()).into());
+        };
+        let start = span.start.min(content.len());
+        let line = content[..start].matches('\n').count() + 1;
+        // the key of the line, prefixed with the table it belongs to
+        let mut table = String::new();
+        for text in content.lines().take(line - 1) {
+            let text = text.trim();
+            if text.starts_with('[') {
+                table = text.trim_matches(['[', ']']).trim().to_string();
+            }
+        }
+        let text = content.lines().nth(line - 1).unwrap_or_default();
+        let key = text.split('=').next().unwrap_or_default().trim();
+        let key = match (table.is_empty(), key.starts_with('[')) {
+            (_, true) => key.trim_matches(['[', ']']).trim().to_string(),
+            (true, false) => key.to_string(),
+            (false, false) => format!("{}.{}", table, key),
+        };
+        Err(format!(
+            "invalid config {}, line {}, key `{}`: {}",
+            path.display(),
+            line,
+            key,
+            e.message()
+        )
+        .into())
+    }
+}
+
+// Named colors accepted in `[tag_colors]`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TagColor {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    Dim,
+    Bold,
+}
+
+impl TagColor {
+    /// Colors given to tags without a configured color
+    const AUTOMATIC: [TagColor; 6] = [
+        TagColor::Red,
+        TagColor::Green,
+        TagColor::Yellow,
+        TagColor::Blue,
+        TagColor::Magenta,
+        TagColor::Cyan,
+    ];
+
+    /// ANSI SGR code of the color
+    fn ansi_code(self) -> u8 {
+        match self {
+            TagColor::Black => 30,
+            TagColor::Red => 31,
+            TagColor::Green => 32,
+            TagColor::Yellow => 33,
+            TagColor::Blue => 34,
+            TagColor::Magenta => 35,
+            TagColor::Cyan => 36,
+            TagColor::White => 37,
+            TagColor::Dim => 2,
+            TagColor::Bold => 1,
+        }
+    }
+
+    /// Wraps text in the escape sequences of the color
+    fn paint(self, text: &str) -> String {
+        format!("\x1b[{}m{}\x1b[0m", self.ansi_code(), text)
+    }
+}
+
+// Color of each tag: configured colors first, then a color picked from the
+// tag name so a tag keeps the same color between runs
+#[derive(Clone, Debug, Default)]
+struct TagPalette {
+    configured: BTreeMap<String, TagColor>,
+}
+
+impl TagPalette {
+    /// Color of a tag. A configured name also covers its hierarchical
+    /// children (`project` colors `project/web`), the longest match wins.
+    ///
+    /// # Parameters
+    /// - `tag: &str` - Tag to color
+    ///
+    /// # Returns
+    /// `(TagColor, bool)` - Color and whether it comes from the config
+    fn color(&self, tag: &str) -> (TagColor, bool) {
+        let configured = self
+            .configured
+            .iter()
+            .filter(|(name, _)| {
+                tag == name.as_str()
+                    || tag
+                        .strip_prefix(name.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+            .max_by_key(|(name, _)| name.len());
+        if let Some((_, color)) = configured {
+            return (*color, true);
+        }
+
+        // FNV-1a, stable across runs and platforms
+        let hash = tag.bytes().fold(0x811c9dc5u32, |hash, byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+        });
+        let automatic = TagColor::AUTOMATIC;
+        (automatic[hash as usize % automatic.len()], false)
+    }
+
+    /// Renders a tag in its color
+    fn paint(&self, tag: &str) -> String {
+        self.color(tag).0.paint(tag)
+    }
+}
+
+/// Reads the content of a note, e.g. piped to `add -` or from a file. One trailing
+/// newline is removed, other newlines are kept.
+///
+/// # Parameters
+/// - `input: impl Read` - Where to read the content
+///
+/// # Returns
+/// `NoteResult<String>` - Content, an error when there is none
+fn read_content(mut input: impl std::io::Read) -> NoteResult<String> {
+    let mut content = String::new();
+    input.read_to_string(&mut content)?;
+    let content = content
+        .strip_suffix("\r\n")
+        .or_else(|| content.strip_suffix('\n'))
+        .unwrap_or(&content);
+    if content.trim().is_empty() {
+        return Err("no content given, nothing added".into());
+    }
+    Ok(content.to_string())
+}
+
+/// Content of a new note: the argument, or what `-`, --stdin or
+/// --from-file point to.
+///
+/// # Parameters
+/// - `content: Option<String>` - Content argument
+/// - `stdin: bool` - Read the content from stdin
+/// - `from_file: Option<&Path>` - File to read the content from
+///
+/// # Returns
+/// `NoteResult<String>` - Content of the note
+fn add_content(
+    content: Option<String>,
+    stdin: bool,
+    from_file: Option<&Path>,
+) -> NoteResult<String> {
+    match (content, from_file) {
+        (_, Some(path)) => {
+            let file =
+                fs::File::open(path).map_err(|e| StorageError::new(StorageOp::Read, path, e))?;
+            Ok(read_content(file).map_err(|e| format!("{}: {}", path.display(), e))?)
+        }
+        (Some(content), None) if !stdin && content != "-" => Ok(content),
+        _ => read_content(std::io::stdin().lock()),
+    }
+}
+
+/// Tells whether both stdin and stdout are terminals, so the user can be
+/// prompted.
+fn interactive() -> bool {
+    use std::io::IsTerminal;
+    std::io::stdin().is_terminal() && std::io::stdout().is_terminal()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Tells whether output to stdout should be colored. In auto mode, stdout
+/// must be a terminal and `NO_COLOR` unset.
+///
+/// # Parameters
+/// - `choice: ColorChoice` - Value of --color
+///
+/// # Returns
+/// `bool` - Whether to color the output
+fn color_enabled(choice: ColorChoice) -> bool {
+    use std::io::IsTerminal;
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::io::stdout().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        }
+    }
+}
+
+// Filters shared by list and search
+#[derive(Args, Clone, Debug, Default)]
+struct FilterArgs {
+    /// Only notes with this tag (repeatable, all must match)
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+
+    /// Only notes created at or after this date (YYYY-MM-DD or RFC 3339, UTC)
+    #[arg(long, value_parser = parse_date)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only notes created before the end of this date (YYYY-MM-DD or RFC 3339, UTC)
+    #[arg(long, value_parser = parse_date_end)]
+    until: Option<DateTime<Utc>>,
+
+    /// Only notes created today, in local time
+    #[arg(long, conflicts_with_all = ["since", "until", "yesterday", "this_week"])]
+    today: bool,
+
+    /// Only notes created yesterday, in local time
+    #[arg(long, conflicts_with_all = ["since", "until", "this_week"])]
+    yesterday: bool,
+
+    /// Only notes created this week, in local time
+    #[arg(long, conflicts_with_all = ["since", "until"])]
+    this_week: bool,
+
+    /// Also show notes past their expiry date
+    #[arg(long)]
+    expired: bool,
+
+    /// Only archived notes
+    #[arg(long, conflicts_with = "with_archived")]
+    archived: bool,
+
+    /// Show archived notes along with the others
+    #[arg(long = "all")]
+    with_archived: bool,
+
+    /// Only notes with this metadata KEY, or KEY=VALUE for an exact value
+    /// (repeatable)
+    #[arg(long = "meta", value_parser = parse_meta_filter)]
+    meta: Vec<(String, Option<String>)>,
+
+    /// Only notes rated at least this
+    #[arg(long, value_parser = parse_rating())]
+    min_rating: Option<u8>,
+
+    /// Only notes due before this date (YYYY-MM-DD or RFC 3339, UTC)
+    #[arg(long, value_parser = parse_date)]
+    due_before: Option<DateTime<Utc>>,
+
+    /// Only notes whose due date has passed
+    #[arg(long)]
+    overdue: bool,
+
+    /// Only notes marked as done
+    #[arg(long, conflicts_with = "pending")]
+    done: bool,
+
+    /// Only notes not marked as done
+    #[arg(long)]
+    pending: bool,
+
+    /// Only notes with at least this many characters
+    #[arg(long)]
+    min_length: Option<usize>,
+
+    /// Only notes with at most this many characters
+    #[arg(long)]
+    max_length: Option<usize>,
+}
+
+impl FilterArgs {
+    /// Tells whether a note passes every filter
+    fn matches(&self, note: &Note) -> bool {
+        self.tags.iter().all(|tag| note.tags.contains(tag))
+            && self.since.is_none_or(|since| note.created_at >= since)
+            && self.until.is_none_or(|until| note.created_at < until)
+            && (self.expired || !note.is_expired(Utc::now()))
+            && (self.with_archived || self.archived == note.archived)
+            && self.meta.iter().all(|(key, value)| match value {
+                Some(value) => note.meta.get(key) == Some(value),
+                None => note.meta.contains_key(key),
+            })
+            && self
+                .min_rating
+                .is_none_or(|min| note.rating.is_some_and(|rating| rating >= min))
+            && self.matches_length(note)
+            && self.matches_due(note, Utc::now())
+            && (!self.done || note.done)
+            && (!self.pending || !note.done)
+    }
+
+    /// Tells whether the due date passes `--due-before` and `--overdue`
+    fn matches_due(&self, note: &Note, now: DateTime<Utc>) -> bool {
+        let due = note_due(note);
+        self.due_before
+            .is_none_or(|before| due.is_some_and(|due| due < before))
+            && (!self.overdue || due.is_some_and(|due| due < now))
+    }
+
+    /// Tells whether the content length, in characters, is within bounds
+    fn matches_length(&self, note: &Note) -> bool {
+        if self.min_length.is_none() && self.max_length.is_none() {
+            return true;
+        }
+        let length = note.content.chars().count();
+        self.min_length.is_none_or(|min| length >= min)
+            && self.max_length.is_none_or(|max| length <= max)
+    }
+
+    /// Turns `--today`, `--yesterday` and `--this-week` into `since` and
+    /// `until`. Day boundaries are midnights in the time zone of `now`.
+    ///
+    /// # Parameters
+    /// - `now: DateTime<Tz>` - Current time in the user's time zone
+    /// - `week_start: Weekday` - First day of the week
+    fn resolve_shortcuts<Tz: TimeZone>(&mut self, now: DateTime<Tz>, week_start: Weekday) {
+        let today = now.date_naive();
+        let (first, days) = if self.today {
+            (today, 1)
+        } else if self.yesterday {
+            (today - Duration::days(1), 1)
+        } else if self.this_week {
+            let back = (7 + today.weekday().num_days_from_monday()
+                - week_start.num_days_from_monday())
+                % 7;
+            (today - Duration::days(i64::from(back)), 7)
+        } else {
+            return;
+        };
+
+        let zone = now.timezone();
+        self.since = Some(local_midnight(&zone, first));
+        self.until = Some(local_midnight(&zone, first + Duration::days(days)));
+    }
+
+    /// Checks the combinations of filters clap can't express
+    fn validate(&self) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.min_length, self.max_length)
+            && min > max
+        {
+            return Err(format!(
+                "--min-length {} is greater than --max-length {}",
+                min, max
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Parser of ratings, rejecting values outside 1 to 5
+fn parse_rating() -> clap::builder::RangedI64ValueParser<u8> {
+    clap::value_parser!(u8).range(1..=5)
+}
+
+/// Tells whether the terminal locale can display UTF-8 symbols, judging
+/// from the first of `LC_ALL`, `LC_CTYPE` and `LANG` that is set.
+fn utf8_locale() -> bool {
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .is_none_or(|value| {
+            let value = value.to_lowercase();
+            value.contains("utf-8") || value.contains("utf8")
+        })
+}
+
+/// Checks a metadata key: not empty, without `=` or whitespace.
+///
+/// # Parameters
+/// - `raw: &str` - Key as typed by the user
+///
+/// # Returns
+/// `Result<String, String>` - The key or a message for clap
+fn parse_meta_key(raw: &str) -> Result<String, String> {
+    if raw.is_empty() {
+        Err("metadata key is empty".to_string())
+    } else if raw.contains('=') || raw.chars().any(char::is_whitespace) {
+        Err(format!(
+            "invalid metadata key '{}', keys can't contain '=' or whitespace",
+            raw
+        ))
+    } else {
+        Ok(raw.to_string())
+    }
+}
+
+/// Parses a `KEY=VALUE` metadata entry, the value may contain `=`.
+fn parse_meta_entry(raw: &str) -> Result<(String, String), String> {
+    let (key, value) = raw
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", raw))?;
+    Ok((parse_meta_key(key)?, value.to_string()))
+}
+
+/// Parses a metadata filter, `KEY` or `KEY=VALUE`.
+fn parse_meta_filter(raw: &str) -> Result<(String, Option<String>), String> {
+    match raw.split_once('=') {
+        Some((key, value)) => Ok((parse_meta_key(key)?, Some(value.to_string()))),
+        None => Ok((parse_meta_key(raw)?, None)),
+    }
+}
+
+/// Start of a day in a time zone, as UTC. When a DST change skips midnight
+/// the day starts at the first instant after the gap.
+fn local_midnight<Tz: TimeZone>(zone: &Tz, date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    (0..24)
+        .find_map(|hour| {
+            zone.from_local_datetime(&(midnight + Duration::hours(hour)))
+                .earliest()
+        })
+        .map_or_else(|| midnight.and_utc(), |start| start.with_timezone(&Utc))
+}
+
+/// Parses a date given as `YYYY-MM-DD` (midnight UTC) or RFC 3339.
+///
+/// # Parameters
+/// - `raw: &str` - Date as typed by the user
+///
+/// # Returns
+/// `Result<DateTime<Utc>, String>` - Parsed instant or a message for clap
+fn parse_date(raw: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(raw, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+    DateTime::parse_from_rfc3339(raw)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| format!("invalid date '{}', expected YYYY-MM-DD or RFC 3339", raw))
+}
+
+/// Parses the end of a date range: a bare `YYYY-MM-DD` covers the whole day,
+/// so the returned instant is the following midnight.
+///
+/// # Parameters
+/// - `raw: &str` - Date as typed by the user
+///
+/// # Returns
+/// `Result<DateTime<Utc>, String>` - Exclusive upper bound or a message for clap
+fn parse_date_end(raw: &str) -> Result<DateTime<Utc>, String> {
+    let end = parse_date(raw)?;
+    if NaiveDate::parse_from_str(raw, "%Y-%m-%d").is_ok() {
+        Ok(end + Duration::days(1))
+    } else {
+        Ok(end)
+    }
+}
+
+/// Checks a strftime format given on the command line.
+///
+/// # Parameters
+/// - `raw: &str` - Format as typed by the user
+///
+/// # Returns
+/// `Result<String, String>` - The format or a message for clap
+fn parse_date_format(raw: &str) -> Result<String, String> {
+    use chrono::format::{Item, StrftimeItems};
+
+    if StrftimeItems::new(raw).any(|item| item == Item::Error) {
+        return Err(format!(
+            "invalid date format '{}', see the chrono strftime specifiers",
+            raw
+        ));
+    }
+    Ok(raw.to_string())
+}
+
+/// Parses a delimiter given on the command line, expanding escapes.
+///
+/// # Parameters
+/// - `raw: &str` - Delimiter as typed by the user
+///
+/// # Returns
+/// `Result<String, String>` - Expanded delimiter or a message for clap
+fn parse_delimiter(raw: &str) -> Result<String, String> {
+    let expanded = match raw {
+        "\\0" => "\0".to_string(),
+        "\\t" => "\t".to_string(),
+        "\\n" => "\n".to_string(),
+        "\\r" => "\r".to_string(),
+        "\\\\" => "\\".to_string(),
+        other => other.to_string(),
+    };
+    if expanded.is_empty() {
+        Err("delimiter cannot be empty".to_string())
+    } else {
+        Ok(expanded)
+    }
+}
+
+/// Parses a duration such as `30s`, `15m`, `1h`, `2d`, `1w` or `1h30m`.
+///
+/// # Parameters
+/// - `raw: &str` - Duration as typed by the user
+///
+/// # Returns
+/// `Result<Duration, String>` - Parsed duration or a message for clap
+fn parse_duration(raw: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration '{}', expected e.g. 30m, 12h or 2d", raw);
+    let mut total = Duration::zero();
+    let mut digits = String::new();
+
+    for c in raw.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let amount: i64 = digits.parse().map_err(|_| invalid())?;
+        let unit = match c {
+            's' => Duration::seconds(amount),
+            'm' => Duration::minutes(amount),
+            'h' => Duration::hours(amount),
+            'd' => Duration::days(amount),
+            'w' => Duration::weeks(amount),
+            _ => return Err(invalid()),
+        };
+        total += unit;
+        digits.clear();
+    }
+
+    if !digits.is_empty() || total.is_zero() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+// Struct for a single note
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+struct Note {
+    id: u32,
+    content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>, // left out of the file when unset, as older versions expect
+    tags: Vec<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    due: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    meta: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rating: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_offset: Option<i32>, // seconds east of UTC where the note was written
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    archived: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    done: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pinned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime<Utc>>, // set while the note is in the trash
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<Revision>, // previous contents, oldest first
+}
+
+// Content a note had before an edit
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+struct Revision {
+    content: String,
+    edited_at: DateTime<Utc>, // when this content was replaced
+}
+
+impl Note {
+    /// Creation or last update date of the note
+    fn date(&self, field: DateField) -> DateTime<Utc> {
+        match field {
+            DateField::Created => self.created_at,
+            DateField::Updated => self.updated_at,
+        }
+    }
+
+    /// Replaces the content, keeping the old one in the history. Only the
+    /// `limit` latest revisions are kept.
+    fn replace_content(&mut self, content: String, now: DateTime<Utc>, limit: usize) {
+        let old = std::mem::replace(&mut self.content, content);
+        self.history.push(Revision {
+            content: old,
+            edited_at: now,
+        });
+        let excess = self.history.len().saturating_sub(limit);
+        self.history.drain(..excess);
+    }
+
+    /// Tells whether the note's expiry date has passed at `now`
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Format version of the notes file written by this note-cli
+const NOTES_VERSION: u32 = 1;
+
+// Struct of json file
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+struct NoteData {
+    #[serde(default = "first_version")]
+    version: u32, // format of the file, see `migrate`
+    notes: Vec<Note>,
+    free_ids: Vec<u32>, // to give ids to new note and keep clear
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    trash: Vec<Note>, // removed notes, newest last, until restored
+}
+
+/// Version of the files written before the format had a version
+fn first_version() -> u32 {
+    1
+}
+
+/// Brings the content of a notes file to the current format:
+/// - version 0, a bare array of notes without free IDs
+/// - version 1, the object with `notes` and `free_ids`, the current format
+///
+/// # Parameters
+/// - `raw: serde_json::Value` - Parsed content of the file
+///
+/// # Returns
+/// `Result<NoteData, serde_json::Error>` - Note data in the current format,
+/// or error when the content matches no version
+fn migrate(raw: serde_json::Value) -> Result<NoteData, serde_json::Error> {
+    let mut data = match raw {
+        serde_json::Value::Array(_) => NoteData {
+            version: 0,
+            notes: serde_json::from_value(raw)?,
+            free_ids: vec![],
+            trash: vec![],
+        },
+        raw => serde_json::from_value(raw)?,
+    };
+    data.version = NOTES_VERSION;
+    Ok(data)
+}
+
+// Struct of meta.json in a notes directory
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct DirMeta {
+    free_ids: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    trash: Vec<Note>,
+}
+
+/// Deletes all the notes, the trash included, after the user typed `yes`
+/// unless `yes` is set.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `yes: bool` - Don't ask for confirmation
+/// - `interactive: bool` - Whether the user can be asked
+/// - `input: &mut impl BufRead` - Where the answer is read
+/// - `output: &mut impl Write` - Where the question is written
+///
+/// # Returns
+/// `NoteResult<Option<usize>>` - Number of notes deleted, `None` if the user
+/// didn't confirm, or error when confirmation can't be asked or I/O fails
+fn clean_notes(
+    store: &dyn NoteStore,
+    yes: bool,
+    interactive: bool,
+    input: &mut impl std::io::BufRead,
+    output: &mut impl std::io::Write,
+) -> NoteResult<Option<usize>> {
+    let data = store.load()?;
+    let count = data.notes.len();
+    if !yes {
+        if !interactive {
+            return Err("refusing to clear the notes without --yes".into());
+        }
+        let question = format!("Delete all {} notes? Type yes to confirm: ", count);
+        if prompt_line(input, output, &question)?.as_deref() != Some("yes") {
+            return Ok(None);
+        }
+    }
+    with_store(store, |data| {
+        let count = data.notes.len();
+        *data = NoteData {
+            version: NOTES_VERSION,
+            notes: vec![],
+            free_ids: vec![],
+            trash: vec![],
+        };
+        Ok(Some(count))
+    })
+}
+
+/// Storage operation that failed, used to give context to I/O errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StorageOp {
+    Read,
+    Create,
+    Write,
+    Remove,
+}
+
+impl std::fmt::Display for StorageOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verb = match self {
+            StorageOp::Read => "read",
+            StorageOp::Create => "create",
+            StorageOp::Write => "write",
+            StorageOp::Remove => "remove",
+        };
+        f.write_str(verb)
+    }
+}
+
+/// Error raised while reading or writing the notes storage, carrying the
+/// operation and the absolute path involved.
+#[derive(Debug)]
+struct StorageError {
+    op: StorageOp,
+    path: PathBuf,
+    source: Box<dyn std::error::Error>,
+}
+
+impl StorageError {
+    /// Builds a storage error, resolving `path` to an absolute path.
+    fn new(
+        op: StorageOp,
+        path: &Path,
+        source: impl Into<Box<dyn std::error::Error>>,
+    ) -> StorageError {
+        StorageError {
+            op,
+            path: std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+            source: source.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cannot {} {}: {}",
+            self.op,
+            self.path.display(),
+            self.source
+        )
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// Number of lines shown around the error position of a malformed file
+const PARSE_CONTEXT_LINES: usize = 2;
+
+/// Error raised when a notes file is not valid JSON for `NoteData`,
+/// rendered with the offending lines and a caret under the error position.
+#[derive(Debug)]
+struct ParseDiagnostic {
+    path: PathBuf,
+    snippet: String,
+    source: serde_json::Error,
+}
+
+impl ParseDiagnostic {
+    /// Builds a diagnostic from the file content and the serde error.
+    fn new(path: &Path, content: &str, source: serde_json::Error) -> ParseDiagnostic {
+        let line = source.line().max(1);
+        let column = source.column().max(1);
+        let lines: Vec<&str> = content.lines().collect();
+        let first = line.saturating_sub(PARSE_CONTEXT_LINES).max(1);
+        let last = (line + PARSE_CONTEXT_LINES).min(lines.len().max(line));
+        let width = last.to_string().len();
+
+        let mut snippet = String::new();
+        for number in first..=last {
+            let text = lines.get(number - 1).copied().unwrap_or("");
+            snippet.push_str(&format!("{:>width$} | {}\n", number, text));
+            if number == line {
+                // mirror tabs so the caret lines up with the error column
+                let pad: String = text
+                    .chars()
+                    .take(column - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                snippet.push_str(&format!("{:>width$} | {}^\n", "", pad));
+            }
+        }
+
+        ParseDiagnostic {
+            path: std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+            snippet,
+            source,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // serde appends the position, which is already in the header
+        let message = self.source.to_string();
+        let position = format!(
+            " at line {} column {}",
+            self.source.line(),
+            self.source.column()
+        );
+        writeln!(
+            f,
+            "cannot parse {}:{}:{}: {}",
+            self.path.display(),
+            self.source.line(),
+            self.source.column(),
+            message.strip_suffix(&position).unwrap_or(&message)
+        )?;
+        write!(f, "{}", self.snippet)?;
+        write!(
+            f,
+            "hint: fix the JSON by hand, restore a backup copy of the file or run again \
+             with --recover to move it aside and start with no notes"
+        )
+    }
+}
+
+impl std::error::Error for ParseDiagnostic {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Error of any command, the variant decides the exit status.
+#[derive(Debug)]
+enum NoteError {
+    /// Note IDs that don't exist
+    NotFound(Vec<u32>),
+    /// A notes file that is not valid JSON
+    Parse(ParseDiagnostic),
+    /// Failure to read or write a file
+    Storage(StorageError),
+    /// Failure of the terminal or a child process
+    Io(std::io::Error),
+    /// Arguments or data the command can't work with
+    InvalidInput(String),
+    /// Error of a library parsing or rendering data
+    Other(Box<dyn std::error::Error>),
+}
+
+impl NoteError {
+    /// Exit status reported for the error
+    fn exit_code(&self) -> ExitCode {
+        match self {
+            NoteError::NotFound(_) => ExitCode::from(EXIT_NOT_FOUND),
+            NoteError::Parse(_) => ExitCode::from(EXIT_CORRUPT),
+            _ => ExitCode::FAILURE,
+        }
+    }
+}
+
+impl std::fmt::Display for NoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NoteError::NotFound(ids) => {
+                let list: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+                let label = if ids.len() == 1 { "ID" } else { "IDs" };
+                write!(f, "{} {} not found", label, list.join(", "))
+            }
+            NoteError::Parse(e) => e.fmt(f),
+            NoteError::Storage(e) => e.fmt(f),
+            NoteError::Io(e) => e.fmt(f),
+            NoteError::InvalidInput(message) => f.write_str(message),
+            NoteError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for NoteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NoteError::Parse(e) => e.source(),
+            NoteError::Storage(e) => e.source(),
+            NoteError::Io(e) => e.source(),
+            NoteError::Other(e) => e.source(),
+            NoteError::NotFound(_) | NoteError::InvalidInput(_) => None,
+        }
+    }
+}
+
+impl From<String> for NoteError {
+    fn from(message: String) -> NoteError {
+        NoteError::InvalidInput(message)
+    }
+}
+
+impl From<&str> for NoteError {
+    fn from(message: &str) -> NoteError {
+        NoteError::InvalidInput(message.to_string())
+    }
+}
+
+impl From<ParseDiagnostic> for NoteError {
+    fn from(e: ParseDiagnostic) -> NoteError {
+        NoteError::Parse(e)
+    }
+}
+
+impl From<StorageError> for NoteError {
+    fn from(e: StorageError) -> NoteError {
+        NoteError::Storage(e)
+    }
+}
+
+impl From<std::io::Error> for NoteError {
+    fn from(e: std::io::Error) -> NoteError {
+        NoteError::Io(e)
+    }
+}
+
+// Library errors with nothing the exit status depends on
+macro_rules! other_note_errors {
+    ($($error:ty),*) => {
+        $(impl From<$error> for NoteError {
+            fn from(e: $error) -> NoteError {
+                NoteError::Other(Box::new(e))
+            }
+        })*
+    };
+}
+
+other_note_errors!(
+    serde_json::Error,
+    csv::Error,
+    csv::IntoInnerError<csv::Writer<Vec<u8>>>,
+    toml::de::Error,
+    regex::Error,
+    ctrlc::Error,
+    clap::Error,
+    std::string::FromUtf8Error
+);
+
+/// Loads note data from a JSON file, returns empty data if file doesn't exist.
+///
+/// # Parameters
+/// - `path: &str` - File path to read from
+///
+/// # Returns
+/// `NoteResult<NoteData>` - Loaded note data or error
+fn load_notes(path: &str) -> NoteResult<NoteData> {
+    use std::io::ErrorKind;
+
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            if content.trim().is_empty() {
+                Ok(NoteData {
+                    version: NOTES_VERSION,
+                    notes: vec![],
+                    free_ids: vec![],
+                    trash: vec![],
+                }) // empty file = no notes
+            } else {
+                let diagnostic = |e| ParseDiagnostic::new(Path::new(path), &content, e);
+                let raw: serde_json::Value = serde_json::from_str(&content).map_err(diagnostic)?;
+                let version = match &raw {
+                    serde_json::Value::Array(_) => 0,
+                    raw => raw
+                        .get("version")
+                        .and_then(serde_json::Value::as_u64)
+                        .unwrap_or(u64::from(first_version())),
+                };
+                if version > u64::from(NOTES_VERSION) {
+                    return Err(format!(
+                        "{} is in format version {}, this note-cli reads up to version {}: please upgrade note-cli",
+                        path, version, NOTES_VERSION
+                    )
+                    .into());
+                }
+                let mut data = match version == u64::from(NOTES_VERSION) {
+                    // parsed from the text, so that errors point at their line
+                    true => serde_json::from_str(&content).map_err(diagnostic)?,
+                    false => migrate(raw).map_err(diagnostic)?,
+                };
+                sanitize_loaded_tags(&mut data.notes, Path::new(path));
+                Ok(data)
+            }
+        }
+
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(NoteData {
+            version: NOTES_VERSION,
+            notes: vec![],
+            free_ids: vec![],
+            trash: vec![],
+        }),
+
+        Err(e) => Err(StorageError::new(StorageOp::Read, Path::new(path), e).into()),
+    }
+}
+
+/// Cleans the tags of notes written by older versions or by hand: trims
+/// them, drops empty ones and duplicates, keeping the first occurrence.
+///
+/// # Parameters
+/// - `notes: &mut [Note]` - Notes to clean in place
+///
+/// # Returns
+/// `usize` - Number of tags trimmed or dropped
+fn normalize_tags(notes: &mut [Note]) -> usize {
+    let mut fixed = 0;
+    for note in notes {
+        let mut tags: Vec<String> = Vec::with_capacity(note.tags.len());
+        for tag in &note.tags {
+            let trimmed = tag.trim();
+            if trimmed.is_empty() || tags.iter().any(|kept| kept == trimmed) {
+                fixed += 1;
+            } else {
+                fixed += usize::from(trimmed.len() != tag.len());
+                tags.push(trimmed.to_string());
+            }
+        }
+        note.tags = tags;
+    }
+    fixed
+}
+
+/// Normalizes the tags of freshly loaded notes and reports the count under
+/// `--verbose`. The file itself is only cleaned by the next save.
+fn sanitize_loaded_tags(notes: &mut [Note], source: &Path) {
+    let fixed = normalize_tags(notes);
+    if fixed > 0 && VERBOSE.load(Ordering::Relaxed) {
+        eprintln!("normalized {} tags loaded from {}", fixed, source.display());
+    }
+}
+
+/// Saves note data to a JSON file with pretty formatting.
+///
+/// # Parameters
+/// - `path: &str` - File path to write to
+/// - `notes: &NoteData` - Note data to serialize
+///
+/// # Returns
+/// `NoteResult<()>` - Success or serialization/I/O error
+fn save_notes(path: &str, notes: &NoteData) -> NoteResult<()> {
+    write_atomic(Path::new(path), |writer| {
+        serde_json::to_writer_pretty(writer, notes)?;
+        Ok(())
+    })
+}
+
+/// Writes a file through a temporary file in the same directory, synced
+/// and then renamed over `path`, so that the file holds either its old or
+/// its new content, never a part of it. The permissions of an existing file
+/// are kept and the temporary file is removed on error.
+///
+/// # Parameters
+/// - `path: &Path` - File to write
+/// - `write: impl FnOnce(&mut dyn Write) -> NoteResult<()>` - Writes the
+///   new content
+///
+/// # Returns
+/// `NoteResult<()>` - Success or error naming `path`
+fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut dyn std::io::Write) -> NoteResult<()>,
+) -> NoteResult<()> {
+    use std::io::Write;
+
+    let error = |e: Box<dyn std::error::Error>| StorageError::new(StorageOp::Write, path, e);
+    create_parent_dir(path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    // dropping the file on error deletes it
+    let file = tempfile::Builder::new()
+        .prefix(&format!(".{}.", name))
+        .suffix(".tmp")
+        .tempfile_in(dir)
+        .map_err(|e| error(e.into()))?;
+
+    let mut writer = std::io::BufWriter::new(file);
+    write(&mut writer).map_err(|e| error(Box::new(e)))?;
+    writer.flush().map_err(|e| error(e.into()))?;
+    let file = writer
+        .into_inner()
+        .map_err(|e| error(e.into_error().into()))?;
+    if let Ok(meta) = fs::metadata(path) {
+        file.as_file()
+            .set_permissions(meta.permissions())
+            .map_err(|e| error(e.into()))?;
+    }
+    file.as_file().sync_all().map_err(|e| error(e.into()))?;
+    file.persist(path).map_err(|e| error(e.error.into()))?;
+    Ok(())
+}
+
+/// Tells whether a `--file` path designates a directory of notes rather
+/// than a single JSON file.
+///
+/// # Parameters
+/// - `path: &str` - Path given on the command line
+///
+/// # Returns
+/// `bool` - True for an existing directory or a path ending with a separator
+fn is_notes_dir(path: &str) -> bool {
+    path.ends_with('/') || path.ends_with(std::path::MAIN_SEPARATOR) || Path::new(path).is_dir()
+}
+
+/// File name of a note in a notes directory, e.g. `0042.json`.
+fn note_file_name(id: u32) -> String {
+    format!("{:04}.json", id)
+}
+
+/// Extracts the note ID from a file of a notes directory, ignoring any
+/// file that is not named like `0042.json`.
+fn note_file_id(path: &Path) -> Option<u32> {
+    if path.extension()? != "json" {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    if stem.chars().all(|c| c.is_ascii_digit()) {
+        stem.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Writes a file only when its content differs, so unchanged notes keep
+/// their file untouched.
+fn write_if_changed(path: &Path, content: &str) -> NoteResult<()> {
+    if fs::read_to_string(path).ok().as_deref() != Some(content) {
+        write_atomic(path, |writer| Ok(writer.write_all(content.as_bytes())?))?;
+    }
+    Ok(())
+}
+
+/// Loads note data from a directory holding one JSON file per note and a
+/// `meta.json` for free IDs, returns empty data if the directory doesn't exist.
+///
+/// # Parameters
+/// - `dir: &Path` - Directory to read from
+///
+/// # Returns
+/// `NoteResult<NoteData>` - Loaded note data or error
+fn load_notes_dir(dir: &Path) -> NoteResult<NoteData> {
+    use std::io::ErrorKind;
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Ok(NoteData {
+                version: NOTES_VERSION,
+                notes: vec![],
+                free_ids: vec![],
+                trash: vec![],
+            });
+        }
+        Err(e) => return Err(StorageError::new(StorageOp::Read, dir, e).into()),
+    };
+
+    let mut notes: Vec<Note> = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| StorageError::new(StorageOp::Read, dir, e))?
+            .path();
+        if note_file_id(&path).is_some() {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| StorageError::new(StorageOp::Read, &path, e))?;
+            notes.push(
+                serde_json::from_str(&content)
+                    .map_err(|e| ParseDiagnostic::new(&path, &content, e))?,
+            );
+        }
+    }
+    notes.sort_by_key(|note| note.id);
+    sanitize_loaded_tags(&mut notes, dir);
+
+    let meta_path = dir.join(DIR_META_FILE);
+    let meta: DirMeta = match fs::read_to_string(&meta_path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| ParseDiagnostic::new(&meta_path, &content, e))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => DirMeta::default(),
+        Err(e) => return Err(StorageError::new(StorageOp::Read, &meta_path, e).into()),
+    };
+
+    Ok(NoteData {
+        version: NOTES_VERSION,
+        notes,
+        free_ids: meta.free_ids,
+        trash: meta.trash,
+    })
+}
+
+/// Saves note data to a directory, rewriting only the notes that changed
+/// and deleting the files of removed notes.
+///
+/// # Parameters
+/// - `dir: &Path` - Directory to write to, created if missing
+/// - `data: &NoteData` - Note data to serialize
+///
+/// # Returns
+/// `NoteResult<()>` - Success or serialization/I/O error
+fn save_notes_dir(dir: &Path, data: &NoteData) -> NoteResult<()> {
+    fs::create_dir_all(dir).map_err(|e| StorageError::new(StorageOp::Create, dir, e))?;
+
+    let mut kept = HashSet::new();
+    for note in &data.notes {
+        let path = dir.join(note_file_name(note.id));
+        let content = serde_json::to_string_pretty(note)
+            .map_err(|e| StorageError::new(StorageOp::Write, &path, e))?;
+        write_if_changed(&path, &content)?;
+        kept.insert(note.id);
+    }
+
+    // drop files of notes that are gone
+    let entries = fs::read_dir(dir).map_err(|e| StorageError::new(StorageOp::Read, dir, e))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| StorageError::new(StorageOp::Read, dir, e))?
+            .path();
+        if note_file_id(&path).is_some_and(|id| !kept.contains(&id)) {
+            fs::remove_file(&path).map_err(|e| StorageError::new(StorageOp::Remove, &path, e))?;
+        }
+    }
+
+    let meta_path = dir.join(DIR_META_FILE);
+    let meta = DirMeta {
+        free_ids: data.free_ids.clone(),
+        trash: data.trash.clone(),
+    };
+    let content = serde_json::to_string_pretty(&meta)
+        .map_err(|e| StorageError::new(StorageOp::Write, &meta_path, e))?;
+    write_if_changed(&meta_path, &content)?;
+    Ok(())
+}
+
+/// Storage backend holding the note data. Commands only talk to this
+/// trait, so every backend gets the same behavior.
+trait NoteStore {
+    /// Loads all note data, empty data if nothing was stored yet
+    fn load(&self) -> NoteResult<NoteData>;
+
+    /// Saves all note data
+    fn save(&self, data: &NoteData) -> NoteResult<()>;
+
+    /// Saves all note data like `save`, without whitespace with `minify`
+    /// when the notes are a single JSON file
+    fn save_as(&self, data: &NoteData, _minify: bool) -> NoteResult<()> {
+        self.save(data)
+    }
+
+    /// Loads a single note by ID
+    fn get(&self, id: u32) -> NoteResult<Option<Note>> {
+        Ok(self.load()?.notes.into_iter().find(|note| note.id == id))
+    }
+
+    /// Inserts a note or replaces the note with the same ID
+    fn upsert(&self, note: Note) -> NoteResult<()> {
+        let mut data = self.load()?;
+        match data.notes.iter_mut().find(|n| n.id == note.id) {
+            Some(existing) => *existing = note,
+            None => data.notes.push(note),
+        }
+        self.save(&data)
+    }
+
+    /// Loads the notes for a read-only query on notes created at or after
+    /// `since`. Backends may skip older notes, so the result must not be saved.
+    fn load_created_since(&self, _since: Option<DateTime<Utc>>) -> NoteResult<NoteData> {
+        self.load()
+    }
+
+    /// Takes the lock held from a load to the save of a change, `None` when
+    /// no other process can save these notes
+    fn lock(&self) -> NoteResult<Option<NotesLock>> {
+        Ok(None)
+    }
+}
+
+/// Loads the notes, applies a change and saves the result, holding the
+/// notes lock from the load to the save so that another process can't save
+/// its own change in between. Nothing is saved when the change fails or
+/// leaves the notes as they were.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `change: impl FnOnce(&mut NoteData) -> NoteResult<R>` - Change to apply
+///
+/// # Returns
+/// `NoteResult<R>` - What the change returned, or error during load or save
+fn with_store<R>(
+    store: &dyn NoteStore,
+    change: impl FnOnce(&mut NoteData) -> NoteResult<R>,
+) -> NoteResult<R> {
+    let _lock = store.lock()?;
+    let before = store.load()?;
+    let mut data = before.clone();
+    let result = change(&mut data)?;
+    if data != before {
+        store.save(&data)?;
+    }
+    Ok(result)
+}
+
+/// Changes a single note like `with_store`, saving only that note.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - ID of the note
+/// - `change: impl FnOnce(&mut Note) -> NoteResult<R>` - Change to apply
+///
+/// # Returns
+/// `NoteResult<R>` - What the change returned, or an `ID N not found` error
+fn with_note<R>(
+    store: &dyn NoteStore,
+    id: u32,
+    change: impl FnOnce(&mut Note) -> NoteResult<R>,
+) -> NoteResult<R> {
+    let _lock = store.lock()?;
+    let before = find_note(store, id)?;
+    let mut note = before.clone();
+    let result = change(&mut note)?;
+    if note != before {
+        store.upsert(note)?;
+    }
+    Ok(result)
+}
+
+// Notes stored in a single JSON file
+struct JsonFileStore {
+    path: String,
+}
+
+impl NoteStore for JsonFileStore {
+    fn load(&self) -> NoteResult<NoteData> {
+        load_or_recover(&self.path, Utc::now(), || {
+            if RECOVER.load(Ordering::Relaxed) {
+                return Ok(true);
+            }
+            let question = format!(
+                "{} is not valid JSON. Move it aside and start with no notes?",
+                self.path
+            );
+            Ok(interactive()
+                && confirm(
+                    &mut std::io::stdin().lock(),
+                    &mut std::io::stderr(),
+                    &question,
+                )?)
+        })
+    }
+
+    fn save(&self, data: &NoteData) -> NoteResult<()> {
+        save_notes(&self.path, data)
+    }
+
+    fn save_as(&self, data: &NoteData, minify: bool) -> NoteResult<()> {
+        match minify {
+            true => write_atomic(Path::new(&self.path), |writer| {
+                serde_json::to_writer(writer, data)?;
+                Ok(())
+            }),
+            false => save_notes(&self.path, data),
+        }
+    }
+
+    fn lock(&self) -> NoteResult<Option<NotesLock>> {
+        NotesLock::acquire(&self.path, LOCK_TIMEOUT).map(Some)
+    }
+}
+
+/// Loads a notes file like `load_notes`. When it is not valid JSON and
+/// `recover` agrees, the file is renamed to `<file>.corrupt.<time>`, kept
+/// as it was, and the notes start empty.
+///
+/// # Parameters
+/// - `path: &str` - File path to read from
+/// - `now: DateTime<Utc>` - Time in the name of the corrupt copy
+/// - `recover: impl FnOnce() -> NoteResult<bool>` - Whether to move a
+///   corrupt file aside
+///
+/// # Returns
+/// `NoteResult<NoteData>` - Loaded note data, empty after a recovery, or
+/// error
+fn load_or_recover(
+    path: &str,
+    now: DateTime<Utc>,
+    recover: impl FnOnce() -> NoteResult<bool>,
+) -> NoteResult<NoteData> {
+    match load_notes(path) {
+        Err(NoteError::Parse(diagnostic)) => {
+            if !recover()? {
+                return Err(NoteError::Parse(diagnostic));
+            }
+            let stamp = now.format("%Y%m%dT%H%M%SZ");
+            let aside = sidecar_path(path, &format!("corrupt.{}", stamp));
+            fs::rename(path, &aside).map_err(|e| StorageError::new(StorageOp::Write, &aside, e))?;
+            eprintln!(
+                "warning: {} is not valid JSON, moved it to {} and started with no notes",
+                path,
+                aside.display()
+            );
+            Ok(NoteData {
+                version: NOTES_VERSION,
+                notes: vec![],
+                free_ids: vec![],
+                trash: vec![],
+            })
+        }
+        result => result,
+    }
+}
+
+// Notes stored one file per note in a directory
+struct DirStore {
+    dir: PathBuf,
+}
+
+impl NoteStore for DirStore {
+    fn load(&self) -> NoteResult<NoteData> {
+        load_notes_dir(&self.dir)
+    }
+
+    fn save(&self, data: &NoteData) -> NoteResult<()> {
+        save_notes_dir(&self.dir, data)
+    }
+
+    fn lock(&self) -> NoteResult<Option<NotesLock>> {
+        NotesLock::acquire(&self.dir.to_string_lossy(), LOCK_TIMEOUT).map(Some)
+    }
+}
+
+// Index file of the sharded layout
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ShardIndex {
+    layout: String,
+    free_ids: Vec<u32>,
+    shards: BTreeMap<u32, i32>, // note id -> creation year
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    trash: Vec<Note>,
+}
+
+// Notes of one creation year in the sharded layout
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Shard {
+    notes: Vec<Note>,
+}
+
+// Top-level keys read to recognize the layout of a notes file
+#[derive(Deserialize)]
+struct StoreHeader {
+    layout: Option<String>,
+}
+
+/// Reads and parses a JSON file, returns `None` if it doesn't exist.
+///
+/// # Parameters
+/// - `path: &Path` - File to read
+///
+/// # Returns
+/// `NoteResult<Option<T>>` - Parsed content, `None` or error
+fn read_json_file<T: serde::de::DeserializeOwned>(path: &Path) -> NoteResult<Option<T>> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| ParseDiagnostic::new(path, &content, e).into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(StorageError::new(StorageOp::Read, path, e).into()),
+    }
+}
+
+/// Serializes a value to pretty JSON and writes it if the file changed.
+fn write_json_file<T: Serialize>(path: &Path, value: &T) -> NoteResult<()> {
+    let content = serde_json::to_string_pretty(value)
+        .map_err(|e| StorageError::new(StorageOp::Write, path, e))?;
+    write_if_changed(path, &content)
+}
+
+// Notes split into one file per creation year (`notes-2024.json`), with the
+// `--file` path holding an index of free IDs and of the shard of each note
+struct ShardedStore {
+    index_path: PathBuf,
+}
+
+impl ShardedStore {
+    /// Path of the shard holding the notes created in `year`
+    fn shard_path(&self, year: i32) -> PathBuf {
+        let stem = self
+            .index_path
+            .file_stem()
+            .map_or("notes".into(), |stem| stem.to_string_lossy());
+        self.index_path
+            .with_file_name(format!("{}-{}.json", stem, year))
+    }
+
+    fn read_index(&self) -> NoteResult<ShardIndex> {
+        Ok(
+            read_json_file(&self.index_path)?.unwrap_or_else(|| ShardIndex {
+                layout: SHARDED_LAYOUT.to_string(),
+                ..ShardIndex::default()
+            }),
+        )
+    }
+
+    fn read_shard(&self, year: i32) -> NoteResult<Vec<Note>> {
+        let path = self.shard_path(year);
+        let mut notes = read_json_file::<Shard>(&path)?.unwrap_or_default().notes;
+        sanitize_loaded_tags(&mut notes, &path);
+        Ok(notes)
+    }
+
+    fn write_shard(&self, year: i32, mut notes: Vec<Note>) -> NoteResult<()> {
+        let path = self.shard_path(year);
+        if notes.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(StorageError::new(StorageOp::Remove, &path, e).into())
+                }
+                _ => Ok(()),
+            };
+        }
+        notes.sort_by_key(|note| note.id);
+        write_json_file(&path, &Shard { notes })
+    }
+
+    /// Loads the notes of the given years along with the free IDs
+    fn load_years(&self, index: ShardIndex, years: &BTreeSet<i32>) -> NoteResult<NoteData> {
+        let mut notes = Vec::new();
+        for year in years {
+            notes.extend(self.read_shard(*year)?);
+        }
+        notes.sort_by_key(|note| note.id);
+        Ok(NoteData {
+            version: NOTES_VERSION,
+            notes,
+            free_ids: index.free_ids,
+            trash: index.trash,
+        })
+    }
+
+    /// Writes all shards and the index, replacing the layout described by `old`
+    fn save_with_index(&self, data: &NoteData, old: &ShardIndex) -> NoteResult<()> {
+        let mut by_year: BTreeMap<i32, Vec<Note>> = BTreeMap::new();
+        for note in &data.notes {
+            by_year
+                .entry(note.created_at.year())
+                .or_default()
+                .push(note.clone());
+        }
+
+        let index = ShardIndex {
+            layout: SHARDED_LAYOUT.to_string(),
+            free_ids: data.free_ids.clone(),
+            trash: data.trash.clone(),
+            shards: data
+                .notes
+                .iter()
+                .map(|note| (note.id, note.created_at.year()))
+                .collect(),
+        };
+
+        // drop shards whose notes are all gone
+        for year in old.shards.values().collect::<BTreeSet<_>>() {
+            if !by_year.contains_key(year) {
+                self.write_shard(*year, vec![])?;
+            }
+        }
+        for (year, notes) in by_year {
+            self.write_shard(year, notes)?;
+        }
+        // the index goes last so it never points to a missing shard
+        write_json_file(&self.index_path, &index)
+    }
+}
+
+impl NoteStore for ShardedStore {
+    fn load(&self) -> NoteResult<NoteData> {
+        let index = self.read_index()?;
+        let years = index.shards.values().copied().collect();
+        self.load_years(index, &years)
+    }
+
+    fn save(&self, data: &NoteData) -> NoteResult<()> {
+        let old = self.read_index()?;
+        self.save_with_index(data, &old)
+    }
+
+    fn get(&self, id: u32) -> NoteResult<Option<Note>> {
+        let index = self.read_index()?;
+        let Some(year) = index.shards.get(&id) else {
+            return Ok(None);
+        };
+        Ok(self
+            .read_shard(*year)?
+            .into_iter()
+            .find(|note| note.id == id))
+    }
+
+    fn upsert(&self, note: Note) -> NoteResult<()> {
+        let mut index = self.read_index()?;
+        let year = note.created_at.year();
+        if index.shards.get(&note.id).is_some_and(|old| *old != year) {
+            // the note changes shard, rewrite everything
+            let mut data = self.load()?;
+            data.notes.retain(|n| n.id != note.id);
+            data.notes.push(note);
+            return self.save_with_index(&data, &index);
+        }
+
+        let mut notes = self.read_shard(year)?;
+        notes.retain(|n| n.id != note.id);
+        index.shards.insert(note.id, year);
+        notes.push(note);
+        self.write_shard(year, notes)?;
+        write_json_file(&self.index_path, &index)
+    }
+
+    fn load_created_since(&self, since: Option<DateTime<Utc>>) -> NoteResult<NoteData> {
+        let index = self.read_index()?;
+        let min_year = since.map_or(i32::MIN, |since| since.year());
+        let years = index
+            .shards
+            .values()
+            .copied()
+            .filter(|year| *year >= min_year)
+            .collect();
+        self.load_years(index, &years)
+    }
+
+    fn lock(&self) -> NoteResult<Option<NotesLock>> {
+        NotesLock::acquire(&self.index_path.to_string_lossy(), LOCK_TIMEOUT).map(Some)
+    }
+}
+
+/// Converts a single notes file to the sharded layout in place: notes move
+/// to yearly files next to it and the file becomes the index.
+///
+/// # Parameters
+/// - `path: &str` - Notes file to convert
+///
+/// # Returns
+/// `NoteResult<Vec<PathBuf>>` - Shard files now holding the notes
+fn migrate_to_shards(path: &str) -> NoteResult<Vec<PathBuf>> {
+    if is_notes_dir(path) || is_sharded(path) {
+        return Err(format!("{} is not a single notes file", path).into());
+    }
+    let _lock = NotesLock::acquire(path, LOCK_TIMEOUT)?;
+    let data = load_notes(path)?;
+    let store = ShardedStore {
+        index_path: PathBuf::from(path),
+    };
+    store.save_with_index(&data, &ShardIndex::default())?;
+
+    let years: BTreeSet<i32> = data.notes.iter().map(|n| n.created_at.year()).collect();
+    Ok(years
+        .into_iter()
+        .map(|year| store.shard_path(year))
+        .collect())
+}
+
+/// Age after which `compact` drops removed notes from the trash unless
+/// configured otherwise
+const DEFAULT_TRASH_RETENTION_DAYS: i64 = 30;
+
+// What `compact` drops besides the whitespace
+#[derive(Clone, Copy, Debug)]
+struct CompactOptions {
+    /// Previous contents kept per note
+    history_limit: usize,
+
+    /// Notes removed longer ago leave the trash
+    trash_retention: Duration,
+
+    /// Date the expiry dates and the removal ages are compared with
+    now: DateTime<Utc>,
+}
+
+/// Normalizes note data: expired notes and notes trashed longer ago than
+/// the retention are deleted, histories trimmed to the limit, notes sorted
+/// by ID and free IDs sorted, deduplicated and without IDs still in use.
+///
+/// # Parameters
+/// - `data: &mut NoteData` - Data to normalize in place
+/// - `options: &CompactOptions` - Limits of the history and the trash
+fn compact_data(data: &mut NoteData, options: &CompactOptions) {
+    let now = options.now;
+    let (expired, kept): (Vec<Note>, Vec<Note>) =
+        data.notes.drain(..).partition(|note| note.is_expired(now));
+    data.notes = kept;
+    data.free_ids.extend(expired.iter().map(|note| note.id));
+    // notes trashed before removal dates were kept count as old
+    let (purged, kept): (Vec<Note>, Vec<Note>) = data.trash.drain(..).partition(|note| {
+        note.deleted_at
+            .is_none_or(|at| now - at > options.trash_retention)
+    });
+    data.trash = kept;
+    data.free_ids.extend(purged.iter().map(|note| note.id));
+    for note in data.notes.iter_mut().chain(data.trash.iter_mut()) {
+        let excess = note.history.len().saturating_sub(options.history_limit);
+        note.history.drain(..excess);
+    }
+
+    data.notes.sort_by_key(|note| note.id);
+    let used: HashSet<u32> = data.notes.iter().map(|note| note.id).collect();
+    data.free_ids.sort_unstable();
+    data.free_ids.dedup();
+    data.free_ids.retain(|id| !used.contains(id));
+}
+
+/// Rewrites a single notes file in canonical form. The file is left
+/// untouched when it is already compact. The notes are saved once through
+/// the store, so that `undo` brings back what compact dropped.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage of the notes file
+/// - `path: &str` - Notes file to compact
+/// - `minify: bool` - Drop whitespace instead of pretty-printing
+/// - `options: &CompactOptions` - Limits of the history and the trash
+///
+/// # Returns
+/// `NoteResult<(u64, u64)>` - File size in bytes before and after
+fn compact_notes(
+    store: &dyn NoteStore,
+    path: &str,
+    minify: bool,
+    options: &CompactOptions,
+) -> NoteResult<(u64, u64)> {
+    if is_notes_dir(path) || is_sharded(path) {
+        return Err(format!("{} is not a single notes file", path).into());
+    }
+    let before = fs::metadata(path).map_or(0, |meta| meta.len());
+
+    let _lock = store.lock()?;
+    let mut data = store.load()?;
+    compact_data(&mut data, options);
+    let content = if minify {
+        serde_json::to_string(&data)
+    } else {
+        serde_json::to_string_pretty(&data)
+    }
+    .map_err(|e| StorageError::new(StorageOp::Write, Path::new(path), e))?;
+    // the store writes this same text, a file holding it is left alone
+    if fs::read_to_string(path).ok().as_deref() != Some(content.as_str()) {
+        store.save_as(&data, minify)?;
+    }
+
+    Ok((before, content.len() as u64))
+}
+
+/// Tells whether a notes file is the index of the sharded layout.
+fn is_sharded(path: &str) -> bool {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str::<StoreHeader>(&content).ok())
+        .is_some_and(|header| header.layout.as_deref() == Some(SHARDED_LAYOUT))
+}
+
+// Notes kept in memory only, used by the test suite
+#[cfg(test)]
+#[derive(Default)]
+struct MemoryStore {
+    data: RefCell<Option<NoteData>>,
+}
+
+#[cfg(test)]
+impl NoteStore for MemoryStore {
+    fn load(&self) -> NoteResult<NoteData> {
+        Ok(self.data.borrow().clone().unwrap_or(NoteData {
+            version: NOTES_VERSION,
+            notes: vec![],
+            free_ids: vec![],
+            trash: vec![],
+        }))
+    }
+
+    fn save(&self, data: &NoteData) -> NoteResult<()> {
+        *self.data.borrow_mut() = Some(data.clone());
+        Ok(())
+    }
+}
+
+/// Opens the store designated by a `--file` path.
+///
+/// # Parameters
+/// - `path: &str` - Path given on the command line
+///
+/// # Returns
+/// `Box<dyn NoteStore>` - Directory store for directories, sharded store for
+/// a shard index, JSON file store otherwise
+fn open_store(path: &str) -> Box<dyn NoteStore> {
+    if is_notes_dir(path) {
+        Box::new(DirStore {
+            dir: PathBuf::from(path),
+        })
+    } else if is_sharded(path) {
+        Box::new(ShardedStore {
+            index_path: PathBuf::from(path),
+        })
+    } else {
+        Box::new(JsonFileStore {
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Path of a file kept next to the notes file, e.g. `notes.json.lock`.
+/// For a notes directory the file sits beside the directory.
+///
+/// # Parameters
+/// - `notes_path: &str` - Path given with `--file`
+/// - `suffix: &str` - Extension appended to the notes file name
+///
+/// # Returns
+/// `PathBuf` - Path of the sidecar file
+fn sidecar_path(notes_path: &str, suffix: &str) -> PathBuf {
+    let trimmed = notes_path.trim_end_matches(['/', std::path::MAIN_SEPARATOR]);
+    let path = Path::new(if trimmed.is_empty() {
+        notes_path
+    } else {
+        trimmed
+    });
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Rotating backups of the notes file kept unless configured otherwise
+const DEFAULT_BACKUPS: usize = 3;
+
+/// Copies a notes file to `<file>.1.bak` after shifting the older copies,
+/// `<file>.1.bak` to `<file>.2.bak` and so on, dropping the copy past
+/// `keep`. Only a JSON file is copied: a notes directory, a shard index or
+/// a file that doesn't exist yet is left alone.
+///
+/// # Parameters
+/// - `notes_path: &str` - Path given with `--file`
+/// - `keep: usize` - Number of copies kept, 0 keeps none
+///
+/// # Returns
+/// `NoteResult<()>` - Success or error naming the file that failed
+fn rotate_backups(notes_path: &str, keep: usize) -> NoteResult<()> {
+    if keep == 0 || !Path::new(notes_path).is_file() || is_sharded(notes_path) {
+        return Ok(());
+    }
+    let backup = |n: usize| sidecar_path(notes_path, &format!("{}.bak", n));
+    for n in (1..keep).rev() {
+        let from = backup(n);
+        if from.exists() {
+            fs::rename(&from, backup(n + 1))
+                .map_err(|e| StorageError::new(StorageOp::Write, &backup(n + 1), e))?;
+        }
+    }
+    fs::copy(notes_path, backup(1))
+        .map_err(|e| StorageError::new(StorageOp::Write, &backup(1), e))?;
+    Ok(())
+}
+
+// Store saving the state before the first change of a command to
+// `<file>.undo`, so that `undo` can bring it back, and rotating the backups
+// of the notes file
+struct UndoStore {
+    inner: Box<dyn NoteStore>,
+    notes_path: String,
+    undo_path: PathBuf,
+    backups: usize,
+    saved: std::cell::Cell<bool>,
+}
+
+impl UndoStore {
+    fn new(notes_path: &str, backups: usize) -> UndoStore {
+        UndoStore {
+            inner: open_store(notes_path),
+            notes_path: notes_path.to_string(),
+            undo_path: sidecar_path(notes_path, "undo"),
+            backups,
+            saved: std::cell::Cell::new(false),
+        }
+    }
+}
+
+impl NoteStore for UndoStore {
+    fn load(&self) -> NoteResult<NoteData> {
+        self.inner.load()
+    }
+
+    fn save(&self, data: &NoteData) -> NoteResult<()> {
+        self.save_as(data, false)
+    }
+
+    fn save_as(&self, data: &NoteData, minify: bool) -> NoteResult<()> {
+        if !self.saved.get() {
+            let before = self.inner.load()?;
+            // saving unchanged notes keeps the previous undo state
+            if before != *data {
+                save_notes(&self.undo_path.to_string_lossy(), &before)?;
+                rotate_backups(&self.notes_path, self.backups)?;
+                self.saved.set(true);
+            }
+        }
+        self.inner.save_as(data, minify)
+    }
+
+    fn get(&self, id: u32) -> NoteResult<Option<Note>> {
+        self.inner.get(id)
+    }
+
+    fn load_created_since(&self, since: Option<DateTime<Utc>>) -> NoteResult<NoteData> {
+        self.inner.load_created_since(since)
+    }
+
+    fn lock(&self) -> NoteResult<Option<NotesLock>> {
+        self.inner.lock()
+    }
+}
+
+// Store keeping the changes of a `--dry-run` command in memory: later loads
+// see them, the notes on disk don't
+struct DryRunStore<'a> {
+    inner: &'a dyn NoteStore,
+    /// State before the first save and after the last one
+    changes: std::cell::RefCell<Option<(NoteData, NoteData)>>,
+}
+
+impl<'a> DryRunStore<'a> {
+    fn new(inner: &'a dyn NoteStore) -> DryRunStore<'a> {
+        DryRunStore {
+            inner,
+            changes: std::cell::RefCell::new(None),
+        }
+    }
+
+    /// Describes what the saves would have changed, nothing if there was no
+    /// save
+    fn summary(&self) -> Vec<String> {
+        match &*self.changes.borrow() {
+            Some((before, after)) => describe_changes(before, after),
+            None => vec![],
+        }
+    }
+}
+
+impl NoteStore for DryRunStore<'_> {
+    fn load(&self) -> NoteResult<NoteData> {
+        match &*self.changes.borrow() {
+            Some((_, after)) => Ok(after.clone()),
+            None => self.inner.load(),
+        }
+    }
+
+    fn save(&self, data: &NoteData) -> NoteResult<()> {
+        let mut changes = self.changes.borrow_mut();
+        match changes.as_mut() {
+            Some((_, after)) => *after = data.clone(),
+            None => *changes = Some((self.inner.load()?, data.clone())),
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> NoteResult<Option<Note>> {
+        match &*self.changes.borrow() {
+            Some((_, after)) => Ok(after.notes.iter().find(|note| note.id == id).cloned()),
+            None => self.inner.get(id),
+        }
+    }
+
+    fn load_created_since(&self, since: Option<DateTime<Utc>>) -> NoteResult<NoteData> {
+        match self.changes.borrow().is_some() {
+            true => self.load(),
+            false => self.inner.load_created_since(since),
+        }
+    }
+}
+
+/// Describes the difference between two states of the notes, one line per
+/// kind of change, e.g. "Would remove 3 notes (IDs 2, 5, 9)".
+///
+/// # Parameters
+/// - `before: &NoteData` - Notes as saved
+/// - `after: &NoteData` - Notes as a command left them
+///
+/// # Returns
+/// `Vec<String>` - Lines to print, a single one when nothing changes
+fn describe_changes(before: &NoteData, after: &NoteData) -> Vec<String> {
+    let find = |data: &NoteData, id: u32| data.notes.iter().find(|note| note.id == id).cloned();
+    let added: Vec<u32> = after
+        .notes
+        .iter()
+        .filter(|note| find(before, note.id).is_none())
+        .map(|note| note.id)
+        .collect();
+    let removed: Vec<u32> = before
+        .notes
+        .iter()
+        .filter(|note| find(after, note.id).is_none())
+        .map(|note| note.id)
+        .collect();
+    let changed: Vec<u32> = after
+        .notes
+        .iter()
+        .filter(|note| find(before, note.id).is_some_and(|old| old != **note))
+        .map(|note| note.id)
+        .collect();
+
+    let mut lines = vec![];
+    for (verb, ids) in [("add", added), ("remove", removed), ("change", changed)] {
+        let list: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        match list.len() {
+            0 => {}
+            1 => lines.push(format!("Would {} note {}", verb, list[0])),
+            count => lines.push(format!(
+                "Would {} {} notes (IDs {})",
+                verb,
+                count,
+                list.join(", ")
+            )),
+        }
+    }
+    if lines.is_empty() && before.trash != after.trash {
+        lines.push("Would update the trash".to_string());
+    }
+    if lines.is_empty() && before.free_ids != after.free_ids {
+        lines.push("Would update the free IDs".to_string());
+    }
+    if lines.is_empty() {
+        lines.push("Nothing would change".to_string());
+    }
+    lines
+}
+
+/// Swaps the notes with the state saved before the last change, which then
+/// becomes the undo state: undoing twice redoes.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `undo_path: &Path` - File holding the state before the last change
+///
+/// # Returns
+/// `NoteResult<()>` - Success, or error when there is nothing to undo or
+/// during load or save
+fn undo_last(store: &dyn NoteStore, undo_path: &Path) -> NoteResult<()> {
+    if !undo_path.exists() {
+        return Err(format!("nothing to undo, {} doesn't exist", undo_path.display()).into());
+    }
+    let undo_path = undo_path.to_string_lossy();
+    let previous = load_notes(&undo_path)?;
+    with_store(store, |data| {
+        let current = std::mem::replace(data, previous);
+        save_notes(&undo_path, &current)
+    })
+}
+
+// A backup found next to the notes file
+#[derive(Debug, Clone, PartialEq)]
+struct Backup {
+    path: PathBuf,
+    created_at: DateTime<Utc>,
+}
+
+impl Backup {
+    /// File name of the backup, as given to `backup restore`
+    fn name(&self) -> String {
+        self.path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Saves a copy of all the notes next to the notes file, as
+/// `<file>.bak.<RFC 3339 timestamp>.json`. Any layout is backed up as a
+/// single JSON file.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `notes_path: &str` - Path given with `--file`
+/// - `now: DateTime<Utc>` - Time stamped in the file name
+///
+/// # Returns
+/// `NoteResult<Backup>` - The backup written
+fn create_backup(
+    store: &dyn NoteStore,
+    notes_path: &str,
+    now: DateTime<Utc>,
+) -> NoteResult<Backup> {
+    use chrono::SubsecRound;
+
+    let data = store.load()?;
+    // the name keeps milliseconds, so does the returned date
+    let now = now.trunc_subsecs(3);
+    let stamp = now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let path = sidecar_path(notes_path, &format!("bak.{}.json", stamp));
+    if path.exists() {
+        return Err(format!("backup {} already exists", path.display()).into());
+    }
+    save_notes(&path.to_string_lossy(), &data)?;
+    Ok(Backup {
+        path,
+        created_at: now,
+    })
+}
+
+/// Finds the backups of a notes file, oldest first. Files whose name
+/// doesn't hold a valid timestamp are ignored.
+///
+/// # Parameters
+/// - `notes_path: &str` - Path given with `--file`
+///
+/// # Returns
+/// `NoteResult<Vec<Backup>>` - Backups found
+fn list_backups(notes_path: &str) -> NoteResult<Vec<Backup>> {
+    use std::io::ErrorKind;
+
+    let pattern = sidecar_path(notes_path, "bak.");
+    let prefix = pattern.file_name().unwrap_or_default().to_string_lossy();
+    let dir = match pattern.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(StorageError::new(StorageOp::Read, dir, e).into()),
+    };
+
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| StorageError::new(StorageOp::Read, dir, e))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let stamp = name
+            .strip_prefix(prefix.as_ref())
+            .and_then(|rest| rest.strip_suffix(".json"));
+        if let Some(Ok(created_at)) = stamp.map(DateTime::parse_from_rfc3339) {
+            backups.push(Backup {
+                path: dir.join(&name),
+                created_at: created_at.with_timezone(&Utc),
+            });
+        }
+    }
+    backups.sort_by_key(|backup| backup.created_at);
+    Ok(backups)
+}
+
+/// Replaces the notes with a backup, after checking it parses. Refuses
+/// when the notes file was modified after the backup was made, unless
+/// `force` is set.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `notes_path: &str` - Path given with `--file`
+/// - `name: &str` - File name of the backup or its path
+/// - `force: bool` - Restore over newer notes
+///
+/// # Returns
+/// `NoteResult<usize>` - Number of notes restored
+fn restore_backup(
+    store: &dyn NoteStore,
+    notes_path: &str,
+    name: &str,
+    force: bool,
+) -> NoteResult<usize> {
+    let backups = list_backups(notes_path)?;
+    let backup = backups
+        .iter()
+        .find(|backup| backup.name() == name || backup.path == Path::new(name))
+        .ok_or_else(|| format!("no backup named {}, see `backup list`", name))?;
+    let data = load_notes(&backup.path.to_string_lossy())?;
+
+    let modified = fs::metadata(notes_path).and_then(|meta| meta.modified());
+    if let Ok(modified) = modified
+        && DateTime::<Utc>::from(modified) > backup.created_at
+        && !force
+    {
+        return Err(format!(
+            "{} changed after backup {} was made, use --force to restore it anyway",
+            notes_path,
+            backup.name()
+        )
+        .into());
+    }
+    let count = data.notes.len();
+    with_store(store, |current| {
+        *current = data;
+        Ok(count)
+    })
+}
+
+/// Tells whether a process is running.
+#[cfg(target_os = "linux")]
+fn process_alive(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Tells whether a process is running.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_alive(pid: u32) -> bool {
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Tells whether a process is running. Without a way to check, a lock is
+/// never considered stale.
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+/// Longest wait for another process to release the notes lock
+const LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Pause between two attempts to take the notes lock
+const LOCK_RETRY: std::time::Duration = std::time::Duration::from_millis(10);
+
+// Lock held from the load of the notes to the save of a change, see
+// `with_store`, so that two commands can't both load the notes and each save
+// their own change over the other. The
+// lock file holds the PID of its owner and is removed on drop.
+struct NotesLock {
+    path: PathBuf,
+}
+
+impl NotesLock {
+    /// Takes the lock of a notes file, waiting for another command to
+    /// release it. A lock left by a process that no longer runs is reclaimed.
+    ///
+    /// # Parameters
+    /// - `notes_path: &str` - Path given with `--file`
+    /// - `timeout: std::time::Duration` - Longest wait, `LOCK_TIMEOUT` for commands
+    ///
+    /// # Returns
+    /// `NoteResult<NotesLock>` - The lock, or error when it is still taken
+    fn acquire(notes_path: &str, timeout: std::time::Duration) -> NoteResult<NotesLock> {
+        use std::io::{ErrorKind, Write};
+
+        let path = sidecar_path(notes_path, "lock");
+        create_parent_dir(&path)?;
+        let start = std::time::Instant::now();
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    let lock = NotesLock { path };
+                    write!(file, "{}", std::process::id())
+                        .map_err(|e| StorageError::new(StorageOp::Write, &lock.path, e))?;
+                    return Ok(lock);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    // an empty file is a lock whose owner hasn't written its PID yet
+                    let owner = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|content| content.trim().parse::<u32>().ok());
+                    if let Some(pid) = owner
+                        && !process_alive(pid)
+                    {
+                        fs::remove_file(&path).or_else(|e| match e.kind() {
+                            ErrorKind::NotFound => Ok(()),
+                            _ => Err(StorageError::new(StorageOp::Remove, &path, e)),
+                        })?;
+                        continue;
+                    }
+                    if start.elapsed() >= timeout {
+                        return Err(format!(
+                            "another note-cli process is using {} (lock file {})",
+                            notes_path,
+                            path.display()
+                        )
+                        .into());
+                    }
+                    std::thread::sleep(LOCK_RETRY);
+                }
+                Err(e) => return Err(StorageError::new(StorageOp::Create, &path, e).into()),
+            }
+        }
+    }
+}
+
+impl Drop for NotesLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Lock keeping a second interactive session from running against the same
+// notes file. The lock file holds the PID of its owner and is removed on drop.
+struct InstanceLock {
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Takes the instance lock of a notes file. A lock left by a process
+    /// that no longer runs is reclaimed.
+    ///
+    /// # Parameters
+    /// - `notes_path: &str` - Path given with `--file`
+    ///
+    /// # Returns
+    /// `NoteResult<InstanceLock>` - The lock, or error naming the PID holding it
+    fn acquire(notes_path: &str) -> NoteResult<InstanceLock> {
+        let pid = std::process::id();
+        // the PID is written aside then linked in place, so that the lock
+        // file never exists without it
+        let staged = sidecar_path(notes_path, &format!("instance.lock.{}", pid));
+        fs::write(&staged, pid.to_string())
+            .map_err(|e| StorageError::new(StorageOp::Write, &staged, e))?;
+        let lock = InstanceLock::link(notes_path, &staged);
+        let _ = fs::remove_file(&staged);
+        lock
+    }
+
+    /// Links a file holding the PID as the instance lock, see `acquire`.
+    fn link(notes_path: &str, staged: &Path) -> NoteResult<InstanceLock> {
+        use std::io::ErrorKind;
+
+        let path = sidecar_path(notes_path, "instance.lock");
+        loop {
+            match fs::hard_link(staged, &path) {
+                Ok(()) => return Ok(InstanceLock { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let owner = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|content| content.trim().parse::<u32>().ok());
+                    match owner {
+                        Some(pid) if process_alive(pid) => {
+                            return Err(format!(
+                                "{} is already in use by process {} (lock file {})",
+                                notes_path,
+                                pid,
+                                path.display()
+                            )
+                            .into());
+                        }
+                        // stale or unreadable lock, take it over
+                        _ => fs::remove_file(&path).or_else(|e| match e.kind() {
+                            ErrorKind::NotFound => Ok(()),
+                            _ => Err(StorageError::new(StorageOp::Remove, &path, e)),
+                        })?,
+                    }
+                }
+                Err(e) => return Err(StorageError::new(StorageOp::Create, &path, e).into()),
+            }
+        }
+    }
+
+    /// Removes the lock file when the process is interrupted with Ctrl-C,
+    /// then exits with the conventional status 130.
+    fn release_on_interrupt(&self) -> NoteResult<()> {
+        let path = self.path.clone();
+        ctrlc::set_handler(move || {
+            let _ = fs::remove_file(&path);
+            std::process::exit(130);
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Optional settings for a new note
+#[derive(Debug, Default, Clone)]
+struct AddOptions {
+    id: Option<u32>,
+    unique: bool,
+    unique_ignore_case: bool,
+    unique_window: Option<Duration>,
+    expires: Option<Duration>,
+    meta: BTreeMap<String, String>,
+    rating: Option<u8>,
+    title: Option<String>,
+    due: Option<DateTime<Utc>>,
+}
+
+// Result of an add
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum AddOutcome {
+    Created(u32),
+    Existing(u32),
+}
+
+/// Looks for a note with the same trimmed content as a new one.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `content: &str` - Content of the new note
+/// - `options: &AddOptions` - Case folding and time window settings
+///
+/// # Returns
+/// `Option<u32>` - ID of the most recent identical note, if any
+fn find_duplicate(data: &NoteData, content: &str, options: &AddOptions) -> Option<u32> {
+    let normalize = |text: &str| {
+        if options.unique_ignore_case {
+            text.trim().to_lowercase()
+        } else {
+            text.trim().to_string()
+        }
+    };
+    let wanted = normalize(content);
+    let since = options.unique_window.map(|window| Utc::now() - window);
+
+    data.notes
+        .iter()
+        .filter(|note| since.is_none_or(|since| note.created_at >= since))
+        .filter(|note| normalize(&note.content) == wanted)
+        .max_by_key(|note| note.created_at)
+        .map(|note| note.id)
+}
+
+/// Picks the ID of a new note: the oldest free ID not in use, or one more
+/// than the highest ID in use.
+///
+/// # Parameters
+/// - `data: &mut NoteData` - Note data, free IDs are consumed
+///
+/// # Returns
+/// `u32` - ID to give to the new note
+fn allocate_id(data: &mut NoteData) -> u32 {
+    while !data.free_ids.is_empty() {
+        let id = data.free_ids.remove(0);
+        // an explicitly assigned ID may still be listed as free
+        if !data.notes.iter().any(|note| note.id == id) {
+            return id;
+        }
+    }
+    data.notes
+        .iter()
+        .map(|note| note.id)
+        .max()
+        .map_or(1, |max| max + 1)
+}
+
+/// Keeps at most `cap` free IDs, the smallest ones, in their order.
+///
+/// # Parameters
+/// - `data: &mut NoteData` - Note data
+/// - `cap: usize` - Number of free IDs to keep, 0 clears them all
+///
+/// # Returns
+/// `Vec<u32>` - Discarded IDs, in ascending order
+fn cap_free_ids(data: &mut NoteData, cap: usize) -> Vec<u32> {
+    let mut sorted = data.free_ids.clone();
+    sorted.sort_unstable();
+    let discarded = sorted.split_off(cap.min(sorted.len()));
+    data.free_ids.retain(|id| !discarded.contains(id));
+    discarded
+}
+
+// Inconsistency in the notes found by `doctor`
+#[derive(Debug, PartialEq, Eq)]
+enum Problem {
+    DuplicateId { id: u32, count: usize },
+    UsedFreeId(u32),
+    RepeatedFreeId { id: u32, count: usize },
+    UpdatedBeforeCreated(u32),
+    EmptyContent(u32),
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::DuplicateId { id, count } => write!(f, "{} notes have ID {}", count, id),
+            Problem::UsedFreeId(id) => write!(f, "free ID {} is used by a note", id),
+            Problem::RepeatedFreeId { id, count } => {
+                write!(f, "free ID {} is listed {} times", id, count)
+            }
+            Problem::UpdatedBeforeCreated(id) => {
+                write!(f, "note {} was updated before it was created", id)
+            }
+            Problem::EmptyContent(id) => write!(f, "note {} has no content", id),
+        }
+    }
+}
+
+/// Looks for the inconsistencies hand edits can leave in the notes. The
+/// trash isn't checked: its notes may share IDs with live ones.
+///
+/// # Parameters
+/// - `data: &NoteData` - Note data to check
+///
+/// # Returns
+/// `Vec<Problem>` - Problems found, by kind then ID
+fn diagnose(data: &NoteData) -> Vec<Problem> {
+    let mut ids: BTreeMap<u32, usize> = BTreeMap::new();
+    for note in &data.notes {
+        *ids.entry(note.id).or_default() += 1;
+    }
+    let mut free: BTreeMap<u32, usize> = BTreeMap::new();
+    for id in &data.free_ids {
+        *free.entry(*id).or_default() += 1;
+    }
+
+    let mut problems: Vec<Problem> = ids
+        .iter()
+        .filter(|(_, count)| **count > 1)
+        .map(|(id, count)| Problem::DuplicateId {
+            id: *id,
+            count: *count,
+        })
+        .collect();
+    problems.extend(
+        free.keys()
+            .filter(|id| ids.contains_key(id))
+            .map(|id| Problem::UsedFreeId(*id)),
+    );
+    problems.extend(
+        free.iter()
+            .filter(|(_, count)| **count > 1)
+            .map(|(id, count)| Problem::RepeatedFreeId {
+                id: *id,
+                count: *count,
+            }),
+    );
+    let mut notes: Vec<&Note> = data.notes.iter().collect();
+    notes.sort_by_key(|note| note.id);
+    problems.extend(
+        notes
+            .iter()
+            .filter(|note| note.updated_at < note.created_at)
+            .map(|note| Problem::UpdatedBeforeCreated(note.id)),
+    );
+    problems.extend(
+        notes
+            .iter()
+            .filter(|note| note.content.trim().is_empty())
+            .map(|note| Problem::EmptyContent(note.id)),
+    );
+    problems
+}
+
+/// Repairs what `diagnose` finds, except empty notes: the free IDs lose
+/// their repeats and the IDs in use, every note sharing an ID but the first
+/// gets a free one, and update dates before the creation date are moved to
+/// it.
+///
+/// # Parameters
+/// - `data: &mut NoteData` - Note data to repair
+///
+/// # Returns
+/// `Vec<String>` - Description of each repair
+fn repair(data: &mut NoteData) -> Vec<String> {
+    let mut repairs = vec![];
+    let used: HashSet<u32> = data.notes.iter().map(|note| note.id).collect();
+    let mut kept = HashSet::new();
+    data.free_ids.retain(|id| {
+        if used.contains(id) {
+            repairs.push(format!("Removed free ID {}, a note uses it", id));
+            false
+        } else if !kept.insert(*id) {
+            repairs.push(format!("Removed a repeat of free ID {}", id));
+            false
+        } else {
+            true
+        }
+    });
+
+    let mut seen = HashSet::new();
+    let duplicates: Vec<usize> = (0..data.notes.len())
+        .filter(|&index| !seen.insert(data.notes[index].id))
+        .collect();
+    for index in duplicates {
+        let old = data.notes[index].id;
+        let id = allocate_id(data);
+        data.notes[index].id = id;
+        repairs.push(format!("Gave ID {} to a note that had ID {}", id, old));
+    }
+
+    for note in &mut data.notes {
+        if note.updated_at < note.created_at {
+            note.updated_at = note.created_at;
+            repairs.push(format!(
+                "Moved the update date of note {} to its creation date",
+                note.id
+            ));
+        }
+    }
+    repairs
+}
+
+/// Claims an explicit ID for a new note, removing it from the free IDs.
+///
+/// # Parameters
+/// - `data: &mut NoteData` - Note data
+/// - `id: u32` - Requested ID
+///
+/// # Returns
+/// `NoteResult<u32>` - The ID or error if a note already uses it
+fn reserve_id(data: &mut NoteData, id: u32) -> NoteResult<u32> {
+    if data.notes.iter().any(|note| note.id == id) {
+        return Err(format!("ID {} is already in use", id).into());
+    }
+    data.free_ids.retain(|&free| free != id);
+    Ok(id)
+}
+
+/// Adds a new note with auto-assigned ID (reuses free IDs when available).
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `content: String` - Text content for the new note
+/// - `tags: Vec<String>` - Tags of the new note
+/// - `options: &AddOptions` - Extra settings such as an explicit ID
+///
+/// # Returns
+/// `NoteResult<AddOutcome>` - ID of the new note, or of the identical note
+/// found with `unique`, or error during load/save operations
+fn add_note(
+    store: &dyn NoteStore,
+    content: String,
+    tags: Vec<String>,
+    options: &AddOptions,
+) -> NoteResult<AddOutcome> {
+    with_store(store, |data| {
+        if options.unique
+            && let Some(id) = find_duplicate(data, &content, options)
+        {
+            return Ok(AddOutcome::Existing(id));
+        }
+
+        // determine id
+        let new_id = match options.id {
+            Some(id) => reserve_id(data, id)?,
+            None => allocate_id(data),
+        };
+
+        // create new note
+        let now = Utc::now();
+        let new_note = Note {
+            id: new_id,
+            content,
+            created_at: now,
+            updated_at: now,
+            tags,
+            expires_at: options.expires.map(|expires| now + expires),
+            meta: options.meta.clone(),
+            rating: options.rating,
+            created_offset: Some(Local::now().offset().local_minus_utc()),
+            archived: false,
+            title: options.title.clone(),
+            due: options.due,
+            done: false,
+            pinned: false,
+            deleted_at: None,
+            history: vec![],
+        };
+
+        // push new note into data
+        data.notes.push(new_note);
+        Ok(AddOutcome::Created(new_id))
+    })
+}
+
+/// Removes several notes with a single save, to the trash unless
+/// `permanent`. Their IDs go to the free list for reuse, so a trashed note
+/// may get a new ID when restored. Missing IDs don't stop the others from
+/// being removed.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `ids: &[u32]` - IDs of the notes to remove, duplicates are ignored
+/// - `permanent: bool` - Delete the notes instead of moving them to the trash
+///
+/// # Returns
+/// `NoteResult<Vec<u32>>` - IDs that matched no note, or error during load
+/// or save
+fn remove_notes(store: &dyn NoteStore, ids: &[u32], permanent: bool) -> NoteResult<Vec<u32>> {
+    with_store(store, |data| {
+        let now = Utc::now();
+        let mut seen = HashSet::new();
+        let mut missing = Vec::new();
+        for id in ids.iter().filter(|id| seen.insert(**id)) {
+            let Some(index) = data.notes.iter().position(|note| note.id == *id) else {
+                missing.push(*id);
+                continue;
+            };
+            let mut note = data.notes.remove(index);
+            if !permanent {
+                note.deleted_at = Some(now);
+                data.trash.push(note);
+            }
+            data.free_ids.push(*id);
+        }
+        Ok(missing)
+    })
+}
+
+/// Puts the most recently removed note with an ID back from the trash. It
+/// keeps its ID when still free, or gets a new one.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - ID the note had when removed
+///
+/// # Returns
+/// `NoteResult<u32>` - ID of the restored note, or error if the trash holds
+/// no such note or I/O fails
+fn restore_note(store: &dyn NoteStore, id: u32) -> NoteResult<u32> {
+    with_store(store, |data| {
+        let Some(index) = data.trash.iter().rposition(|note| note.id == id) else {
+            return Err(match data.trash.is_empty() {
+                true => "the trash is empty".into(),
+                false => format!("ID {} is not in the trash", id).into(),
+            });
+        };
+        let mut note = data.trash.remove(index);
+        note.deleted_at = None;
+        if data.notes.iter().any(|n| n.id == id) {
+            note.id = allocate_id(data);
+        } else {
+            data.free_ids.retain(|free| *free != id);
+        }
+        let restored = note.id;
+        data.notes.push(note);
+        Ok(restored)
+    })
+}
+
+/// Deletes notes from the trash for good. Their IDs stay free when no note
+/// took them.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `older_than: Option<Duration>` - Only purge notes removed longer ago,
+///   all of them when `None`
+/// - `now: DateTime<Utc>` - Current date
+/// - `dry_run: bool` - Only tell which notes would be purged
+///
+/// # Returns
+/// `NoteResult<Vec<u32>>` - IDs of the purged notes, or error during load or save
+fn purge_trash(
+    store: &dyn NoteStore,
+    older_than: Option<Duration>,
+    now: DateTime<Utc>,
+    dry_run: bool,
+) -> NoteResult<Vec<u32>> {
+    let purge = |data: &mut NoteData| {
+        // notes trashed before removal dates were kept count as old
+        let (purged, kept): (Vec<Note>, Vec<Note>) = data.trash.drain(..).partition(|note| {
+            older_than.is_none_or(|age| note.deleted_at.is_none_or(|at| now - at > age))
+        });
+        data.trash = kept;
+        let ids: Vec<u32> = purged.iter().map(|note| note.id).collect();
+        for id in &ids {
+            if !data.notes.iter().any(|n| n.id == *id) && !data.free_ids.contains(id) {
+                data.free_ids.push(*id);
+            }
+        }
+        Ok(ids)
+    };
+    match dry_run {
+        true => purge(&mut store.load()?),
+        false => with_store(store, purge),
+    }
+}
+
+/// Editor command from `$VISUAL` or `$EDITOR`, `vi` when neither is set.
+fn editor_command() -> String {
+    ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+/// Lets the user change a text in an editor, through a temporary file.
+///
+/// # Parameters
+/// - `editor: &str` - Editor command, run by the shell so it can have arguments
+/// - `text: &str` - Initial text
+///
+/// # Returns
+/// `NoteResult<Option<String>>` - Edited text without its final newline,
+/// `None` if the editor failed
+fn edit_text(editor: &str, text: &str) -> NoteResult<Option<String>> {
+    edit_text_with(text, |path| spawn_editor(editor, path))
+}
+
+/// Runs an editor on a file and waits for it.
+///
+/// # Parameters
+/// - `editor: &str` - Editor command, run by the shell so it can have arguments
+/// - `path: &Path` - File to edit
+///
+/// # Returns
+/// `NoteResult<bool>` - Whether the editor exited successfully
+fn spawn_editor(editor: &str, path: &Path) -> NoteResult<bool> {
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(path)
+        .status()?;
+    Ok(status.success())
+}
+
+/// Writes a text to a temporary file, lets `spawn` change it and reads it
+/// back. The file is deleted afterwards, whatever happens.
+///
+/// # Parameters
+/// - `text: &str` - Initial text
+/// - `spawn: impl FnOnce(&Path) -> NoteResult<bool>` - Runs the editor on
+///   the file, false when it failed
+///
+/// # Returns
+/// `NoteResult<Option<String>>` - Edited text without its final newline,
+/// `None` if the editor failed
+fn edit_text_with(
+    text: &str,
+    spawn: impl FnOnce(&Path) -> NoteResult<bool>,
+) -> NoteResult<Option<String>> {
+    let file = tempfile::Builder::new()
+        .prefix("note-")
+        .suffix(".md")
+        .tempfile()?;
+    fs::write(file.path(), format!("{}\n", text))?;
+    if !spawn(file.path())? {
+        return Ok(None);
+    }
+    let edited = fs::read_to_string(file.path())?;
+    Ok(Some(edited.trim_end_matches(['\n', '\r']).to_string()))
+}
+
+/// Changes the content of a note in an editor. Nothing is saved when the
+/// editor fails or the content is the same.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - Id of the note to edit
+/// - `history_limit: usize` - Previous contents kept
+/// - `spawn: impl FnOnce(&Path) -> NoteResult<bool>` - Runs the editor on
+///   the file holding the content
+///
+/// # Returns
+/// `NoteResult<()>` - Success or error during load or save
+fn edit_with_editor(
+    store: &dyn NoteStore,
+    id: u32,
+    history_limit: usize,
+    spawn: impl FnOnce(&Path) -> NoteResult<bool>,
+) -> NoteResult<()> {
+    let note = find_note(store, id)?;
+    match edit_text_with(&note.content, spawn)? {
+        Some(content) => edit_note(store, id, content, history_limit),
+        None => {
+            eprintln!("The editor failed, note {} unchanged", id);
+            Ok(())
+        }
+    }
+}
+
+/// Number of notes offered by the interactive note picker
+const PICKER_SIZE: usize = 15;
+
+/// Writes a prompt and reads one line of answer, without its line ending.
+///
+/// # Parameters
+/// - `input: &mut impl BufRead` - Where the answer is read
+/// - `output: &mut impl Write` - Where the prompt is written
+/// - `prompt: &str` - Question to ask
+///
+/// # Returns
+/// `NoteResult<Option<String>>` - Answer, `None` at end of input
+fn prompt_line(
+    input: &mut impl std::io::BufRead,
+    output: &mut impl std::io::Write,
+    prompt: &str,
+) -> NoteResult<Option<String>> {
+    write!(output, "{}", prompt)?;
+    output.flush()?;
+    let mut answer = String::new();
+    if input.read_line(&mut answer)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(answer.trim_end_matches(['\n', '\r']).to_string()))
+}
+
+/// Asks a yes/no question, no being the default.
+///
+/// # Parameters
+/// - `input: &mut impl BufRead` - Where the answer is read
+/// - `output: &mut impl Write` - Where the question is written
+/// - `question: &str` - Question, without the `[y/N]` suffix
+///
+/// # Returns
+/// `NoteResult<bool>` - Whether the user answered yes
+fn confirm(
+    input: &mut impl std::io::BufRead,
+    output: &mut impl std::io::Write,
+    question: &str,
+) -> NoteResult<bool> {
+    let answer = prompt_line(input, output, &format!("{} [y/N] ", question))?;
+    Ok(answer.is_some_and(|answer| matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")))
+}
+
+/// Asks before a destructive change, in the terminal, unless `--yes` was
+/// given. Without a terminal nobody can answer, so the change is refused.
+///
+/// # Parameters
+/// - `yes: bool` - Whether `--yes` was given
+/// - `question: &str` - Question, without the `[y/N]` suffix
+/// - `action: &str` - What is refused without `--yes`, e.g. "remove notes"
+///
+/// # Returns
+/// `NoteResult<bool>` - Whether to go on, or error when nobody can answer
+fn confirm_change(yes: bool, question: &str, action: &str) -> NoteResult<bool> {
+    confirm_change_with(
+        yes,
+        interactive(),
+        &mut std::io::stdin().lock(),
+        &mut std::io::stdout(),
+        question,
+        action,
+    )
+}
+
+/// [`confirm_change`] reading the answer from `input`.
+///
+/// # Parameters
+/// - `yes: bool` - Whether `--yes` was given
+/// - `interactive: bool` - Whether the user can be asked
+/// - `input: &mut impl BufRead` - Where the answer is read
+/// - `output: &mut impl Write` - Where the question is written
+/// - `question: &str` - Question, without the `[y/N]` suffix
+/// - `action: &str` - What is refused without `--yes`
+///
+/// # Returns
+/// `NoteResult<bool>` - Whether to go on, or error when nobody can answer
+fn confirm_change_with(
+    yes: bool,
+    interactive: bool,
+    input: &mut impl std::io::BufRead,
+    output: &mut impl std::io::Write,
+    question: &str,
+    action: &str,
+) -> NoteResult<bool> {
+    if yes {
+        return Ok(true);
+    }
+    if !interactive {
+        return Err(format!("refusing to {} without --yes", action).into());
+    }
+    confirm(input, output, question)
+}
+
+/// Width of the content preview shown before removing notes
+const REMOVE_PREVIEW_WIDTH: usize = 30;
+
+/// Question asked before removing notes, with the beginning of each.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `ids: &[u32]` - IDs given to `remove`
+/// - `ellipsis: &str` - Marks a cut preview
+///
+/// # Returns
+/// `Option<String>` - The question, `None` when no ID matches a note
+fn removal_question(data: &NoteData, ids: &[u32], ellipsis: &str) -> Option<String> {
+    let mut seen = HashSet::new();
+    let previews: Vec<String> = ids
+        .iter()
+        .filter(|id| seen.insert(**id))
+        .filter_map(|id| data.notes.iter().find(|note| note.id == *id))
+        .map(|note| {
+            let first_line = note.content.lines().next().unwrap_or_default();
+            let preview = truncate_width(first_line, REMOVE_PREVIEW_WIDTH, ellipsis);
+            format!("{}: '{}'", note.id, preview)
+        })
+        .collect();
+    match previews.len() {
+        0 => None,
+        1 => Some(format!("Remove note {}?", previews[0])),
+        count => Some(format!("Remove {} notes, {}?", count, previews.join(", "))),
+    }
+}
+
+/// Lets the user pick one of the most recently updated notes by number.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `input: &mut impl BufRead` - Where the choice is read
+/// - `output: &mut impl Write` - Where the choices are written
+///
+/// # Returns
+/// `NoteResult<Option<u32>>` - ID of the picked note, `None` if the user
+/// gave no answer
+fn pick_note(
+    data: &NoteData,
+    input: &mut impl std::io::BufRead,
+    output: &mut impl std::io::Write,
+) -> NoteResult<Option<u32>> {
+    let sort = SortArgs {
+        methods: vec![SortMethod::Update],
+        reverse: true,
+    };
+    let mut notes = select_notes(data, &FilterArgs::default(), &sort);
+    notes.truncate(PICKER_SIZE);
+    if notes.is_empty() {
+        return Err("no note to pick from".into());
+    }
+
+    for (i, note) in notes.iter().enumerate() {
+        let first_line = note.content.lines().next().unwrap_or("");
+        writeln!(output, "{:>2}) #{} {}", i + 1, note.id, first_line)?;
+    }
+    loop {
+        let prompt = format!("Pick a note [1-{}]: ", notes.len());
+        let Some(answer) = prompt_line(input, output, &prompt)? else {
+            return Ok(None);
+        };
+        if answer.trim().is_empty() {
+            return Ok(None);
+        }
+        match answer.trim().parse::<usize>() {
+            Ok(n) if (1..=notes.len()).contains(&n) => return Ok(Some(notes[n - 1].id)),
+            _ => writeln!(output, "Enter a number between 1 and {}", notes.len())?,
+        }
+    }
+}
+
+/// Looks for an executable in the directories of `$PATH`.
+///
+/// # Parameters
+/// - `name: &str` - Name of the program
+///
+/// # Returns
+/// `Option<PathBuf>` - Path of the program, `None` if it isn't installed
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Quotes a string for a POSIX shell.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Lets the user pick a note through fzf, fed with the `fzf` output format.
+///
+/// # Parameters
+/// - `fzf: &Path` - Path of the fzf program
+/// - `lines: &str` - Notes rendered in the `fzf` format
+/// - `preview: Option<&str>` - Shell command fzf runs on the highlighted line
+///
+/// # Returns
+/// `NoteResult<Option<u32>>` - ID of the picked note, `None` if the user
+/// cancelled
+fn fzf_pick(fzf: &Path, lines: &str, preview: Option<&str>) -> NoteResult<Option<u32>> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut command = Command::new(fzf);
+    command.args(["--delimiter", "\t", "--with-nth", "2.."]);
+    if let Some(preview) = preview {
+        command.args(["--preview", preview]);
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // fzf may quit before reading everything, a broken pipe is fine then
+    let _ = child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(lines.as_bytes());
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let picked = String::from_utf8_lossy(&output.stdout);
+    let id = picked.split('\t').next().unwrap_or("").trim();
+    Ok(id.parse().ok())
+}
+
+/// Copies a note into another store, where it gets an ID of its own. The
+/// source store isn't modified.
+///
+/// # Parameters
+/// - `source: &dyn NoteStore` - Storage holding the note
+/// - `dest: &dyn NoteStore` - Storage receiving the copy
+/// - `id: u32` - ID of the note to copy
+/// - `touch: bool` - Set the update date of the copy to now
+///
+/// # Returns
+/// `NoteResult<u32>` - ID of the copy in the destination
+fn copy_note(
+    source: &dyn NoteStore,
+    dest: &dyn NoteStore,
+    id: u32,
+    touch: bool,
+) -> NoteResult<u32> {
+    let mut note = find_note(source, id)?;
+
+    with_store(dest, |data| {
+        note.id = allocate_id(data);
+        if touch {
+            note.updated_at = Utc::now();
+        }
+        let new_id = note.id;
+        data.notes.push(note);
+        Ok(new_id)
+    })
+}
+
+/// Moves a note into another store, where it gets an ID of its own, and
+/// frees its ID in the source. Dates and tags are kept. The destination is
+/// saved first, so a failure in between leaves the note in both stores
+/// rather than in none.
+///
+/// # Parameters
+/// - `source: &dyn NoteStore` - Storage holding the note
+/// - `dest: &dyn NoteStore` - Storage receiving the note
+/// - `id: u32` - ID of the note to move
+///
+/// # Returns
+/// `NoteResult<u32>` - ID of the note in the destination
+fn move_note(source: &dyn NoteStore, dest: &dyn NoteStore, id: u32) -> NoteResult<u32> {
+    with_store(source, |data| {
+        let index = data
+            .notes
+            .iter()
+            .position(|note| note.id == id)
+            .ok_or_else(|| NoteError::NotFound(vec![id]))?;
+        let mut note = data.notes.remove(index);
+        data.free_ids.push(id);
+
+        with_store(dest, |dest_data| {
+            note.id = allocate_id(dest_data);
+            let new_id = note.id;
+            dest_data.notes.push(note);
+            Ok(new_id)
+        })
+    })
+}
+
+// What `merge` did with a note of the other file
+#[derive(Debug, PartialEq, Eq)]
+enum MergeDecision {
+    /// Same content and dates in both files, kept once
+    Same { id: u32 },
+
+    /// Only in the other file, added with its ID
+    Added { id: u32 },
+
+    /// Changed in both files, this file's version is newer
+    KeptOurs { id: u32, theirs_as: Option<u32> },
+
+    /// Changed in both files, the other file's version is newer
+    TookTheirs { id: u32, ours_as: Option<u32> },
+}
+
+impl std::fmt::Display for MergeDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MergeDecision::Same { id } => write!(f, "note {}: identical, kept once", id),
+            MergeDecision::Added { id } => write!(f, "note {}: added", id),
+            MergeDecision::KeptOurs { id, theirs_as } => {
+                write!(f, "note {}: kept this version, newer", id)?;
+                match theirs_as {
+                    Some(new_id) => write!(f, ", the other one added as note {}", new_id),
+                    None => Ok(()),
+                }
+            }
+            MergeDecision::TookTheirs { id, ours_as } => {
+                write!(f, "note {}: took the other version, newer", id)?;
+                match ours_as {
+                    Some(new_id) => write!(f, ", this one added as note {}", new_id),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Merges the notes of another copy into a store. A note identical in
+/// content and dates to one of the store is kept once, a note whose ID is
+/// free is added with it, and of two notes sharing an ID the one updated last
+/// wins. With `keep_both`, the losing version gets a new ID instead of being
+/// dropped. The free IDs are cleaned of every ID in use.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage receiving the notes
+/// - `other: NoteData` - Notes of the other copy
+/// - `keep_both: bool` - Keep the losing versions as new notes
+///
+/// # Returns
+/// `NoteResult<Vec<MergeDecision>>` - What was done with each note of the
+/// other copy, by ID
+fn merge_notes(
+    store: &dyn NoteStore,
+    other: NoteData,
+    keep_both: bool,
+) -> NoteResult<Vec<MergeDecision>> {
+    with_store(store, |data| {
+        let mut theirs = other.notes;
+        theirs.sort_by_key(|note| note.id);
+
+        let mut decisions = Vec::new();
+        // versions that lost, given new IDs once every kept ID is known
+        let mut losers = Vec::new();
+        for note in theirs {
+            let same = |ours: &Note| {
+                ours.content == note.content
+                    && ours.created_at == note.created_at
+                    && ours.updated_at == note.updated_at
+            };
+            if data.notes.iter().any(same) {
+                decisions.push(MergeDecision::Same { id: note.id });
+                continue;
+            }
+            let id = note.id;
+            match data.notes.iter_mut().find(|ours| ours.id == id) {
+                None => {
+                    data.notes.push(note);
+                    decisions.push(MergeDecision::Added { id });
+                }
+                Some(ours) if note.updated_at > ours.updated_at => {
+                    let ours = std::mem::replace(ours, note);
+                    decisions.push(MergeDecision::TookTheirs { id, ours_as: None });
+                    losers.push((decisions.len() - 1, ours));
+                }
+                Some(_) => {
+                    decisions.push(MergeDecision::KeptOurs {
+                        id,
+                        theirs_as: None,
+                    });
+                    losers.push((decisions.len() - 1, note));
+                }
+            }
+        }
+
+        let used: HashSet<u32> = data.notes.iter().map(|note| note.id).collect();
+        let mut seen = HashSet::new();
+        data.free_ids
+            .retain(|id| !used.contains(id) && seen.insert(*id));
+        if keep_both {
+            for (index, mut note) in losers {
+                note.id = allocate_id(data);
+                match &mut decisions[index] {
+                    MergeDecision::KeptOurs { theirs_as, .. } => *theirs_as = Some(note.id),
+                    MergeDecision::TookTheirs { ours_as, .. } => *ours_as = Some(note.id),
+                    _ => {}
+                }
+                data.notes.push(note);
+            }
+        }
+        Ok(decisions)
+    })
+}
+
+/// Path of a `move` destination: a name without a dot or a path separator
+/// is a notebook, anything else a file.
+///
+/// # Parameters
+/// - `to: &str` - Value of --to
+///
+/// # Returns
+/// `NoteResult<String>` - The notes file or error if a notebook has no data
+/// directory
+fn destination_path(to: &str) -> NoteResult<String> {
+    match parse_notebook_name(to) {
+        Ok(name) => notebook_path(&name),
+        Err(_) => Ok(to.to_string()),
+    }
+}
+
+/// Tells whether two `--file` paths name the same existing file or directory.
+fn same_notes_path(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Sorts a selection of notes in place, by ID after the given keys.
+///
+/// # Parameters
+/// - `notes: &mut [&Note]` - Notes to sort
+/// - `sort: &SortArgs` - Sort criteria and direction
+fn sort_notes(notes: &mut [&Note], sort: &SortArgs) {
+    notes.sort_by(|a, b| {
+        let order = sort
+            .methods
+            .iter()
+            .fold(std::cmp::Ordering::Equal, |order, method| {
+                order.then_with(|| compare_notes(a, b, *method))
+            });
+        let order = if sort.reverse { order.reverse() } else { order };
+        order.then(a.id.cmp(&b.id))
+    });
+}
+
+/// Moves the pinned notes before the others, keeping the order within each
+/// group.
+fn pinned_first(notes: &mut [&Note]) {
+    notes.sort_by_key(|note| !note.pinned);
+}
+
+/// Compares two notes on the key of a sort method, in its default direction.
+fn compare_notes(a: &Note, b: &Note, method: SortMethod) -> std::cmp::Ordering {
+    match method {
+        SortMethod::Id => a.id.cmp(&b.id),
+        SortMethod::Date => a.created_at.cmp(&b.created_at),
+        SortMethod::Update => a.updated_at.cmp(&b.updated_at),
+        SortMethod::Content => a.content.cmp(&b.content),
+        // None sorts before Some, so comparing b to a puts unrated notes last
+        SortMethod::Rating => b.rating.cmp(&a.rating),
+        SortMethod::TagCount => tag_count(b).cmp(&tag_count(a)),
+        SortMethod::Tags => tag_count(a).cmp(&tag_count(b)),
+        SortMethod::Title => (a.title.is_none(), &a.title).cmp(&(b.title.is_none(), &b.title)),
+        SortMethod::Due => {
+            let (a, b) = (note_due(a), note_due(b));
+            (a.is_none(), a).cmp(&(b.is_none(), b))
+        }
+    }
+}
+
+/// Number of distinct tags of a note
+fn tag_count(note: &Note) -> usize {
+    note.tags.iter().collect::<HashSet<_>>().len()
+}
+
+/// Escapes a field for tab-separated output so it never spans several
+/// fields or lines.
+///
+/// # Parameters
+/// - `field: &str` - Raw field value
+///
+/// # Returns
+/// `String` - Field with `\\`, `\t`, `\n` and `\r` escaped
+fn escape_tsv(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes a field for the plain output when it contains one of the
+/// delimiters, so records stay unambiguous. The first character of each
+/// delimiter found gets a backslash in front of it, or is written as
+/// `\xHH` when it is a backslash or a letter of the escapes.
+///
+/// # Parameters
+/// - `field: &str` - Raw field value
+/// - `delimiters: &[&str]` - Field and record delimiters in use
+///
+/// # Returns
+/// `String` - Field with control characters and delimiters escaped
+fn escape_plain(field: &str, delimiters: &[&str]) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(c) = rest.chars().next() {
+        // the delimiters go first, they may start with an escaped character
+        if delimiters.iter().any(|d| rest.starts_with(*d)) {
+            match c {
+                '\\' | '0' | 't' | 'n' | 'r' | 'x' => {
+                    escaped.push_str(&format!("\\x{:02x}", c as u8))
+                }
+                _ => {
+                    escaped.push('\\');
+                    escaped.push(c);
+                }
+            }
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            _ => escaped.push(c),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    escaped
+}
+
+/// Width of a grapheme in terminal columns. Emoji sequences (flags, ZWJ
+/// families) are drawn as one wide glyph rather than the sum of their chars.
+fn grapheme_width(grapheme: &str) -> usize {
+    use unicode_width::UnicodeWidthChar;
+
+    let widths: Vec<usize> = grapheme
+        .chars()
+        .filter_map(|c| c.width())
+        .filter(|&w| w > 0)
+        .collect();
+    match widths.len() {
+        0 => 0,
+        1 => widths[0],
+        _ => 2,
+    }
+}
+
+/// Width of a text in terminal columns.
+fn text_width(text: &str) -> usize {
+    use unicode_segmentation::UnicodeSegmentation;
+    text.graphemes(true).map(grapheme_width).sum()
+}
+
+/// Breaks a line into lines of at most a number of columns, between words
+/// when possible. Words longer than a line are cut between graphemes.
+///
+/// # Parameters
+/// - `text: &str` - Line to wrap
+/// - `width: usize` - Maximum width in columns
+///
+/// # Returns
+/// `Vec<String>` - Wrapped lines, a single empty one for an empty line
+fn wrap_width(text: &str, width: usize) -> Vec<String> {
+    use unicode_segmentation::UnicodeSegmentation;
+
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut used = 0;
+    for word in text.split_whitespace() {
+        let word_width = text_width(word);
+        if !line.is_empty() && used + 1 + word_width <= width {
+            line.push(' ');
+            line.push_str(word);
+            used += 1 + word_width;
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+            used = 0;
+        }
+        for grapheme in word.graphemes(true) {
+            let w = grapheme_width(grapheme);
+            if used + w > width && !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+                used = 0;
+            }
+            line.push_str(grapheme);
+            used += w;
+        }
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Cuts text to fit in a number of terminal columns, ending it with an
+/// ellipsis when something was cut. Whole graphemes are kept, so accents
+/// and emoji sequences are never split.
+///
+/// # Parameters
+/// - `text: &str` - Text to cut
+/// - `width: usize` - Maximum width in columns, ellipsis included
+/// - `ellipsis: &str` - Marker appended to cut text
+///
+/// # Returns
+/// `String` - Text at most `width` columns wide
+fn truncate_width(text: &str, width: usize, ellipsis: &str) -> String {
+    use unicode_segmentation::UnicodeSegmentation;
+
+    if text_width(text) <= width {
+        return text.to_string();
+    }
+    let budget = width.saturating_sub(text_width(ellipsis));
+    let mut out = String::new();
+    let mut used = 0;
+    for grapheme in text.graphemes(true) {
+        used += grapheme_width(grapheme);
+        if used > budget {
+            break;
+        }
+        out.push_str(grapheme);
+    }
+    out.push_str(ellipsis);
+    out
+}
+
+/// Renders one field of a note for the table output, tags are colored
+/// when a palette is given.
+fn table_field(note: &Note, column: Column, display: &DisplayArgs) -> String {
+    match column {
+        Column::Id if note.pinned => {
+            let pin = if display.no_emoji { "*" } else { "📌" };
+            format!("{} {}", pin, note.id)
+        }
+        Column::Id => note.id.to_string(),
+        Column::Title => note.title.clone().unwrap_or_else(|| "-".to_string()),
+        Column::Content if display.full => note.content.clone(),
+        Column::Content => {
+            let ellipsis = if display.no_emoji { "..." } else { "…" };
+            let lines = note.content.lines();
+            let lines: Vec<String> = match display.wrap {
+                Some(width) => lines.flat_map(|line| wrap_width(line, width)).collect(),
+                None => lines
+                    .map(|line| truncate_width(line, display.width, ellipsis))
+                    .collect(),
+            };
+            lines.join("\n")
+        }
+        Column::Tags => {
+            if note.tags.is_empty() {
+                "-".to_string()
+            } else if let Some(palette) = &display.palette {
+                let tags: Vec<String> = note.tags.iter().map(|tag| palette.paint(tag)).collect();
+                tags.join(", ")
+            } else {
+                note.tags.join(", ")
+            }
+        }
+        Column::Created => display.format_date(note.created_at),
+        Column::Updated => display.format_date(note.updated_at),
+        Column::Due => note_due(note).map_or("-".to_string(), |due| display.format_date(due)),
+        Column::Status => raw_field(note, column),
+        Column::Meta => {
+            if note.meta.is_empty() {
+                "-".to_string()
+            } else {
+                let entries: Vec<String> = note
+                    .meta
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                entries.join("\n")
+            }
+        }
+        Column::Rating => match note.rating {
+            None => "-".to_string(),
+            Some(rating) if display.no_emoji => format!("{}/5", rating),
+            Some(rating) => {
+                let rating = usize::from(rating);
+                format!("{}{}", "★".repeat(rating), "☆".repeat(5 - rating))
+            }
+        },
+    }
+}
+
+/// Renders one field of a note for machine-readable outputs.
+fn raw_field(note: &Note, column: Column) -> String {
+    match column {
+        Column::Id => note.id.to_string(),
+        Column::Title => note.title.clone().unwrap_or_default(),
+        Column::Content => note.content.clone(),
+        Column::Tags => note.tags.join(","),
+        Column::Created => note.created_at.to_rfc3339(),
+        Column::Updated => note.updated_at.to_rfc3339(),
+        Column::Meta => json_field(note, column).to_string(),
+        Column::Rating => note
+            .rating
+            .map_or(String::new(), |rating| rating.to_string()),
+        Column::Due => note_due(note).map_or(String::new(), |due| due.to_rfc3339()),
+        Column::Status => match note.done {
+            true => "done".to_string(),
+            false => "pending".to_string(),
+        },
+    }
+}
+
+/// Renders one field of a note for the JSON output.
+fn json_field(note: &Note, column: Column) -> serde_json::Value {
+    use serde_json::json;
+
+    match column {
+        Column::Id => json!(note.id),
+        Column::Title => json!(note.title),
+        Column::Content => json!(note.content),
+        Column::Tags => json!(note.tags),
+        Column::Created => json!(note.created_at),
+        Column::Updated => json!(note.updated_at),
+        Column::Meta => json!(note.meta),
+        Column::Rating => json!(note.rating),
+        Column::Due => json!(note_due(note)),
+        Column::Status => json!(raw_field(note, column)),
+    }
+}
+
+/// Renders notes as a bordered table. Overdue dates are marked with `!`. When
+/// colored, the header is bold, tags take their palette color, dates older
+/// than [`RECENT_DAYS`] are dimmed and overdue dates are red.
+///
+/// # Parameters
+/// - `notes: &[&Note]` - Notes to render
+/// - `display: &DisplayArgs` - Columns, and the palette when colored
+/// - `now: DateTime<Utc>` - Current time, telling recent dates apart
+///
+/// # Returns
+/// `String` - Rendered table
+fn render_table(notes: &[&Note], display: &DisplayArgs, now: DateTime<Utc>) -> String {
+    let painted = display.palette.is_some();
+    let mut columns = display.columns().to_vec();
+    if display.columns.is_none() && notes.iter().any(|note| note.title.is_some()) {
+        columns.insert(1, Column::Title);
+    }
+    if display.columns.is_none() && notes.iter().any(|note| note.done) {
+        columns.push(Column::Status);
+    }
+    if display.columns.is_none() && notes.iter().any(|note| note_due(note).is_some()) {
+        columns.push(Column::Due);
+    }
+    let mut table = Table::new();
+    table.add_row(Row::new(
+        columns
+            .iter()
+            .map(|column| match painted {
+                true => Cell::new(&TagColor::Bold.paint(column.title())),
+                false => Cell::new(column.title()),
+            })
+            .collect(),
+    ));
+    for note in notes {
+        table.add_row(Row::new(
+            columns
+                .iter()
+                .map(|column| {
+                    let mut field = table_field(note, *column, display);
+                    let date = match column {
+                        Column::Created => Some(note.created_at),
+                        Column::Updated => Some(note.updated_at),
+                        _ => None,
+                    };
+                    let old = date.is_some_and(|d| now - d > Duration::days(RECENT_DAYS));
+                    let overdue =
+                        *column == Column::Due && note_due(note).is_some_and(|due| due < now);
+                    if overdue {
+                        field = format!("! {}", field);
+                    }
+                    match (painted, overdue, old) {
+                        (true, true, _) => Cell::new(&TagColor::Red.paint(&field)),
+                        (true, false, true) => Cell::new(&TagColor::Dim.paint(&field)),
+                        _ => Cell::new(&field),
+                    }
+                })
+                .collect(),
+        ));
+    }
+    table.to_string()
+}
+
+/// Renders selected notes in the requested output format.
+///
+/// # Parameters
+/// - `notes: &[&Note]` - Notes to render, already filtered and sorted
+/// - `display: &DisplayArgs` - Output format and columns
+///
+/// # Returns
+/// `String` - Rendered output, ready to print
+fn render_notes(notes: &[&Note], display: &DisplayArgs) -> String {
+    if display.ids_only {
+        return notes.iter().map(|note| format!("{}\n", note.id)).collect();
+    }
+
+    match display.format {
+        OutputFormat::Table => render_table(notes, display, Utc::now()),
+        OutputFormat::Tsv => {
+            let mut out = display
+                .columns()
+                .iter()
+                .map(|column| column.key())
+                .collect::<Vec<_>>()
+                .join("\t");
+            out.push('\n');
+            for note in notes {
+                let fields: Vec<String> = display
+                    .columns()
+                    .iter()
+                    .map(|column| escape_tsv(&raw_field(note, *column)))
+                    .collect();
+                out.push_str(&fields.join("\t"));
+                out.push('\n');
+            }
+            out
+        }
+        OutputFormat::Plain => {
+            let delimiters = [
+                display.delimiter.as_str(),
+                display.record_delimiter.as_str(),
+            ];
+            let mut warned = false;
+            let mut out = String::new();
+            for note in notes {
+                let fields: Vec<String> = display
+                    .columns()
+                    .iter()
+                    .map(|column| {
+                        let field = raw_field(note, *column);
+                        if delimiters.iter().any(|d| field.contains(d)) {
+                            if !warned {
+                                eprintln!(
+                                    "warning: some fields contain the delimiter, escaping them"
+                                );
+                                warned = true;
+                            }
+                            escape_plain(&field, &delimiters)
+                        } else {
+                            field
+                        }
+                    })
+                    .collect();
+                out.push_str(&fields.join(&display.delimiter));
+                out.push_str(&display.record_delimiter);
+            }
+            out
+        }
+        OutputFormat::Json if display.columns.is_none() => {
+            let mut out = serde_json::to_string_pretty(notes).unwrap_or_default();
+            out.push('\n');
+            out
+        }
+        OutputFormat::Json => {
+            let records: Vec<serde_json::Value> = notes
+                .iter()
+                .map(|note| {
+                    display
+                        .columns()
+                        .iter()
+                        .map(|column| (column.key().to_string(), json_field(note, *column)))
+                        .collect()
+                })
+                .collect();
+            let mut out = serde_json::to_string_pretty(&records).unwrap_or_default();
+            out.push('\n');
+            out
+        }
+        OutputFormat::Porcelain => notes
+            .iter()
+            .map(|note| {
+                let fields: Vec<String> = DEFAULT_COLUMNS
+                    .iter()
+                    .map(|column| escape_tsv(&raw_field(note, *column)))
+                    .collect();
+                format!("{}\n", fields.join("\t"))
+            })
+            .collect(),
+        OutputFormat::Fzf => notes
+            .iter()
+            .map(|note| {
+                format!(
+                    "{}\t{}\t{}\n",
+                    note.id,
+                    escape_tsv(note.content.lines().next().unwrap_or("")),
+                    escape_tsv(&note.tags.join(","))
+                )
+            })
+            .collect(),
+    }
+}
+
+/// Selects the notes shown by list, sorted.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `filter: &FilterArgs` - Filters to apply
+/// - `sort: &SortArgs` - Sort criteria and direction
+///
+/// # Returns
+/// `Vec<&Note>` - Selected notes
+fn select_notes<'a>(data: &'a NoteData, filter: &FilterArgs, sort: &SortArgs) -> Vec<&'a Note> {
+    let mut results: Vec<&Note> = data.notes.iter().filter(|n| filter.matches(n)).collect();
+    sort_notes(&mut results, sort);
+    results
+}
+
+/// Displays all notes in a formatted table or shows empty message.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `sort: &SortArgs` - Sort criteria and direction
+/// - `filter: &FilterArgs` - Filters to apply
+/// - `sample: &SampleArgs` - Random selection among the filtered notes
+/// - `display: &DisplayArgs` - Output format and columns
+///
+/// # Returns
+/// `NoteResult<usize>` - Number of notes shown or error during load operation
+fn list_note(
+    store: &dyn NoteStore,
+    sort: &SortArgs,
+    filter: &FilterArgs,
+    sample: &SampleArgs,
+    display: &DisplayArgs,
+) -> NoteResult<usize> {
+    let data = store.load_created_since(filter.since)?;
+
+    if display.format == OutputFormat::Table
+        && !display.ids_only
+        && holds_no_notes(store, &data, filter.since)?
+    {
+        if display.no_emoji {
+            println!("No notes saved.");
+        } else {
+            println!("📭 No notes saved.");
+        }
+        return Ok(0);
+    }
+
+    let mut results = select_notes(&data, filter, sort);
+    pinned_first(&mut results);
+    if results.is_empty() && display.format == OutputFormat::Table && !display.ids_only {
+        if display.no_emoji {
+            println!("No notes match.");
+        } else {
+            println!("🔍 No notes match.");
+        }
+        return Ok(0);
+    }
+    sample.apply(&mut results);
+    print_notes(&results, display)
+}
+
+/// Tells whether a store holds no notes at all. A load pruned with `--since`
+/// is empty in the sharded layout when only older shards hold notes, so the
+/// whole store is only checked then.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `pruned: &NoteData` - Notes loaded for the `--since` date
+/// - `since: Option<DateTime<Utc>>` - Date the load was pruned with
+///
+/// # Returns
+/// `NoteResult<bool>` - Whether there is no note, or error during load
+fn holds_no_notes(
+    store: &dyn NoteStore,
+    pruned: &NoteData,
+    since: Option<DateTime<Utc>>,
+) -> NoteResult<bool> {
+    match (pruned.notes.is_empty(), since) {
+        (false, _) => Ok(false),
+        (true, None) => Ok(true),
+        (true, Some(_)) => Ok(store.load()?.notes.is_empty()),
+    }
+}
+
+/// Prints selected notes, applying `--offset` and `--limit`; a table then
+/// ends with the number of notes left out. With `--page-size` in a
+/// terminal, the table is shown one page at a time.
+///
+/// # Parameters
+/// - `notes: &[&Note]` - Notes to print, already filtered and sorted
+/// - `display: &DisplayArgs` - Output format, columns and paging
+///
+/// # Returns
+/// `NoteResult<usize>` - Number of notes selected after offset and limit
+fn print_notes(notes: &[&Note], display: &DisplayArgs) -> NoteResult<usize> {
+    let (offset, limit) = match display.per_page {
+        Some(per_page) => ((display.page.unwrap_or(1) - 1) * per_page, Some(per_page)),
+        None => (display.offset, display.limit),
+    };
+    let shown = slice_notes(notes, offset, limit);
+
+    match display.page_size {
+        Some(page_size)
+            if interactive() && display.format == OutputFormat::Table && !display.ids_only =>
+        {
+            let mut input = std::io::stdin().lock();
+            page_notes(
+                shown,
+                page_size,
+                display,
+                &mut input,
+                &mut std::io::stdout(),
+            )?;
+        }
+        _ => {
+            print!("{}", render_notes(shown, display));
+            let table = display.format == OutputFormat::Table && !display.ids_only;
+            if let Some(per_page) = display.per_page {
+                // keep machine-readable output clean, scripts get it on stderr
+                let summary = page_summary(display.page.unwrap_or(1), per_page, notes.len());
+                if table {
+                    println!("{}", summary);
+                } else {
+                    eprintln!("{}", summary);
+                }
+            } else if table && shown.len() < notes.len() {
+                println!("showing {} of {} notes", shown.len(), notes.len());
+            }
+        }
+    }
+    Ok(shown.len())
+}
+
+/// Describes a page of `--page`, such as `page 2/5, 243 notes`.
+///
+/// # Parameters
+/// - `page: usize` - Page shown, from 1, possibly past the last one
+/// - `per_page: usize` - Notes per page
+/// - `total: usize` - Number of selected notes
+///
+/// # Returns
+/// `String` - Page, number of pages and of notes
+fn page_summary(page: usize, per_page: usize, total: usize) -> String {
+    format!(
+        "page {}/{}, {} notes",
+        page,
+        total.div_ceil(per_page).max(1),
+        total
+    )
+}
+
+/// Notes left after skipping `offset` of them and keeping at most `limit`,
+/// a limit of 0 keeping them all.
+fn slice_notes<'a, 'b>(
+    notes: &'b [&'a Note],
+    offset: usize,
+    limit: Option<usize>,
+) -> &'b [&'a Note] {
+    let rest = &notes[offset.min(notes.len())..];
+    let limit = limit.filter(|&limit| limit > 0);
+    &rest[..limit.map_or(rest.len(), |limit| limit.min(rest.len()))]
+}
+
+/// Shows notes one page at a time, asking between pages whether to go to
+/// the next or previous page or to quit. End of input quits.
+///
+/// # Parameters
+/// - `notes: &[&Note]` - Notes to show, in order
+/// - `page_size: usize` - Notes per page
+/// - `display: &DisplayArgs` - Output format and columns
+/// - `input: &mut impl BufRead` - Where the answers are read
+/// - `output: &mut impl Write` - Where the pages are written
+///
+/// # Returns
+/// `NoteResult<()>` - Success or error while reading or writing
+fn page_notes(
+    notes: &[&Note],
+    page_size: usize,
+    display: &DisplayArgs,
+    input: &mut impl std::io::BufRead,
+    output: &mut impl std::io::Write,
+) -> NoteResult<()> {
+    let pages = notes.len().div_ceil(page_size).max(1);
+    let mut page = 0;
+    loop {
+        let start = page * page_size;
+        let end = (start + page_size).min(notes.len());
+        write!(output, "{}", render_notes(&notes[start..end], display))?;
+        if pages == 1 {
+            return Ok(());
+        }
+
+        loop {
+            let prompt = format!("Page {}/{} [n]ext, [p]rev, [q]uit: ", page + 1, pages);
+            let Some(answer) = prompt_line(input, output, &prompt)? else {
+                writeln!(output)?;
+                return Ok(());
+            };
+            match answer.trim().to_lowercase().as_str() {
+                "n" | "" if page + 1 < pages => page += 1,
+                "p" if page > 0 => page -= 1,
+                "n" | "" | "p" => {
+                    writeln!(output, "No more pages that way")?;
+                    continue;
+                }
+                "q" => return Ok(()),
+                _ => {
+                    writeln!(output, "Type n, p or q")?;
+                    continue;
+                }
+            }
+            break;
+        }
+    }
+}
+
+/// Add a tag to a note
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - Id of the note we want to add a tag
+/// - `tag: String` - Tag to add to the note specified by id
+///
+/// # Returns
+/// `NoteResult<()>` - Success, or error when no tag is given, the note
+/// doesn't exist or during load or save
+fn add_tag(store: &dyn NoteStore, id: u32, tags: Vec<String>) -> NoteResult<()> {
+    if tags.is_empty() {
+        return Err("no tag given".into());
+    }
+    with_note(store, id, |note| {
+        // add tags
+        for tag in tags {
+            if !note.tags.contains(&tag) {
+                note.tags.push(tag);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Remove tags from a note
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - Id of the note
+/// - `tags: &[String]` - Tags to remove, all must be on the note
+///
+/// # Returns
+/// `NoteResult<()>` - Success, or error if the note or one of the tags is
+/// missing, in which case nothing is changed
+fn remove_tag(store: &dyn NoteStore, id: u32, tags: &[String]) -> NoteResult<()> {
+    with_note(store, id, |note| {
+        let missing: Vec<&str> = tags
+            .iter()
+            .filter(|tag| !note.tags.contains(tag))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("note {} has no tag {}", id, missing.join(", ")).into());
+        }
+        note.tags.retain(|tag| !tags.contains(tag));
+        note.updated_at = Utc::now();
+        Ok(())
+    })
+}
+
+/// Renames a tag on every note, merging it into `new` on notes that
+/// already carry both.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `old: &str` - Current name of the tag
+/// - `new: &str` - New name of the tag
+/// - `dry_run: bool` - Only find the notes, don't save anything
+///
+/// # Returns
+/// `NoteResult<Vec<u32>>` - IDs of the changed notes, ascending, or error
+/// if no note has the tag
+fn rename_tag(store: &dyn NoteStore, old: &str, new: &str, dry_run: bool) -> NoteResult<Vec<u32>> {
+    let rename = |data: &mut NoteData| {
+        let now = Utc::now();
+        let mut changed = Vec::new();
+        for note in data.notes.iter_mut() {
+            if !note.tags.iter().any(|tag| tag == old) {
+                continue;
+            }
+            let mut tags = Vec::new();
+            for tag in note.tags.drain(..) {
+                let tag = if tag == old { new.to_string() } else { tag };
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+            note.tags = tags;
+            note.updated_at = now;
+            changed.push(note.id);
+        }
+        if changed.is_empty() {
+            return Err(format!("no note has the tag {}", old).into());
+        }
+        changed.sort_unstable();
+        Ok(changed)
+    };
+    match dry_run {
+        true => rename(&mut store.load()?),
+        false => with_store(store, rename),
+    }
+}
+
+/// Edit a note
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - Id of the note to edit
+/// - `content: String` - New content
+/// - `history_limit: usize` - Previous contents kept
+///
+/// # Returns
+/// `NoteResult<()>` - Success or error during load operation
+fn edit_note(
+    store: &dyn NoteStore,
+    id: u32,
+    content: String,
+    history_limit: usize,
+) -> NoteResult<()> {
+    let changes = NoteChanges {
+        content: Some(content),
+        history_limit,
+        ..NoteChanges::default()
+    };
+    update_note(store, id, &changes)
+}
+
+// Changes made to a note by one edit
+#[derive(Debug)]
+struct NoteChanges {
+    content: Option<String>,
+    title: Option<String>, // empty to remove the title
+    rating: Option<u8>,
+    due: Option<DateTime<Utc>>,
+    add_tags: Vec<String>,
+    remove_tags: Vec<String>,
+    set_tags: Option<Vec<String>>,
+    history_limit: usize, // previous contents kept
+}
+
+impl Default for NoteChanges {
+    fn default() -> Self {
+        NoteChanges {
+            content: None,
+            title: None,
+            rating: None,
+            due: None,
+            add_tags: vec![],
+            remove_tags: vec![],
+            set_tags: None,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+        }
+    }
+}
+
+/// Applies several changes to a note with a single save. The update date
+/// only moves when the note actually changed.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - Id of the note to edit
+/// - `changes: &NoteChanges` - Changes to apply
+///
+/// # Returns
+/// `NoteResult<()>` - Success, or error when the content is empty, the note
+/// doesn't exist or during load or save
+fn update_note(store: &dyn NoteStore, id: u32, changes: &NoteChanges) -> NoteResult<()> {
+    if changes.content.as_ref().is_some_and(|c| c.is_empty()) {
+        return Err("no content given".into());
+    }
+    with_note(store, id, |note| {
+        let before = note.clone();
+        let now = Utc::now();
+        if let Some(content) = changes.content.as_ref().filter(|c| **c != note.content) {
+            note.replace_content(content.clone(), now, changes.history_limit);
+        }
+        if let Some(title) = &changes.title {
+            note.title = Some(title.clone()).filter(|title| !title.is_empty());
+        }
+        if let Some(rating) = changes.rating {
+            note.rating = Some(rating);
+        }
+        if let Some(due) = changes.due {
+            note.due = Some(due);
+        }
+        if let Some(tags) = &changes.set_tags {
+            note.tags.clear();
+            for tag in tags {
+                if !note.tags.contains(tag) {
+                    note.tags.push(tag.clone());
+                }
+            }
+        }
+        note.tags.retain(|tag| !changes.remove_tags.contains(tag));
+        for tag in &changes.add_tags {
+            if !note.tags.contains(tag) {
+                note.tags.push(tag.clone());
+            }
+        }
+
+        if before == *note {
+            println!("Note {} unchanged", id);
+            return Ok(());
+        }
+        note.updated_at = now;
+        Ok(())
+    })
+}
+
+/// Rate a note
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - Id of the note to rate
+/// - `rating: u8` - Rating from 1 to 5
+///
+/// # Returns
+/// `NoteResult<()>` - Success, or error when the note doesn't exist or
+/// during load or save
+fn rate_note(store: &dyn NoteStore, id: u32, rating: u8) -> NoteResult<()> {
+    with_note(store, id, |note| {
+        note.rating = Some(rating);
+        note.updated_at = Utc::now();
+        Ok(())
+    })
+}
+
+/// Sets or removes a metadata entry of a note
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - Id of the note to change
+/// - `key: &str` - Metadata key
+/// - `value: Option<String>` - New value, `None` removes the entry
+///
+/// # Returns
+/// `NoteResult<()>` - Success, or error when the note or the entry to remove
+/// doesn't exist or during load or save
+fn set_meta(store: &dyn NoteStore, id: u32, key: &str, value: Option<String>) -> NoteResult<()> {
+    with_note(store, id, |note| {
+        match value {
+            Some(value) => {
+                note.meta.insert(key.to_string(), value);
+            }
+            None => {
+                if note.meta.remove(key).is_none() {
+                    return Err(format!("note {} has no metadata {}", id, key).into());
+                }
+            }
+        }
+        note.updated_at = Utc::now();
+        Ok(())
+    })
+}
+
+// Text matcher shared by search and show --grep, case-insensitive unless
+// asked otherwise
+enum Matcher {
+    /// Lowercased text to find
+    Substring(String),
+    /// Text to find with its case
+    Exact(String),
+    Regex(regex::Regex),
+    /// Letters to find in order, with the score of a perfect match
+    Fuzzy(Box<SkimMatcherV2>, String, i64),
+    /// Every matcher must match, in any field of a note
+    All(Vec<Matcher>),
+    /// One of the matchers must match
+    Any(Vec<Matcher>),
+}
+
+/// Share of the score of a perfect match a fuzzy match needs, so that
+/// letters scattered over a long note don't count as a match
+const FUZZY_THRESHOLD: f64 = 0.85;
+
+impl Matcher {
+    /// Builds a case-insensitive matcher from a pattern given on the
+    /// command line.
+    ///
+    /// # Parameters
+    /// - `pattern: &str` - Text or regular expression to find
+    /// - `regex: bool` - Treat the pattern as a regular expression
+    ///
+    /// # Returns
+    /// `NoteResult<Matcher>` - Matcher or error for an invalid regex
+    fn new(pattern: &str, regex: bool) -> NoteResult<Matcher> {
+        Matcher::with_case(pattern, regex, false)
+    }
+
+    /// Builds a matcher, telling whether case matters.
+    ///
+    /// # Parameters
+    /// - `pattern: &str` - Text or regular expression to find
+    /// - `regex: bool` - Treat the pattern as a regular expression
+    /// - `case_sensitive: bool` - Only match text with the same case
+    ///
+    /// # Returns
+    /// `NoteResult<Matcher>` - Matcher or error for an invalid regex
+    fn with_case(pattern: &str, regex: bool, case_sensitive: bool) -> NoteResult<Matcher> {
+        if regex {
+            let regex = regex::RegexBuilder::new(pattern)
+                .case_insensitive(!case_sensitive)
+                .build()?;
+            Ok(Matcher::Regex(regex))
+        } else if case_sensitive {
+            Ok(Matcher::Exact(pattern.to_string()))
+        } else {
+            Ok(Matcher::Substring(pattern.to_lowercase()))
+        }
+    }
+
+    /// Tells whether matches are scored by quality
+    fn is_fuzzy(&self) -> bool {
+        match self {
+            Matcher::Fuzzy(..) => true,
+            Matcher::All(matchers) | Matcher::Any(matchers) => {
+                matchers.iter().any(Matcher::is_fuzzy)
+            }
+            _ => false,
+        }
+    }
+
+    /// Builds a case-insensitive matcher tolerating typos.
+    fn fuzzy(pattern: &str) -> Matcher {
+        let matcher = SkimMatcherV2::default().ignore_case();
+        let best = matcher.fuzzy_match(pattern, pattern).unwrap_or(0);
+        Matcher::Fuzzy(Box::new(matcher), pattern.to_string(), best)
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            Matcher::Substring(keyword) => text.to_lowercase().contains(keyword),
+            Matcher::Exact(keyword) => text.contains(keyword),
+            Matcher::Regex(regex) => regex.is_match(text),
+            Matcher::Fuzzy(..) => self.score(text).is_some(),
+            Matcher::All(matchers) => matchers.iter().all(|m| m.is_match(text)),
+            Matcher::Any(matchers) => matchers.iter().any(|m| m.is_match(text)),
+        }
+    }
+
+    /// Quality of a fuzzy match, higher is better. Other matchers score
+    /// every match 0.
+    ///
+    /// # Parameters
+    /// - `text: &str` - Text to look in
+    ///
+    /// # Returns
+    /// `Option<i64>` - Score, `None` when the text doesn't match
+    fn score(&self, text: &str) -> Option<i64> {
+        match self {
+            Matcher::Fuzzy(matcher, pattern, best) => matcher
+                .fuzzy_match(text, pattern)
+                .filter(|&score| score as f64 >= *best as f64 * FUZZY_THRESHOLD),
+            _ => self.is_match(text).then_some(0),
+        }
+    }
+}
+
+/// Describes how long ago something happened, in the largest whole unit:
+/// "just now", "35 minutes ago", "2 months ago". Negative durations are in
+/// the future and give "in 3 days". Months count 30 days and years 365.
+///
+/// # Parameters
+/// - `elapsed: Duration` - Time since the event
+///
+/// # Returns
+/// `String` - Human-friendly description
+fn humanize_duration(elapsed: Duration) -> String {
+    let seconds = elapsed.num_seconds().unsigned_abs();
+    let units = [
+        (365 * 86400, "year"),
+        (30 * 86400, "month"),
+        (86400, "day"),
+        (3600, "hour"),
+        (60, "minute"),
+    ];
+    let Some((amount, unit)) = units
+        .iter()
+        .find(|(length, _)| seconds >= *length)
+        .map(|(length, unit)| (seconds / length, unit))
+    else {
+        return "just now".to_string();
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    if elapsed < Duration::zero() {
+        format!("in {} {}{}", amount, unit, plural)
+    } else {
+        format!("{} {}{} ago", amount, unit, plural)
+    }
+}
+
+/// Formats a stored date in a time zone.
+///
+/// # Parameters
+/// - `date: DateTime<Utc>` - Date to format
+/// - `zone: &Tz` - Time zone to show the date in
+/// - `format: &str` - strftime format, checked by [`parse_date_format`]
+///
+/// # Returns
+/// `String` - Formatted date
+fn format_date_in<Tz: TimeZone>(date: DateTime<Utc>, zone: &Tz, format: &str) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    // formatting can still fail when a specifier needs a field the date lacks
+    if write!(out, "{}", date.with_timezone(zone).format(format)).is_err() {
+        out = date
+            .with_timezone(zone)
+            .format(DEFAULT_DATE_FORMAT)
+            .to_string();
+    }
+    out
+}
+
+// Time zone of the dates shown by show
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DateZone {
+    /// Offset the note was written in, UTC for notes without one
+    Original,
+    Utc,
+    Local,
+}
+
+/// Formats a date for show. Dates outside UTC carry their offset.
+///
+/// # Parameters
+/// - `date: DateTime<Utc>` - Date to format
+/// - `note: &Note` - Note the date belongs to
+/// - `zone: DateZone` - Time zone to show the date in
+/// - `format: &str` - strftime format
+///
+/// # Returns
+/// `String` - Formatted date
+fn format_note_date(date: DateTime<Utc>, note: &Note, zone: DateZone, format: &str) -> String {
+    let offset = match zone {
+        DateZone::Original => note.created_offset.and_then(chrono::FixedOffset::east_opt),
+        DateZone::Utc => None,
+        DateZone::Local => Some(*date.with_timezone(&Local).offset()),
+    };
+    match offset {
+        Some(offset) => format!("{} {}", format_date_in(date, &offset, format), offset),
+        None => format_date_in(date, &Utc, format),
+    }
+}
+
+/// Renders a note with all its fields, content last.
+///
+/// # Parameters
+/// - `note: &Note` - Note to render
+/// - `display: &DisplayArgs` - Tag colors and symbols, as in the table
+/// - `zone: DateZone` - Time zone of the dates
+///
+/// # Returns
+/// `String` - Rendered note
+fn render_note_details(note: &Note, display: &DisplayArgs, zone: DateZone) -> String {
+    let mut out = format!("Note {}\n", note.id);
+    if let Some(title) = &note.title {
+        out.push_str(&format!("{}: {}\n", Column::Title.title(), title));
+    }
+    if note.tags.is_empty() {
+        out.push_str(&format!("{}: -\n", Column::Tags.title()));
+    } else {
+        // one tag per line, a long list stays readable
+        out.push_str(&format!("{}:\n", Column::Tags.title()));
+        for tag in &note.tags {
+            let tag = display
+                .palette
+                .as_ref()
+                .map_or(tag.clone(), |palette| palette.paint(tag));
+            out.push_str(&format!("  {}\n", tag));
+        }
+    }
+    for (column, date) in [
+        (Column::Created, note.created_at),
+        (Column::Updated, note.updated_at),
+    ] {
+        let value = format_note_date(date, note, zone, &display.date_format);
+        out.push_str(&format!("{}: {}\n", column.title(), value));
+    }
+    if note.rating.is_some() {
+        let value = table_field(note, Column::Rating, display);
+        out.push_str(&format!("{}: {}\n", Column::Rating.title(), value));
+    }
+    if let Some(due) = note_due(note) {
+        let overdue = if due < Utc::now() { " (overdue)" } else { "" };
+        let due = format_note_date(due, note, zone, &display.date_format);
+        out.push_str(&format!("{}: {}{}\n", Column::Due.title(), due, overdue));
+    }
+    if let Some(expires_at) = note.expires_at {
+        let expires_at = format_note_date(expires_at, note, zone, &display.date_format);
+        out.push_str(&format!("Expires at: {}\n", expires_at));
+    }
+    if note.archived {
+        out.push_str("Archived: yes\n");
+    }
+    if note.done {
+        out.push_str(&format!("{}: done\n", Column::Status.title()));
+    }
+    if note.pinned {
+        out.push_str("Pinned: yes\n");
+    }
+    for (key, value) in &note.meta {
+        out.push_str(&format!("{}: {}\n", key, value));
+    }
+    out.push('\n');
+    out.push_str(&note.content);
+    out.push('\n');
+    out
+}
+
+/// Fetches a note by ID.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - ID of the note
+///
+/// # Returns
+/// `NoteResult<Note>` - The note, or an `ID N not found` error
+fn find_note(store: &dyn NoteStore, id: u32) -> NoteResult<Note> {
+    store.get(id)?.ok_or(NoteError::NotFound(vec![id]))
+}
+
+/// Marks a note as done or pending and moves its update date.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - ID of the note
+/// - `done: bool` - New status
+///
+/// # Returns
+/// `NoteResult<bool>` - Whether the status changed, or error for an unknown
+/// ID or during load or save
+fn set_done(store: &dyn NoteStore, id: u32, done: bool) -> NoteResult<bool> {
+    with_note(store, id, |note| {
+        if note.done == done {
+            return Ok(false);
+        }
+        note.done = done;
+        note.updated_at = Utc::now();
+        Ok(true)
+    })
+}
+
+/// Pins or unpins a note. Its update date is kept, the content didn't change.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - ID of the note
+/// - `pinned: bool` - Whether the note should be pinned
+///
+/// # Returns
+/// `NoteResult<bool>` - Whether the note changed, or error for an unknown
+/// ID or during load or save
+fn set_pinned(store: &dyn NoteStore, id: u32, pinned: bool) -> NoteResult<bool> {
+    with_note(store, id, |note| {
+        if note.pinned == pinned {
+            return Ok(false);
+        }
+        note.pinned = pinned;
+        Ok(true)
+    })
+}
+
+/// Adds text at the end of a note and moves its update date.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - ID of the note
+/// - `text: &str` - Text to add
+/// - `newline: bool` - Put the text on a new line
+/// - `history_limit: usize` - Previous contents kept
+///
+/// # Returns
+/// `NoteResult<()>` - Success, or an `ID N not found` error
+fn append_note(
+    store: &dyn NoteStore,
+    id: u32,
+    text: &str,
+    newline: bool,
+    history_limit: usize,
+) -> NoteResult<()> {
+    with_note(store, id, |note| {
+        let mut content = note.content.clone();
+        if newline && !content.is_empty() {
+            content.push('\n');
+        }
+        content.push_str(text);
+        let now = Utc::now();
+        note.replace_content(content, now, history_limit);
+        note.updated_at = now;
+        Ok(())
+    })
+}
+
+/// Puts back a previous content of a note. The replaced content becomes the
+/// latest revision.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - ID of the note
+/// - `revision: usize` - Revision number, 1 being the oldest kept
+/// - `history_limit: usize` - Previous contents kept
+///
+/// # Returns
+/// `NoteResult<()>` - Success, or error for an unknown note or revision
+fn revert_note(
+    store: &dyn NoteStore,
+    id: u32,
+    revision: usize,
+    history_limit: usize,
+) -> NoteResult<()> {
+    let note = find_note(store, id)?;
+    let Some(old) = revision.checked_sub(1).and_then(|i| note.history.get(i)) else {
+        return Err(format!("note {} has no revision {}", id, revision).into());
+    };
+    edit_note(store, id, old.content.clone(), history_limit)
+}
+
+/// Renders the previous contents of a note as a table, oldest first.
+///
+/// # Parameters
+/// - `note: &Note` - Note whose history is shown
+/// - `display: &DisplayArgs` - Date format and emoji settings
+///
+/// # Returns
+/// `String` - Rendered table
+fn render_history(note: &Note, display: &DisplayArgs) -> String {
+    let ellipsis = if display.no_emoji { "..." } else { "…" };
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![
+        Cell::new("#"),
+        Cell::new("Edited at"),
+        Cell::new("Content"),
+    ]));
+    for (index, revision) in note.history.iter().enumerate() {
+        let first_line = revision.content.lines().next().unwrap_or_default();
+        table.add_row(Row::new(vec![
+            Cell::new(&(index + 1).to_string()),
+            Cell::new(&display.format_date(revision.edited_at)),
+            Cell::new(&truncate_width(first_line, display.width, ellipsis)),
+        ]));
+    }
+    table.to_string()
+}
+
+/// Fetches notes by ID, failing before anything is shown when some are
+/// missing.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `ids: &[u32]` - IDs of the notes
+///
+/// # Returns
+/// `NoteResult<Vec<Note>>` - Notes in the order of `ids`, or an error
+/// naming every missing ID
+fn get_notes(store: &dyn NoteStore, ids: &[u32]) -> NoteResult<Vec<Note>> {
+    let mut notes = Vec::new();
+    let mut missing = Vec::new();
+    for &id in ids {
+        match store.get(id)? {
+            Some(note) => notes.push(note),
+            None => missing.push(id),
+        }
+    }
+    match missing.is_empty() {
+        true => Ok(notes),
+        false => Err(NoteError::NotFound(missing)),
+    }
+}
+
+/// Renders notes with all their fields, separated by a horizontal rule.
+///
+/// # Parameters
+/// - `notes: &[Note]` - Notes to render
+/// - `display: &DisplayArgs` - Tag colors and symbols, as in the table
+/// - `zone: DateZone` - Time zone of the dates
+///
+/// # Returns
+/// `String` - Rendered notes
+fn render_notes_details(notes: &[Note], display: &DisplayArgs, zone: DateZone) -> String {
+    let rule = if display.no_emoji { "-" } else { "─" }.repeat(40);
+    notes
+        .iter()
+        .map(|note| render_note_details(note, display, zone))
+        .collect::<Vec<_>>()
+        .join(&format!("{}\n", rule))
+}
+
+/// Renders the lines of a text matching a matcher like grep: each line
+/// prefixed by its number and `:`, context lines by `-`, and with context
+/// `--` between groups of lines that aren't contiguous.
+///
+/// # Parameters
+/// - `text: &str` - Text to filter, with `\n` or `\r\n` line endings
+/// - `matcher: &Matcher` - Lines to keep
+/// - `context: usize` - Number of lines kept around each match
+///
+/// # Returns
+/// `Option<String>` - Rendered lines, `None` when no line matches
+fn grep_lines(text: &str, matcher: &Matcher, context: usize) -> Option<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let matching: Vec<usize> = (0..lines.len())
+        .filter(|&i| matcher.is_match(lines[i]))
+        .collect();
+    if matching.is_empty() {
+        return None;
+    }
+
+    let mut out = String::new();
+    let mut next = 0; // first line not printed yet
+    for &i in &matching {
+        let start = i.saturating_sub(context).max(next);
+        if context > 0 && start > next && next > 0 {
+            out.push_str("--\n");
+        }
+        let end = (i + context).min(lines.len() - 1);
+        for (j, line) in lines.iter().enumerate().take(end + 1).skip(start) {
+            let mark = if matching.binary_search(&j).is_ok() {
+                ':'
+            } else {
+                '-'
+            };
+            out.push_str(&format!("{}{}{}\n", j + 1, mark, line));
+        }
+        next = next.max(end + 1);
+    }
+    Some(out)
+}
+
+/// Selects the notes whose content or tags match a keyword, sorted.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `matcher: &Matcher` - Keyword or pattern to look for
+/// - `scope: SearchScope` - Fields the keyword is looked for in
+/// - `filter: &FilterArgs` - Filters to apply
+/// - `sort: &SortArgs` - Sort criteria and direction
+///
+/// # Returns
+/// `Vec<&Note>` - Matching notes
+fn search_matches<'a>(
+    data: &'a NoteData,
+    matcher: &Matcher,
+    scope: SearchScope,
+    filter: &FilterArgs,
+    sort: &SortArgs,
+) -> Vec<&'a Note> {
+    let mut results: Vec<(i64, &Note)> = data
+        .notes
+        .iter()
+        .filter(|n| filter.matches(n))
+        .filter_map(|n| note_score(matcher, n, scope).map(|score| (score, n)))
+        .collect();
+
+    if matcher.is_fuzzy() && sort.methods.is_empty() {
+        results.sort_by_key(|(score, note)| (std::cmp::Reverse(*score), note.id));
+        if sort.reverse {
+            results.reverse();
+        }
+        return results.into_iter().map(|(_, note)| note).collect();
+    }
+    let mut results: Vec<&Note> = results.into_iter().map(|(_, note)| note).collect();
+    sort_notes(&mut results, sort);
+    results
+}
+
+/// Scores a note against a matcher, each keyword of a combined matcher
+/// being looked for in all the searched fields.
+///
+/// # Parameters
+/// - `matcher: &Matcher` - Keywords to look for
+/// - `note: &Note` - Note to look in
+/// - `scope: SearchScope` - Fields the keywords are looked for in
+///
+/// # Returns
+/// `Option<i64>` - Score as given by `Matcher::score`, `None` when the note
+/// doesn't match
+fn note_score(matcher: &Matcher, note: &Note, scope: SearchScope) -> Option<i64> {
+    match matcher {
+        Matcher::All(matchers) => matchers.iter().map(|m| note_score(m, note, scope)).sum(),
+        Matcher::Any(matchers) => matchers
+            .iter()
+            .filter_map(|m| note_score(m, note, scope))
+            .max(),
+        _ => {
+            let content = (scope != SearchScope::Tags)
+                .then(|| {
+                    let title = note.title.as_deref().and_then(|title| matcher.score(title));
+                    title.max(matcher.score(&note.content))
+                })
+                .flatten();
+            let tags = (scope != SearchScope::Content)
+                .then(|| note.tags.iter().filter_map(|tag| matcher.score(tag)).max())
+                .flatten();
+            content.max(tags)
+        }
+    }
+}
+
+/// Search field in all notes
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `keywords: &[String]` - Keywords to search
+/// - `matching: &MatchArgs` - How the keywords are compared with the notes
+/// - `scope: SearchScope` - Fields the keyword is looked for in
+/// - `sort: &SortArgs` - Sort criteria and direction
+/// - `filter: &FilterArgs` - Filters to apply
+/// - `display: &DisplayArgs` - Output format and columns
+///
+/// # Returns
+/// `NoteResult<usize>` - Number of matching notes, or error during load
+/// operation or for an invalid regex
+fn search_note(
+    store: &dyn NoteStore,
+    keywords: &[String],
+    matching: &MatchArgs,
+    scope: SearchScope,
+    sort: &SortArgs,
+    filter: &FilterArgs,
+    display: &DisplayArgs,
+) -> NoteResult<usize> {
+    let data = store.load_created_since(filter.since)?;
+
+    if keywords.iter().all(|keyword| keyword.is_empty()) {
+        return Err("no keyword given".into());
+    }
+
+    let matcher = matching.matcher(keywords)?;
+    let results = search_matches(&data, &matcher, scope, filter, sort);
+    print_notes(&results, display)
+}
+
+/// Counts the notes list or search would show.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `keyword: Option<&str>` - Keyword the notes must contain, in content or tags
+/// - `filter: &FilterArgs` - Filters to apply
+///
+/// # Returns
+/// `NoteResult<usize>` - Number of notes or error during load operation
+fn count_notes(
+    store: &dyn NoteStore,
+    keyword: Option<&str>,
+    filter: &FilterArgs,
+) -> NoteResult<usize> {
+    let data = store.load_created_since(filter.since)?;
+    let sort = SortMethod::Id.into();
+    Ok(match keyword {
+        Some(keyword) => {
+            let matcher = Matcher::new(keyword, false)?;
+            search_matches(&data, &matcher, SearchScope::All, filter, &sort).len()
+        }
+        None => data.notes.iter().filter(|n| filter.matches(n)).count(),
+    })
+}
+
+/// Turns a tag into a valid Org tag: letters, digits, `_`, `@`, `#` and
+/// `%` are kept, anything else becomes `_`.
+fn org_tag(tag: &str) -> String {
+    tag.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "_@#%".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Formats a date as an inactive Org timestamp, e.g. `[2024-06-01 Sat 12:00]`.
+fn org_timestamp(date: DateTime<Utc>) -> String {
+    date.format("[%Y-%m-%d %a %H:%M]").to_string()
+}
+
+/// Selects the notes to export, ordered by ID. Expired and archived notes
+/// are kept.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `tags: &[String]` - Tags every exported note must have
+///
+/// # Returns
+/// `Vec<&Note>` - Notes to export
+fn export_selection<'a>(data: &'a NoteData, tags: &[String]) -> Vec<&'a Note> {
+    let filter = FilterArgs {
+        tags: tags.to_vec(),
+        expired: true,
+        with_archived: true,
+        ..FilterArgs::default()
+    };
+    let mut notes: Vec<&Note> = data.notes.iter().filter(|n| filter.matches(n)).collect();
+    notes.sort_by_key(|note| note.id);
+    notes
+}
+
+/// Renders notes as an Org-mode document: a heading with the first line and
+/// tags of each note, its due date as a deadline, a property drawer, then
+/// the rest of the content.
+///
+/// # Parameters
+/// - `notes: &[&Note]` - Notes to render, in order
+///
+/// # Returns
+/// `String` - Org document
+fn render_org(notes: &[&Note]) -> String {
+    let mut out = String::new();
+    for note in notes.iter() {
+        let mut lines = note.content.lines();
+        let title = lines.next().unwrap_or("").trim();
+        out.push_str("* ");
+        out.push_str(if title.is_empty() { "(empty)" } else { title });
+
+        let mut tags: Vec<String> = Vec::new();
+        for tag in note.tags.iter().map(|tag| org_tag(tag)) {
+            if !tag.is_empty() && !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        if !tags.is_empty() {
+            out.push_str(&format!(" :{}:", tags.join(":")));
+        }
+        out.push('\n');
+
+        if let Some(due) = note_due(note) {
+            let format = match due.time() == chrono::NaiveTime::MIN {
+                true => "<%Y-%m-%d %a>",
+                false => "<%Y-%m-%d %a %H:%M>",
+            };
+            out.push_str(&format!("DEADLINE: {}\n", due.format(format)));
+        }
+        out.push_str(":PROPERTIES:\n");
+        out.push_str(&format!(":ID: {}\n", note.id));
+        out.push_str(&format!(":CREATED: {}\n", org_timestamp(note.created_at)));
+        out.push_str(&format!(":UPDATED: {}\n", org_timestamp(note.updated_at)));
+        out.push_str(":END:\n");
+
+        for line in lines {
+            // a line starting with stars would be read as a heading
+            if line.starts_with('*') {
+                out.push(' ');
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Renders notes as CSV with a header row. Tags are joined with `;` and
+/// dates written in RFC 3339; the header matches the default import fields,
+/// so the file can be imported back.
+///
+/// # Parameters
+/// - `notes: &[&Note]` - Notes to render, in order
+///
+/// # Returns
+/// `NoteResult<String>` - CSV document
+fn render_csv(notes: &[&Note]) -> NoteResult<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["id", "content", "tags", "created_at", "updated_at"])?;
+    for note in notes {
+        writer.write_record([
+            note.id.to_string(),
+            note.content.clone(),
+            note.tags.join(";"),
+            note.created_at.to_rfc3339(),
+            note.updated_at.to_rfc3339(),
+        ])?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Makes a file name part from the first words of the first line of a note: lowercase
+/// letters and digits, anything else collapsed into single dashes.
+///
+/// # Parameters
+/// - `content: &str` - Note content
+///
+/// # Returns
+/// `String` - Slug, empty when the note has no letter or digit
+fn slug(content: &str) -> String {
+    let title = content.lines().next().unwrap_or("");
+    let words: Vec<&str> = title.split_whitespace().take(SLUG_WORDS).collect();
+    let mut slug = String::new();
+    for c in words.join(" ").chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Renders a note as a Markdown file: YAML front matter with its tags and
+/// dates, then the content.
+///
+/// # Parameters
+/// - `note: &Note` - Note to render
+///
+/// # Returns
+/// `(String, String)` - File name and file content
+fn render_markdown_file(note: &Note) -> (String, String) {
+    let slug = slug(&note.content);
+    let name = if slug.is_empty() {
+        format!("{}.md", note.id)
+    } else {
+        format!("{}-{}.md", note.id, slug)
+    };
+    // JSON strings are valid YAML scalars and keep any tag safe to parse
+    let tags: Vec<String> = note
+        .tags
+        .iter()
+        .map(|tag| serde_json::Value::from(tag.as_str()).to_string())
+        .collect();
+    let content = format!(
+        "---\ntags: [{}]\ncreated_at: {}\nupdated_at: {}\n---\n{}\n",
+        tags.join(", "),
+        note.created_at.to_rfc3339(),
+        note.updated_at.to_rfc3339(),
+        note.content.trim_end()
+    );
+    (name, content)
+}
+
+/// Renders notes as a single Markdown document with a `## Note <id>`
+/// section per note.
+///
+/// # Parameters
+/// - `notes: &[&Note]` - Notes to render, in order
+///
+/// # Returns
+/// `String` - Markdown document
+fn render_markdown(notes: &[&Note]) -> String {
+    notes
+        .iter()
+        .map(|note| format!("## Note {}\n\n{}\n", note.id, note.content.trim_end()))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Writes one Markdown file per note in a directory, created if needed.
+/// Nothing is written when a file exists, unless `force` is set.
+///
+/// # Parameters
+/// - `notes: &[&Note]` - Notes to write
+/// - `dir: &Path` - Target directory
+/// - `force: bool` - Overwrite existing files
+///
+/// # Returns
+/// `NoteResult<usize>` - Number of files written
+fn write_markdown_dir(notes: &[&Note], dir: &Path, force: bool) -> NoteResult<usize> {
+    let files: Vec<(PathBuf, String)> = notes
+        .iter()
+        .map(|note| {
+            let (name, content) = render_markdown_file(note);
+            (dir.join(name), content)
+        })
+        .collect();
+    if !force {
+        let existing: Vec<String> = files
+            .iter()
+            .filter(|(path, _)| path.exists())
+            .map(|(path, _)| path.display().to_string())
+            .collect();
+        if !existing.is_empty() {
+            return Err(format!(
+                "{} already exist, use --force to overwrite",
+                existing.join(", ")
+            )
+            .into());
+        }
+    }
+    fs::create_dir_all(dir).map_err(|e| StorageError::new(StorageOp::Write, dir, e))?;
+    for (path, content) in &files {
+        fs::write(path, content).map_err(|e| StorageError::new(StorageOp::Write, path, e))?;
+    }
+    Ok(files.len())
+}
+
+// Settings of an import
+#[derive(Clone, Debug)]
+struct ImportOptions {
+    format: Option<ImportFormat>,
+    map: FieldMap,
+    tag_separator: String,
+    preview: bool,
+    skip_duplicates: bool,
+}
+
+type ImportRecord = serde_json::Map<String, serde_json::Value>;
+
+/// Reads the records of a CSV or JSON file, all CSV values are strings.
+///
+/// # Parameters
+/// - `path: &Path` - File to read
+/// - `format: ImportFormat` - Format of the file
+///
+/// # Returns
+/// `NoteResult<(Vec<String>, Vec<ImportRecord>)>` - Source columns and records
+fn read_import_records(
+    path: &Path,
+    format: ImportFormat,
+) -> NoteResult<(Vec<String>, Vec<ImportRecord>)> {
+    let content =
+        fs::read_to_string(path).map_err(|e| StorageError::new(StorageOp::Read, path, e))?;
+
+    match format {
+        ImportFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(content.as_bytes());
+            let columns: Vec<String> = reader.headers()?.iter().map(String::from).collect();
+            let mut records = Vec::new();
+            for row in reader.records() {
+                let row = row?;
+                records.push(
+                    columns
+                        .iter()
+                        .zip(row.iter())
+                        .map(|(column, value)| (column.clone(), value.into()))
+                        .collect(),
+                );
+            }
+            Ok((columns, records))
+        }
+        ImportFormat::Json | ImportFormat::Taskwarrior | ImportFormat::Notes => {
+            let records: Vec<ImportRecord> = if format == ImportFormat::Notes {
+                let notes = load_notes(&path.to_string_lossy())?.notes;
+                serde_json::from_value(serde_json::to_value(notes)?)?
+            } else {
+                serde_json::from_str(&content)
+                    .map_err(|e| ParseDiagnostic::new(path, &content, e))?
+            };
+            let mut columns: Vec<String> = Vec::new();
+            for key in records.iter().flat_map(|record| record.keys()) {
+                if !columns.contains(key) {
+                    columns.push(key.clone());
+                }
+            }
+            Ok((columns, records))
+        }
+    }
+}
+
+/// Converts one import record to a note, with ID 0 until it's imported.
+///
+/// # Parameters
+/// - `record: &ImportRecord` - Source record
+/// - `options: &ImportOptions` - Field mapping and tag separator
+/// - `now: DateTime<Utc>` - Creation date of records without one
+///
+/// # Returns
+/// `Result<Note, String>` - Converted note or what's wrong with the record
+fn convert_record(
+    record: &ImportRecord,
+    options: &ImportOptions,
+    now: DateTime<Utc>,
+) -> Result<Note, String> {
+    use serde_json::Value;
+
+    let text = |field: &str| match record.get(options.map.source(field)) {
+        None | Some(Value::Null) => None,
+        Some(Value::String(text)) => Some(text.trim().to_string()),
+        Some(other) => Some(other.to_string()),
+    };
+    let date = |field: &str| {
+        text(field)
+            .filter(|raw| !raw.is_empty())
+            .map(|raw| parse_date(&raw))
+            .transpose()
+            .map_err(|e| format!("{}: {}", options.map.source(field), e))
+    };
+
+    let content = text("content")
+        .filter(|content| !content.is_empty())
+        .ok_or_else(|| format!("empty {}", options.map.source("content")))?;
+    let tags = match record.get(options.map.source("tags")) {
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|item| item.as_str().map_or(item.to_string(), String::from))
+            .collect(),
+        _ => text("tags")
+            .unwrap_or_default()
+            .split(options.tag_separator.as_str())
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(String::from)
+            .collect(),
+    };
+    let created_at = date("created_at")?.unwrap_or(now);
+    let updated_at = date("updated_at")?.unwrap_or(created_at);
+
+    Ok(Note {
+        id: 0,
+        content,
+        tags,
+        created_at,
+        updated_at,
+        expires_at: None,
+        meta: BTreeMap::new(),
+        rating: None,
+        created_offset: None,
+        archived: false,
+        title: None,
+        due: None,
+        done: false,
+        pinned: false,
+        deleted_at: None,
+        history: vec![],
+    })
+}
+
+/// Extensions of the files read by import-files
+const TEXT_FILE_EXTENSIONS: [&str; 3] = ["md", "markdown", "txt"];
+
+/// Splits a text file into its body and the tags of a `tags:` line in its
+/// front matter, given as `tags: a, b` or `tags: [a, "b"]`.
+///
+/// # Parameters
+/// - `text: &str` - Content of the file
+///
+/// # Returns
+/// `(String, Vec<String>)` - Trimmed body and tags
+fn parse_text_file(text: &str) -> (String, Vec<String>) {
+    let front = text
+        .strip_prefix("---\n")
+        .or_else(|| text.strip_prefix("---\r\n"));
+    let Some(rest) = front else {
+        return (text.trim().to_string(), vec![]);
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim() == "---" {
+            let tags = rest[..offset]
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("tags:"))
+                .map(|value| {
+                    let value = value.trim();
+                    let list = value
+                        .strip_prefix('[')
+                        .and_then(|value| value.strip_suffix(']'))
+                        .unwrap_or(value);
+                    list.split(',')
+                        .map(|tag| tag.trim().trim_matches(['"', '\'']).trim())
+                        .filter(|tag| !tag.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default();
+            return (rest[offset + line.len()..].trim().to_string(), tags);
+        }
+        offset += line.len();
+    }
+    // without a closing line there is no front matter
+    (text.trim().to_string(), vec![])
+}
+
+/// Finds the Markdown and text files of a directory, sorted by path.
+///
+/// # Parameters
+/// - `dir: &Path` - Directory to look in
+/// - `recursive: bool` - Also look in subdirectories
+///
+/// # Returns
+/// `NoteResult<Vec<PathBuf>>` - Files found
+fn find_text_files(dir: &Path, recursive: bool) -> NoteResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let entries = fs::read_dir(dir).map_err(|e| StorageError::new(StorageOp::Read, dir, e))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| StorageError::new(StorageOp::Read, dir, e))?
+            .path();
+        if path.is_dir() {
+            if recursive {
+                files.extend(find_text_files(&path, true)?);
+            }
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                TEXT_FILE_EXTENSIONS
+                    .iter()
+                    .any(|e| ext.eq_ignore_ascii_case(e))
+            })
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Creates a note from each Markdown or text file of a directory, dated
+/// by the modification time of the file. Files that aren't UTF-8 or have
+/// no content are skipped with a warning.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage receiving the notes
+/// - `dir: &Path` - Directory holding the files
+/// - `recursive: bool` - Also import the files of subdirectories
+///
+/// # Returns
+/// `NoteResult<ImportSummary>` - Created notes and the number of files skipped
+fn import_files(store: &dyn NoteStore, dir: &Path, recursive: bool) -> NoteResult<ImportSummary> {
+    let files = find_text_files(dir, recursive)?;
+    with_store(store, |data| {
+        let mut notes = Vec::new();
+        let mut skipped = 0;
+        for path in files {
+            let bytes =
+                fs::read(&path).map_err(|e| StorageError::new(StorageOp::Read, &path, e))?;
+            let Ok(text) = String::from_utf8(bytes) else {
+                eprintln!("warning: skipping {}, not UTF-8 text", path.display());
+                skipped += 1;
+                continue;
+            };
+            let (content, tags) = parse_text_file(&text);
+            if content.is_empty() {
+                eprintln!("warning: skipping {}, no content", path.display());
+                skipped += 1;
+                continue;
+            }
+            let created_at = fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .map_or_else(|_| Utc::now(), DateTime::<Utc>::from);
+            let note = Note {
+                id: allocate_id(data),
+                content,
+                tags,
+                created_at,
+                updated_at: created_at,
+                expires_at: None,
+                meta: BTreeMap::new(),
+                rating: None,
+                created_offset: None,
+                archived: false,
+                title: None,
+                due: None,
+                done: false,
+                pinned: false,
+                deleted_at: None,
+                history: vec![],
+            };
+            data.notes.push(note.clone());
+            notes.push(note);
+        }
+        normalize_tags(&mut data.notes);
+        Ok(ImportSummary {
+            notes,
+            skipped,
+            renumbered: 0,
+        })
+    })
+}
+
+/// Tag given to the notes imported from completed Taskwarrior tasks
+const TASKWARRIOR_DONE_TAG: &str = "done";
+
+/// Parses a Taskwarrior date, `YYYYMMDDTHHMMSSZ`, or RFC 3339.
+fn parse_taskwarrior_date(raw: &str) -> Result<DateTime<Utc>, String> {
+    chrono::NaiveDateTime::parse_from_str(raw, "%Y%m%dT%H%M%SZ")
+        .map(|date| date.and_utc())
+        .or_else(|_| parse_date(raw))
+        .map_err(|_| format!("invalid date '{}'", raw))
+}
+
+/// Converts a task of `task export` to a note, with ID 0 until it's
+/// imported. Deleted tasks are skipped, completed tasks are tagged `done`
+/// and a due date is kept in the `due` metadata.
+///
+/// # Parameters
+/// - `task: &ImportRecord` - Task object
+/// - `now: DateTime<Utc>` - Creation date of tasks without one
+///
+/// # Returns
+/// `Result<Option<Note>, String>` - Converted note, `None` for a skipped task,
+/// or what's wrong with the task
+fn convert_task(task: &ImportRecord, now: DateTime<Utc>) -> Result<Option<Note>, String> {
+    let text = |key: &str| task.get(key).and_then(|value| value.as_str());
+    let date = |key: &str| text(key).map(parse_taskwarrior_date).transpose();
+
+    if text("status") == Some("deleted") {
+        return Ok(None);
+    }
+    let content = text("description")
+        .map(str::trim)
+        .filter(|content| !content.is_empty())
+        .ok_or("no description")?;
+
+    let mut tags: Vec<String> = task
+        .get("tags")
+        .and_then(|tags| tags.as_array())
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|tag| tag.as_str())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    if text("status") == Some("completed") && !tags.iter().any(|t| t == TASKWARRIOR_DONE_TAG) {
+        tags.push(TASKWARRIOR_DONE_TAG.to_string());
+    }
+
+    let created_at = date("entry")?.unwrap_or(now);
+    let mut meta = BTreeMap::new();
+    if let Some(due) = date("due")? {
+        meta.insert("due".to_string(), due.to_rfc3339());
+    }
+
+    Ok(Some(Note {
+        id: 0,
+        content: content.to_string(),
+        tags,
+        created_at,
+        updated_at: date("modified")?.unwrap_or(created_at),
+        expires_at: None,
+        meta,
+        rating: None,
+        created_offset: None,
+        archived: false,
+        title: None,
+        due: None,
+        done: text("status") == Some("completed"),
+        pinned: false,
+        deleted_at: None,
+        history: vec![],
+    }))
+}
+
+// Notes produced by an import
+#[derive(Debug)]
+struct ImportSummary {
+    notes: Vec<Note>,
+    skipped: usize,
+    renumbered: usize,
+}
+
+/// Imports notes from a CSV, JSON or Taskwarrior file. Every record is
+/// converted before anything is saved, so a bad record imports nothing.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage receiving the notes
+/// - `input: &Path` - File to import
+/// - `options: &ImportOptions` - Format, field mapping and preview mode
+///
+/// # Returns
+/// `NoteResult<ImportSummary>` - Imported notes, or the notes that would be
+/// imported with `preview`, and the number of records skipped
+fn import_notes(
+    store: &dyn NoteStore,
+    input: &Path,
+    options: &ImportOptions,
+) -> NoteResult<ImportSummary> {
+    let format = match options.format {
+        Some(format) => format,
+        None => match input.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => ImportFormat::Csv,
+            Some(ext) if ext.eq_ignore_ascii_case("json") => {
+                let content = fs::read_to_string(input)
+                    .map_err(|e| StorageError::new(StorageOp::Read, input, e))?;
+                match content.trim_start().chars().next() {
+                    None | Some('{') => ImportFormat::Notes,
+                    _ => ImportFormat::Json,
+                }
+            }
+            _ => return Err("cannot guess the input format, use --format".into()),
+        },
+    };
+    if format == ImportFormat::Notes {
+        let notes = load_notes(&input.to_string_lossy())?.notes;
+        return save_imported(store, notes, 0, true, options);
+    }
+    let (columns, records) = read_import_records(input, format)?;
+    let now = Utc::now();
+
+    if format == ImportFormat::Taskwarrior {
+        let mut notes = Vec::new();
+        for (i, task) in records.iter().enumerate() {
+            if let Some(note) =
+                convert_task(task, now).map_err(|e| format!("task {}: {}", i + 1, e))?
+            {
+                notes.push(note);
+            }
+        }
+        let skipped = records.len() - notes.len();
+        return save_imported(store, notes, skipped, false, options);
+    }
+
+    // every field must come from a column that exists, except optional
+    // fields left with their default name
+    let missing: Vec<String> = IMPORT_FIELDS
+        .iter()
+        .enumerate()
+        .filter(|(i, field)| *i == 0 || options.map.0.contains_key(**field))
+        .map(|(_, field)| (field, options.map.source(field)))
+        .filter(|(_, source)| !columns.iter().any(|column| column == source))
+        .map(|(field, source)| format!("{} (column '{}')", field, source))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "no source column for {}, available columns: {}; use --map field=column",
+            missing.join(", "),
+            columns.join(", ")
+        )
+        .into());
+    }
+
+    let mut notes = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        let note =
+            convert_record(record, options, now).map_err(|e| format!("record {}: {}", i + 1, e))?;
+        notes.push(note);
+    }
+    save_imported(store, notes, 0, false, options)
+}
+
+/// Gives IDs to converted notes and saves them unless previewing. With
+/// `keep_ids`, notes keep their ID when it's free and the others are
+/// renumbered, free IDs first.
+fn save_imported(
+    store: &dyn NoteStore,
+    mut notes: Vec<Note>,
+    mut skipped: usize,
+    keep_ids: bool,
+    options: &ImportOptions,
+) -> NoteResult<ImportSummary> {
+    let import = |data: &mut NoteData| {
+        if options.skip_duplicates {
+            let count = notes.len();
+            notes.retain(|note| !data.notes.iter().any(|n| n.content == note.content));
+            skipped += count - notes.len();
+        }
+
+        let mut taken: HashSet<u32> = data.notes.iter().map(|note| note.id).collect();
+        let kept: Vec<bool> = notes
+            .iter()
+            .map(|note| keep_ids && note.id != 0 && taken.insert(note.id))
+            .collect();
+        data.free_ids.retain(|id| !taken.contains(id));
+        for (note, _) in notes.iter().zip(&kept).filter(|(_, kept)| **kept) {
+            data.notes.push(note.clone());
+        }
+        let mut renumbered = 0;
+        for (note, _) in notes.iter_mut().zip(&kept).filter(|(_, kept)| !**kept) {
+            if keep_ids {
+                renumbered += 1;
+            }
+            note.id = allocate_id(data);
+            data.notes.push(note.clone());
+        }
+        Ok(ImportSummary {
+            notes,
+            skipped,
+            renumbered,
+        })
+    };
+    match options.preview {
+        true => import(&mut store.load()?),
+        false => with_store(store, import),
+    }
+}
+
+/// Collects the notes whose expiry date has passed, deleting them with `purge`.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `purge: bool` - Delete the expired notes and free their IDs
+///
+/// # Returns
+/// `NoteResult<Vec<Note>>` - Expired notes, sorted by ID
+fn expire_notes(store: &dyn NoteStore, purge: bool) -> NoteResult<Vec<Note>> {
+    let now = Utc::now();
+    let expire = |data: &mut NoteData| {
+        let (mut expired, kept): (Vec<Note>, Vec<Note>) =
+            data.notes.drain(..).partition(|note| note.is_expired(now));
+        expired.sort_by_key(|note| note.id);
+        data.notes = kept;
+        data.free_ids.extend(expired.iter().map(|note| note.id));
+        Ok(expired)
+    };
+    match purge {
+        true => with_store(store, expire),
+        false => expire(&mut store.load()?),
+    }
+}
+
+/// Selects the notes not archived yet dated before a cutoff. Pinned notes
+/// are skipped.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `before: DateTime<Utc>` - Cutoff date, excluded
+/// - `field: DateField` - Date compared with the cutoff
+///
+/// # Returns
+/// `(Vec<u32>, usize)` - IDs of the notes to archive, ascending, and the
+/// number of pinned notes skipped
+fn archive_candidates(
+    data: &NoteData,
+    before: DateTime<Utc>,
+    field: DateField,
+) -> (Vec<u32>, usize) {
+    let (pinned, mut ids): (Vec<&Note>, Vec<&Note>) = data
+        .notes
+        .iter()
+        .filter(|note| !note.archived && note.date(field) < before)
+        .partition(|note| note.pinned);
+    ids.sort_unstable_by_key(|note| note.id);
+    (ids.iter().map(|note| note.id).collect(), pinned.len())
+}
+
+/// Flags notes as archived. Their update date is kept, so that archiving
+/// by update date again selects the same notes.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `ids: &[u32]` - IDs of the notes to archive
+///
+/// # Returns
+/// `NoteResult<()>` - Success or error during load or save
+fn archive_notes(store: &dyn NoteStore, ids: &[u32]) -> NoteResult<()> {
+    with_store(store, |data| {
+        for note in data.notes.iter_mut().filter(|n| ids.contains(&n.id)) {
+            note.archived = true;
+        }
+        Ok(())
+    })
+}
+
+/// Archives or unarchives a single note and moves its update date.
+///
+/// # Parameters
+/// - `store: &dyn NoteStore` - Storage holding the notes
+/// - `id: u32` - ID of the note
+/// - `archived: bool` - Whether the note should be archived
+///
+/// # Returns
+/// `NoteResult<bool>` - Whether the note changed, or error for an unknown
+/// ID or during load or save
+fn set_archived(store: &dyn NoteStore, id: u32, archived: bool) -> NoteResult<bool> {
+    with_note(store, id, |note| {
+        if note.archived == archived {
+            return Ok(false);
+        }
+        note.archived = archived;
+        note.updated_at = Utc::now();
+        Ok(true)
+    })
+}
+
+// Last update date seen by `new`, per notes file
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Cursors(BTreeMap<String, DateTime<Utc>>);
+
+impl Cursors {
+    /// Path of the cursors file: `$XDG_STATE_HOME/note-cli/cursors.json`,
+    /// falling back to `~/.local/state`
+    fn path() -> NoteResult<PathBuf> {
+        xdg_dir("XDG_STATE_HOME", ".local/state")
+            .map(|dir| dir.join(CURSORS_FILE))
+            .ok_or_else(|| "cannot locate the state directory, set XDG_STATE_HOME".into())
+    }
+
+    fn load(path: &Path) -> NoteResult<Cursors> {
+        Ok(read_json_file(path)?.unwrap_or_default())
+    }
+
+    fn save(&self, path: &Path) -> NoteResult<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| StorageError::new(StorageOp::Create, dir, e))?;
+        }
+        write_json_file(path, self)
+    }
+
+    /// Key of a notes file, its absolute path
+    fn key(notes_path: &str) -> String {
+        std::path::absolute(notes_path)
+            .unwrap_or_else(|_| PathBuf::from(notes_path))
+            .display()
+            .to_string()
+    }
+}
+
+/// Selects the notes updated after a cursor, oldest change first, and the
+/// cursor to store next: the latest update date seen, which unlike the
+/// clock is immune to skew between machines sharing the file.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `cursor: Option<DateTime<Utc>>` - Latest update already seen
+///
+/// # Returns
+/// `(Vec<&Note>, Option<DateTime<Utc>>)` - New notes and the next cursor
+fn notes_since_cursor(
+    data: &NoteData,
+    cursor: Option<DateTime<Utc>>,
+) -> (Vec<&Note>, Option<DateTime<Utc>>) {
+    let mut notes: Vec<&Note> = data
+        .notes
+        .iter()
+        .filter(|note| cursor.is_none_or(|cursor| note.updated_at > cursor))
+        .collect();
+    sort_notes(&mut notes, &SortMethod::Update.into());
+    let latest = data.notes.iter().map(|note| note.updated_at).max();
+    (notes, latest.max(cursor))
+}
+
+/// Renders notes as a journal: a heading per day, newest day first, then
+/// the time, ID and first line of each note of that day.
+///
+/// # Parameters
+/// - `notes: &[&Note]` - Notes to render
+/// - `field: DateField` - Date used to group and order the notes
+/// - `zone: &Tz` - Time zone of the days and times shown
+/// - `full: bool` - Show every line of the content
+///
+/// # Returns
+/// `String` - Rendered timeline
+fn render_timeline<Tz: TimeZone>(notes: &[&Note], field: DateField, zone: &Tz, full: bool) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let mut notes = notes.to_vec();
+    notes.sort_by(|a, b| b.date(field).cmp(&a.date(field)).then(a.id.cmp(&b.id)));
+
+    let mut out = String::new();
+    let mut current_day = None;
+    for note in notes {
+        let date = note.date(field).with_timezone(zone);
+        if current_day != Some(date.date_naive()) {
+            if current_day.is_some() {
+                out.push('\n');
+            }
+            current_day = Some(date.date_naive());
+            out.push_str(&format!("== {} ==\n", date.format("%Y-%m-%d")));
+        }
+
+        let mut lines = note.content.lines();
+        let first = lines.next().unwrap_or("");
+        out.push_str(&format!(
+            "{}  #{}  {}\n",
+            date.format("%H:%M"),
+            note.id,
+            first
+        ));
+        if full {
+            for line in lines {
+                out.push_str(&format!(
+                    "{:w$}{}\n",
+                    "",
+                    line,
+                    w = 7 + note.id.to_string().len() + 3
+                ));
+            }
+        }
+    }
+    out
+}
+
+/// Counts the notes of each day between two dates, both included. Days
+/// are taken in the time zone `zone`, days without notes count zero.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `field: DateField` - Date counted for each note
+/// - `zone: &Tz` - Time zone deciding which day a note belongs to
+/// - `first: NaiveDate` - First day counted
+/// - `last: NaiveDate` - Last day counted
+///
+/// # Returns
+/// `BTreeMap<NaiveDate, usize>` - Number of notes of every day in the range
+fn count_per_day<Tz: TimeZone>(
+    data: &NoteData,
+    field: DateField,
+    zone: &Tz,
+    first: NaiveDate,
+    last: NaiveDate,
+) -> BTreeMap<NaiveDate, usize> {
+    let mut counts: BTreeMap<NaiveDate, usize> = first
+        .iter_days()
+        .take_while(|day| *day <= last)
+        .map(|day| (day, 0))
+        .collect();
+    for note in &data.notes {
+        let day = note.date(field).with_timezone(zone).date_naive();
+        if let Some(count) = counts.get_mut(&day) {
+            *count += 1;
+        }
+    }
+    counts
+}
+
+/// Symbol of a calendar cell: a shade relative to the busiest day, or the
+/// count itself (capped at 9) with `no_emoji`.
+fn calendar_cell(count: usize, max: usize, no_emoji: bool) -> char {
+    if no_emoji {
+        return match count {
+            0 => '.',
+            n => char::from_digit(n.min(9) as u32, 10).unwrap(),
+        };
+    }
+    let levels = CALENDAR_SHADES.len() - 1;
+    let level = if count == 0 {
+        0
+    } else {
+        (count * levels).div_ceil(max).clamp(1, levels)
+    };
+    CALENDAR_SHADES[level]
+}
+
+/// Renders daily counts as a grid with one row per weekday and one column
+/// per week, month labels on top and a legend below.
+///
+/// # Parameters
+/// - `counts: &BTreeMap<NaiveDate, usize>` - Number of notes per day
+/// - `week_start: Weekday` - Weekday of the first row
+/// - `no_emoji: bool` - Write counts as digits instead of shades
+///
+/// # Returns
+/// `String` - Rendered calendar
+fn render_calendar(
+    counts: &BTreeMap<NaiveDate, usize>,
+    week_start: Weekday,
+    no_emoji: bool,
+) -> String {
+    let (Some(first), Some(last)) = (counts.keys().next(), counts.keys().next_back()) else {
+        return String::new();
+    };
+    let back = (7 + first.weekday().num_days_from_monday() - week_start.num_days_from_monday()) % 7;
+    let grid_start = *first - Duration::days(i64::from(back));
+    let weeks = ((*last - grid_start).num_days() / 7 + 1) as usize;
+    let max = counts.values().copied().max().unwrap_or(0);
+
+    // month names above the week holding the 1st, or the first day shown
+    let mut months = vec![' '; weeks * 2];
+    for (day, _) in counts.iter() {
+        if day.day() == 1 || day == first {
+            let column = ((*day - grid_start).num_days() / 7) as usize * 2;
+            let label = day.format("%b").to_string();
+            if months[column..].len() >= label.len()
+                && months[column.saturating_sub(1)..column + label.len()]
+                    .iter()
+                    .all(|c| *c == ' ')
+            {
+                for (i, c) in label.chars().enumerate() {
+                    months[column + i] = c;
+                }
+            }
+        }
+    }
+
+    let mut out = format!("    {}", months.iter().collect::<String>())
+        .trim_end()
+        .to_string();
+    out.push('\n');
+    let mut weekday = week_start;
+    for row in 0..7 {
+        let mut line = format!("{} ", weekday);
+        for week in 0..weeks {
+            let day = grid_start + Duration::days((week * 7 + row) as i64);
+            let cell = counts
+                .get(&day)
+                .map_or(' ', |count| calendar_cell(*count, max, no_emoji));
+            line.push(cell);
+            line.push(' ');
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+        weekday = weekday.succ();
+    }
+
+    if no_emoji {
+        out.push_str("\n    notes per day, 9 means 9 or more\n");
+    } else {
+        let shades: Vec<String> = CALENDAR_SHADES.iter().map(char::to_string).collect();
+        out.push_str(&format!(
+            "\n    Less {} More (busiest day: {})\n",
+            shades.join(" "),
+            max
+        ));
+    }
+    out
+}
+
+/// Renders the tags in use, one per line, with their color and its origin
+/// when `palette` is given.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `palette: Option<&TagPalette>` - Tag colors to preview
+/// - `paint: bool` - Write the colors as escape sequences
+///
+/// # Returns
+/// `String` - Rendered tag list
+fn render_tags(data: &NoteData, palette: Option<&TagPalette>, paint: bool) -> String {
+    let tags: BTreeSet<&str> = data
+        .notes
+        .iter()
+        .flat_map(|note| note.tags.iter().map(String::as_str))
+        .collect();
+
+    let mut out = String::new();
+    for tag in tags {
+        match palette {
+            Some(palette) => {
+                let (color, configured) = palette.color(tag);
+                let name = format!("{:?}", color).to_lowercase();
+                let origin = if configured { "config" } else { "automatic" };
+                let shown = if paint {
+                    color.paint(tag)
+                } else {
+                    tag.to_string()
+                };
+                out.push_str(&format!("{}\t{}\t{}\n", shown, name, origin));
+            }
+            None => out.push_str(&format!("{}\n", tag)),
+        }
+    }
+    out
+}
+
+/// Counts the notes carrying each tag, a note counting once per tag.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `sort: TagSortMethod` - Order of the tags, by name when counts are equal
+///
+/// # Returns
+/// `Vec<(&str, usize)>` - Tags and their number of notes
+fn tag_counts(data: &NoteData, sort: TagSortMethod) -> Vec<(&str, usize)> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for note in &data.notes {
+        let tags: BTreeSet<&str> = note.tags.iter().map(String::as_str).collect();
+        for tag in tags {
+            *counts.entry(tag).or_default() += 1;
+        }
+    }
+    let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+    if sort == TagSortMethod::Count {
+        counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    }
+    counts
+}
+
+/// Renders tag counts as a table.
+///
+/// # Parameters
+/// - `counts: &[(&str, usize)]` - Tags and their number of notes
+/// - `palette: Option<&TagPalette>` - Tag colors, as in the notes table
+///
+/// # Returns
+/// `String` - Rendered table
+fn render_tag_counts(counts: &[(&str, usize)], palette: Option<&TagPalette>) -> String {
+    let mut table = Table::new();
+    table.add_row(Row::new(vec![Cell::new("Tag"), Cell::new("Count")]));
+    for (tag, count) in counts {
+        let tag = palette.map_or(tag.to_string(), |palette| palette.paint(tag));
+        table.add_row(Row::new(vec![
+            Cell::new(&tag),
+            Cell::new(&count.to_string()),
+        ]));
+    }
+    table.to_string()
+}
+
+/// Due date of a note, given with `--due` or kept in its `due` metadata by
+/// imports.
+///
+/// # Parameters
+/// - `note: &Note` - Note to read
+///
+/// # Returns
+/// `Option<DateTime<Utc>>` - The due date, `None` when unset or unreadable
+fn note_due(note: &Note) -> Option<DateTime<Utc>> {
+    if note.due.is_some() {
+        return note.due;
+    }
+    let due = note.meta.get("due")?;
+    DateTime::parse_from_rfc3339(due)
+        .ok()
+        .map(|due| due.with_timezone(&Utc))
+}
+
+// Counts shown by `status`
+struct StatusCounts<'a> {
+    total: usize,
+    // pending notes with a due date, soonest first
+    due: Vec<(DateTime<Utc>, &'a Note)>,
+    overdue: usize,
+}
+
+/// Counts the live notes and the pending ones having a due date, those
+/// marked or tagged done being finished.
+///
+/// # Parameters
+/// - `data: &NoteData` - Loaded note data
+/// - `now: DateTime<Utc>` - Current date, for expiry and overdue notes
+///
+/// # Returns
+/// `StatusCounts` - Counts of the notes
+fn status_counts(data: &NoteData, now: DateTime<Utc>) -> StatusCounts<'_> {
+    let live: Vec<&Note> = data.notes.iter().filter(|n| !n.is_expired(now)).collect();
+    let mut due: Vec<(DateTime<Utc>, &Note)> = live
+        .iter()
+        .filter(|note| !note.done && !note.tags.iter().any(|t| t == TASKWARRIOR_DONE_TAG))
+        .filter_map(|note| note_due(note).map(|due| (due, *note)))
+        .collect();
+    due.sort_by_key(|(due, note)| (*due, note.id));
+    let overdue = due.iter().filter(|(due, _)| *due < now).count();
+    StatusCounts {
+        total: live.len(),
+        due,
+        overdue,
+    }
+}
+
+/// Renders the status summary, as a line or as a waybar custom module
+/// object.
+///
+/// # Parameters
+/// - `counts: &StatusCounts` - Counts of the notes
+/// - `now: DateTime<Utc>` - Current date, to flag overdue notes
+/// - `format: StatusFormat` - Output format
+/// - `no_emoji: bool` - Write `notes` instead of the memo emoji
+///
+/// # Returns
+/// `String` - Rendered summary, without trailing newline
+fn render_status(
+    counts: &StatusCounts,
+    now: DateTime<Utc>,
+    format: StatusFormat,
+    no_emoji: bool,
+) -> String {
+    let text = format!(
+        "{} {} | due {} | overdue {}",
+        if no_emoji { "notes" } else { "📝" },
+        counts.total,
+        counts.due.len() - counts.overdue,
+        counts.overdue
+    );
+    match format {
+        StatusFormat::Text => text,
+        StatusFormat::Json => {
+            let tooltip: Vec<String> = counts
+                .due
+                .iter()
+                .map(|(due, note)| {
+                    format!(
+                        "{}#{} {} (due {})",
+                        if *due < now { "! " } else { "" },
+                        note.id,
+                        note.content.lines().next().unwrap_or(""),
+                        due.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+                    )
+                })
+                .collect();
+            let class = if counts.overdue > 0 {
+                "overdue"
+            } else if !counts.due.is_empty() {
+                "due"
+            } else {
+                "idle"
+            };
+            serde_json::json!({
+                "text": text,
+                "tooltip": tooltip.join("\n"),
+                "class": class,
+            })
+            .to_string()
+        }
+    }
+}
+
+/// Renders the man page of a command.
+///
+/// # Parameters
+/// - `cmd: clap::Command` - Command to document
+///
+/// # Returns
+/// `NoteResult<Vec<u8>>` - Page in roff format
+fn render_man_page(cmd: clap::Command) -> NoteResult<Vec<u8>> {
+    let mut page = Vec::new();
+    clap_mangen::Man::new(cmd).render(&mut page)?;
+    Ok(page)
+}
+
+/// Writes `note-cli.1` and one `note-cli-<subcommand>.1` page per visible
+/// subcommand into a directory.
+///
+/// # Parameters
+/// - `dir: &Path` - Output directory, created if missing
+///
+/// # Returns
+/// `NoteResult<Vec<PathBuf>>` - Paths of the written pages
+fn write_man_pages(dir: &Path) -> NoteResult<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let cmd = Cli::command();
+    let mut written = Vec::new();
+
+    let path = dir.join("note-cli.1");
+    fs::write(&path, render_man_page(cmd.clone())?)?;
+    written.push(path);
+
+    for sub in cmd.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        let name = format!("note-cli-{}", sub.get_name());
+        let path = dir.join(format!("{}.1", name));
+        fs::write(&path, render_man_page(sub.clone().name(name))?)?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if let Err(message) = cli.validate() {
+        Cli::command()
+            .error(clap::error::ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+    let verbose = cli.verbose;
+    VERBOSE.store(verbose, Ordering::Relaxed);
+    RECOVER.store(cli.recover, Ordering::Relaxed);
+
+    match run(cli) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            if verbose {
+                let mut cause = std::error::Error::source(&e);
+                while let Some(err) = cause {
+                    eprintln!("  caused by: {}", err);
+                    cause = err.source();
+                }
+            }
+            e.exit_code()
+        }
+    }
+}
+
+/// Runs the parsed command line. With `--dry-run`, the changes are kept in
+/// memory and described once the command is done.
+///
+/// # Parameters
+/// - `cli: Cli` - Parsed command line
+///
+/// # Returns
+/// `NoteResult<ExitCode>` - Exit status to report or error to print
+fn run(mut cli: Cli) -> NoteResult<ExitCode> {
+    let config = Config::load_or_default(cli.config.as_deref())?;
+    cli.file = cli.notes_file(&config)?;
+    if cli.verbose {
+        eprintln!("Notes file: {}", cli.file);
+    }
+    // a notebook is created by the first command saving notes in it
+    let new_notebook = cli
+        .notebook(&config)?
+        .filter(|_| !Path::new(&cli.file).exists())
+        .map(|name| (name.to_string(), cli.file.clone()));
+    let backups = if cli.no_backup { 0 } else { config.backups };
+    let undoable = UndoStore::new(&cli.file, backups);
+    let code = if !cli.dry_run {
+        run_command(cli, config, &undoable, &undoable)?
+    } else {
+        let dry = DryRunStore::new(&undoable);
+        let code = run_command(cli, config, &dry, &undoable)?;
+        for line in dry.summary() {
+            println!("{}", line);
+        }
+        code
+    };
+    if let Some((name, path)) = new_notebook
+        && Path::new(&path).exists()
+    {
+        eprintln!("Created notebook {}", name);
+    }
+    Ok(code)
+}
+
+/// Runs a command against the notes.
+///
+/// # Parameters
+/// - `cli: Cli` - Parsed command line
+/// - `config: Config` - Settings of the config file
+/// - `store: &dyn NoteStore` - Storage the command reads and changes
+/// - `undoable: &UndoStore` - Storage of `--file` with its undo state
+///
+/// # Returns
+/// `NoteResult<ExitCode>` - Exit status to report or error to print
+fn run_command(
+    cli: Cli,
+    config: Config,
+    store: &dyn NoteStore,
+    undoable: &UndoStore,
+) -> NoteResult<ExitCode> {
+    if config.purge_expired && !matches!(cli.command, Commands::Man { .. } | Commands::Notebooks) {
+        // the purge isn't a change of the user: saving it past the undo
+        // state keeps `undo` on the last command
+        let purged: &dyn NoteStore = match cli.dry_run {
+            true => store,
+            false => undoable.inner.as_ref(),
+        };
+        expire_notes(purged, true)?;
+    }
+
+    let no_emoji = cli.no_emoji || !utf8_locale();
+    let date_format = cli
+        .date_format
+        .or(config.date_format.clone())
+        .unwrap_or_else(|| DEFAULT_DATE_FORMAT.to_string());
+    let dry_run = cli.dry_run;
+    // nothing is changed, so there is nothing to confirm
+    let yes = cli.yes || dry_run;
+    let painted = color_enabled(cli.color.or(config.color).unwrap_or(ColorChoice::Auto));
+    let palette = painted.then(|| TagPalette {
+        configured: config.tag_colors.clone(),
+    });
+
+    match cli.command {
+        Commands::Add {
+            content,
+            stdin,
+            from_file,
+            title,
+            tags,
+            id,
+            unique,
+            unique_ignore_case,
+            unique_window,
+            expires,
+            due,
+            meta,
+            rating,
+            edit,
+        } => {
+            let options = AddOptions {
+                id,
+                unique,
+                unique_ignore_case,
+                unique_window,
+                expires,
+                meta: meta.into_iter().collect(),
+                rating,
+                title: title.filter(|title| !title.is_empty()),
+                due,
+            };
+            let content = add_content(content, stdin, from_file.as_deref())?;
+            let id = match add_note(store, content.clone(), tags, &options)? {
+                AddOutcome::Created(id) => id,
+                AddOutcome::Existing(id) => {
+                    println!("{}", id);
+                    return Ok(ExitCode::from(EXIT_EXISTS));
+                }
+            };
+            if edit {
+                match edit_text(&editor_command(), &content)? {
+                    Some(edited) if edited != content => {
+                        edit_note(store, id, edited, config.history_limit)?
+                    }
+                    Some(_) => {}
+                    None => eprintln!("The editor failed, note {} keeps its original content", id),
+                }
+            }
+        }
+        Commands::List {
+            mut sort,
+            mut filter,
+            sample,
+            mut display,
+        } => {
+            filter.resolve_shortcuts(Local::now(), config.week_start);
+            filter.pending |= config.hide_done && !filter.done;
+            if sort.methods.is_empty() {
+                sort.methods = config.default_sort.clone();
+            }
+            display.palette = palette;
+            display.no_emoji = no_emoji;
+            display.date_format = date_format;
+            if display.porcelain {
+                display.format = OutputFormat::Porcelain;
+            }
+            let shown = list_note(store, &sort, &filter, &sample, &display)?;
+            if display.fail_if_empty && shown == 0 {
+                return Ok(ExitCode::from(EXIT_EMPTY));
+            }
+        }
+        Commands::Remove { ids, permanent } => {
+            let ellipsis = if no_emoji { "..." } else { "…" };
+            if let Some(question) = removal_question(&store.load()?, &ids, ellipsis)
+                && !confirm_change(yes || !config.confirm_remove, &question, "remove notes")?
+            {
+                return Ok(ExitCode::SUCCESS);
+            }
+            let missing = remove_notes(store, &ids, permanent)?;
+            if !missing.is_empty() {
+                return Err(NoteError::NotFound(missing));
+            }
+        }
+        Commands::Undo if dry_run => {
+            if !undoable.undo_path.exists() {
+                return Err(format!(
+                    "nothing to undo, {} doesn't exist",
+                    undoable.undo_path.display()
+                )
+                .into());
+            }
+            store.save(&load_notes(&undoable.undo_path.to_string_lossy())?)?;
+        }
+        Commands::Undo => {
+            undo_last(undoable.inner.as_ref(), &undoable.undo_path)?;
+            println!("Undid the last change");
+        }
+        Commands::Restore { id } => {
+            let restored = restore_note(store, id)?;
+            if dry_run {
+                // the summary tells the ID
+            } else if restored == id {
+                println!("Restored note {}", id);
+            } else {
+                println!("Restored note {} as {}, its ID was taken", id, restored);
+            }
+        }
+        Commands::Trash {
+            action: TrashAction::Purge { older_than },
+        } => {
+            let now = Utc::now();
+            if !dry_run {
+                let count = purge_trash(store, older_than, now, true)?.len();
+                let question = format!("Purge {} notes from the trash?", count);
+                if count > 0 && !confirm_change(yes, &question, "purge the trash")? {
+                    return Ok(ExitCode::SUCCESS);
+                }
+            }
+            let purged = purge_trash(store, older_than, now, dry_run)?;
+            if dry_run {
+                println!("Would purge {} notes", purged.len());
+            } else {
+                println!("Purged {} notes", purged.len());
+            }
+        }
+        Commands::Trash {
+            action: TrashAction::List,
+        } => {
+            let data = store.load()?;
+            if data.trash.is_empty() {
+                println!("The trash is empty");
+            } else {
+                let display = DisplayArgs {
+                    palette,
+                    no_emoji,
+                    date_format,
+                    ..DisplayArgs::default()
+                };
+                let notes: Vec<&Note> = data.trash.iter().collect();
+                print!("{}", render_table(&notes, &display, Utc::now()));
+            }
+        }
+        Commands::Append {
+            id,
+            text,
+            no_newline,
+        } => {
+            append_note(store, id, &text, !no_newline, config.history_limit)?;
+        }
+        Commands::AddTag { id, tags } => {
+            add_tag(store, id, tags)?;
+        }
+        Commands::RenameTag { old, new } => {
+            let changed = rename_tag(store, &old, &new, dry_run)?;
+            if dry_run {
+                let ids: Vec<String> = changed.iter().map(|id| id.to_string()).collect();
+                println!("Would rename {} on notes {}", old, ids.join(", "));
+            } else {
+                println!("Renamed {} to {} on {} notes", old, new, changed.len());
+            }
+        }
+        Commands::RemoveTag { id, tags } => {
+            remove_tag(store, id, &tags)?;
+        }
+        Commands::Edit {
+            id: Some(id),
+            editor: true,
+            ..
+        } => {
+            let editor = editor_command();
+            edit_with_editor(store, id, config.history_limit, |path| {
+                spawn_editor(&editor, path)
+            })?;
+        }
+        Commands::Edit {
+            id: Some(id),
+            content,
+            title,
+            rating,
+            due,
+            add_tags,
+            remove_tags,
+            set_tags,
+            editor: false,
+        } => {
+            let changes = NoteChanges {
+                content,
+                title,
+                rating,
+                due,
+                add_tags,
+                remove_tags,
+                set_tags,
+                history_limit: config.history_limit,
+            };
+            update_note(store, id, &changes)?;
+        }
+        Commands::Edit { id: None, .. } => {
+            let lock = InstanceLock::acquire(&cli.file)?;
+            lock.release_on_interrupt()?;
+            let mut input = std::io::stdin().lock();
+            let mut output = std::io::stdout();
+            let data = store.load()?;
+            if let Some(id) = pick_note(&data, &mut input, &mut output)? {
+                let note = data.notes.iter().find(|note| note.id == id).unwrap();
+                println!("{}", note.content);
+                if let Some(content) = prompt_line(&mut input, &mut output, "New content: ")? {
+                    edit_note(store, id, content, config.history_limit)?;
+                }
+            }
+        }
+        Commands::Cat { id } => {
+            let note = find_note(store, id)?;
+            println!("{}", note.content);
+        }
+        Commands::Pick { no_preview } => {
+            let data = store.load()?;
+            let picked = match find_in_path("fzf") {
+                Some(fzf) => {
+                    let sort = SortArgs {
+                        methods: vec![SortMethod::Update],
+                        reverse: true,
+                    };
+                    let display = DisplayArgs {
+                        format: OutputFormat::Fzf,
+                        ..DisplayArgs::default()
+                    };
+                    let notes = select_notes(&data, &FilterArgs::default(), &sort);
+                    let preview = format!(
+                        "{} --file {} cat {{1}}",
+                        shell_quote(&std::env::current_exe()?.display().to_string()),
+                        shell_quote(&cli.file)
+                    );
+                    let preview = (!no_preview).then_some(preview.as_str());
+                    fzf_pick(&fzf, &render_notes(&notes, &display), preview)?
+                }
+                // the ID alone goes to stdout, so that $(note-cli pick) works
+                None => pick_note(&data, &mut std::io::stdin().lock(), &mut std::io::stderr())?,
+            };
+            match picked {
+                Some(id) => println!("{}", id),
+                None => return Ok(ExitCode::from(EXIT_EMPTY)),
+            }
+        }
+        Commands::History { id } => {
+            let note = find_note(store, id)?;
+            if note.history.is_empty() {
+                println!("Note {} has no previous content", id);
+            } else {
+                let display = DisplayArgs {
+                    no_emoji,
+                    date_format,
+                    ..DisplayArgs::default()
+                };
+                print!("{}", render_history(&note, &display));
+            }
+        }
+        Commands::Revert { id, revision } => {
+            revert_note(store, id, revision, config.history_limit)?;
+        }
+        Commands::Rate { id, rating } => {
+            rate_note(store, id, rating)?;
+        }
+        Commands::Done { id } => {
+            if !set_done(store, id, true)? {
+                println!("Note {} is already done", id);
+            }
+        }
+        Commands::Undone { id } => {
+            if !set_done(store, id, false)? {
+                println!("Note {} is already pending", id);
+            }
+        }
+        Commands::Pin { id } => {
+            if !set_pinned(store, id, true)? {
+                println!("Note {} is already pinned", id);
+            }
+        }
+        Commands::Unpin { id } => {
+            if !set_pinned(store, id, false)? {
+                println!("Note {} is not pinned", id);
+            }
+        }
+        Commands::Copy { id, to, touch } => {
+            if same_notes_path(&cli.file, &to) {
+                return Err(
+                    format!("cannot copy note {} into {}, it is the source file", id, to).into(),
+                );
+            }
+            let dest = open_store(&to);
+            if dry_run {
+                let dest = DryRunStore::new(dest.as_ref());
+                copy_note(store, &dest, id, touch)?;
+                for line in dest.summary() {
+                    println!("{} in {}", line, to);
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            let new_id = copy_note(store, dest.as_ref(), id, touch)?;
+            println!("Copied note {} to {} as note {}", id, to, new_id);
+        }
+        Commands::Merge { other, keep_both } => {
+            if same_notes_path(&cli.file, &other) {
+                return Err(format!("cannot merge {} into itself", other).into());
+            }
+            // a missing file would merge as no notes
+            fs::metadata(&other)
+                .map_err(|e| StorageError::new(StorageOp::Read, Path::new(&other), e))?;
+            let decisions = merge_notes(store, open_store(&other).load()?, keep_both)?;
+            if decisions.is_empty() {
+                println!("{} holds no notes", other);
+            }
+            for decision in decisions {
+                println!("{}", decision);
+            }
+        }
+        Commands::Move { id, to } => {
+            let path = destination_path(&to)?;
+            if same_notes_path(&cli.file, &path) {
+                return Err(format!("note {} is already in {}", id, to).into());
+            }
+            let dest = open_store(&path);
+            if dry_run {
+                let dest = DryRunStore::new(dest.as_ref());
+                move_note(store, &dest, id)?;
+                for line in dest.summary() {
+                    println!("{} in {}", line, to);
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            let new_notebook = path != to && !Path::new(&path).exists();
+            let new_id = move_note(store, dest.as_ref(), id)?;
+            if new_notebook {
+                eprintln!("Created notebook {}", to);
+            }
+            println!("Moved note {} to {} as note {}", id, to, new_id);
+        }
+        Commands::Show {
+            ids,
+            grep,
+            regex,
+            context,
+            utc,
+            local,
+            format,
+        } => {
+            let notes = get_notes(store, &ids)?;
+            match grep {
+                Some(pattern) => {
+                    let matcher = Matcher::new(&pattern, regex)?;
+                    match grep_lines(&notes[0].content, &matcher, context) {
+                        Some(lines) => print!("{}", lines),
+                        None => return Ok(ExitCode::from(EXIT_NO_MATCH)),
+                    }
+                }
+                None if format == ShowFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&notes)?);
+                }
+                None => {
+                    let display = DisplayArgs {
+                        palette,
+                        no_emoji,
+                        date_format,
+                        ..DisplayArgs::default()
+                    };
+                    let zone = if utc {
+                        DateZone::Utc
+                    } else if local {
+                        DateZone::Local
+                    } else {
+                        DateZone::Original
+                    };
+                    print!("{}", render_notes_details(&notes, &display, zone));
+                }
+            }
+        }
+        Commands::Search {
+            keywords,
+            scope,
+            matching,
+            mut sort,
+            mut filter,
+            mut display,
+        } => {
+            filter.resolve_shortcuts(Local::now(), config.week_start);
+            // fuzzy search ranks the best matches first instead
+            if sort.methods.is_empty() && !matching.fuzzy {
+                sort.methods = config.default_sort.clone();
+            }
+            display.palette = palette;
+            display.no_emoji = no_emoji;
+            display.date_format = date_format;
+            if display.porcelain {
+                display.format = OutputFormat::Porcelain;
+            }
+            let shown = search_note(store, &keywords, &matching, scope, &sort, &filter, &display)?;
+            if display.fail_if_empty && shown == 0 {
+                return Ok(ExitCode::from(EXIT_EMPTY));
+            }
+        }
+        Commands::Count {
+            keyword,
+            mut filter,
+        } => {
+            filter.resolve_shortcuts(Local::now(), config.week_start);
+            println!("{}", count_notes(store, keyword.as_deref(), &filter)?);
+        }
+        Commands::Export {
+            format,
+            format_flag,
+            output,
+            dir,
+            force,
+            tags,
+        } => {
+            let data = store.load()?;
+            let notes = export_selection(&data, &tags);
+            if let Some(dir) = dir {
+                let written = write_markdown_dir(&notes, &dir, force)?;
+                println!("Wrote {} files to {}", written, dir.display());
+            } else {
+                let content = match format.or(format_flag).unwrap_or(ExportFormat::Org) {
+                    ExportFormat::Org => render_org(&notes),
+                    ExportFormat::Csv => render_csv(&notes)?,
+                    ExportFormat::Markdown => render_markdown(&notes),
+                };
+                match output {
+                    Some(path) => fs::write(&path, content)
+                        .map_err(|e| StorageError::new(StorageOp::Write, &path, e))?,
+                    None => print!("{}", content),
+                }
+            }
+        }
+        Commands::Import {
+            input,
+            format,
+            map,
+            map_file,
+            tag_separator,
+            preview,
+            skip_duplicates,
+        } => {
+            let map = match map_file {
+                Some(path) => {
+                    let content = fs::read_to_string(&path)
+                        .map_err(|e| StorageError::new(StorageOp::Read, &path, e))?;
+                    toml::from_str::<FieldMap>(&content)
+                        .map_err(|e| format!("cannot parse {}: {}", path.display(), e))?
+                        .validate()?
+                }
+                None => map.unwrap_or_default(),
+            };
+            let preview = preview || dry_run;
+            let options = ImportOptions {
+                format,
+                map,
+                tag_separator,
+                preview,
+                skip_duplicates,
+            };
+            let summary = import_notes(store, &input, &options)?;
+            let count = summary.notes.len();
+            if preview {
+                let shown: Vec<&Note> = summary.notes.iter().take(IMPORT_PREVIEW_COUNT).collect();
+                print!("{}", render_notes(&shown, &DisplayArgs::default()));
+                println!(
+                    "{} notes would be imported, {} renumbered, {} skipped",
+                    count, summary.renumbered, summary.skipped
+                );
+            } else {
+                println!(
+                    "Imported {} notes, {} renumbered, {} skipped",
+                    count, summary.renumbered, summary.skipped
+                );
+            }
+        }
+        Commands::ImportFiles { dir, recursive } => {
+            let summary = import_files(store, &dir, recursive)?;
+            if !dry_run {
+                println!(
+                    "Created {} notes from {}, {} skipped",
+                    summary.notes.len(),
+                    dir.display(),
+                    summary.skipped
+                );
+            }
+        }
+        Commands::Archive { id: Some(id), .. } => {
+            if !set_archived(store, id, true)? {
+                println!("Note {} is already archived", id);
+            }
+        }
+        Commands::Archive { before, field, .. } => {
+            let before = before.expect("clap requires --before without an ID");
+            let data = store.load()?;
+            let (ids, pinned) = archive_candidates(&data, before, field);
+            let skipped = match pinned {
+                0 => String::new(),
+                1 => ", skipping 1 pinned note".to_string(),
+                count => format!(", skipping {} pinned notes", count),
+            };
+            let (Some(first), Some(last)) = (ids.first(), ids.last()) else {
+                println!("No note to archive{}", skipped);
+                return Ok(ExitCode::SUCCESS);
+            };
+            let summary = format!(
+                "{} notes {} before {} (IDs {}-{}){}",
+                ids.len(),
+                match field {
+                    DateField::Created => "created",
+                    DateField::Updated => "updated",
+                },
+                before.format("%Y-%m-%d"),
+                first,
+                last,
+                skipped
+            );
+            if dry_run {
+                println!("Would archive {}", summary);
+                return Ok(ExitCode::SUCCESS);
+            }
+            if !confirm_change(yes, &format!("Archive {}?", summary), "archive notes")? {
+                return Ok(ExitCode::SUCCESS);
+            }
+            archive_notes(store, &ids)?;
+            println!("Archived {}", summary);
+        }
+        Commands::Unarchive { id } => {
+            if !set_archived(store, id, false)? {
+                println!("Note {} is not archived", id);
+            }
+        }
+        Commands::Clear => {
+            let mut input = std::io::stdin().lock();
+            match clean_notes(
+                store,
+                yes,
+                interactive(),
+                &mut input,
+                &mut std::io::stdout(),
+            )? {
+                Some(_) if dry_run => {}
+                Some(count) => println!("Deleted {} notes", count),
+                None => println!("Nothing deleted"),
+            }
+        }
+        Commands::Expire { purge } => {
+            let expired = expire_notes(store, purge)?;
+            if !purge {
+                let shown: Vec<&Note> = expired.iter().collect();
+                print!("{}", render_notes(&shown, &DisplayArgs::default()));
+            } else if !dry_run {
+                println!("Deleted {} expired notes", expired.len());
+            }
+        }
+        Commands::Backup { action } => match action {
+            BackupAction::Create => {
+                let backup = create_backup(store, &cli.file, Utc::now())?;
+                println!("Saved backup {}", backup.path.display());
+            }
+            BackupAction::List => {
+                let backups = list_backups(&cli.file)?;
+                if backups.is_empty() {
+                    println!("No backups of {}", cli.file);
+                }
+                for backup in backups {
+                    let notes = load_notes(&backup.path.to_string_lossy())?.notes.len();
+                    println!("{}\t{} notes", backup.name(), notes);
+                }
+            }
+            BackupAction::Restore { name, force } => {
+                let restored = restore_backup(store, &cli.file, &name, force)?;
+                if !dry_run {
+                    println!("Restored {} notes from {}", restored, name);
+                }
+            }
+        },
+        Commands::Meta { action } => match action {
+            MetaAction::Set { id, key, value } => set_meta(store, id, &key, Some(value))?,
+            MetaAction::Unset { id, key } => set_meta(store, id, &key, None)?,
+        },
+        Commands::Timeline {
+            field,
+            full,
+            mut filter,
+        } => {
+            filter.resolve_shortcuts(Local::now(), config.week_start);
+            let data = store.load_created_since(filter.since)?;
+            let notes = select_notes(&data, &filter, &SortMethod::Id.into());
+            print!("{}", render_timeline(&notes, field, &Local, full));
+        }
+        Commands::New {
+            peek,
+            since_cursor,
+            mut display,
+        } => {
+            display.palette = palette;
+            display.no_emoji = no_emoji;
+            display.date_format = date_format;
+            if display.porcelain {
+                display.format = OutputFormat::Porcelain;
+            }
+            let path = Cursors::path()?;
+            let mut cursors = Cursors::load(&path)?;
+            let key = Cursors::key(&cli.file);
+
+            if since_cursor == Some(CursorAction::Reset) {
+                cursors.0.remove(&key);
+                cursors.save(&path)?;
+                println!("Cursor reset for {}", cli.file);
+                return Ok(ExitCode::SUCCESS);
+            }
+
+            let data = store.load()?;
+            let (notes, next) = notes_since_cursor(&data, cursors.0.get(&key).copied());
+            if notes.is_empty() && display.format == OutputFormat::Table && !display.ids_only {
+                println!("nothing new");
+            } else {
+                print_notes(&notes, &display)?;
+            }
+            if let Some(next) = next
+                && !peek
+                && !dry_run
+            {
+                cursors.0.insert(key, next);
+                cursors.save(&path)?;
+            }
+        }
+        Commands::Calendar {
+            year,
+            field,
+            format,
+        } => {
+            let (first, last) = match year {
+                Some(year) => (
+                    NaiveDate::from_ymd_opt(year, 1, 1).ok_or("invalid year")?,
+                    NaiveDate::from_ymd_opt(year, 12, 31).ok_or("invalid year")?,
+                ),
+                None => {
+                    let today = Local::now().date_naive();
+                    (today - Duration::days(364), today)
+                }
+            };
+            let data = store.load()?;
+            let counts = count_per_day(&data, field, &Local, first, last);
+            match format {
+                CalendarFormat::Grid => {
+                    print!("{}", render_calendar(&counts, config.week_start, no_emoji))
+                }
+                CalendarFormat::Json => {
+                    let days: BTreeMap<String, usize> = counts
+                        .iter()
+                        .map(|(day, count)| (day.to_string(), *count))
+                        .collect();
+                    println!("{}", serde_json::to_string_pretty(&days)?);
+                }
+            }
+        }
+        Commands::Status { format } => {
+            let now = Utc::now();
+            let data = store.load()?;
+            let counts = status_counts(&data, now);
+            println!("{}", render_status(&counts, now, format, no_emoji));
+            if counts.overdue > 0 {
+                return Ok(ExitCode::from(EXIT_OVERDUE));
+            }
+        }
+        Commands::Tags { sort, show_colors } => {
+            let data = store.load()?;
+            if show_colors {
+                let preview = TagPalette {
+                    configured: config.tag_colors.clone(),
+                };
+                print!("{}", render_tags(&data, Some(&preview), painted));
+            } else {
+                let counts = tag_counts(&data, sort);
+                print!("{}", render_tag_counts(&counts, palette.as_ref()));
+            }
+        }
+        Commands::Migrate { .. } => {
+            for path in migrate_to_shards(&cli.file)? {
+                println!("{}", path.display());
+            }
+        }
+        Commands::Compact { minify } => {
+            let options = CompactOptions {
+                history_limit: config.history_limit,
+                trash_retention: config.trash_retention,
+                now: Utc::now(),
+            };
+            let (before, after) = compact_notes(store, &cli.file, minify, &options)?;
+            if before == after {
+                println!("{}: already compact ({} bytes)", cli.file, after);
+            } else {
+                println!("{}: {} -> {} bytes", cli.file, before, after);
+            }
+        }
+        Commands::Ids { clear_free, cap } => {
+            let mut data = store.load()?;
+            let cap = match (clear_free, cap) {
+                (true, _) => 0,
+                (false, Some(cap)) => cap,
+                (false, None) => {
+                    for id in &data.free_ids {
+                        println!("{}", id);
+                    }
+                    return Ok(ExitCode::SUCCESS);
+                }
+            };
+            let discarded = cap_free_ids(&mut data, cap);
+            if discarded.is_empty() {
+                println!("No free ID to discard");
+                return Ok(ExitCode::SUCCESS);
+            }
+            let list: Vec<String> = discarded.iter().map(|id| id.to_string()).collect();
+            let question = format!("Discard free IDs {}?", list.join(", "));
+            if !confirm_change(yes, &question, "discard free IDs")? {
+                return Ok(ExitCode::SUCCESS);
+            }
+            let discarded = with_store(store, |data| Ok(cap_free_ids(data, cap)))?;
+            let list: Vec<String> = discarded.iter().map(|id| id.to_string()).collect();
+            if !dry_run {
+                println!("Discarded free IDs {}", list.join(", "));
+            }
+        }
+        Commands::Doctor { fix } => {
+            let (data, repairs) = match fix {
+                true => with_store(store, |data| {
+                    let repairs = repair(data);
+                    Ok((data.clone(), repairs))
+                })?,
+                false => (store.load()?, vec![]),
+            };
+            for line in &repairs {
+                println!("{}", line);
+            }
+            let problems = diagnose(&data);
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if repairs.is_empty() && problems.is_empty() {
+                println!("No problem found");
+            } else if !fix && !problems.is_empty() {
+                let found = match problems.len() {
+                    1 => "1 problem found".to_string(),
+                    count => format!("{} problems found", count),
+                };
+                match problems
+                    .iter()
+                    .all(|p| matches!(p, Problem::EmptyContent(_)))
+                {
+                    true => println!("{}, edit or remove the empty notes", found),
+                    false => println!("{}, `doctor --fix` repairs all but empty notes", found),
+                }
+                return Ok(ExitCode::from(EXIT_PROBLEMS));
+            }
+        }
+        Commands::Notebooks => {
+            let dir = notebooks_dir().ok_or_else(|| {
+                NoteError::from("cannot locate the data directory, set XDG_DATA_HOME")
+            })?;
+            let notebooks = list_notebooks(&dir)?;
+            if notebooks.is_empty() {
+                println!("No notebooks in {}", dir.display());
+            }
+            for (name, count) in notebooks {
+                println!("{}\t{} notes", name, count);
+            }
+        }
+        Commands::Man { out_dir } => {
+            for path in write_man_pages(&out_dir)? {
+                println!("{}", path.display());
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    // Helper to run a scenario against every store implementation
+    fn for_each_store(scenario: impl Fn(&dyn NoteStore) -> NoteResult<()>) -> NoteResult<()> {
+        let tmpfile = NamedTempFile::new()?;
+        scenario(open_store(tmpfile.path().to_str().unwrap()).as_ref())?;
+
+        let tmpdir = tempfile::tempdir()?;
+        scenario(open_store(tmpdir.path().to_str().unwrap()).as_ref())?;
+
+        let tmpdir = tempfile::tempdir()?;
+        scenario(&ShardedStore {
+            index_path: tmpdir.path().join("notes.json"),
+        })?;
+
+        scenario(&MemoryStore::default())?;
+        Ok(())
+    }
+
+    // Helper deleting a single note for good
+    fn remove_note(store: &dyn NoteStore, id: u32) -> NoteResult<()> {
+        match remove_notes(store, &[id], true)?.is_empty() {
+            true => Ok(()),
+            false => Err(NoteError::NotFound(vec![id])),
+        }
+    }
+
+    // Helper moving a single note to the trash
+    fn trash_note(store: &dyn NoteStore, id: u32) -> NoteResult<()> {
+        match remove_notes(store, &[id], false)?.is_empty() {
+            true => Ok(()),
+            false => Err(NoteError::NotFound(vec![id])),
+        }
+    }
+
+    // Helper to verify if a datetime is in the past or present
+    fn assert_time_valid(dt: &DateTime<Utc>) {
+        let now = Utc::now();
+        assert!(dt <= &now, "datetime should be in the past or present");
+    }
+
+    #[test]
+    fn test_add_note() -> NoteResult<()> {
+        for_each_store(|store| {
+            add_note(
+                store,
+                "content0".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+            add_note(
+                store,
+                "content1".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+
+            let data = store.load()?;
+
+            assert_eq!(data.notes.len(), 2);
+
+            let note1 = &data.notes[0];
+            assert_eq!(note1.id, 1);
+            assert_eq!(note1.content, "content0");
+            assert!(note1.tags.is_empty());
+            assert_time_valid(&note1.created_at);
+            assert_time_valid(&note1.updated_at);
+
+            let note2 = &data.notes[1];
+            assert_eq!(note2.id, 2);
+            assert_eq!(note2.content, "content1");
+            assert!(note2.tags.is_empty());
+            assert_time_valid(&note2.created_at);
+            assert_time_valid(&note2.updated_at);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_remove_note() -> NoteResult<()> {
+        for_each_store(|store| {
+            add_note(
+                store,
+                "content0".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+            add_note(
+                store,
+                "content1".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+
+            remove_note(store, 2)?;
+
+            let data = store.load()?;
+            assert_eq!(data.notes.len(), 1);
+            let note1 = &data.notes[0];
+            assert_eq!(note1.id, 1);
+            assert_eq!(note1.content, "content0");
+            assert!(note1.tags.is_empty());
+
+            // free_ids contient l'ID libéré
+            assert_eq!(data.free_ids, vec![2]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_id_allocation() -> NoteResult<()> {
+        for_each_store(|store| {
+            add_note(
+                store,
+                "content1".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+            add_note(
+                store,
+                "content2".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+
+            remove_note(store, 1)?;
+            remove_note(store, 2)?;
+
+            let mut data = store.load()?;
+            assert_eq!(data.free_ids, vec![1, 2]);
+
+            // Ajouter de nouvelles notes doit réutiliser les IDs
+            add_note(store, "new1".to_string(), vec![], &AddOptions::default())?;
+            add_note(store, "new2".to_string(), vec![], &AddOptions::default())?;
+            data = store.load()?;
+            assert_eq!(data.free_ids.len(), 0);
+            assert_eq!(
+                data.notes.iter().map(|n| n.id).collect::<Vec<_>>(),
+                vec![1, 2]
+            );
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_add_note_with_tag() -> NoteResult<()> {
+        for_each_store(|store| {
+            let tags = vec!["tag1".to_string(), "tag2".to_string()];
+            add_note(
+                store,
+                "hello world".to_string(),
+                tags.clone(),
+                &AddOptions::default(),
+            )?;
+
+            let data = store.load()?;
+            let note = &data.notes[0];
+            assert_eq!(note.tags, tags);
+            assert_time_valid(&note.created_at);
+            assert_time_valid(&note.updated_at);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_add_tag_existing_note() -> NoteResult<()> {
+        for_each_store(|store| {
+            add_note(store, "note".to_string(), vec![], &AddOptions::default())?;
+            add_tag(store, 1, vec!["rust".to_string(), "cli".to_string()])?;
+
+            let data = store.load()?;
+            let note = &data.notes[0];
+            assert_eq!(note.tags, vec!["rust", "cli"]);
+
+            // Ajouter un tag déjà existant ne doit pas créer de doublon
+            add_tag(store, 1, vec!["rust".to_string()])?;
+            let data = store.load()?;
+            let note = &data.notes[0];
+            assert_eq!(note.tags, vec!["rust", "cli"]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_edit_note() -> NoteResult<()> {
+        for_each_store(|store| {
+            add_note(
+                store,
+                "old content".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+            edit_note(store, 1, "new content".to_string(), DEFAULT_HISTORY_LIMIT)?;
+
+            let data = store.load()?;
+            let note = &data.notes[0];
+            assert_eq!(note.content, "new content");
+            assert_time_valid(&note.updated_at);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_render_tsv_escapes_tabs() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let store = &store;
+
+        add_note(
+            store,
+            "col1\tcol2\nline2".to_string(),
+            vec!["a".to_string()],
+            &AddOptions::default(),
+        )?;
+        let data = store.load()?;
+        let notes: Vec<&Note> = data.notes.iter().collect();
+
+        let display = DisplayArgs {
+            format: OutputFormat::Tsv,
+            columns: Some(vec![Column::Id, Column::Content, Column::Tags]),
+            ..DisplayArgs::default()
+        };
+        let out = render_notes(&notes, &display);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(
+            lines,
+            vec!["id\tcontent\ttags", "1\tcol1\\tcol2\\nline2\ta"]
+        );
+
+        Ok(())
+    }
+
+    /// Splits a record of the plain output into its fields, undoing
+    /// `escape_plain`.
+    fn split_plain(record: &str, delimiter: &str) -> Vec<String> {
+        let mut fields = vec![String::new()];
+        let mut rest = record;
+        while let Some(c) = rest.chars().next() {
+            let next = rest[c.len_utf8()..].chars().next();
+            let escape = c == '\\'
+                && next.is_some_and(|n| "\\0tnrx".contains(n) || delimiter.starts_with(n));
+            if escape {
+                let n = next.unwrap();
+                let (value, len) = match n {
+                    '0' => ('\0', 2),
+                    't' => ('\t', 2),
+                    'n' => ('\n', 2),
+                    'r' => ('\r', 2),
+                    'x' => (u8::from_str_radix(&rest[2..4], 16).unwrap() as char, 4),
+                    _ => (n, 1 + n.len_utf8()),
+                };
+                fields.last_mut().unwrap().push(value);
+                rest = &rest[len..];
+            } else if rest.starts_with(delimiter) {
+                fields.push(String::new());
+                rest = &rest[delimiter.len()..];
+            } else {
+                fields.last_mut().unwrap().push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn test_render_plain_delimiters() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let store = &store;
+
+        add_note(
+            store,
+            "line1\nline2".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        add_note(store, "a|b".to_string(), vec![], &AddOptions::default())?;
+        let data = store.load()?;
+        let notes: Vec<&Note> = data.notes.iter().collect();
+
+        let display = DisplayArgs {
+            format: OutputFormat::Plain,
+            columns: Some(vec![Column::Id, Column::Content]),
+            delimiter: parse_delimiter("|")?,
+            record_delimiter: parse_delimiter("\\0")?,
+            ..DisplayArgs::default()
+        };
+        let out = render_notes(&notes, &display);
+
+        // newlines are safe with NUL records, the field delimiter is escaped
+        assert_eq!(out, "1|line1\nline2\x002|a\\|b\x00");
+
+        // escape letters and backslashes read back as they were
+        assert_eq!(parse_delimiter("\\t")?, "\t");
+        assert_eq!(parse_delimiter("\\\\")?, "\\");
+        for delimiter in ["n", "t", "\\\\", "tab", "name", "a\\b"] {
+            let delimiter = parse_delimiter(delimiter)?;
+            let content = format!("a{}b\nc\\d\tnt", delimiter);
+            let data = NoteData {
+                version: NOTES_VERSION,
+                notes: vec![Note {
+                    content: content.clone(),
+                    ..data.notes[0].clone()
+                }],
+                free_ids: vec![],
+                trash: vec![],
+            };
+            let display = DisplayArgs {
+                delimiter: delimiter.clone(),
+                ..display.clone()
+            };
+            let out = render_notes(&data.notes.iter().collect::<Vec<_>>(), &display);
+            let record = out.strip_suffix('\0').unwrap();
+            assert_eq!(
+                split_plain(record, &delimiter),
+                ["1", content.as_str()],
+                "{:?}",
+                delimiter
+            );
+        }
+        assert!(parse_delimiter("").is_err());
+        // a newline in a delimiter is escaped like any other character
+        let delimiters = ["\n-", "\n"];
+        assert_eq!(escape_plain("a\n-b\nc", &delimiters), "a\\\n-b\\\nc");
+        assert_eq!(escape_plain("xn\t\\", &["xn"]), "\\x78n\\t\\\\");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ids_only() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let store = &store;
+
+        add_note(store, "beta".to_string(), vec![], &AddOptions::default())?;
+        add_note(store, "alpha".to_string(), vec![], &AddOptions::default())?;
+        add_note(store, "gamma".to_string(), vec![], &AddOptions::default())?;
+        let data = store.load()?;
+
+        let display = DisplayArgs {
+            ids_only: true,
+            columns: Some(vec![Column::Content]),
+            ..DisplayArgs::default()
+        };
+        let notes = select_notes(&data, &FilterArgs::default(), &SortMethod::Content.into());
+        assert_eq!(render_notes(&notes, &display), "2\n1\n3\n");
+
+        let notes = search_matches(
+            &data,
+            &Matcher::new("ALPHA", false)?,
+            SearchScope::All,
+            &FilterArgs::default(),
+            &SortMethod::Id.into(),
+        );
+        assert_eq!(render_notes(&notes, &display), "2\n");
+
+        let notes = search_matches(
+            &data,
+            &Matcher::new("delta", false)?,
+            SearchScope::All,
+            &FilterArgs::default(),
+            &SortMethod::Id.into(),
+        );
+        assert_eq!(render_notes(&notes, &display), "");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_man_page_mentions_subcommands() -> NoteResult<()> {
+        let cmd = Cli::command();
+        // roff escapes dashes, as in `add\-tag`
+        let page = String::from_utf8(render_man_page(cmd.clone())?)?.replace("\\-", "-");
+
+        assert!(!page.is_empty());
+        for sub in cmd.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+            assert!(page.contains(sub.get_name()), "missing {}", sub.get_name());
+        }
+        assert!(page.contains("Exit status"));
+
+        let dir = tempfile::tempdir()?;
+        let written = write_man_pages(dir.path())?;
+        assert!(written.contains(&dir.path().join("note-cli-list.1")));
+        let list_page = fs::read_to_string(dir.path().join("note-cli-list.1"))?;
+        assert!(list_page.contains("content"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_backend_files() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        // a trailing slash selects the directory backend even before it exists
+        let path = format!("{}/notes/", tmpdir.path().display());
+        let dir = tmpdir.path().join("notes");
+        let store = open_store(&path);
+        let store = store.as_ref();
+
+        add_note(store, "first".to_string(), vec![], &AddOptions::default())?;
+        add_note(store, "second".to_string(), vec![], &AddOptions::default())?;
+        assert!(dir.join("0001.json").is_file());
+        assert!(dir.join("0002.json").is_file());
+
+        // editing one note leaves the other file untouched
+        let untouched = fs::metadata(dir.join("0001.json"))?.modified()?;
+        edit_note(
+            store,
+            2,
+            "second, edited".to_string(),
+            DEFAULT_HISTORY_LIMIT,
+        )?;
+        assert_eq!(fs::metadata(dir.join("0001.json"))?.modified()?, untouched);
+
+        remove_note(store, 1)?;
+        assert!(!dir.join("0001.json").exists());
+        let meta: DirMeta = serde_json::from_str(&fs::read_to_string(dir.join(DIR_META_FILE))?)?;
+        assert_eq!(meta.free_ids, vec![1]);
+
+        let data = store.load()?;
+        assert_eq!(data.notes.len(), 1);
+        assert_eq!(data.notes[0].content, "second, edited");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_errors_name_path_and_operation() -> NoteResult<()> {
+        let tmpfile = NamedTempFile::new()?;
+        // a regular file used as a directory can be neither read nor written
+        let path = tmpfile.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+
+        let err = load_notes(path_str).unwrap_err().to_string();
+        assert!(err.contains("cannot read"), "{}", err);
+        assert!(err.contains(path_str), "{}", err);
+
+        let empty = NoteData {
+            version: NOTES_VERSION,
+            notes: vec![],
+            free_ids: vec![],
+            trash: vec![],
+        };
+        let err = save_notes(path_str, &empty).unwrap_err().to_string();
+        assert!(err.contains("cannot write"), "{}", err);
+        assert!(err.contains(path_str), "{}", err);
+
+        Ok(())
+    }
+
+    // Helper loading a broken fixture and returning the rendered diagnostic
+    fn parse_diagnostic(fixture: &str) -> NoteResult<String> {
+        let tmpfile = NamedTempFile::new()?;
+        fs::write(tmpfile.path(), fixture)?;
+        let err = load_notes(tmpfile.path().to_str().unwrap()).unwrap_err();
+
+        let NoteError::Parse(diagnostic) = &err else {
+            panic!("expected a parse error, got {:?}", err);
+        };
+        assert!(
+            diagnostic
+                .path
+                .ends_with(tmpfile.path().file_name().unwrap())
+        );
+        assert!(std::error::Error::source(diagnostic).is_some());
+        Ok(err.to_string())
+    }
+
+    #[test]
+    fn test_parse_diagnostics() -> NoteResult<()> {
+        let truncated = parse_diagnostic("{\n  \"notes\": [\n")?;
+        assert!(
+            truncated.contains(":3:0: EOF while parsing"),
+            "{}",
+            truncated
+        );
+        assert!(truncated.contains("hint:"), "{}", truncated);
+
+        let trailing_comma = parse_diagnostic("{\n  \"notes\": [],\n  \"free_ids\": [],\n}\n")?;
+        assert!(
+            trailing_comma.contains("trailing comma"),
+            "{}",
+            trailing_comma
+        );
+        assert!(
+            trailing_comma.contains("3 |   \"free_ids\": [],\n4 | }\n  | ^\n"),
+            "{}",
+            trailing_comma
+        );
+
+        let wrong_type = parse_diagnostic(
+            "{\n  \"notes\": [\n    {\"id\": \"one\", \"content\": \"a\"}\n  ],\n  \"free_ids\": []\n}",
+        )?;
+        assert!(
+            wrong_type.contains("invalid type: string \"one\""),
+            "{}",
+            wrong_type
+        );
+        assert!(
+            wrong_type
+                .contains("3 |     {\"id\": \"one\", \"content\": \"a\"}\n  |                ^\n"),
+            "{}",
+            wrong_type
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_note_with_explicit_id() -> NoteResult<()> {
+        for_each_store(|store| {
+            let explicit = |id| AddOptions {
+                id: Some(id),
+                ..AddOptions::default()
+            };
+
+            add_note(store, "one".to_string(), vec![], &AddOptions::default())?;
+            assert_eq!(
+                add_note(store, "ten".to_string(), vec![], &explicit(10))?,
+                AddOutcome::Created(10)
+            );
+
+            // automatic allocation continues above the explicit ID
+            add_note(store, "eleven".to_string(), vec![], &AddOptions::default())?;
+            assert!(add_note(store, "dup".to_string(), vec![], &explicit(10)).is_err());
+
+            // claiming a free ID removes it from the free list
+            remove_note(store, 1)?;
+            add_note(store, "one again".to_string(), vec![], &explicit(1))?;
+            let data = store.load()?;
+            assert!(data.free_ids.is_empty());
+
+            let mut ids: Vec<u32> = data.notes.iter().map(|n| n.id).collect();
+            ids.sort();
+            assert_eq!(ids, vec![1, 10, 11]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_allocation_skips_ids_in_use() -> NoteResult<()> {
+        for_each_store(|store| {
+            add_note(store, "a".to_string(), vec![], &AddOptions::default())?;
+            add_note(store, "b".to_string(), vec![], &AddOptions::default())?;
+            add_note(store, "c".to_string(), vec![], &AddOptions::default())?;
+            remove_note(store, 1)?;
+            add_note(
+                store,
+                "reuses 1".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+            add_note(store, "new".to_string(), vec![], &AddOptions::default())?;
+
+            // a free ID taken by an explicit add is never handed out twice
+            remove_note(store, 2)?;
+            let mut data = store.load()?;
+            data.notes.push(Note {
+                id: 2,
+                ..data.notes[0].clone()
+            });
+            store.save(&data)?;
+            add_note(store, "after".to_string(), vec![], &AddOptions::default())?;
+
+            let data = store.load()?;
+            let mut ids: Vec<u32> = data.notes.iter().map(|n| n.id).collect();
+            ids.sort();
+            assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::seconds(30)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::hours(12)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::minutes(90)));
+        assert_eq!(parse_duration("2w"), Ok(Duration::days(14)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("0d").is_err());
+    }
+
+    #[test]
+    fn test_add_unique() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let store = &store;
+        let unique = AddOptions {
+            unique: true,
+            ..AddOptions::default()
+        };
+
+        add_note(store, "buy milk".to_string(), vec![], &unique)?;
+        let outcome = add_note(store, "  buy milk\n".to_string(), vec![], &unique)?;
+        assert_eq!(outcome, AddOutcome::Existing(1));
+
+        // case only matters without folding
+        let outcome = add_note(store, "Buy Milk".to_string(), vec![], &unique)?;
+        assert_eq!(outcome, AddOutcome::Created(2));
+        let folded = AddOptions {
+            unique_ignore_case: true,
+            ..unique.clone()
+        };
+        let outcome = add_note(store, "BUY MILK".to_string(), vec![], &folded)?;
+        assert_eq!(outcome, AddOutcome::Existing(2));
+
+        // notes older than the window are not considered duplicates
+        let mut data = store.load()?;
+        for note in data.notes.iter_mut() {
+            note.created_at = Utc::now() - Duration::days(2);
+        }
+        store.save(&data)?;
+        let windowed = AddOptions {
+            unique_window: Some(Duration::hours(1)),
+            ..unique.clone()
+        };
+        let outcome = add_note(store, "buy milk".to_string(), vec![], &windowed)?;
+        assert_eq!(outcome, AddOutcome::Created(3));
+        assert_eq!(store.load()?.notes.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_date_filters() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for (content, created) in [("old", "2023-06-01"), ("new", "2024-03-15")] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+            let mut data = store.load()?;
+            data.notes.last_mut().unwrap().created_at = parse_date(created)?;
+            store.save(&data)?;
+        }
+        let data = store.load()?;
+
+        let filter = FilterArgs {
+            since: Some(parse_date("2024-01-01")?),
+            ..FilterArgs::default()
+        };
+        let ids: Vec<u32> = select_notes(&data, &filter, &SortMethod::Id.into())
+            .iter()
+            .map(|n| n.id)
+            .collect();
+        assert_eq!(ids, vec![2]);
+
+        // a bare date as upper bound includes the whole day
+        let filter = FilterArgs {
+            until: Some(parse_date_end("2023-06-01")?),
+            ..FilterArgs::default()
+        };
+        let ids: Vec<u32> = search_matches(
+            &data,
+            &Matcher::new("o", false)?,
+            SearchScope::All,
+            &filter,
+            &SortMethod::Id.into(),
+        )
+        .iter()
+        .map(|n| n.id)
+        .collect();
+        assert_eq!(ids, vec![1]);
+
+        assert!(parse_date("01/02/2024").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_to_shards() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let file = open_store(path_str);
+
+        for (content, created) in [
+            ("a", "2023-05-01"),
+            ("b", "2024-02-01"),
+            ("c", "2024-07-01"),
+        ] {
+            add_note(
+                file.as_ref(),
+                content.to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+            let mut data = file.load()?;
+            data.notes.last_mut().unwrap().created_at = parse_date(created)?;
+            file.save(&data)?;
+        }
+        remove_note(file.as_ref(), 2)?;
+        add_note(
+            file.as_ref(),
+            "d, reuses 2".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        let before = file.load()?;
+
+        // "d" was created today, so its year gets a shard too
+        let shards = migrate_to_shards(path_str)?;
+        assert_eq!(shards.len(), 3);
+        assert!(migrate_to_shards(path_str).is_err());
+
+        let store = open_store(path_str);
+        let mut after = store.load()?;
+        let mut expected = before.clone();
+        expected.notes.sort_by_key(|n| n.id);
+        after.notes.sort_by_key(|n| n.id);
+        assert_eq!(after, expected);
+
+        // single-ID commands and --since only read the shards they need
+        fs::remove_file(tmpdir.path().join("notes-2023.json"))?;
+        edit_note(
+            store.as_ref(),
+            3,
+            "c, edited".to_string(),
+            DEFAULT_HISTORY_LIMIT,
+        )?;
+        assert_eq!(store.get(3)?.unwrap().content, "c, edited");
+        let recent = store.load_created_since(Some(parse_date("2024-01-01")?))?;
+        assert_eq!(recent.notes.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let store = open_store(path_str);
+        for content in ["one", "two", "three"] {
+            add_note(
+                store.as_ref(),
+                content.to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+        }
+        let mut data = store.load()?;
+        data.notes.reverse();
+        data.free_ids = vec![7, 2, 7, 5];
+        store.save(&data)?;
+
+        let options = CompactOptions {
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            trash_retention: Duration::days(DEFAULT_TRASH_RETENTION_DAYS),
+            now: Utc::now(),
+        };
+        let (before, after) = compact_notes(store.as_ref(), path_str, true, &options)?;
+        assert!(after < before);
+        let data = store.load()?;
+        let ids: Vec<u32> = data.notes.iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(data.free_ids, vec![5, 7]);
+
+        // compacting again leaves the file byte-identical
+        let content = fs::read_to_string(&path)?;
+        assert_eq!(
+            compact_notes(store.as_ref(), path_str, true, &options)?,
+            (after, after)
+        );
+        assert_eq!(fs::read_to_string(&path)?, content);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_mapping() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let csv_path = tmpdir.path().join("export.csv");
+        fs::write(
+            &csv_path,
+            "body,labels,created\n\"buy milk\",home; errands,2024-02-01\nread book,,\n",
+        )?;
+        let options = ImportOptions {
+            format: None,
+            map: parse_field_map("content=body,tags=labels,created_at=created")?,
+            tag_separator: ";".to_string(),
+            preview: true,
+            skip_duplicates: false,
+        };
+
+        let store = MemoryStore::default();
+        let store = &store;
+        let notes = import_notes(store, &csv_path, &options)?.notes;
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].tags, vec!["home", "errands"]);
+        assert_eq!(notes[0].created_at, parse_date("2024-02-01")?);
+        assert!(notes[1].tags.is_empty());
+        // preview saves nothing
+        assert!(store.load()?.notes.is_empty());
+
+        let options = ImportOptions {
+            preview: false,
+            ..options
+        };
+        import_notes(store, &csv_path, &options)?;
+        assert_eq!(store.load()?.notes.len(), 2);
+
+        // a mapping to a missing column fails before importing
+        let json_path = tmpdir.path().join("export.json");
+        fs::write(&json_path, r#"[{"text": "x", "labels": ["a", "b"]}]"#)?;
+        let bad = ImportOptions {
+            map: parse_field_map("tags=labels")?,
+            ..options.clone()
+        };
+        let err = import_notes(store, &json_path, &bad).unwrap_err();
+        assert!(err.to_string().contains("content (column 'content')"));
+        assert_eq!(store.load()?.notes.len(), 2);
+
+        let good = ImportOptions {
+            map: parse_field_map("content=text,tags=labels")?,
+            ..options
+        };
+        let notes = import_notes(store, &json_path, &good)?.notes;
+        assert_eq!(notes[0].tags, vec!["a", "b"]);
+        assert_eq!(notes[0].id, 3);
+
+        assert!(parse_field_map("title=body").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_expiry() -> NoteResult<()> {
+        for_each_store(|store| {
+            let options = AddOptions {
+                expires: Some(Duration::days(2)),
+                ..AddOptions::default()
+            };
+            add_note(store, "parking spot 3B".to_string(), vec![], &options)?;
+            add_note(store, "old reminder".to_string(), vec![], &options)?;
+            add_note(store, "keep".to_string(), vec![], &AddOptions::default())?;
+
+            // manufacture an expiry in the past for note 2
+            let mut old = store.get(2)?.unwrap();
+            old.expires_at = Some(Utc::now() - Duration::hours(1));
+            store.upsert(old)?;
+
+            let data = store.load()?;
+            assert!(store.get(1)?.unwrap().expires_at.unwrap() > Utc::now());
+            let shown = |filter: &FilterArgs| -> Vec<u32> {
+                select_notes(&data, filter, &SortMethod::Id.into())
+                    .iter()
+                    .map(|n| n.id)
+                    .collect()
+            };
+            assert_eq!(shown(&FilterArgs::default()), vec![1, 3]);
+            let all = FilterArgs {
+                expired: true,
+                ..FilterArgs::default()
+            };
+            assert_eq!(shown(&all), vec![1, 2, 3]);
+
+            // listing expired notes deletes nothing
+            assert_eq!(expire_notes(store, false)?.len(), 1);
+            assert_eq!(store.load()?.notes.len(), 3);
+
+            let purged = expire_notes(store, true)?;
+            assert_eq!(purged[0].id, 2);
+            assert!(store.get(2)?.is_none());
+            assert!(store.load()?.free_ids.contains(&2));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_tag_colors() -> NoteResult<()> {
+        let config: Config = toml::from_str(
+            "[tag_colors]\nurgent = \"red\"\nsomeday = \"dim\"\nproject = \"blue\"\n\"project/web\" = \"cyan\"\n",
+        )?;
+        let palette = TagPalette {
+            configured: config.tag_colors,
+        };
+        assert_eq!(palette.color("urgent"), (TagColor::Red, true));
+        assert_eq!(palette.color("project/cli"), (TagColor::Blue, true));
+        assert_eq!(palette.color("project/web/api"), (TagColor::Cyan, true));
+        assert!(!palette.color("projects").1);
+        // automatic colors don't change between calls
+        assert_eq!(palette.color("misc"), palette.color("misc"));
+
+        let err = toml::from_str::<Config>("[tag_colors]\nurgent = \"pink\"\n").unwrap_err();
+        assert!(err.to_string().contains("`red`"));
+
+        let store = MemoryStore::default();
+        add_note(
+            &store,
+            "fix".to_string(),
+            vec!["urgent".to_string()],
+            &AddOptions::default(),
+        )?;
+        let data = store.load()?;
+        let display = DisplayArgs {
+            palette: Some(palette.clone()),
+            ..DisplayArgs::default()
+        };
+        let notes: Vec<&Note> = data.notes.iter().collect();
+        let table = render_notes(&notes, &display);
+        assert!(table.contains("\x1b[31murgent\x1b[0m"));
+        // escapes don't count in the column width
+        let widths: HashSet<usize> = table
+            .lines()
+            .map(|line| {
+                line.replace("\x1b[31m", "")
+                    .replace("\x1b[1m", "")
+                    .replace("\x1b[0m", "")
+                    .chars()
+                    .count()
+            })
+            .collect();
+        assert_eq!(widths.len(), 1);
+        assert!(!render_notes(&notes, &DisplayArgs::default()).contains('\x1b'));
+        assert_eq!(
+            render_tags(&data, Some(&palette), false),
+            "urgent\tred\tconfig\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_meta() -> NoteResult<()> {
+        for_each_store(|store| {
+            let options = AddOptions {
+                meta: [
+                    parse_meta_entry("phone=555-0123")?,
+                    parse_meta_entry("quote=120")?,
+                ]
+                .into_iter()
+                .collect(),
+                ..AddOptions::default()
+            };
+            add_note(store, "call plumber".to_string(), vec![], &options)?;
+            add_note(store, "other".to_string(), vec![], &AddOptions::default())?;
+            set_meta(store, 2, "quote", Some("80".to_string()))?;
+
+            let data = store.load()?;
+            let matching = |raw: &[&str]| -> NoteResult<Vec<u32>> {
+                let filter = FilterArgs {
+                    meta: raw
+                        .iter()
+                        .map(|raw| parse_meta_filter(raw))
+                        .collect::<Result<_, _>>()?,
+                    ..FilterArgs::default()
+                };
+                Ok(select_notes(&data, &filter, &SortMethod::Id.into())
+                    .iter()
+                    .map(|n| n.id)
+                    .collect())
+            };
+            assert_eq!(matching(&["phone"])?, vec![1]);
+            assert_eq!(matching(&["quote"])?, vec![1, 2]);
+            assert_eq!(matching(&["quote=120"])?, vec![1]);
+            assert_eq!(matching(&["quote=80", "phone"])?, Vec::<u32>::new());
+
+            set_meta(store, 1, "phone", None)?;
+            assert!(!store.get(1)?.unwrap().meta.contains_key("phone"));
+
+            // the JSON output keeps the map as an object
+            let note = store.get(1)?.unwrap();
+            let display = DisplayArgs {
+                format: OutputFormat::Json,
+                columns: Some(vec![Column::Id, Column::Meta]),
+                ..DisplayArgs::default()
+            };
+            let json: serde_json::Value = serde_json::from_str(&render_notes(&[&note], &display))?;
+            assert_eq!(json[0]["meta"]["quote"], "120");
+            Ok(())
+        })?;
+
+        assert!(parse_meta_key("has space").is_err());
+        assert!(parse_meta_entry("novalue").is_err());
+        assert_eq!(parse_meta_entry("url=a=b")?.1, "a=b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rating() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let store = &store;
+        for (content, rating) in [("a", Some(3)), ("b", None), ("c", Some(5)), ("d", Some(3))] {
+            let options = AddOptions {
+                rating,
+                ..AddOptions::default()
+            };
+            add_note(store, content.to_string(), vec![], &options)?;
+        }
+        rate_note(store, 1, 4)?;
+
+        let data = store.load()?;
+        let ids = |filter: &FilterArgs| -> Vec<u32> {
+            select_notes(&data, filter, &SortMethod::Rating.into())
+                .iter()
+                .map(|n| n.id)
+                .collect()
+        };
+        assert_eq!(ids(&FilterArgs::default()), vec![3, 1, 4, 2]);
+        let filter = FilterArgs {
+            min_rating: Some(4),
+            ..FilterArgs::default()
+        };
+        assert_eq!(ids(&filter), vec![3, 1]);
+
+        let note = store.get(1)?.unwrap();
+        let mut display = DisplayArgs::default();
+        assert_eq!(table_field(&note, Column::Rating, &display), "★★★★☆");
+        display.no_emoji = true;
+        assert_eq!(table_field(&note, Column::Rating, &display), "4/5");
+
+        let cli = Cli::try_parse_from(["note-cli", "rate", "1", "6"]);
+        assert!(cli.is_err());
+        assert!(Cli::try_parse_from(["note-cli", "edit", "1"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_by_tag_count() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let tags = |names: &[&str]| names.iter().map(|t| t.to_string()).collect();
+        add_note(
+            &store,
+            "two".to_string(),
+            tags(&["a", "b"]),
+            &AddOptions::default(),
+        )?;
+        add_note(&store, "none".to_string(), vec![], &AddOptions::default())?;
+        add_note(
+            &store,
+            "three".to_string(),
+            tags(&["a", "b", "c"]),
+            &AddOptions::default(),
+        )?;
+        add_note(
+            &store,
+            "dup".to_string(),
+            tags(&["a", "a", "b"]),
+            &AddOptions::default(),
+        )?;
+
+        let data = store.load()?;
+        let ids = |sort: SortArgs| -> Vec<u32> {
+            select_notes(&data, &FilterArgs::default(), &sort)
+                .iter()
+                .map(|n| n.id)
+                .collect()
+        };
+        // duplicate tags count once, ties fall back to ID
+        assert_eq!(ids(SortMethod::TagCount.into()), vec![3, 1, 4, 2]);
+        let reversed = SortArgs {
+            methods: vec![SortMethod::TagCount],
+            reverse: true,
+        };
+        assert_eq!(ids(reversed), vec![2, 1, 4, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_length_filters() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        // 5 characters but 15 bytes
+        add_note(
+            &store,
+            "日本語です".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        add_note(
+            &store,
+            "abcdefgh".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        add_note(&store, "ab".to_string(), vec![], &AddOptions::default())?;
+
+        let data = store.load()?;
+        let ids = |filter: FilterArgs| -> Vec<u32> {
+            select_notes(&data, &filter, &SortMethod::Id.into())
+                .iter()
+                .map(|n| n.id)
+                .collect()
+        };
+        let bounds = |min_length, max_length| FilterArgs {
+            min_length,
+            max_length,
+            ..FilterArgs::default()
+        };
+        assert_eq!(ids(bounds(None, Some(5))), vec![1, 3]);
+        assert_eq!(ids(bounds(Some(6), None)), vec![2]);
+        assert_eq!(ids(bounds(Some(2), Some(5))), vec![1, 3]);
+        assert!(bounds(Some(6), Some(5)).validate().is_err());
+
+        let cli =
+            Cli::try_parse_from(["note-cli", "list", "--min-length", "9", "--max-length", "3"])?;
+        assert!(cli.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_date_shortcuts() -> NoteResult<()> {
+        use chrono::FixedOffset;
+
+        // Thursday 2024-06-06 at 01:30 in UTC+9, still Wednesday in UTC
+        let zone = FixedOffset::east_opt(9 * 3600).unwrap();
+        let now = zone.with_ymd_and_hms(2024, 6, 6, 1, 30, 0).unwrap();
+        let range = |filter: FilterArgs, week_start| {
+            let mut filter = filter;
+            filter.resolve_shortcuts(now, week_start);
+            (filter.since.unwrap(), filter.until.unwrap())
+        };
+        let utc = |raw: &str| {
+            DateTime::parse_from_rfc3339(raw)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+
+        let today = FilterArgs {
+            today: true,
+            ..FilterArgs::default()
+        };
+        assert_eq!(
+            range(today, Weekday::Mon),
+            (utc("2024-06-05T15:00:00Z"), utc("2024-06-06T15:00:00Z"))
+        );
+        let yesterday = FilterArgs {
+            yesterday: true,
+            ..FilterArgs::default()
+        };
+        assert_eq!(
+            range(yesterday, Weekday::Mon).0,
+            utc("2024-06-04T15:00:00Z")
+        );
+        let week = FilterArgs {
+            this_week: true,
+            ..FilterArgs::default()
+        };
+        assert_eq!(
+            range(week.clone(), Weekday::Mon),
+            (utc("2024-06-02T15:00:00Z"), utc("2024-06-09T15:00:00Z"))
+        );
+        assert_eq!(range(week, Weekday::Sun).0, utc("2024-06-01T15:00:00Z"));
+
+        let config: Config = toml::from_str("week_start = \"sunday\"")?;
+        assert_eq!(config.week_start, Weekday::Sun);
+        assert!(
+            Cli::try_parse_from(["note-cli", "list", "--today", "--since", "2024-01-01"]).is_err()
+        );
+        assert!(
+            Cli::try_parse_from(["note-cli", "search", "x", "--today", "--this-week"]).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_calendar() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for created in ["2024-01-01", "2024-01-01", "2024-01-03", "2023-12-31"] {
+            add_note(&store, created.to_string(), vec![], &AddOptions::default())?;
+            let mut data = store.load()?;
+            data.notes.last_mut().unwrap().created_at = parse_date(created)? + Duration::hours(12);
+            store.save(&data)?;
+        }
+        let data = store.load()?;
+        let day = |raw: &str| NaiveDate::parse_from_str(raw, "%Y-%m-%d").unwrap();
+
+        let counts = count_per_day(
+            &data,
+            DateField::Created,
+            &Utc,
+            day("2024-01-01"),
+            day("2024-01-14"),
+        );
+        assert_eq!(counts.len(), 14);
+        assert_eq!(counts[&day("2024-01-01")], 2);
+        assert_eq!(counts[&day("2024-01-02")], 0);
+        assert_eq!(counts.values().sum::<usize>(), 3);
+
+        // 2024-01-01 is a Monday, so the grid is exactly two weeks wide
+        let grid = render_calendar(&counts, Weekday::Mon, true);
+        let lines: Vec<&str> = grid.lines().collect();
+        assert_eq!(lines[0], "    Jan");
+        assert_eq!(lines[1], "Mon 2 .");
+        assert_eq!(lines[3], "Wed 1 .");
+        let shaded = render_calendar(&counts, Weekday::Mon, false);
+        assert!(shaded.lines().nth(1).unwrap().starts_with("Mon █ ·"));
+        assert!(shaded.lines().nth(3).unwrap().starts_with("Wed ▒ ·"));
+
+        // starting the week on Sunday shifts the first day to the second row
+        let grid = render_calendar(&counts, Weekday::Sun, true);
+        assert!(grid.lines().nth(1).unwrap().starts_with("Sun   ."));
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeline() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let notes = [
+            ("standup\nnotes below", "2024-06-01T09:15:00Z", vec!["work"]),
+            ("lunch", "2024-06-01T12:30:00Z", vec![]),
+            ("weekend plan", "2024-06-03T08:00:00Z", vec!["work"]),
+        ];
+        for (content, created, tags) in notes {
+            let tags = tags.into_iter().map(String::from).collect();
+            add_note(&store, content.to_string(), tags, &AddOptions::default())?;
+            let mut data = store.load()?;
+            data.notes.last_mut().unwrap().created_at = parse_date(created)?;
+            store.save(&data)?;
+        }
+        let data = store.load()?;
+        let all: Vec<&Note> = data.notes.iter().collect();
+
+        assert_eq!(
+            render_timeline(&all, DateField::Created, &Utc, false),
+            "== 2024-06-03 ==\n08:00  #3  weekend plan\n\n\
+             == 2024-06-01 ==\n12:30  #2  lunch\n09:15  #1  standup\n"
+        );
+        assert!(
+            render_timeline(&all, DateField::Created, &Utc, true)
+                .contains("#1  standup\n           notes below\n")
+        );
+
+        let filter = FilterArgs {
+            tags: vec!["work".to_string()],
+            since: Some(parse_date("2024-06-02")?),
+            ..FilterArgs::default()
+        };
+        let selected = select_notes(&data, &filter, &SortMethod::Id.into());
+        assert_eq!(
+            render_timeline(&selected, DateField::Created, &Utc, false),
+            "== 2024-06-03 ==\n08:00  #3  weekend plan\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_grep_lines() -> NoteResult<()> {
+        let text = "alpha\r\nbeta\r\ngamma\ndelta\nepsilon\nzeta\nAlphabet";
+        let matcher = Matcher::new("ALPHA", false)?;
+        assert_eq!(
+            grep_lines(text, &matcher, 0).unwrap(),
+            "1:alpha\n7:Alphabet\n"
+        );
+        assert_eq!(
+            grep_lines(text, &matcher, 1).unwrap(),
+            "1:alpha\n2-beta\n--\n6-zeta\n7:Alphabet\n"
+        );
+        // overlapping context is printed once
+        let matcher = Matcher::new("^(gamma|epsilon)$", true)?;
+        assert_eq!(
+            grep_lines(text, &matcher, 1).unwrap(),
+            "2-beta\n3:gamma\n4-delta\n5:epsilon\n6-zeta\n"
+        );
+        assert!(grep_lines(text, &Matcher::new("omega", false)?, 2).is_none());
+        assert!(Matcher::new("(", true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_note() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let source_path = tmpdir.path().join("personal.json");
+        let dest_path = tmpdir.path().join("team.json");
+        let source = open_store(source_path.to_str().unwrap());
+        add_note(
+            source.as_ref(),
+            "shared idea".to_string(),
+            vec!["x".to_string()],
+            &AddOptions::default(),
+        )?;
+        let before = fs::read_to_string(&source_path)?;
+
+        // the destination is created on first copy, then IDs follow its own data
+        let dest = open_store(dest_path.to_str().unwrap());
+        assert_eq!(copy_note(source.as_ref(), dest.as_ref(), 1, false)?, 1);
+        add_note(
+            dest.as_ref(),
+            "team only".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        remove_note(dest.as_ref(), 1)?;
+        assert_eq!(copy_note(source.as_ref(), dest.as_ref(), 1, true)?, 1);
+
+        let original = source.get(1)?.unwrap();
+        let copy = dest.get(1)?.unwrap();
+        assert_eq!(copy.content, original.content);
+        assert_eq!(copy.tags, original.tags);
+        assert_eq!(copy.created_at, original.created_at);
+        assert!(copy.updated_at >= original.updated_at);
+        assert_eq!(fs::read_to_string(&source_path)?, before);
+
+        assert!(copy_note(source.as_ref(), dest.as_ref(), 9, false).is_err());
+        assert!(same_notes_path(
+            source_path.to_str().unwrap(),
+            tmpdir
+                .path()
+                .join(".")
+                .join("personal.json")
+                .to_str()
+                .unwrap()
+        ));
+        assert!(!same_notes_path(
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap()
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pick_note() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for i in 1..=20 {
+            add_note(
+                &store,
+                format!("note {}\nmore", i),
+                vec![],
+                &AddOptions::default(),
+            )?;
+        }
+        edit_note(&store, 3, "touched".to_string(), DEFAULT_HISTORY_LIMIT)?;
+        let data = store.load()?;
+
+        // an invalid answer is asked again; the most recently updated is first
+        let mut output = Vec::new();
+        let picked = pick_note(&data, &mut "abc\n99\n1\n".as_bytes(), &mut output)?;
+        assert_eq!(picked, Some(3));
+        let output = String::from_utf8(output)?;
+        assert!(output.starts_with(" 1) #3 touched\n"));
+        assert_eq!(output.matches(") #").count(), PICKER_SIZE);
+        assert_eq!(output.matches("Enter a number").count(), 2);
+
+        let mut output = Vec::new();
+        assert_eq!(pick_note(&data, &mut "\n".as_bytes(), &mut output)?, None);
+        assert_eq!(pick_note(&data, &mut "".as_bytes(), &mut output)?, None);
+
+        assert!(Cli::try_parse_from(["note-cli", "edit", "1"]).is_err());
+        assert!(Cli::try_parse_from(["note-cli", "edit", "--content", "x"]).is_err());
+        assert!(Cli::try_parse_from(["note-cli", "edit"]).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_created_offset() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        add_note(&store, "travel".to_string(), vec![], &AddOptions::default())?;
+        let mut note = store.get(1)?.unwrap();
+        assert!(note.created_offset.is_some());
+
+        note.created_at = parse_date("2024-06-01T03:00:00Z")?;
+        note.updated_at = note.created_at;
+        note.created_offset = Some(9 * 3600);
+        let display = DisplayArgs::default();
+        let shown = render_note_details(&note, &display, DateZone::Original);
+        assert!(shown.contains("Created at: 01/06/2024 - 12:00 +09:00\n"));
+        let shown = render_note_details(&note, &display, DateZone::Utc);
+        assert!(shown.contains("Created at: 01/06/2024 - 03:00\n"));
+
+        // notes written before the offset was recorded look as before
+        note.created_offset = None;
+        let shown = render_note_details(&note, &display, DateZone::Original);
+        assert!(shown.contains("Created at: 01/06/2024 - 03:00\n"));
+        let old: Note = serde_json::from_str(
+            r#"{"id":1,"content":"x","tags":[],"created_at":"2024-06-01T03:00:00Z","updated_at":"2024-06-01T03:00:00Z"}"#,
+        )?;
+        assert_eq!(old.created_offset, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_instance_lock() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let notes = tmpdir.path().join("notes.json");
+        let notes = notes.to_str().unwrap();
+        let lock_path = tmpdir.path().join("notes.json.instance.lock");
+        assert_eq!(sidecar_path(notes, "instance.lock"), lock_path);
+        assert_eq!(
+            sidecar_path(
+                &format!("{}/", tmpdir.path().join("notes").display()),
+                "lock"
+            ),
+            tmpdir.path().join("notes.lock")
+        );
+
+        let lock = InstanceLock::acquire(notes)?;
+        assert_eq!(
+            fs::read_to_string(&lock_path)?,
+            std::process::id().to_string()
+        );
+        let err = InstanceLock::acquire(notes).err().unwrap();
+        assert!(err.to_string().contains(&std::process::id().to_string()));
+        // the PID is linked in place, no file is left beside the lock
+        let pid = std::process::id();
+        assert!(!sidecar_path(notes, &format!("instance.lock.{}", pid)).exists());
+        // a one-shot command isn't kept waiting by the session
+        let store = JsonFileStore {
+            path: notes.to_string(),
+        };
+        add_note(
+            &store,
+            "meanwhile".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        drop(lock);
+        assert!(!lock_path.exists());
+
+        // a lock whose owner is gone is reclaimed
+        fs::write(&lock_path, u32::MAX.to_string())?;
+        let _lock = InstanceLock::acquire(notes)?;
+        assert_eq!(
+            fs::read_to_string(&lock_path)?,
+            std::process::id().to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_org() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let tags = vec![
+            "rust".to_string(),
+            "cli tools".to_string(),
+            "cli-tools".to_string(),
+        ];
+        add_note(
+            &store,
+            "Learn clap\nderive API\n** not a heading".to_string(),
+            tags,
+            &AddOptions::default(),
+        )?;
+        add_note(&store, "plain".to_string(), vec![], &AddOptions::default())?;
+        let mut data = store.load()?;
+        data.notes.reverse();
+        for note in &mut data.notes {
+            note.created_at = parse_date("2024-06-01T12:00:00Z")?;
+            note.updated_at = note.created_at;
+        }
+        data.notes[0].due = Some(parse_date("2026-11-01")?);
+        data.notes[1].due = Some(parse_date("2024-06-05T17:30:00Z")?);
+
+        assert_eq!(
+            render_org(&export_selection(&data, &[])),
+            "* Learn clap :rust:cli_tools:\n\
+             DEADLINE: <2024-06-05 Wed 17:30>\n\
+             :PROPERTIES:\n:ID: 1\n\
+             :CREATED: [2024-06-01 Sat 12:00]\n:UPDATED: [2024-06-01 Sat 12:00]\n\
+             :END:\nderive API\n ** not a heading\n\
+             * plain\n\
+             DEADLINE: <2026-11-01 Sun>\n\
+             :PROPERTIES:\n:ID: 2\n\
+             :CREATED: [2024-06-01 Sat 12:00]\n:UPDATED: [2024-06-01 Sat 12:00]\n\
+             :END:\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_taskwarrior() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("tasks.json");
+        fs::write(
+            &path,
+            r#"[
+                {"description": "buy milk", "tags": ["home"], "status": "pending",
+                 "entry": "20240601T081500Z", "modified": "20240602T090000Z",
+                 "due": "20240605T000000Z"},
+                {"description": "old chore", "status": "completed", "entry": "20240101T000000Z"},
+                {"description": "never mind", "status": "deleted"}
+            ]"#,
+        )?;
+        let options = ImportOptions {
+            format: Some(ImportFormat::Taskwarrior),
+            map: FieldMap::default(),
+            tag_separator: ",".to_string(),
+            preview: true,
+            skip_duplicates: false,
+        };
+
+        let store = MemoryStore::default();
+        let summary = import_notes(&store, &path, &options)?;
+        assert_eq!((summary.notes.len(), summary.skipped), (2, 1));
+        assert!(store.load()?.notes.is_empty());
+
+        let options = ImportOptions {
+            preview: false,
+            ..options
+        };
+        import_notes(&store, &path, &options)?;
+        let milk = store.get(1)?.unwrap();
+        assert_eq!(milk.created_at, parse_date("2024-06-01T08:15:00Z")?);
+        assert_eq!(milk.updated_at, parse_date("2024-06-02T09:00:00Z")?);
+        assert_eq!(milk.meta["due"], "2024-06-05T00:00:00+00:00");
+        assert_eq!(store.get(2)?.unwrap().tags, vec!["done"]);
+        assert!(parse_taskwarrior_date("2024-06-01T08").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_normalization_on_load() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let fixture = r#"{
+  "notes": [
+    {"id": 1, "content": "legacy", "tags": [" rust", "cli ", "", "rust", "cli", "  "],
+     "created_at": "2023-01-01T00:00:00Z", "updated_at": "2023-01-01T00:00:00Z"},
+    {"id": 2, "content": "clean", "tags": ["ok"],
+     "created_at": "2023-01-01T00:00:00Z", "updated_at": "2023-01-01T00:00:00Z"}
+  ],
+  "free_ids": []
+}"#;
+        fs::write(&path, fixture)?;
+
+        let mut raw: NoteData = serde_json::from_str(fixture)?;
+        assert_eq!(normalize_tags(&mut raw.notes), 6);
+        assert_eq!(raw.notes[0].tags, vec!["rust", "cli"]);
+        assert_eq!(raw.notes[1].tags, vec!["ok"]);
+
+        let store = open_store(path.to_str().unwrap());
+        assert_eq!(store.load()?, raw);
+        // reading doesn't rewrite the file, the next save does
+        assert_eq!(fs::read_to_string(&path)?, fixture);
+        add_tag(store.as_ref(), 2, vec!["new".to_string()])?;
+        assert!(!fs::read_to_string(&path)?.contains("\" rust\""));
+        Ok(())
+    }
+
+    #[test]
+    fn test_paging() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for i in 1..=5 {
+            add_note(
+                &store,
+                format!("note {}", i),
+                vec![],
+                &AddOptions::default(),
+            )?;
+        }
+        let data = store.load()?;
+        let notes = select_notes(&data, &FilterArgs::default(), &SortMethod::Id.into());
+        let ids = |notes: &[&Note]| notes.iter().map(|n| n.id).collect::<Vec<_>>();
+        assert_eq!(ids(slice_notes(&notes, 1, Some(2))), vec![2, 3]);
+        assert_eq!(ids(slice_notes(&notes, 4, Some(9))), vec![5]);
+        assert!(slice_notes(&notes, 9, None).is_empty());
+
+        let display = DisplayArgs {
+            columns: Some(vec![Column::Id]),
+            ..DisplayArgs::default()
+        };
+        let page = |answers: &str| -> NoteResult<String> {
+            let mut output = Vec::new();
+            page_notes(&notes, 2, &display, &mut answers.as_bytes(), &mut output)?;
+            Ok(String::from_utf8(output)?)
+        };
+        // next, invalid key, back, next at the last page, then end of input
+        let output = page("n\nx\np\nn\nn\nn\n")?;
+        assert_eq!(output.matches("Page 1/3").count(), 2);
+        assert_eq!(output.matches("Type n, p or q").count(), 1);
+        assert_eq!(output.matches("No more pages that way").count(), 1);
+        assert!(output.contains("| 5  |"));
+        let output = page("q\n")?;
+        assert!(!output.contains("| 3  |"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_since_cursor() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        add_note(&store, "first".to_string(), vec![], &AddOptions::default())?;
+        add_note(&store, "second".to_string(), vec![], &AddOptions::default())?;
+
+        let data = store.load()?;
+        let (notes, cursor) = notes_since_cursor(&data, None);
+        assert_eq!(notes.len(), 2);
+        assert_eq!(cursor, Some(data.notes[1].updated_at));
+        assert!(notes_since_cursor(&data, cursor).0.is_empty());
+
+        // the cursor follows the data, not the clock: an edit stamped in the
+        // past by a machine whose clock is late is still seen
+        let mut note = store.get(1)?.unwrap();
+        note.updated_at = cursor.unwrap() + Duration::milliseconds(1);
+        store.upsert(note)?;
+        let data = store.load()?;
+        let (notes, next) = notes_since_cursor(&data, cursor);
+        assert_eq!(notes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![1]);
+        assert!(next > cursor);
+
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("state").join("cursors.json");
+        let mut cursors = Cursors::load(&path)?;
+        cursors.0.insert(Cursors::key("notes.json"), next.unwrap());
+        cursors.save(&path)?;
+        assert_eq!(
+            Cursors::load(&path)?.0.get(&Cursors::key("notes.json")),
+            next.as_ref()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let now = Utc::now();
+        let with_due = |due: DateTime<Utc>| AddOptions {
+            meta: BTreeMap::from([("due".to_string(), due.to_rfc3339())]),
+            ..AddOptions::default()
+        };
+        add_note(&store, "plain".to_string(), vec![], &AddOptions::default())?;
+        add_note(
+            &store,
+            "soon".to_string(),
+            vec![],
+            &with_due(now + Duration::days(2)),
+        )?;
+        add_note(
+            &store,
+            "late".to_string(),
+            vec![],
+            &with_due(now - Duration::days(1)),
+        )?;
+        add_note(
+            &store,
+            "finished".to_string(),
+            vec!["done".to_string()],
+            &with_due(now - Duration::days(3)),
+        )?;
+
+        let data = store.load()?;
+        let counts = status_counts(&data, now);
+        assert_eq!((counts.total, counts.due.len(), counts.overdue), (4, 2, 1));
+        assert_eq!(
+            render_status(&counts, now, StatusFormat::Text, true),
+            "notes 4 | due 1 | overdue 1"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render_status(&counts, now, StatusFormat::Json, false))?;
+        assert_eq!(json["text"], "📝 4 | due 1 | overdue 1");
+        assert_eq!(json["class"], "overdue");
+        let tooltip = json["tooltip"].as_str().unwrap();
+        assert!(tooltip.starts_with("! #3 late"));
+        assert!(tooltip.lines().nth(1).unwrap().starts_with("#2 soon"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fzf_format() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        add_note(
+            &store,
+            "groceries\nmilk\teggs".to_string(),
+            vec!["home".to_string(), "todo".to_string()],
+            &AddOptions::default(),
+        )?;
+        add_note(
+            &store,
+            "tab\there".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+
+        let data = store.load()?;
+        let notes: Vec<&Note> = data.notes.iter().collect();
+        let display = DisplayArgs {
+            format: OutputFormat::Fzf,
+            ..DisplayArgs::default()
+        };
+        assert_eq!(
+            render_notes(&notes, &display),
+            "1\tgroceries\thome,todo\n2\ttab\\there\t\n"
+        );
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for i in 0..10 {
+            add_note(
+                &store,
+                format!("note {}", i),
+                vec![],
+                &AddOptions::default(),
+            )?;
+        }
+        let data = store.load()?;
+        let sorted = select_notes(
+            &data,
+            &FilterArgs::default(),
+            &SortArgs::from(SortMethod::Id),
+        );
+        let sample = |count, seed| {
+            let mut notes = sorted.clone();
+            SampleArgs {
+                sample: Some(count),
+                seed: Some(seed),
+            }
+            .apply(&mut notes);
+            notes.iter().map(|n| n.id).collect::<Vec<_>>()
+        };
+
+        let ids = sample(4, 7);
+        assert_eq!(ids.len(), 4);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]), "sorted, no repeat");
+        assert_eq!(sample(4, 7), ids);
+        assert_eq!(sample(25, 7), (1..=10).collect::<Vec<_>>());
+
+        // every note can be picked
+        let seen: BTreeSet<u32> = (0..200).flat_map(|seed| sample(1, seed)).collect();
+        assert_eq!(seen.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_note() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let tags = vec!["a".to_string(), "b".to_string()];
+        add_note(&store, "draft".to_string(), tags, &AddOptions::default())?;
+        let created = store.get(1)?.unwrap().updated_at;
+
+        let changes = NoteChanges {
+            content: Some("final".to_string()),
+            add_tags: vec!["c".to_string(), "a".to_string()],
+            remove_tags: vec!["b".to_string()],
+            ..NoteChanges::default()
+        };
+        update_note(&store, 1, &changes)?;
+        let note = store.get(1)?.unwrap();
+        assert_eq!(note.content, "final");
+        assert_eq!(note.tags, vec!["a", "c"]);
+        assert!(note.updated_at > created);
+
+        // applying the same changes again is a no-op
+        update_note(&store, 1, &changes)?;
+        assert_eq!(store.get(1)?.unwrap().updated_at, note.updated_at);
+
+        let changes = NoteChanges {
+            set_tags: Some(vec!["x".to_string(), "x".to_string(), "y".to_string()]),
+            rating: Some(2),
+            ..NoteChanges::default()
+        };
+        update_note(&store, 1, &changes)?;
+        let note = store.get(1)?.unwrap();
+        assert_eq!(note.tags, vec!["x", "y"]);
+        assert_eq!(note.rating, Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_text() -> NoteResult<()> {
+        assert_eq!(
+            edit_text("sed -i s/sam/Sam/", "meeting with sam")?,
+            Some("meeting with Sam".to_string())
+        );
+        assert_eq!(
+            edit_text("true", "untouched")?,
+            Some("untouched".to_string())
+        );
+        assert_eq!(edit_text("false", "kept")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_show_several() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for content in ["one", "two", "three"] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+        }
+
+        let notes = get_notes(&store, &[3, 1])?;
+        assert_eq!(notes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![3, 1]);
+        let err = get_notes(&store, &[1, 7, 9]).unwrap_err();
+        assert_eq!(err.to_string(), "IDs 7, 9 not found");
+
+        let display = DisplayArgs {
+            no_emoji: true,
+            ..DisplayArgs::default()
+        };
+        let out = render_notes_details(&notes, &display, DateZone::Utc);
+        let rule = format!("\n{}\n", "-".repeat(40));
+        assert_eq!(out.matches(&rule).count(), 1);
+        assert!(out.starts_with("Note 3\n"));
+        assert!(out.ends_with("\none\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cap_free_ids() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for i in 1..=8 {
+            add_note(
+                &store,
+                format!("note {}", i),
+                vec![],
+                &AddOptions::default(),
+            )?;
+        }
+        for id in [6, 2, 4, 3] {
+            remove_note(&store, id)?;
+        }
+
+        let mut data = store.load()?;
+        assert_eq!(cap_free_ids(&mut data, 2), vec![4, 6]);
+        assert_eq!(data.free_ids, vec![2, 3]);
+        store.save(&data)?;
+        assert_eq!(allocate_id(&mut store.load()?), 2);
+
+        let mut data = store.load()?;
+        assert_eq!(cap_free_ids(&mut data, 0), vec![2, 3]);
+        store.save(&data)?;
+        let new = add_note(&store, "after".to_string(), vec![], &AddOptions::default())?;
+        assert!(matches!(new, AddOutcome::Created(9)));
+
+        assert!(confirm(&mut "Y\n".as_bytes(), &mut Vec::new(), "Sure?")?);
+        assert!(!confirm(&mut "\n".as_bytes(), &mut Vec::new(), "Sure?")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive() -> NoteResult<()> {
+        for_each_store(|store| {
+            for day in [3, 10, 20] {
+                add_note(
+                    store,
+                    format!("day {}", day),
+                    vec![],
+                    &AddOptions::default(),
+                )?;
+            }
+            let mut data = store.load()?;
+            for note in &mut data.notes {
+                let day = note.id * 7;
+                note.created_at = Utc.with_ymd_and_hms(2023, 12, day, 12, 0, 0).unwrap();
+            }
+            data.notes[0].updated_at = Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap();
+            store.save(&data)?;
+
+            let cutoff = parse_date("2023-12-15")?;
+            let data = store.load()?;
+            assert_eq!(
+                archive_candidates(&data, cutoff, DateField::Created),
+                (vec![1, 2], 0)
+            );
+            assert_eq!(
+                archive_candidates(&data, cutoff, DateField::Updated),
+                (vec![], 0)
+            );
+            // pinned notes stay out, counted apart
+            set_pinned(store, 2, true)?;
+            assert_eq!(
+                archive_candidates(&store.load()?, cutoff, DateField::Created),
+                (vec![1], 1)
+            );
+            set_pinned(store, 2, false)?;
+
+            archive_notes(store, &[1, 2])?;
+            let data = store.load()?;
+            assert_eq!(
+                archive_candidates(&data, cutoff, DateField::Created),
+                (vec![], 0)
+            );
+            let shown = select_notes(&data, &FilterArgs::default(), &SortMethod::Id.into());
+            assert_eq!(shown.iter().map(|n| n.id).collect::<Vec<_>>(), vec![3]);
+            let filter = FilterArgs {
+                with_archived: true,
+                ..FilterArgs::default()
+            };
+            assert_eq!(
+                select_notes(&data, &filter, &SortMethod::Id.into()).len(),
+                3
+            );
+            let filter = FilterArgs {
+                archived: true,
+                ..FilterArgs::default()
+            };
+            assert_eq!(
+                select_notes(&data, &filter, &SortMethod::Id.into()).len(),
+                2
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_remove_tag() -> NoteResult<()> {
+        for_each_store(|store| {
+            let tags = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+            add_note(store, "tagged".to_string(), tags, &AddOptions::default())?;
+            let before = store.get(1)?.unwrap().updated_at;
+
+            remove_tag(store, 1, &["b".to_string()])?;
+            let note = store.get(1)?.unwrap();
+            assert_eq!(note.tags, vec!["a", "c"]);
+            assert!(note.updated_at > before);
+
+            // nothing is removed when one tag is missing
+            let err = remove_tag(store, 1, &["a".to_string(), "zzz".to_string()]).unwrap_err();
+            assert_eq!(err.to_string(), "note 1 has no tag zzz");
+            assert_eq!(store.get(1)?.unwrap().tags, vec!["a", "c"]);
+
+            let err = remove_tag(store, 9, &["a".to_string()]).unwrap_err();
+            assert_eq!(err.to_string(), "ID 9 not found");
+
+            remove_tag(store, 1, &["a".to_string(), "c".to_string()])?;
+            let note = store.get(1)?.unwrap();
+            assert!(note.tags.is_empty());
+            assert_eq!(
+                table_field(&note, Column::Tags, &DisplayArgs::default()),
+                "-"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_tag_counts() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for tags in [
+            vec!["rust", "cli"],
+            vec!["rust"],
+            vec!["web", "web"],
+            vec!["cli"],
+        ] {
+            let tags = tags.into_iter().map(String::from).collect();
+            add_note(&store, "note".to_string(), tags, &AddOptions::default())?;
+        }
+        // a tag repeated on a note counts once
+        let mut data = store.load()?;
+        data.notes[2].tags = vec!["web".to_string(), "web".to_string()];
+
+        assert_eq!(
+            tag_counts(&data, TagSortMethod::Count),
+            vec![("cli", 2), ("rust", 2), ("web", 1)]
+        );
+        let by_name = tag_counts(&data, TagSortMethod::Name);
+        assert_eq!(by_name, vec![("cli", 2), ("rust", 2), ("web", 1)]);
+        let table = render_tag_counts(&by_name, None);
+        assert!(table.lines().nth(1).unwrap().contains("Count"));
+        assert!(table.contains("| web  | 1     |"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_tag() -> NoteResult<()> {
+        for_each_store(|store| {
+            for tags in [
+                vec!["rustlang"],
+                vec!["rust", "rustlang", "cli"],
+                vec!["web"],
+            ] {
+                let tags = tags.into_iter().map(String::from).collect();
+                add_note(store, "note".to_string(), tags, &AddOptions::default())?;
+            }
+            let before = store.load()?;
+
+            assert_eq!(rename_tag(store, "rustlang", "rust", true)?, vec![1, 2]);
+            assert_eq!(store.load()?, before);
+
+            assert_eq!(rename_tag(store, "rustlang", "rust", false)?, vec![1, 2]);
+            assert_eq!(store.get(1)?.unwrap().tags, vec!["rust"]);
+            assert_eq!(store.get(2)?.unwrap().tags, vec!["rust", "cli"]);
+            assert!(store.get(2)?.unwrap().updated_at > before.notes[1].updated_at);
+            assert_eq!(store.get(3)?.unwrap(), before.notes[2]);
+
+            let err = rename_tag(store, "rustlang", "rust", false).unwrap_err();
+            assert_eq!(err.to_string(), "no note has the tag rustlang");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_tag_filter() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for (tags, created) in [
+            (vec!["work", "urgent"], "2024-03-01"),
+            (vec!["work"], "2024-01-01"),
+            (vec!["urgent", "work", "home"], "2024-02-01"),
+        ] {
+            let tags = tags.into_iter().map(String::from).collect();
+            add_note(&store, "task".to_string(), tags, &AddOptions::default())?;
+            let mut data = store.load()?;
+            data.notes.last_mut().unwrap().created_at = parse_date(created)?;
+            store.save(&data)?;
+        }
+        let data = store.load()?;
+        let ids = |tags: &[&str], sort: SortMethod| {
+            let filter = FilterArgs {
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                ..FilterArgs::default()
+            };
+            select_notes(&data, &filter, &sort.into())
+                .iter()
+                .map(|n| n.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids(&["work"], SortMethod::Id), vec![1, 2, 3]);
+        assert_eq!(ids(&["work", "urgent"], SortMethod::Date), vec![3, 1]);
+        assert!(ids(&["work", "nothing"], SortMethod::Id).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_scope() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let tagged = vec!["Rustlang".to_string()];
+        add_note(
+            &store,
+            "borrow checker".to_string(),
+            tagged,
+            &AddOptions::default(),
+        )?;
+        add_note(
+            &store,
+            "rust at home".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        let data = store.load()?;
+        let matcher = Matcher::new("rust", false)?;
+        let ids = |scope| {
+            search_matches(
+                &data,
+                &matcher,
+                scope,
+                &FilterArgs::default(),
+                &SortMethod::Id.into(),
+            )
+            .iter()
+            .map(|n| n.id)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids(SearchScope::All), vec![1, 2]);
+        assert_eq!(ids(SearchScope::Tags), vec![1]);
+        assert_eq!(ids(SearchScope::Content), vec![2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_regex() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for content in [
+            "Meeting 2024-05 with Sam",
+            "meeting notes",
+            "lunch, then meeting 2023-11",
+        ] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+        }
+        let data = store.load()?;
+        let ids = |pattern: &str| -> NoteResult<Vec<u32>> {
+            let matcher = Matcher::new(pattern, true)?;
+            let filter = FilterArgs::default();
+            let notes = search_matches(
+                &data,
+                &matcher,
+                SearchScope::Content,
+                &filter,
+                &SortMethod::Id.into(),
+            );
+            Ok(notes.iter().map(|n| n.id).collect())
+        };
+
+        assert_eq!(ids(r"meeting \d{4}-\d{2}")?, vec![1, 3]);
+        assert_eq!(ids(r"^meeting")?, vec![1, 2]);
+        assert_eq!(ids(r"[0-9]{4}-1[0-2]$")?, vec![3]);
+        let err = ids(r"meeting (\d").unwrap_err();
+        assert!(err.to_string().contains("unclosed group"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_case_sensitive() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        add_note(
+            &store,
+            "RUST meetup".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        add_note(
+            &store,
+            "rust meetup".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        let data = store.load()?;
+        let ids = |regex, case_sensitive| -> NoteResult<Vec<u32>> {
+            let matcher = Matcher::with_case("RUST", regex, case_sensitive)?;
+            let filter = FilterArgs::default();
+            let notes = search_matches(
+                &data,
+                &matcher,
+                SearchScope::All,
+                &filter,
+                &SortMethod::Id.into(),
+            );
+            Ok(notes.iter().map(|n| n.id).collect())
+        };
+
+        assert_eq!(ids(false, false)?, vec![1, 2]);
+        assert_eq!(ids(false, true)?, vec![1]);
+        assert_eq!(ids(true, true)?, vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_fuzzy() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for content in [
+            "mail the electrician tonight, then bring groceries",
+            "team meeting notes",
+            "meeting with Sam",
+        ] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+        }
+        let data = store.load()?;
+        let ids = |keyword: &str, sort: &SortArgs| {
+            let matcher = Matcher::fuzzy(keyword);
+            let filter = FilterArgs::default();
+            search_matches(&data, &matcher, SearchScope::All, &filter, sort)
+                .iter()
+                .map(|n| n.id)
+                .collect::<Vec<_>>()
+        };
+        let by_score = SortArgs {
+            methods: vec![],
+            reverse: false,
+        };
+
+        // a missing letter still finds the meetings, best match first, but
+        // not the note merely containing the letters far apart
+        assert_eq!(ids("meetng", &by_score), vec![3, 2]);
+        assert_eq!(ids("meetng", &SortMethod::Id.into()), vec![2, 3]);
+        assert!(ids("xylophone", &by_score).is_empty());
+        // without --fuzzy the typo finds nothing
+        let exact = Matcher::new("meetng", false)?;
+        let filter = FilterArgs::default();
+        assert!(search_matches(&data, &exact, SearchScope::All, &filter, &by_score).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_several_keywords() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        add_note(
+            &store,
+            "a rust cli".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        add_note(
+            &store,
+            "rust web server".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        let tags = vec!["cli".to_string()];
+        add_note(
+            &store,
+            "argument parsing in rust".to_string(),
+            tags,
+            &AddOptions::default(),
+        )?;
+        add_note(
+            &store,
+            "python cli".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        let data = store.load()?;
+        let ids = |keywords: &[&str], any: bool| -> NoteResult<Vec<u32>> {
+            let matching = MatchArgs {
+                any,
+                ..MatchArgs::default()
+            };
+            let keywords: Vec<String> = keywords.iter().map(|k| k.to_string()).collect();
+            let matcher = matching.matcher(&keywords)?;
+            let filter = FilterArgs::default();
+            let notes = search_matches(
+                &data,
+                &matcher,
+                SearchScope::All,
+                &filter,
+                &SortMethod::Id.into(),
+            );
+            Ok(notes.iter().map(|n| n.id).collect())
+        };
+
+        // keywords may match different fields of a note
+        assert_eq!(ids(&["rust", "cli"], false)?, vec![1, 3]);
+        assert_eq!(ids(&["Rust", "CLI"], false)?, vec![1, 3]);
+        assert_eq!(ids(&["web", "python"], true)?, vec![2, 4]);
+        assert_eq!(ids(&["web", "python"], false)?, Vec::<u32>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_date_table() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for (content, created) in [
+            ("middle", "2024-02-01"),
+            ("newest", "2024-03-01"),
+            ("oldest", "2024-01-01"),
+        ] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+            let mut data = store.load()?;
+            data.notes.last_mut().unwrap().created_at = parse_date(created)?;
+            store.save(&data)?;
+        }
+        let data = store.load()?;
+        let sort = SortArgs {
+            methods: vec![SortMethod::Date],
+            reverse: true,
+        };
+        let display = DisplayArgs {
+            columns: Some(vec![Column::Content]),
+            ..DisplayArgs::default()
+        };
+        let first_row = |notes: &[&Note]| {
+            render_notes(notes, &display)
+                .lines()
+                .nth(3)
+                .unwrap()
+                .to_string()
+        };
+
+        assert!(first_row(&select_notes(&data, &FilterArgs::default(), &sort)).contains("newest"));
+        let matcher = Matcher::new("e", false)?;
+        let found = search_matches(
+            &data,
+            &matcher,
+            SearchScope::All,
+            &FilterArgs::default(),
+            &sort,
+        );
+        assert!(first_row(&found).contains("newest"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_several_keys() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for content in ["b", "a", "b", "a"] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+        }
+        let mut data = store.load()?;
+        let same_time = parse_date("2024-05-01")?;
+        for note in &mut data.notes {
+            note.updated_at = same_time;
+        }
+        data.notes[3].updated_at = parse_date("2024-06-01")?;
+        let ids = |methods: Vec<SortMethod>, reverse| {
+            let sort = SortArgs { methods, reverse };
+            select_notes(&data, &FilterArgs::default(), &sort)
+                .iter()
+                .map(|n| n.id)
+                .collect::<Vec<_>>()
+        };
+
+        use SortMethod::{Content, Id, Update};
+        assert_eq!(ids(vec![Update], false), vec![1, 2, 3, 4]);
+        assert_eq!(ids(vec![Update, Content], false), vec![2, 1, 3, 4]);
+        assert_eq!(ids(vec![Update, Content, Id], true), vec![4, 3, 1, 2]);
+        assert_eq!(ids(vec![Content, Update], false), vec![2, 4, 1, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_by_tags() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for tags in [vec!["a", "b", "c"], vec![], vec!["a"], vec![]] {
+            let tags = tags.into_iter().map(String::from).collect();
+            add_note(&store, "note".to_string(), tags, &AddOptions::default())?;
+        }
+        let mut data = store.load()?;
+        // Repeated tags count once, as they do for tag-count.
+        data.notes[2].tags = ["a", "a", "a", "a"].map(String::from).to_vec();
+        let sort = SortMethod::Tags.into();
+
+        let listed = select_notes(&data, &FilterArgs::default(), &sort);
+        assert_eq!(
+            listed.iter().map(|n| n.id).collect::<Vec<_>>(),
+            vec![2, 4, 3, 1]
+        );
+        let matcher = Matcher::new("note", false)?;
+        let found = search_matches(
+            &data,
+            &matcher,
+            SearchScope::All,
+            &FilterArgs::default(),
+            &sort,
+        );
+        assert_eq!(
+            found.iter().map(|n| n.id).collect::<Vec<_>>(),
+            vec![2, 4, 3, 1]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_limit_after_sort() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for (content, created) in [
+            ("b", "2024-02-01"),
+            ("c", "2024-03-01"),
+            ("a", "2024-01-01"),
+        ] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+            let mut data = store.load()?;
+            data.notes.last_mut().unwrap().created_at = parse_date(created)?;
+            store.save(&data)?;
+        }
+        let data = store.load()?;
+        let ids = |notes: &[&Note]| notes.iter().map(|n| n.id).collect::<Vec<_>>();
+
+        let by_date = select_notes(&data, &FilterArgs::default(), &SortMethod::Date.into());
+        assert_eq!(ids(slice_notes(&by_date, 0, Some(2))), vec![3, 1]);
+        let newest_first = SortArgs {
+            methods: vec![SortMethod::Date],
+            reverse: true,
+        };
+        let newest_first = select_notes(&data, &FilterArgs::default(), &newest_first);
+        assert_eq!(ids(slice_notes(&newest_first, 0, Some(2))), vec![2, 1]);
+        assert_eq!(ids(slice_notes(&newest_first, 0, Some(0))), vec![2, 1, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_and_per_page() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for content in ["f", "a", "e", "b", "d", "c", "g"] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+        }
+        let data = store.load()?;
+        let notes = select_notes(&data, &FilterArgs::default(), &SortMethod::Content.into());
+        let page = |page: usize, per_page: usize| {
+            slice_notes(&notes, (page - 1) * per_page, Some(per_page))
+                .iter()
+                .map(|n| n.content.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(page(2, 3), vec!["d", "e", "f"]);
+        assert_eq!(page(3, 3), vec!["g"]);
+        assert!(page(4, 3).is_empty());
+        assert_eq!(page_summary(3, 3, 7), "page 3/3, 7 notes");
+        assert_eq!(page_summary(2, 7, 7), "page 2/1, 7 notes");
+        assert_eq!(page_summary(1, 5, 0), "page 1/1, 0 notes");
+
+        let empty = DisplayArgs {
+            columns: Some(vec![Column::Id]),
+            ..DisplayArgs::default()
+        };
+        assert_eq!(render_notes(&[], &empty).lines().count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_show_tags_per_line() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let tags = vec!["work".to_string(), "project/cli".to_string()];
+        add_note(
+            &store,
+            "line one\nline two".to_string(),
+            tags,
+            &AddOptions::default(),
+        )?;
+        add_note(
+            &store,
+            "untagged".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+
+        let display = DisplayArgs::default();
+        let shown = render_note_details(&find_note(&store, 1)?, &display, DateZone::Utc);
+        assert!(shown.starts_with("Note 1\nTags:\n  work\n  project/cli\n"));
+        assert!(shown.ends_with("\nline one\nline two\n"));
+        let shown = render_note_details(&find_note(&store, 2)?, &display, DateZone::Utc);
+        assert!(shown.contains("\nTags: -\n"));
+        assert_eq!(
+            find_note(&store, 3).unwrap_err().to_string(),
+            "ID 3 not found"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_notes() -> NoteResult<()> {
+        for_each_store(|store| {
+            assert_eq!(count_notes(store, None, &FilterArgs::default())?, 0);
+
+            let work = vec!["work".to_string()];
+            add_note(
+                store,
+                "meeting at 10".to_string(),
+                work.clone(),
+                &AddOptions::default(),
+            )?;
+            add_note(store, "groceries".to_string(), work, &AddOptions::default())?;
+            add_note(
+                store,
+                "Meeting with Sam".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+
+            assert_eq!(count_notes(store, None, &FilterArgs::default())?, 3);
+            let filter = FilterArgs {
+                tags: vec!["work".to_string()],
+                ..FilterArgs::default()
+            };
+            assert_eq!(count_notes(store, None, &filter)?, 2);
+            assert_eq!(
+                count_notes(store, Some("meeting"), &FilterArgs::default())?,
+                2
+            );
+            assert_eq!(count_notes(store, Some("meeting"), &filter)?, 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_json_notes() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let options = AddOptions {
+            rating: Some(4),
+            expires: Some(Duration::days(2)),
+            ..AddOptions::default()
+        };
+        add_note(
+            &store,
+            "b, with \"quotes\"".to_string(),
+            vec!["x".to_string()],
+            &options,
+        )?;
+        add_note(
+            &store,
+            "a\nmultiline".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        let data = store.load()?;
+
+        let display = DisplayArgs {
+            format: OutputFormat::Json,
+            ..DisplayArgs::default()
+        };
+        let notes = select_notes(&data, &FilterArgs::default(), &SortMethod::Content.into());
+        let parsed: Vec<Note> = serde_json::from_str(&render_notes(&notes, &display))?;
+        let expected: Vec<Note> = notes.iter().map(|n| (*n).clone()).collect();
+        assert_eq!(parsed, expected);
+        assert_eq!(parsed[0].id, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_csv() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let content = "one, two\nsay \"hi\"";
+        let tags = vec!["work".to_string(), "cli".to_string()];
+        add_note(&store, content.to_string(), tags, &AddOptions::default())?;
+        add_note(&store, "other".to_string(), vec![], &AddOptions::default())?;
+        let data = store.load()?;
+
+        let csv = render_csv(&export_selection(&data, &["work".to_string()]))?;
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        assert_eq!(
+            reader.headers()?,
+            vec!["id", "content", "tags", "created_at", "updated_at"]
+        );
+        let rows: Vec<csv::StringRecord> = reader.records().collect::<Result<_, _>>()?;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(&rows[0][0], "1");
+        assert_eq!(&rows[0][1], content);
+        assert_eq!(&rows[0][2], "work;cli");
+        assert_eq!(parse_date(&rows[0][3])?, data.notes[0].created_at);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_markdown() -> NoteResult<()> {
+        assert_eq!(
+            slug("Hello, World! C++ & Rust: a/b test ignored"),
+            "hello-world-c-rust-a-b"
+        );
+        assert_eq!(slug("  ../../etc  "), "etc");
+        assert_eq!(slug("Été à Paris"), "été-à-paris");
+        assert_eq!(slug("?!"), "");
+
+        let store = MemoryStore::default();
+        let tags = vec!["rust".to_string(), "a: b".to_string()];
+        add_note(
+            &store,
+            "First note\nbody".to_string(),
+            tags,
+            &AddOptions::default(),
+        )?;
+        add_note(&store, "#!".to_string(), vec![], &AddOptions::default())?;
+        let data = store.load()?;
+        let notes = export_selection(&data, &[]);
+
+        let (name, content) = render_markdown_file(notes[0]);
+        assert_eq!(name, "1-first-note.md");
+        let created = notes[0].created_at.to_rfc3339();
+        assert_eq!(
+            content,
+            format!(
+                "---\ntags: [\"rust\", \"a: b\"]\ncreated_at: {created}\nupdated_at: {created}\n---\nFirst note\nbody\n"
+            )
+        );
+        assert_eq!(render_markdown_file(notes[1]).0, "2.md");
+
+        let tmpdir = tempfile::tempdir()?;
+        let dir = tmpdir.path().join("vault");
+        assert_eq!(write_markdown_dir(&notes, &dir, false)?, 2);
+        fs::write(dir.join("2.md"), "mine")?;
+        assert!(write_markdown_dir(&notes, &dir, false).is_err());
+        assert_eq!(fs::read_to_string(dir.join("2.md"))?, "mine");
+        write_markdown_dir(&notes, &dir, true)?;
+        assert!(fs::read_to_string(dir.join("2.md"))?.ends_with("---\n#!\n"));
+
+        assert_eq!(
+            render_markdown(&notes),
+            "## Note 1\n\nFirst note\nbody\n\n## Note 2\n\n#!\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_porcelain_lines() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let tags = vec!["work".to_string(), "cli".to_string()];
+        add_note(
+            &store,
+            "first\nsecond\tline".to_string(),
+            tags,
+            &AddOptions::default(),
+        )?;
+        add_note(&store, "plain".to_string(), vec![], &AddOptions::default())?;
+        let mut data = store.load()?;
+        for note in &mut data.notes {
+            note.created_at = parse_date("2024-06-01T12:00:00Z")?;
+            note.updated_at = parse_date("2024-06-02T08:30:00Z")?;
+        }
+
+        let display = DisplayArgs {
+            format: OutputFormat::Porcelain,
+            ..DisplayArgs::default()
+        };
+        let sort = SortArgs {
+            reverse: true,
+            ..SortMethod::Content.into()
+        };
+        let notes = select_notes(&data, &FilterArgs::default(), &sort);
+        assert_eq!(
+            render_notes(&notes, &display),
+            "2\tplain\t\t2024-06-01T12:00:00+00:00\t2024-06-02T08:30:00+00:00\n\
+             1\tfirst\\nsecond\\tline\twork,cli\t2024-06-01T12:00:00+00:00\t2024-06-02T08:30:00+00:00\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_colors() -> NoteResult<()> {
+        assert!(color_enabled(ColorChoice::Always));
+        assert!(!color_enabled(ColorChoice::Never));
+
+        let store = MemoryStore::default();
+        add_note(
+            &store,
+            "colored".to_string(),
+            vec!["work".to_string()],
+            &AddOptions::default(),
+        )?;
+        let data = store.load()?;
+        let notes: Vec<&Note> = data.notes.iter().collect();
+        let now = notes[0].created_at + Duration::days(30);
+
+        let plain = render_table(&notes, &DisplayArgs::default(), now);
+        assert!(!plain.contains('\x1b'));
+        assert!(plain.contains("Content") && plain.contains("work"));
+
+        let display = DisplayArgs {
+            palette: Some(TagPalette::default()),
+            ..DisplayArgs::default()
+        };
+        let colored = render_table(&notes, &display, now);
+        assert!(colored.contains(&TagColor::Bold.paint("Content")));
+        assert!(colored.contains(&TagPalette::default().paint("work")));
+        let created = display.format_date(notes[0].created_at);
+        assert!(colored.contains(&TagColor::Dim.paint(&created)));
+        let recent = render_table(&notes, &display, notes[0].created_at);
+        assert!(!recent.contains(&TagColor::Dim.paint(&created)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_width() {
+        assert_eq!(truncate_width("short", 10, "…"), "short");
+        assert_eq!(truncate_width("exactly10!", 10, "…"), "exactly10!");
+        assert_eq!(truncate_width("a long sentence", 10, "…"), "a long se…");
+        assert_eq!(truncate_width("a long sentence", 10, "..."), "a long ...");
+        // accents, precomposed or combining, stay on their letter
+        assert_eq!(truncate_width("café crème brûlée", 10, "…"), "café crèm…");
+        assert_eq!(
+            truncate_width("cafe\u{301} cre\u{300}me brûlée", 10, "…"),
+            "cafe\u{301} cre\u{300}m…"
+        );
+        // emoji are two columns wide, a sequence is never split
+        assert_eq!(truncate_width("🎉🎉🎉🎉🎉🎉", 8, "…"), "🎉🎉🎉…");
+        assert_eq!(truncate_width("👩‍👩‍👧‍👦👩‍👩‍👧‍👦👩‍👩‍👧‍👦", 4, "…"), "👩‍👩‍👧‍👦…");
+        assert_eq!(truncate_width("🇫🇷🇫🇷🇫🇷", 5, "…"), "🇫🇷🇫🇷…");
+
+        let note = Note {
+            id: 1,
+            content: format!("{}\nshort", "x".repeat(80)),
+            tags: vec![],
+            created_at: Utc::now(),
+            created_offset: None,
+            title: None,
+            due: None,
+            done: false,
+            pinned: false,
+            deleted_at: None,
+            history: vec![],
+            updated_at: Utc::now(),
+            expires_at: None,
+            meta: BTreeMap::new(),
+            rating: None,
+            archived: false,
+        };
+        let cut = table_field(&note, Column::Content, &DisplayArgs::default());
+        assert_eq!(cut, format!("{}…\nshort", "x".repeat(59)));
+        let display = DisplayArgs {
+            full: true,
+            ..DisplayArgs::default()
+        };
+        assert_eq!(table_field(&note, Column::Content, &display), note.content);
+    }
+
+    #[test]
+    fn test_wrap_width() {
+        let paragraph = "The quick brown fox jumps over the lazy dog while the \
+                         supercalifragilisticexpialidocious cat watches";
+        let lines = wrap_width(paragraph, 20);
+        assert_eq!(
+            lines,
+            vec![
+                "The quick brown fox",
+                "jumps over the lazy",
+                "dog while the",
+                "supercalifragilistic",
+                "expialidocious cat",
+                "watches",
+            ]
+        );
+        assert!(lines.iter().all(|line| text_width(line) <= 20));
+        assert_eq!(wrap_width("", 20), vec![""]);
+        assert_eq!(wrap_width("é🎉é🎉é🎉", 4), vec!["é🎉é", "🎉é", "🎉"]);
+
+        let note = Note {
+            id: 1,
+            content: format!("{}\n\nend", paragraph),
+            tags: vec![],
+            created_at: Utc::now(),
+            created_offset: None,
+            title: None,
+            due: None,
+            done: false,
+            pinned: false,
+            deleted_at: None,
+            history: vec![],
+            updated_at: Utc::now(),
+            expires_at: None,
+            meta: BTreeMap::new(),
+            rating: None,
+            archived: false,
+        };
+        let display = DisplayArgs {
+            wrap: Some(20),
+            ..DisplayArgs::default()
+        };
+        assert_eq!(
+            table_field(&note, Column::Content, &display)
+                .lines()
+                .count(),
+            8
+        );
+    }
+
+    #[test]
+    fn test_table_dates_zone() -> NoteResult<()> {
+        let date = parse_date("2024-06-01T23:30:00Z")?;
+        assert_eq!(
+            format_date_in(date, &Utc, DEFAULT_DATE_FORMAT),
+            "01/06/2024 - 23:30"
+        );
+        let paris = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+        assert_eq!(
+            format_date_in(date, &paris, DEFAULT_DATE_FORMAT),
+            "02/06/2024 - 01:30"
+        );
+        let new_york = chrono::FixedOffset::west_opt(4 * 3600).unwrap();
+        assert_eq!(
+            format_date_in(date, &new_york, DEFAULT_DATE_FORMAT),
+            "01/06/2024 - 19:30"
+        );
+
+        let display = DisplayArgs {
+            utc: true,
+            ..DisplayArgs::default()
+        };
+        assert_eq!(display.format_date(date), "01/06/2024 - 23:30");
+        assert_eq!(
+            DisplayArgs::default().format_date(date),
+            format_date_in(date, &Local, DEFAULT_DATE_FORMAT)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_date_format() -> NoteResult<()> {
+        let command = Cli::command();
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == "date_format")
+            .unwrap();
+        let help = arg.get_help().unwrap().to_string();
+        assert!(help.contains(&format!("[default: \"{}\"]", DEFAULT_DATE_FORMAT)));
+        assert!(parse_date_format("%Y-%m-%dT%H:%M:%S%:z").is_ok());
+        assert!(parse_date_format("%Y-%Q").is_err());
+        assert!(parse_date_format("%").is_err());
+
+        let store = MemoryStore::default();
+        add_note(&store, "dated".to_string(), vec![], &AddOptions::default())?;
+        let mut data = store.load()?;
+        data.notes[0].created_at = parse_date("2024-06-01T23:30:00Z")?;
+        let note = &data.notes[0];
+
+        let iso = DisplayArgs {
+            utc: true,
+            date_format: "%Y-%m-%dT%H:%M:%S%:z".to_string(),
+            ..DisplayArgs::default()
+        };
+        assert_eq!(
+            table_field(note, Column::Created, &iso),
+            "2024-06-01T23:30:00+00:00"
+        );
+        let day = DisplayArgs {
+            date_format: "%Y-%m-%d".to_string(),
+            ..iso
+        };
+        assert_eq!(table_field(note, Column::Created, &day), "2024-06-01");
+        let table = render_table(&[note], &day, Utc::now());
+        assert!(table.contains("| 2024-06-01 |"), "{}", table);
+        Ok(())
+    }
+
+    #[test]
+    fn test_humanize_duration() {
+        assert_eq!(humanize_duration(Duration::zero()), "just now");
+        assert_eq!(humanize_duration(Duration::seconds(59)), "just now");
+        assert_eq!(humanize_duration(Duration::seconds(60)), "1 minute ago");
+        assert_eq!(humanize_duration(Duration::minutes(35)), "35 minutes ago");
+        assert_eq!(humanize_duration(Duration::minutes(59)), "59 minutes ago");
+        assert_eq!(humanize_duration(Duration::minutes(60)), "1 hour ago");
+        assert_eq!(humanize_duration(Duration::hours(23)), "23 hours ago");
+        assert_eq!(humanize_duration(Duration::hours(24)), "1 day ago");
+        assert_eq!(humanize_duration(Duration::days(3)), "3 days ago");
+        assert_eq!(humanize_duration(Duration::days(29)), "29 days ago");
+        assert_eq!(humanize_duration(Duration::days(60)), "2 months ago");
+        assert_eq!(humanize_duration(Duration::days(364)), "12 months ago");
+        assert_eq!(humanize_duration(Duration::days(800)), "2 years ago");
+        assert_eq!(humanize_duration(Duration::seconds(-30)), "just now");
+        assert_eq!(humanize_duration(Duration::days(-3)), "in 3 days");
+        assert_eq!(humanize_duration(Duration::hours(-1)), "in 1 hour");
+
+        let display = DisplayArgs {
+            relative: true,
+            ..DisplayArgs::default()
+        };
+        let date = Utc::now() - Duration::days(3) - Duration::minutes(1);
+        assert_eq!(display.format_date(date), "3 days ago");
+    }
+
+    #[test]
+    fn test_backup_restore() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path = path.to_str().unwrap();
+        let store = JsonFileStore {
+            path: path.to_string(),
+        };
+        add_note(
+            &store,
+            "keep me".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        let original = fs::read_to_string(path)?;
+
+        let now = Utc::now() + Duration::seconds(5);
+        let backup = create_backup(&store, path, now)?;
+        assert!(backup.name().starts_with("notes.json.bak."));
+        assert!(backup.name().ends_with("Z.json"));
+        assert!(create_backup(&store, path, now).is_err());
+        assert_eq!(list_backups(path)?, vec![backup.clone()]);
+
+        remove_note(&store, 1)?;
+        add_note(&store, "oops".to_string(), vec![], &AddOptions::default())?;
+        assert_eq!(restore_backup(&store, path, &backup.name(), false)?, 1);
+        assert_eq!(fs::read_to_string(path)?, original);
+
+        // the live file is newer than a backup stamped in the past
+        let old = create_backup(&store, path, Utc::now() - Duration::hours(1))?;
+        add_note(&store, "newer".to_string(), vec![], &AddOptions::default())?;
+        let err = restore_backup(&store, path, &old.name(), false).unwrap_err();
+        assert!(err.to_string().contains("--force"), "{}", err);
+        restore_backup(&store, path, &old.path.to_string_lossy(), true)?;
+        assert_eq!(store.load()?.notes.len(), 1);
+
+        fs::write(&old.path, "{ not json")?;
+        assert!(restore_backup(&store, path, &old.name(), true).is_err());
+        assert_eq!(store.load()?.notes.len(), 1);
+        assert!(restore_backup(&store, path, "nope", true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_notes_file() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let other_path = tmpdir.path().join("other.json");
+        let other = JsonFileStore {
+            path: other_path.to_string_lossy().into_owned(),
+        };
+        let dated = AddOptions {
+            meta: BTreeMap::from([("source".to_string(), "laptop".to_string())]),
+            ..AddOptions::default()
+        };
+        add_note(&other, "shared".to_string(), vec![], &AddOptions::default())?;
+        add_note(
+            &other,
+            "from laptop".to_string(),
+            vec!["x".to_string()],
+            &dated,
+        )?;
+        let mut data = other.load()?;
+        data.notes[1].id = 7;
+        data.notes[1].created_at = parse_date("2024-01-01T10:00:00Z")?;
+        other.save(&data)?;
+
+        let store = MemoryStore::default();
+        add_note(&store, "shared".to_string(), vec![], &AddOptions::default())?;
+        add_note(
+            &store,
+            "desktop".to_string(),
+            vec![],
+            &AddOptions::default(),
+        )?;
+        add_note(&store, "gone".to_string(), vec![], &AddOptions::default())?;
+        remove_note(&store, 3)?;
+
+        let options = ImportOptions {
+            format: None,
+            map: FieldMap::default(),
+            tag_separator: ",".to_string(),
+            preview: false,
+            skip_duplicates: false,
+        };
+        let summary = import_notes(&store, &other_path, &options)?;
+        assert_eq!(
+            (summary.notes.len(), summary.renumbered, summary.skipped),
+            (2, 1, 0)
+        );
+        // ID 1 is taken, so the note gets the free ID 3; ID 7 is kept
+        let data = store.load()?;
+        let ids: Vec<u32> = data.notes.iter().map(|note| note.id).collect();
+        assert_eq!(ids, vec![1, 2, 7, 3]);
+        let laptop = store.get(7)?.unwrap();
+        assert_eq!(laptop.created_at, parse_date("2024-01-01T10:00:00Z")?);
+        assert_eq!(laptop.meta["source"], "laptop");
+        assert_eq!(allocate_id(&mut store.load()?), 8);
+
+        let options = ImportOptions {
+            skip_duplicates: true,
+            ..options
+        };
+        let summary = import_notes(&store, &other_path, &options)?;
+        assert_eq!((summary.notes.len(), summary.skipped), (0, 2));
+
+        let empty_path = tmpdir.path().join("empty.json");
+        fs::write(&empty_path, "")?;
+        let summary = import_notes(&store, &empty_path, &options)?;
+        assert_eq!((summary.notes.len(), summary.skipped), (0, 0));
+        assert_eq!(store.load()?.notes.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_files() -> NoteResult<()> {
+        assert_eq!(
+            parse_text_file("---\ntitle: x\ntags: [\"rust\", cli]\n---\n\nBody\n"),
+            (
+                "Body".to_string(),
+                vec!["rust".to_string(), "cli".to_string()]
+            )
+        );
+        assert_eq!(
+            parse_text_file("---\ntags: a, b\n---\ntext"),
+            ("text".to_string(), vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            parse_text_file("tags: a\nplain"),
+            ("tags: a\nplain".to_string(), vec![])
+        );
+        assert_eq!(parse_text_file("---\ntags: a\n").1, Vec::<String>::new());
+
+        let tmpdir = tempfile::tempdir()?;
+        let dir = tmpdir.path();
+        fs::write(dir.join("a.md"), "---\ntags: work\n---\nMeeting notes\n")?;
+        fs::write(dir.join("b.txt"), "groceries")?;
+        fs::write(dir.join("c.txt"), [0xff, 0xfe, b'x'])?;
+        fs::write(dir.join("d.md"), "  \n")?;
+        fs::write(dir.join("e.json"), "{}")?;
+        fs::create_dir(dir.join("sub"))?;
+        fs::write(dir.join("sub/f.markdown"), "nested")?;
+
+        let store = MemoryStore::default();
+        let summary = import_files(&store, dir, false)?;
+        assert_eq!((summary.notes.len(), summary.skipped), (2, 2));
+        let meeting = store.get(1)?.unwrap();
+        assert_eq!(meeting.content, "Meeting notes");
+        assert_eq!(meeting.tags, vec!["work"]);
+        let mtime = fs::metadata(dir.join("a.md"))?.modified()?;
+        assert_eq!(meeting.created_at, DateTime::<Utc>::from(mtime));
+        assert_eq!(store.get(2)?.unwrap().content, "groceries");
+
+        let store = MemoryStore::default();
+        let summary = import_files(&store, dir, true)?;
+        assert_eq!(summary.notes.len(), 3);
+        assert_eq!(store.get(3)?.unwrap().content, "nested");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_content() -> NoteResult<()> {
+        use std::io::Cursor;
+
+        let log = "commit 1a2b\nAuthor: me\n\n    Fix the thing\n\n";
+        let content = read_content(Cursor::new(log))?;
+        assert_eq!(content, "commit 1a2b\nAuthor: me\n\n    Fix the thing\n");
+        assert_eq!(read_content(Cursor::new("one line\r\n"))?, "one line");
+        assert!(read_content(Cursor::new("")).is_err());
+        assert!(read_content(Cursor::new(" \n\n")).is_err());
+
+        let store = MemoryStore::default();
+        let tags = vec!["git".to_string()];
+        add_note(&store, content.clone(), tags, &AddOptions::default())?;
+        let note = store.get(1)?.unwrap();
+        assert_eq!(note.content, content);
+        assert_eq!(note.tags, vec!["git"]);
+
+        let cli = Cli::try_parse_from(["note-cli", "add", "-", "--tag", "git"])?;
+        assert!(matches!(
+            cli.command,
+            Commands::Add { content: Some(ref c), ref tags, .. } if c == "-" && tags == &["git"]
+        ));
+        assert!(Cli::try_parse_from(["note-cli", "add", "--stdin"]).is_ok());
+        assert!(Cli::try_parse_from(["note-cli", "add", "x", "--stdin"]).is_err());
+        assert!(Cli::try_parse_from(["note-cli", "add"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_from_file() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("snippet.txt");
+        let long = "lorem ipsum ".repeat(500);
+        fs::write(&path, format!("{}\nsecond line\n", long))?;
+
+        let cli = Cli::try_parse_from(["note-cli", "add", "--from-file", path.to_str().unwrap()])?;
+        let Commands::Add {
+            content,
+            stdin,
+            from_file,
+            ..
+        } = cli.command
+        else {
+            panic!("not an add command");
+        };
+        let content = add_content(content, stdin, from_file.as_deref())?;
+        assert_eq!(content, format!("{}\nsecond line", long));
+
+        let store = MemoryStore::default();
+        add_note(&store, content, vec![], &AddOptions::default())?;
+        let note = store.get(1)?.unwrap();
+        let cut = table_field(&note, Column::Content, &DisplayArgs::default());
+        assert!(
+            cut.lines()
+                .all(|line| text_width(line) <= DEFAULT_CONTENT_WIDTH)
+        );
+
+        let conflict = Cli::try_parse_from(["note-cli", "add", "text", "--from-file", "x.txt"]);
+        assert!(conflict.is_err());
+        assert_eq!(add_content(Some("text".to_string()), false, None)?, "text");
+
+        let missing = tmpdir.path().join("missing.txt");
+        let err = add_content(None, false, Some(&missing))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("missing.txt"), "{}", err);
+        fs::write(&path, [0xff, 0xfe])?;
+        let err = add_content(None, false, Some(&path))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("snippet.txt"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_with_editor() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        add_note(&store, "draft".to_string(), vec![], &AddOptions::default())?;
+        let mut data = store.load()?;
+        data.notes[0].updated_at = parse_date("2024-06-01")?;
+        store.save(&data)?;
+        let unchanged = store.get(1)?.unwrap();
+
+        let mut temp = PathBuf::new();
+        edit_with_editor(&store, 1, DEFAULT_HISTORY_LIMIT, |path| {
+            temp = path.to_path_buf();
+            assert_eq!(fs::read_to_string(path)?, "draft\n");
+            Ok(true)
+        })?;
+        assert_eq!(store.get(1)?.unwrap(), unchanged);
+        assert!(!temp.exists());
+
+        edit_with_editor(&store, 1, DEFAULT_HISTORY_LIMIT, |path| {
+            fs::write(path, "rewritten")?;
+            Ok(false)
+        })?;
+        assert_eq!(store.get(1)?.unwrap(), unchanged);
+
+        edit_with_editor(&store, 1, DEFAULT_HISTORY_LIMIT, |path| {
+            fs::write(path, "final\ntext\n")?;
+            Ok(true)
+        })?;
+        let note = store.get(1)?.unwrap();
+        assert_eq!(note.content, "final\ntext");
+        assert!(note.updated_at > unchanged.updated_at);
+
+        let err = edit_with_editor(&store, 1, DEFAULT_HISTORY_LIMIT, |_| {
+            Err("cannot run the editor".into())
+        });
+        assert!(err.is_err());
+        assert!(edit_with_editor(&store, 9, DEFAULT_HISTORY_LIMIT, |_| Ok(true)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_note() -> NoteResult<()> {
+        for_each_store(|store| {
+            add_note(
+                store,
+                "groceries".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+            let mut data = store.load()?;
+            data.notes[0].created_at = parse_date("2024-06-01")?;
+            data.notes[0].updated_at = data.notes[0].created_at;
+            store.save(&data)?;
+
+            append_note(store, 1, "- milk", true, DEFAULT_HISTORY_LIMIT)?;
+            append_note(store, 1, " (2L)", false, DEFAULT_HISTORY_LIMIT)?;
+            let note = store.get(1)?.unwrap();
+            assert_eq!(note.content, "groceries\n- milk (2L)");
+            assert_eq!(note.created_at, parse_date("2024-06-01")?);
+            assert!(note.updated_at > note.created_at);
+
+            let err = append_note(store, 9, "x", true, DEFAULT_HISTORY_LIMIT).unwrap_err();
+            assert_eq!(err.to_string(), "ID 9 not found");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_note_title() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path = path.to_str().unwrap();
+        // written before notes had a title
+        let fixture = r#"{"notes":[{"id":1,"content":"old note","tags":[],
+            "created_at":"2024-01-01T10:00:00Z","updated_at":"2024-01-01T10:00:00Z"}],
+            "free_ids":[]}"#;
+        fs::write(path, fixture)?;
+        let store = JsonFileStore {
+            path: path.to_string(),
+        };
+        assert_eq!(store.get(1)?.unwrap().title, None);
+
+        let options = AddOptions {
+            title: Some("Shopping".to_string()),
+            ..AddOptions::default()
+        };
+        add_note(&store, "milk".to_string(), vec![], &options)?;
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        assert!(saved["notes"][0].get("title").is_none());
+        assert_eq!(saved["notes"][1]["title"], "Shopping");
+
+        let data = store.load()?;
+        let sorted = select_notes(&data, &FilterArgs::default(), &SortMethod::Title.into());
+        assert_eq!(sorted.iter().map(|n| n.id).collect::<Vec<_>>(), vec![2, 1]);
+        let matcher = Matcher::new("shop", false)?;
+        assert!(note_score(&matcher, &data.notes[1], SearchScope::Content).is_some());
+        assert!(note_score(&matcher, &data.notes[0], SearchScope::All).is_none());
+
+        let table = render_table(&sorted, &DisplayArgs::default(), Utc::now());
+        assert!(table.contains("| Title"), "{}", table);
+        let untitled = render_table(&sorted[1..], &DisplayArgs::default(), Utc::now());
+        assert!(!untitled.contains("Title"), "{}", untitled);
+
+        let changes = NoteChanges {
+            title: Some(String::new()),
+            ..NoteChanges::default()
+        };
+        update_note(&store, 2, &changes)?;
+        assert_eq!(store.get(2)?.unwrap().title, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_due_dates() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        let now = Utc::now();
+        let due = |due: DateTime<Utc>| AddOptions {
+            due: Some(due),
+            ..AddOptions::default()
+        };
+        add_note(
+            &store,
+            "later".to_string(),
+            vec![],
+            &due(now + Duration::days(3)),
+        )?;
+        add_note(&store, "none".to_string(), vec![], &AddOptions::default())?;
+        add_note(
+            &store,
+            "late".to_string(),
+            vec![],
+            &due(now - Duration::days(1)),
+        )?;
+        // a due date kept in the metadata by older imports still counts
+        let imported = AddOptions {
+            meta: BTreeMap::from([("due".to_string(), now.to_rfc3339())]),
+            ..AddOptions::default()
+        };
+        add_note(&store, "imported".to_string(), vec![], &imported)?;
+        let data = store.load()?;
+
+        let ids = |filter: &FilterArgs, sort: SortMethod| -> Vec<u32> {
+            select_notes(&data, filter, &sort.into())
+                .iter()
+                .map(|n| n.id)
+                .collect()
+        };
+        assert_eq!(
+            ids(&FilterArgs::default(), SortMethod::Due),
+            vec![3, 4, 1, 2]
+        );
+        let overdue = FilterArgs {
+            overdue: true,
+            ..FilterArgs::default()
+        };
+        assert_eq!(ids(&overdue, SortMethod::Id), vec![3, 4]);
+        let before = FilterArgs {
+            due_before: Some(now + Duration::days(5)),
+            ..FilterArgs::default()
+        };
+        assert_eq!(ids(&before, SortMethod::Id), vec![1, 3, 4]);
+
+        let notes = select_notes(&data, &overdue, &SortMethod::Id.into());
+        let table = render_table(&notes, &DisplayArgs::default(), now + Duration::hours(1));
+        assert!(table.contains("| Due"), "{}", table);
+        assert!(table.contains("| ! "), "{}", table);
+
+        // notes written before due dates existed load without one
+        let old: Note = serde_json::from_str(
+            r#"{"id":1,"content":"a","tags":[],"created_at":"2024-01-01T00:00:00Z",
+                "updated_at":"2024-01-01T00:00:00Z"}"#,
+        )?;
+        assert_eq!(old.due, None);
+        assert!(parse_date("next week").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_done_status() -> NoteResult<()> {
+        for_each_store(|store| {
+            for content in ["call the bank", "buy milk"] {
+                add_note(store, content.to_string(), vec![], &AddOptions::default())?;
+            }
+            let mut data = store.load()?;
+            data.notes[0].updated_at = parse_date("2024-06-01")?;
+            store.save(&data)?;
+
+            assert!(set_done(store, 1, true)?);
+            assert!(!set_done(store, 1, true)?);
+            let note = store.get(1)?.unwrap();
+            assert!(note.done);
+            assert!(note.updated_at > parse_date("2024-06-01")?);
+            assert!(set_done(store, 9, true).is_err());
+
+            let data = store.load()?;
+            let ids = |filter: FilterArgs| -> Vec<u32> {
+                select_notes(&data, &filter, &SortMethod::Id.into())
+                    .iter()
+                    .map(|n| n.id)
+                    .collect()
+            };
+            assert_eq!(ids(FilterArgs::default()), vec![1, 2]);
+            let done = FilterArgs {
+                done: true,
+                ..FilterArgs::default()
+            };
+            assert_eq!(ids(done), vec![1]);
+            let pending = FilterArgs {
+                pending: true,
+                ..FilterArgs::default()
+            };
+            assert_eq!(ids(pending), vec![2]);
+
+            assert!(set_done(store, 1, false)?);
+            assert!(!store.get(1)?.unwrap().done);
+            Ok(())
+        })?;
+
+        // files written before the status existed load as all pending
+        let data: NoteData = serde_json::from_str(
+            r#"{"notes":[{"id":1,"content":"a","tags":[],"created_at":"2024-01-01T00:00:00Z",
+                "updated_at":"2024-01-01T00:00:00Z"}],"free_ids":[]}"#,
+        )?;
+        assert!(!data.notes[0].done);
+        let notes: Vec<&Note> = data.notes.iter().collect();
+        assert!(!render_table(&notes, &DisplayArgs::default(), Utc::now()).contains("Status"));
+        assert!(!serde_json::to_string(&data)?.contains("done"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_first() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        for (content, rating) in [("c", 1), ("a", 5), ("d", 3), ("b", 2)] {
+            let options = AddOptions {
+                rating: Some(rating),
+                ..AddOptions::default()
+            };
+            add_note(&store, content.to_string(), vec![], &options)?;
         }
+        assert!(set_pinned(&store, 3, true)?);
+        assert!(set_pinned(&store, 1, true)?);
+        assert!(!set_pinned(&store, 1, true)?);
+        let data = store.load()?;
 
-        Err(e) if e.kind() == ErrorKind::NotFound => Ok(NoteData {
-            notes: vec![],
-            free_ids: vec![],
-        }),
+        for method in SortMethod::value_variants() {
+            for reverse in [false, true] {
+                let sort = SortArgs {
+                    methods: vec![*method],
+                    reverse,
+                };
+                let mut notes = select_notes(&data, &FilterArgs::default(), &sort);
+                let sorted: Vec<u32> = notes.iter().map(|n| n.id).collect();
+                pinned_first(&mut notes);
+                let ids: Vec<u32> = notes.iter().map(|n| n.id).collect();
+                let (pinned, others): (Vec<u32>, Vec<u32>) =
+                    sorted.iter().partition(|id| [1, 3].contains(*id));
+                assert_eq!(ids, [pinned, others].concat(), "{:?}", method);
+            }
+        }
 
-        Err(e) => Err(Box::new(e)),
+        let notes: Vec<&Note> = data.notes.iter().collect();
+        let display = DisplayArgs {
+            no_emoji: true,
+            ..DisplayArgs::default()
+        };
+        assert!(render_table(&notes, &display, Utc::now()).contains("| * 1 "));
+        assert!(set_pinned(&store, 1, false)?);
+        assert!(!set_pinned(&store, 1, false)?);
+        Ok(())
     }
-}
 
-/// Saves note data to a JSON file with pretty formatting.
-///
-/// # Parameters
-/// - `path: &str` - File path to write to
-/// - `notes: &NoteData` - Note data to serialize
-///
-/// # Returns
-/// `NoteResult<()>` - Success or serialization/I/O error
-fn save_notes(path: &str, notes: &NoteData) -> NoteResult<()> {
-    // serialize to a json
-    let data = serde_json::to_string_pretty(notes)?;
+    #[test]
+    fn test_archive_one_note() -> NoteResult<()> {
+        for_each_store(|store| {
+            for content in ["old plan", "new plan", "notes"] {
+                add_note(store, content.to_string(), vec![], &AddOptions::default())?;
+            }
+            let before = store.get(1)?.unwrap().updated_at;
+            assert!(set_archived(store, 1, true)?);
+            assert!(!set_archived(store, 1, true)?);
+            assert!(store.get(1)?.unwrap().updated_at > before);
 
-    //write on filesystem
-    fs::write(path, data)?;
-    Ok(())
-}
+            let data = store.load()?;
+            let ids = |filter: FilterArgs| -> Vec<u32> {
+                select_notes(&data, &filter, &SortMethod::Id.into())
+                    .iter()
+                    .map(|n| n.id)
+                    .collect()
+            };
+            assert_eq!(ids(FilterArgs::default()), vec![2, 3]);
+            let all = FilterArgs {
+                with_archived: true,
+                ..FilterArgs::default()
+            };
+            assert_eq!(ids(all.clone()), vec![1, 2, 3]);
+            let archived = FilterArgs {
+                archived: true,
+                ..FilterArgs::default()
+            };
+            assert_eq!(ids(archived), vec![1]);
 
-/// Adds a new note with auto-assigned ID (reuses free IDs when available).
-///
-/// # Parameters
-/// - `path: &str` - File path where notes are stored
-/// - `content: String` - Text content for the new note
-///
-/// # Returns
-/// `NoteResult<()>` - Success or error during load/save operations
-fn add_note(path: &str, content: String, tags: Vec<String>) -> NoteResult<()> {
-    let mut data = load_notes(path)?;
+            let keywords = ["plan".to_string()];
+            let search = |filter: &FilterArgs| {
+                search_note(
+                    store,
+                    &keywords,
+                    &MatchArgs::default(),
+                    SearchScope::All,
+                    &SortMethod::Id.into(),
+                    filter,
+                    &DisplayArgs::default(),
+                )
+            };
+            assert_eq!(search(&FilterArgs::default())?, 1);
+            assert_eq!(search(&all)?, 2);
 
-    // determine id
-    let mut new_id: u32 = match data.notes.last() {
-        Some(note) => note.id + 1,
-        None => 1,
-    }; // last known id
+            assert!(set_archived(store, 1, false)?);
+            assert_eq!(search(&FilterArgs::default())?, 2);
 
-    // if free id not empty take one from it
-    if !data.free_ids.is_empty() {
-        new_id = data.free_ids.remove(0);
+            set_archived(store, 3, true)?;
+            remove_note(store, 3)?;
+            assert!(store.get(3)?.is_none());
+            Ok(())
+        })
     }
 
-    // create new note
-    let new_note = Note {
-        id: new_id,
-        content: content,
-        created_at: Utc::now(),
-        updated_at: Utc::now(),
-        tags: tags,
-    };
+    #[test]
+    fn test_trash_restore() -> NoteResult<()> {
+        for_each_store(|store| {
+            let err = restore_note(store, 1).unwrap_err();
+            assert_eq!(err.to_string(), "the trash is empty");
 
-    // push new note into data
-    data.notes.push(new_note);
-    save_notes(path, &data)?;
-    Ok(())
-}
+            for content in ["keep", "oops"] {
+                add_note(store, content.to_string(), vec![], &AddOptions::default())?;
+            }
+            trash_note(store, 2)?;
+            let data = store.load()?;
+            assert_eq!(data.notes.len(), 1);
+            assert_eq!(data.trash[0].content, "oops");
+            assert!(restore_note(store, 1).is_err());
 
-/// Removes a note by ID and adds the ID to the free list for reuse.
-///
-/// # Parameters
-/// - `path: &str` - File path where notes are stored
-/// - `id: u32` - ID of the note to remove
-///
-/// # Returns
-/// `NoteResult<()>` - Success or error if ID not found or I/O fails
-fn remove_note(path: &str, id: u32) -> NoteResult<()> {
-    // load data
-    let mut data = load_notes(path)?;
+            assert_eq!(restore_note(store, 2)?, 2);
+            let data = store.load()?;
+            assert!(data.trash.is_empty());
+            assert!(data.free_ids.is_empty());
+            assert_eq!(store.get(2)?.unwrap().content, "oops");
 
-    // get index of note to remove
-    let index = data
-        .notes
-        .iter()
-        .position(|note| note.id == id)
-        .ok_or_else(|| format!("ID {} not found", id))?;
-
-    // remove note
-    data.notes.swap_remove(index);
-    // push its id into free_ids
-    data.free_ids.push(id);
-    save_notes(path, &data)?;
-    Ok(())
-}
+            // the ID was given to a new note in the meantime
+            trash_note(store, 2)?;
+            add_note(store, "new".to_string(), vec![], &AddOptions::default())?;
+            assert_eq!(restore_note(store, 2)?, 3);
+            assert_eq!(store.get(2)?.unwrap().content, "new");
+            assert_eq!(store.get(3)?.unwrap().content, "oops");
 
-/// Displays all notes in a formatted table or shows empty message.
-///
-/// # Parameters
-/// - `path: &str` - File path where notes are stored
-///
-/// # Returns
-/// `NoteResult<()>` - Success or error during load operation
-fn list_note(path: &str, method: SortMethod) -> NoteResult<()> {
-    let mut data = load_notes(path)?;
+            remove_note(store, 3)?;
+            assert!(store.load()?.trash.is_empty());
+            Ok(())
+        })
+    }
 
-    if data.notes.is_empty() {
-        println!("📭 No notes saved.");
-    } else {
-        // Sort notes by ID
-        match method {
-            SortMethod::Id => data.notes.sort_by_key(|note| note.id),
-            SortMethod::Date => data.notes.sort_by_key(|note| note.created_at),
-            SortMethod::Update => data.notes.sort_by_key(|note| note.updated_at),
-            SortMethod::Content => data.notes.sort_by_key(|note| note.content.clone()),
-            _ => (),
-        }
+    #[test]
+    fn test_purge_trash() -> NoteResult<()> {
+        assert_eq!(parse_duration("30d")?, Duration::days(30));
+        assert_eq!(parse_duration("12h")?, Duration::hours(12));
 
-        let mut table = Table::new();
-        table.add_row(row!["ID", "Content", "Tags", "Created at", "Update at"]);
+        for_each_store(|store| {
+            for content in ["a", "b", "c"] {
+                add_note(store, content.to_string(), vec![], &AddOptions::default())?;
+            }
+            for id in [1, 2, 3] {
+                trash_note(store, id)?;
+            }
+            let now = Utc::now();
+            let mut data = store.load()?;
+            data.trash[0].deleted_at = Some(now - Duration::days(40));
+            data.trash[1].deleted_at = Some(now - Duration::days(10));
+            // the ID of an old purged note was lost from the free list
+            data.free_ids.retain(|id| *id != 1);
+            store.save(&data)?;
 
-        for note in data.notes {
-            let tag_str = if note.tags.is_empty() {
-                "-".to_string()
-            } else {
-                note.tags.join(", ")
-            };
-            table.add_row(row![
-                note.id,
-                note.content,
-                tag_str,
-                note.created_at.format("%d/%m/%Y - %H:%M").to_string(),
-                note.updated_at.format("%d/%m/%Y - %H:%M").to_string()
-            ]);
-        }
+            let month = Some(Duration::days(30));
+            assert_eq!(purge_trash(store, month, now, true)?, vec![1]);
+            assert_eq!(store.load()?.trash.len(), 3);
+            assert_eq!(purge_trash(store, month, now, false)?, vec![1]);
+            let data = store.load()?;
+            assert_eq!(data.trash.len(), 2);
+            let mut free = data.free_ids.clone();
+            free.sort();
+            assert_eq!(free, vec![1, 2, 3]);
 
-        table.printstd();
+            assert_eq!(purge_trash(store, None, now, false)?, vec![2, 3]);
+            let data = store.load()?;
+            assert!(data.trash.is_empty());
+            assert_eq!(data.free_ids.len(), 3);
+            assert!(purge_trash(store, None, now, false)?.is_empty());
+            Ok(())
+        })
     }
-    Ok(())
-}
 
-/// Add a tag to a note
-///
-/// # Parameters
-/// - `path: &str` - File path where notes are stored
-/// - `id: u32` - Id of the note we want to add a tag
-/// - `tag: String` - Tag to add to the note specified by id
-///
-/// # Returns
-/// `NoteResult<()>` - Success or error during load operation
-fn add_tag(path: &str, id: u32, tags: Vec<String>) -> NoteResult<()> {
-    let mut data = load_notes(path)?;
+    #[test]
+    fn test_undo() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let store = UndoStore::new(path.to_str().unwrap(), 0);
+        assert_eq!(store.undo_path, tmpdir.path().join("notes.json.undo"));
+        let err = undo_last(store.inner.as_ref(), &store.undo_path).unwrap_err();
+        assert!(err.to_string().starts_with("nothing to undo"), "{}", err);
 
-    if tags.is_empty() {
-        println!("No tags given.");
-    } else if let Some(note) = data.notes.iter_mut().find(|n| n.id == id) {
-        // add tags
-        for tag in tags {
-            if !note.tags.contains(&tag) {
-                note.tags.push(tag);
-            }
+        for content in ["a", "b"] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
         }
-        save_notes(path, &data)?;
-    } else {
-        println!("Note {} not found", id);
+        // each run of the program starts with a fresh store
+        let store = UndoStore::new(path.to_str().unwrap(), 0);
+        remove_note(&store, 1)?;
+        assert!(store.get(1)?.is_none());
+
+        undo_last(store.inner.as_ref(), &store.undo_path)?;
+        assert_eq!(store.get(1)?.unwrap().content, "a");
+        undo_last(store.inner.as_ref(), &store.undo_path)?;
+        assert!(store.get(1)?.is_none());
+        assert_eq!(store.load()?.notes.len(), 1);
+        Ok(())
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_note_history() -> NoteResult<()> {
+        let store = MemoryStore::default();
+        add_note(&store, "v1".to_string(), vec![], &AddOptions::default())?;
+        edit_note(&store, 1, "v2".to_string(), 3)?;
+        append_note(&store, 1, "more", true, 3)?;
+        // a change leaving the content alone adds no revision
+        let changes = NoteChanges {
+            rating: Some(3),
+            history_limit: 3,
+            ..NoteChanges::default()
+        };
+        update_note(&store, 1, &changes)?;
+        let note = store.get(1)?.unwrap();
+        let contents: Vec<&str> = note.history.iter().map(|r| r.content.as_str()).collect();
+        assert_eq!(contents, vec!["v1", "v2"]);
+        assert_eq!(note.content, "v2\nmore");
 
-/// Edit a note
-///
-/// # Parameters
-/// - `path: &str` - File path where notes are stored
-/// - `id: u32` - Id of the note to edit
-/// - `content: String` - New content
-///
-/// # Returns
-/// `NoteResult<()>` - Success or error during load operation
-fn edit_note(path: &str, id: u32, content: String) -> NoteResult<()> {
-    let mut data = load_notes(path)?;
+        edit_note(&store, 1, "v4".to_string(), 3)?;
+        edit_note(&store, 1, "v5".to_string(), 3)?;
+        let note = store.get(1)?.unwrap();
+        let contents: Vec<&str> = note.history.iter().map(|r| r.content.as_str()).collect();
+        assert_eq!(contents, vec!["v2", "v2\nmore", "v4"]);
 
-    if content.is_empty() {
-        println!("No content given.");
-    } else if let Some(note) = data.notes.iter_mut().find(|n| n.id == id) {
-        note.content = content;
-        note.updated_at = Utc::now();
-        save_notes(path, &data)?;
-    } else {
-        println!("Note {} not found", id);
-    }
+        revert_note(&store, 1, 1, 3)?;
+        let note = store.get(1)?.unwrap();
+        assert_eq!(note.content, "v2");
+        assert_eq!(note.history.last().unwrap().content, "v5");
+        assert_eq!(note.history.len(), 3);
+        let err = revert_note(&store, 1, 4, 3).unwrap_err();
+        assert_eq!(err.to_string(), "note 1 has no revision 4");
+        assert!(revert_note(&store, 1, 0, 3).is_err());
 
-    Ok(())
-}
+        let table = render_history(&note, &DisplayArgs::default());
+        assert!(table.contains("| 3 "), "{}", table);
+        assert!(table.contains("v5"), "{}", table);
 
-/// Search field in all notes
-///
-/// # Parameters
-/// - `path: &str` - File path where notes are stored
-/// - `content: String` - Field to search
-///
-/// # Returns
-/// `NoteResult<()>` - Success or error during load operation
-fn search_note(path: &str, keyword: String, method: SortMethod) -> NoteResult<()> {
-    let mut data = load_notes(path)?;
+        edit_note(&store, 1, "v6".to_string(), 0)?;
+        assert!(store.get(1)?.unwrap().history.is_empty());
+        Ok(())
+    }
 
-    if keyword.is_empty() {
-        print!("No keyword given");
-    } else {
-        let mut results: Vec<&Note> = data
-            .notes
-            .iter()
-            .filter(|n| n.content.to_lowercase().contains(&keyword.to_lowercase()))
-            .collect();
+    #[test]
+    fn test_remove_notes() -> NoteResult<()> {
+        for_each_store(|store| {
+            for content in ["a", "b", "c", "d", "e"] {
+                add_note(store, content.to_string(), vec![], &AddOptions::default())?;
+            }
+            assert!(remove_notes(store, &[1, 2], true)?.is_empty());
+            assert_eq!(remove_notes(store, &[3, 9, 4, 8], false)?, vec![9, 8]);
+            let data = store.load()?;
+            assert_eq!(data.notes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![5]);
+            assert_eq!(data.trash.len(), 2);
+            assert_eq!(data.free_ids, vec![1, 2, 3, 4]);
+
+            // a repeated ID is removed once and not reported missing
+            assert!(remove_notes(store, &[5, 5], true)?.is_empty());
+            let data = store.load()?;
+            assert!(data.notes.is_empty());
+            assert_eq!(data.free_ids, vec![1, 2, 3, 4, 5]);
+            assert_eq!(remove_notes(store, &[6], true)?, vec![6]);
+            Ok(())
+        })
+    }
 
-        // Sort notes by ID
-        match method {
-            SortMethod::Id => results.sort_by_key(|note| note.id),
-            SortMethod::Date => results.sort_by_key(|note| note.created_at),
-            SortMethod::Update => results.sort_by_key(|note| note.updated_at),
-            SortMethod::Content => results.sort_by_key(|note| note.content.clone()),
-            _ => (),
+    #[test]
+    fn test_clean_notes() -> NoteResult<()> {
+        let tmpfile = NamedTempFile::new()?;
+        let path = tmpfile.path().to_str().unwrap();
+        let store = JsonFileStore {
+            path: path.to_string(),
+        };
+        for content in ["a", "b"] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
         }
+        let mut output = Vec::new();
 
-        let mut table = Table::new();
-        table.add_row(row!["ID", "Content", "Tags", "Created at", "Update at"]);
-        for note in results {
-            let tag_str = if note.tags.is_empty() {
-                "-".to_string()
-            } else {
-                note.tags.join(", ")
-            };
-            table.add_row(row![
-                note.id,
-                note.content,
-                tag_str,
-                note.created_at.format("%d/%m/%Y - %H:%M").to_string(),
-                note.updated_at.format("%d/%m/%Y - %H:%M").to_string()
-            ]);
+        let err = clean_notes(&store, false, false, &mut "".as_bytes(), &mut output);
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "refusing to clear the notes without --yes"
+        );
+        // only the full word confirms
+        let answer = clean_notes(&store, false, true, &mut "y\n".as_bytes(), &mut output)?;
+        assert_eq!(answer, None);
+        assert_eq!(load_notes(path)?.notes.len(), 2);
+
+        let answer = clean_notes(&store, false, true, &mut "yes\n".as_bytes(), &mut output)?;
+        assert_eq!(answer, Some(2));
+        assert!(load_notes(path)?.notes.is_empty());
+
+        add_note(&store, "c".to_string(), vec![], &AddOptions::default())?;
+        assert_eq!(
+            clean_notes(&store, true, false, &mut "".as_bytes(), &mut output)?,
+            Some(1)
+        );
+        assert!(load_notes(path)?.notes.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_confirm_change() -> NoteResult<()> {
+        let ask = |yes: bool, interactive: bool, answer: &str| {
+            let mut output = Vec::new();
+            let go = confirm_change_with(
+                yes,
+                interactive,
+                &mut answer.as_bytes(),
+                &mut output,
+                "Remove note 1?",
+                "remove notes",
+            );
+            (
+                go.map_err(|e| e.to_string()),
+                String::from_utf8(output).unwrap(),
+            )
+        };
+        assert_eq!(
+            ask(false, true, "y\n"),
+            (Ok(true), "Remove note 1? [y/N] ".into())
+        );
+        assert_eq!(ask(false, true, "n\n").0, Ok(false));
+        assert_eq!(ask(false, true, "\n").0, Ok(false));
+        assert_eq!(ask(false, true, "").0, Ok(false));
+        assert_eq!(ask(true, false, ""), (Ok(true), String::new()));
+        assert_eq!(
+            ask(false, false, "y\n").0,
+            Err("refusing to remove notes without --yes".to_string())
+        );
+
+        let store = MemoryStore::default();
+        for content in [
+            "buy milk\nand bread",
+            "call the plumber about the leaking sink",
+        ] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
         }
-        table.printstd();
+        let data = store.load()?;
+        assert_eq!(
+            removal_question(&data, &[1, 1, 9], "..."),
+            Some("Remove note 1: 'buy milk'?".to_string())
+        );
+        assert_eq!(
+            removal_question(&data, &[2, 1], "..."),
+            Some("Remove 2 notes, 2: 'call the plumber about the ...', 1: 'buy milk'?".to_string())
+        );
+        assert_eq!(removal_question(&data, &[9], "..."), None);
+        Ok(())
     }
-    Ok(())
-}
 
-// Tests
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
+    #[test]
+    fn test_dry_run() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let store = JsonFileStore {
+            path: path_str.to_string(),
+        };
+        for content in ["first", "second", "third"] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+        }
+        let before = fs::read(&path)?;
 
-    // Helper to verify if a datetime is in the past or present
-    fn assert_time_valid(dt: &DateTime<Utc>) {
-        let now = Utc::now();
-        assert!(dt <= &now, "datetime should be in the past or present");
+        for args in [["add", "fourth"], ["remove", "2"]] {
+            let cli = Cli::try_parse_from(
+                ["note-cli", "--file", path_str, "--dry-run"]
+                    .into_iter()
+                    .chain(args),
+            )?;
+            assert_eq!(run(cli)?, ExitCode::SUCCESS);
+            assert_eq!(fs::read(&path)?, before);
+        }
+        assert!(!sidecar_path(path_str, "undo").exists());
+
+        // later loads see the changes kept in memory
+        let dry = DryRunStore::new(&store);
+        assert_eq!(dry.summary(), Vec::<String>::new());
+        add_note(&dry, "fourth".to_string(), vec![], &AddOptions::default())?;
+        remove_notes(&dry, &[1, 3], false)?;
+        edit_note(&dry, 2, "2nd".to_string(), DEFAULT_HISTORY_LIMIT)?;
+        assert_eq!(dry.load()?.notes.len(), 2);
+        assert_eq!(dry.get(2)?.unwrap().content, "2nd");
+        assert_eq!(
+            dry.summary(),
+            vec![
+                "Would add note 4",
+                "Would remove 2 notes (IDs 1, 3)",
+                "Would change note 2"
+            ]
+        );
+        assert_eq!(fs::read(&path)?, before);
+
+        let data = store.load()?;
+        assert_eq!(describe_changes(&data, &data), vec!["Nothing would change"]);
+        Ok(())
     }
 
     #[test]
-    fn test_add_note() -> NoteResult<()> {
-        let tmpfile = NamedTempFile::new()?;
-        let path = tmpfile.path().to_str().unwrap();
+    fn test_change_errors() -> NoteResult<()> {
+        for_each_store(|store| {
+            add_note(store, "first".to_string(), vec![], &AddOptions::default())?;
+            let error = |result: NoteResult<()>| result.unwrap_err().to_string();
+            assert_eq!(error(rate_note(store, 5, 3)), "ID 5 not found");
+            assert_eq!(
+                error(add_tag(store, 5, vec!["a".to_string()])),
+                "ID 5 not found"
+            );
+            assert_eq!(error(add_tag(store, 1, vec![])), "no tag given");
+            assert_eq!(error(set_meta(store, 5, "k", None)), "ID 5 not found");
+            assert_eq!(
+                error(set_meta(store, 1, "k", None)),
+                "note 1 has no metadata k"
+            );
+            assert_eq!(
+                error(edit_note(store, 1, String::new(), DEFAULT_HISTORY_LIMIT)),
+                "no content given"
+            );
+            assert_eq!(store.get(1)?.unwrap().content, "first");
+            Ok(())
+        })
+    }
 
-        add_note(path, "content0".to_string(), vec![])?;
-        add_note(path, "content1".to_string(), vec![])?;
+    #[test]
+    fn test_note_error_variants() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, "{ not json")?;
+        let err = load_notes(path_str).unwrap_err();
+        assert!(matches!(err, NoteError::Parse(_)));
+        assert!(err.to_string().contains(path_str));
 
-        let data = load_notes(path)?;
+        fs::create_dir(tmpdir.path().join("dir.json"))?;
+        let err = load_notes(tmpdir.path().join("dir.json").to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, NoteError::Storage(_)));
+        assert!(err.to_string().contains("dir.json"));
 
-        assert_eq!(data.notes.len(), 2);
+        let store = MemoryStore::default();
+        add_note(&store, "first".to_string(), vec![], &AddOptions::default())?;
+        assert!(matches!(remove_note(&store, 5), Err(NoteError::NotFound(ids)) if ids == [5]));
+        let missing = remove_notes(&store, &[1, 7, 9], false)?;
+        assert_eq!(
+            NoteError::NotFound(missing).to_string(),
+            "IDs 7, 9 not found"
+        );
+        let err = edit_note(&store, 1, "x".to_string(), DEFAULT_HISTORY_LIMIT).unwrap_err();
+        assert!(matches!(err, NoteError::NotFound(ids) if ids == [1]));
+        let err = edit_note(&store, 1, String::new(), DEFAULT_HISTORY_LIMIT).unwrap_err();
+        assert!(matches!(err, NoteError::InvalidInput(message) if message == "no content given"));
+        Ok(())
+    }
 
-        let note1 = &data.notes[0];
-        assert_eq!(note1.id, 1);
-        assert_eq!(note1.content, "content0");
-        assert!(note1.tags.is_empty());
-        assert_time_valid(&note1.created_at);
-        assert_time_valid(&note1.updated_at);
+    #[test]
+    fn test_atomic_save() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let store = JsonFileStore {
+            path: path_str.to_string(),
+        };
+        add_note(&store, "first".to_string(), vec![], &AddOptions::default())?;
+        let before = fs::read_to_string(&path)?;
 
-        let note2 = &data.notes[1];
-        assert_eq!(note2.id, 2);
-        assert_eq!(note2.content, "content1");
-        assert!(note2.tags.is_empty());
-        assert_time_valid(&note2.created_at);
-        assert_time_valid(&note2.updated_at);
+        // a writer failing halfway leaves the old file alone
+        let err = write_atomic(&path, |writer| {
+            writer.write_all(b"{\"notes\": [")?;
+            Err("poisoned payload".into())
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("poisoned payload"), "{}", err);
+        assert!(err.to_string().contains(path_str), "{}", err);
+        assert_eq!(fs::read_to_string(&path)?, before);
+        assert_eq!(fs::read_dir(tmpdir.path())?.count(), 1);
 
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        }
+        add_note(&store, "second".to_string(), vec![], &AddOptions::default())?;
+        assert_eq!(load_notes(path_str)?.notes.len(), 2);
+        assert_eq!(fs::read_dir(tmpdir.path())?.count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        }
         Ok(())
     }
 
     #[test]
-    fn test_remove_note() -> NoteResult<()> {
-        let tmpfile = NamedTempFile::new()?;
-        let path = tmpfile.path().to_str().unwrap();
+    fn test_notes_lock() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap().to_string();
+
+        let threads: Vec<_> = (0..2)
+            .map(|thread| {
+                let path_str = path_str.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let content = format!("note {} of thread {}", i, thread);
+                        let args = ["note-cli", "--file", &path_str, "add", &content];
+                        let cli = Cli::try_parse_from(args).unwrap();
+                        run(cli).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(load_notes(&path_str)?.notes.len(), 100);
+        assert!(!sidecar_path(&path_str, "lock").exists());
 
-        add_note(path, "content0".to_string(), vec![])?;
-        add_note(path, "content1".to_string(), vec![])?;
+        let timeout = std::time::Duration::from_millis(50);
+        let lock = NotesLock::acquire(&path_str, timeout)?;
+        let start = std::time::Instant::now();
+        let err = NotesLock::acquire(&path_str, timeout).err().unwrap();
+        assert!(start.elapsed() >= timeout);
+        assert!(
+            err.to_string()
+                .starts_with(&format!("another note-cli process is using {}", path_str)),
+            "{}",
+            err
+        );
+        drop(lock);
 
-        remove_note(path, 2)?;
+        // the lock isn't held while the editor runs, only once it's done
+        let store = JsonFileStore {
+            path: path_str.clone(),
+        };
+        edit_with_editor(&store, 1, DEFAULT_HISTORY_LIMIT, |file| {
+            let other = JsonFileStore {
+                path: path_str.clone(),
+            };
+            add_note(
+                &other,
+                "added meanwhile".to_string(),
+                vec![],
+                &AddOptions::default(),
+            )?;
+            fs::write(file, "edited\n")?;
+            Ok(true)
+        })?;
+        let data = load_notes(&path_str)?;
+        assert_eq!(data.notes.len(), 101);
+        assert_eq!(find_note(&store, 1)?.content, "edited");
+        assert!(
+            data.notes
+                .iter()
+                .any(|note| note.content == "added meanwhile")
+        );
+        Ok(())
+    }
 
-        let data = load_notes(path)?;
-        assert_eq!(data.notes.len(), 1);
-        let note1 = &data.notes[0];
-        assert_eq!(note1.id, 1);
-        assert_eq!(note1.content, "content0");
-        assert!(note1.tags.is_empty());
+    #[test]
+    fn test_rotate_backups() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let backup = |n: usize| sidecar_path(path_str, &format!("{}.bak", n));
+        let contents = |n: usize| -> NoteResult<Vec<String>> {
+            let data = load_notes(&backup(n).to_string_lossy())?;
+            Ok(data.notes.into_iter().map(|note| note.content).collect())
+        };
 
-        // free_ids contient l'ID libéré
-        assert_eq!(data.free_ids, vec![2]);
+        for content in ["one", "two", "three", "four", "five"] {
+            let store = UndoStore::new(path_str, 3);
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
+            // a second save of the same command doesn't rotate again
+            rate_note(&store, 1, 3)?;
+        }
+        assert_eq!(contents(1)?, ["one", "two", "three", "four"]);
+        assert_eq!(contents(2)?, ["one", "two", "three"]);
+        assert_eq!(contents(3)?, ["one", "two"]);
+        assert!(!backup(4).exists());
+
+        // nothing changes, nothing rotates
+        let store = UndoStore::new(path_str, 3);
+        store.save(&store.load()?)?;
+        assert_eq!(contents(1)?, ["one", "two", "three", "four"]);
 
+        let other = tmpdir.path().join("other.json");
+        let store = UndoStore::new(other.to_str().unwrap(), 0);
+        add_note(&store, "first".to_string(), vec![], &AddOptions::default())?;
+        add_note(&store, "second".to_string(), vec![], &AddOptions::default())?;
+        assert!(!sidecar_path(other.to_str().unwrap(), "1.bak").exists());
         Ok(())
     }
 
     #[test]
-    fn test_id_allocation() -> NoteResult<()> {
-        let tmpfile = NamedTempFile::new()?;
-        let path = tmpfile.path().to_str().unwrap();
+    fn test_notes_versions() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let note = r#"{"id": 2, "content": "old", "tags": ["a"],
+            "created_at": "2024-01-01T00:00:00Z", "updated_at": "2024-01-01T00:00:00Z"}"#;
+
+        // version 0: a bare array of notes
+        fs::write(&path, format!("[{}]", note))?;
+        let data = load_notes(path_str)?;
+        assert_eq!(data.version, NOTES_VERSION);
+        assert_eq!(data.notes[0].content, "old");
+        assert!(data.free_ids.is_empty());
+        save_notes(path_str, &data)?;
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        assert_eq!(saved["version"], NOTES_VERSION);
+        assert_eq!(load_notes(path_str)?, data);
 
-        add_note(path, "content1".to_string(), vec![])?;
-        add_note(path, "content2".to_string(), vec![])?;
+        // version 1, with or without the version field
+        for version in ["", r#""version": 1,"#] {
+            let fixture = format!(r#"{{{} "notes": [{}], "free_ids": [1]}}"#, version, note);
+            fs::write(&path, fixture)?;
+            let data = load_notes(path_str)?;
+            assert_eq!((data.version, data.free_ids), (1, vec![1]));
+        }
 
-        remove_note(path, 1)?;
-        remove_note(path, 2)?;
+        fs::write(
+            &path,
+            format!(r#"{{"version": 9, "notes": [{}], "free_ids": []}}"#, note),
+        )?;
+        let err = load_notes(path_str).unwrap_err().to_string();
+        assert!(err.contains("format version 9"), "{}", err);
+        assert!(err.ends_with("please upgrade note-cli"), "{}", err);
+        Ok(())
+    }
 
-        let mut data = load_notes(path)?;
-        assert_eq!(data.free_ids, vec![1, 2]);
+    #[test]
+    fn test_doctor() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let note = |id: u32, content: &str, created: &str, updated: &str| {
+            format!(
+                r#"{{"id": {}, "content": "{}", "tags": [], "created_at": "{}", "updated_at": "{}"}}"#,
+                id, content, created, updated
+            )
+        };
+        let day = "2024-01-02T00:00:00Z";
+        let fixture = format!(
+            r#"{{"notes": [{}, {}, {}], "free_ids": [4, 2, 2, 3]}}"#,
+            note(4, "first", day, "2024-01-01T00:00:00Z"),
+            note(4, " ", day, day),
+            note(1, "other", day, day)
+        );
+        fs::write(&path, fixture)?;
 
-        // Ajouter de nouvelles notes doit réutiliser les IDs
-        add_note(path, "new1".to_string(), vec![])?;
-        add_note(path, "new2".to_string(), vec![])?;
-        data = load_notes(path)?;
-        assert_eq!(data.free_ids.len(), 0);
+        let mut data = load_notes(path_str)?;
         assert_eq!(
-            data.notes.iter().map(|n| n.id).collect::<Vec<_>>(),
-            vec![1, 2]
+            diagnose(&data),
+            vec![
+                Problem::DuplicateId { id: 4, count: 2 },
+                Problem::UsedFreeId(4),
+                Problem::RepeatedFreeId { id: 2, count: 2 },
+                Problem::UpdatedBeforeCreated(4),
+                Problem::EmptyContent(4),
+            ]
         );
-
+        assert_eq!(
+            repair(&mut data),
+            vec![
+                "Removed free ID 4, a note uses it",
+                "Removed a repeat of free ID 2",
+                "Gave ID 2 to a note that had ID 4",
+                "Moved the update date of note 4 to its creation date",
+            ]
+        );
+        let ids: Vec<u32> = data.notes.iter().map(|note| note.id).collect();
+        assert_eq!(ids, [4, 2, 1]);
+        assert_eq!(data.free_ids, [3]);
+        assert_eq!(data.notes[0].updated_at, data.notes[0].created_at);
+        assert_eq!(diagnose(&data), vec![Problem::EmptyContent(2)]);
+        assert_eq!(repair(&mut data), Vec::<String>::new());
         Ok(())
     }
 
     #[test]
-    fn test_add_note_with_tag() -> NoteResult<()> {
-        let tmpfile = NamedTempFile::new()?;
-        let path = tmpfile.path().to_str().unwrap();
+    fn test_recover_corrupt_file() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap();
 
-        let tags = vec!["tag1".to_string(), "tag2".to_string()];
-        add_note(path, "hello world".to_string(), tags.clone())?;
+        for fixture in [
+            r#"{"notes": [{"id": 1,"#,
+            r#"{"notes": {"id": 1}, "free_ids": []}"#,
+        ] {
+            fs::write(&path, fixture)?;
+            let err = load_or_recover(path_str, now, || Ok(false)).unwrap_err();
+            assert!(matches!(err, NoteError::Parse(_)));
+            let message = err.to_string();
+            assert!(
+                message.starts_with(&format!("cannot parse {}:1:", path_str)),
+                "{}",
+                message
+            );
+            assert!(message.contains("--recover"), "{}", message);
+            assert_eq!(fs::read_to_string(&path)?, fixture);
+        }
 
-        let data = load_notes(path)?;
-        let note = &data.notes[0];
-        assert_eq!(note.tags, tags);
-        assert_time_valid(&note.created_at);
-        assert_time_valid(&note.updated_at);
+        let broken = b"{\"notes\": [\r\n\t{\"id\": 1, \"content\": \"caf\xc3\xa9\"},],";
+        fs::write(&path, broken)?;
+        let data = load_or_recover(path_str, now, || Ok(true))?;
+        assert!(data.notes.is_empty() && data.free_ids.is_empty());
+        assert!(!path.exists());
+        let aside = tmpdir.path().join("notes.json.corrupt.20240506T070809Z");
+        assert_eq!(fs::read(&aside)?, broken);
 
+        // valid files are loaded without asking
+        save_notes(path_str, &data)?;
+        load_or_recover(path_str, now, || panic!("asked about a valid file"))?;
         Ok(())
     }
 
     #[test]
-    fn test_add_tag_existing_note() -> NoteResult<()> {
-        let tmpfile = NamedTempFile::new()?;
-        let path = tmpfile.path().to_str().unwrap();
+    fn test_config_file() -> NoteResult<()> {
+        let path = Path::new("config.toml");
+        let config = Config::parse(path, "")?;
+        assert_eq!(config.file, None);
+        assert!(config.default_sort.is_empty() && config.confirm_remove);
+        assert!(config.color.is_none() && config.date_format.is_none());
 
-        add_note(path, "note".to_string(), vec![])?;
-        add_tag(path, 1, vec!["rust".to_string(), "cli".to_string()])?;
+        let config = Config::parse(path, "default_sort = \"update\"\nconfirm_remove = false\n")?;
+        assert_eq!(config.default_sort, vec![SortMethod::Update]);
+        assert!(!config.confirm_remove);
+        assert_eq!(config.history_limit, DEFAULT_HISTORY_LIMIT);
 
-        let data = load_notes(path)?;
-        let note = &data.notes[0];
-        assert_eq!(note.tags, vec!["rust", "cli"]);
+        let config = Config::parse(
+            path,
+            "file = \"/srv/notes.json\"\ndefault_sort = \"tag-count, id\"\n\
+             date_format = \"%d/%m/%Y\"\ncolor = \"never\"\nconfirm_remove = true\n\
+             hide_done = true\nbackups = 1\n[tag_colors]\nurgent = \"red\"\n",
+        )?;
+        assert_eq!(config.file.as_deref(), Some("/srv/notes.json"));
+        assert_eq!(
+            config.default_sort,
+            vec![SortMethod::TagCount, SortMethod::Id]
+        );
+        assert_eq!(config.date_format.as_deref(), Some("%d/%m/%Y"));
+        assert_eq!(config.color, Some(ColorChoice::Never));
+        assert!(config.hide_done && config.backups == 1);
 
-        // Ajouter un tag déjà existant ne doit pas créer de doublon
-        add_tag(path, 1, vec!["rust".to_string()])?;
-        let data = load_notes(path)?;
-        let note = &data.notes[0];
-        assert_eq!(note.tags, vec!["rust", "cli"]);
+        let err = Config::parse(
+            path,
+            "hide_done = true\n\n[tag_colors]\nurgent = \"pink\"\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.starts_with("invalid config config.toml, line 4, key `tag_colors.urgent`: "));
+        let err = Config::parse(path, "default_sort = \"size\"")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 1, key `default_sort`: unknown sort key 'size'"));
+        assert!(Config::parse(path, "date_format = \"%Q\"").is_err());
 
+        // --file, then NOTE_CLI_FILE, then the config, then the default
+        let cli = Cli::try_parse_from(["note-cli", "--file", "here.json", "list"])?;
+        assert_eq!(cli.notes_file(&config)?, "here.json");
+        let cli = Cli::try_parse_from(["note-cli", "--file", "", "list"])?;
+        assert_eq!(cli.notes_file(&config)?, "/srv/notes.json");
+        assert_eq!(cli.notes_file(&Config::default())?, default_notes_file());
         Ok(())
     }
 
     #[test]
-    fn test_edit_note() -> NoteResult<()> {
-        let tmpfile = NamedTempFile::new()?;
-        let path = tmpfile.path().to_str().unwrap();
+    fn test_move_note() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let source = open_store(tmpdir.path().join("scratch.json").to_str().unwrap());
+        let dest = open_store(tmpdir.path().join("work.json").to_str().unwrap());
+        for content in ["first", "belongs at work"] {
+            add_note(
+                source.as_ref(),
+                content.to_string(),
+                vec!["meeting".to_string()],
+                &AddOptions::default(),
+            )?;
+        }
+        let original = source.get(2)?.unwrap();
 
-        add_note(path, "old content".to_string(), vec![])?;
-        edit_note(path, 1, "new content".to_string())?;
+        // the destination file is created, then note 1 finds its ID taken there
+        assert_eq!(move_note(source.as_ref(), dest.as_ref(), 2)?, 1);
+        assert_eq!(move_note(source.as_ref(), dest.as_ref(), 1)?, 2);
+        assert_eq!(dest.get(2)?.unwrap().content, "first");
+        let moved = dest.get(1)?.unwrap();
+        assert_eq!(moved.content, original.content);
+        assert_eq!(moved.tags, original.tags);
+        assert_eq!(moved.created_at, original.created_at);
+        assert_eq!(moved.updated_at, original.updated_at);
 
-        let data = load_notes(path)?;
-        let note = &data.notes[0];
-        assert_eq!(note.content, "new content");
-        assert_time_valid(&note.updated_at);
+        let data = source.load()?;
+        assert!(data.notes.is_empty());
+        assert_eq!(data.free_ids, vec![2, 1]);
+        assert!(matches!(
+            move_note(source.as_ref(), dest.as_ref(), 1),
+            Err(NoteError::NotFound(_))
+        ));
+        assert_eq!(dest.load()?.notes.len(), 2);
 
+        assert_eq!(destination_path("team.json")?, "team.json");
+        assert_eq!(destination_path("../x")?, "../x");
+        assert!(destination_path("work")?.ends_with("work.json"));
         Ok(())
     }
-}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+    #[test]
+    fn test_merge_notes() -> NoteResult<()> {
+        let at = |raw: &str| {
+            DateTime::parse_from_rfc3339(raw)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        let note = |id: u32, content: &str, updated: &str| Note {
+            id,
+            content: content.to_string(),
+            tags: vec![],
+            created_at: at("2024-05-01T09:00:00Z"),
+            created_offset: None,
+            title: None,
+            due: None,
+            done: false,
+            pinned: false,
+            deleted_at: None,
+            history: vec![],
+            updated_at: at(updated),
+            expires_at: None,
+            meta: BTreeMap::new(),
+            rating: None,
+            archived: false,
+        };
+        let notes = |notes: Vec<Note>, free_ids: Vec<u32>| NoteData {
+            version: NOTES_VERSION,
+            notes,
+            free_ids,
+            trash: vec![],
+        };
+        let contents = |store: &MemoryStore| -> NoteResult<Vec<(u32, String)>> {
+            let mut notes: Vec<_> = store
+                .load()?
+                .notes
+                .into_iter()
+                .map(|note| (note.id, note.content))
+                .collect();
+            notes.sort();
+            Ok(notes)
+        };
 
-    match cli.command {
-        Commands::Add { content, tags } => {
-            add_note(&cli.file, content, tags)?;
-        }
-        Commands::List { method } => {
-            list_note(&cli.file, method)?;
-        }
-        Commands::Remove { id } => {
-            remove_note(&cli.file, id)?;
-        }
-        Commands::AddTag { id, tags } => {
-            add_tag(&cli.file, id, tags)?;
+        // disjoint sets, into an empty store
+        let store = MemoryStore::default();
+        let other = notes(vec![note(1, "a", "2024-05-02T00:00:00Z")], vec![]);
+        let decisions = merge_notes(&store, other, false)?;
+        assert_eq!(decisions, vec![MergeDecision::Added { id: 1 }]);
+        let other = notes(vec![note(3, "c", "2024-05-02T00:00:00Z")], vec![2]);
+        merge_notes(&store, other, false)?;
+        assert_eq!(contents(&store)?, vec![(1, "a".into()), (3, "c".into())]);
+
+        // same ID, different content: the newer version wins
+        let ours = || {
+            let store = MemoryStore::default();
+            store.save(&notes(
+                vec![
+                    note(1, "a", "2024-05-02T00:00:00Z"),
+                    note(2, "ours", "2024-05-03T00:00:00Z"),
+                    note(3, "older ours", "2024-05-01T00:00:00Z"),
+                ],
+                vec![4, 4],
+            ))?;
+            NoteResult::Ok(store)
+        };
+        let theirs = || {
+            notes(
+                vec![
+                    note(1, "a", "2024-05-02T00:00:00Z"),
+                    note(2, "theirs", "2024-05-02T00:00:00Z"),
+                    note(3, "newer theirs", "2024-05-04T00:00:00Z"),
+                    note(5, "e", "2024-05-04T00:00:00Z"),
+                ],
+                vec![],
+            )
+        };
+        let store = ours()?;
+        let decisions = merge_notes(&store, theirs(), false)?;
+        assert_eq!(
+            decisions,
+            vec![
+                MergeDecision::Same { id: 1 },
+                MergeDecision::KeptOurs {
+                    id: 2,
+                    theirs_as: None
+                },
+                MergeDecision::TookTheirs {
+                    id: 3,
+                    ours_as: None
+                },
+                MergeDecision::Added { id: 5 },
+            ]
+        );
+        assert_eq!(
+            contents(&store)?,
+            vec![
+                (1, "a".into()),
+                (2, "ours".into()),
+                (3, "newer theirs".into()),
+                (5, "e".into())
+            ]
+        );
+        assert_eq!(store.load()?.free_ids, vec![4]);
+
+        // --keep-both adds the losers, reusing the free IDs first
+        let store = ours()?;
+        let decisions = merge_notes(&store, theirs(), true)?;
+        assert_eq!(
+            decisions[1].to_string(),
+            "note 2: kept this version, newer, the other one added as note 4"
+        );
+        assert_eq!(
+            decisions[2].to_string(),
+            "note 3: took the other version, newer, this one added as note 6"
+        );
+        let merged = contents(&store)?;
+        assert!(merged.contains(&(4, "theirs".into())));
+        assert!(merged.contains(&(6, "older ours".into())));
+        assert_eq!(merged.len(), 6);
+        assert!(store.load()?.free_ids.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_with_purge_expired() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let config = tmpdir.path().join("config.toml");
+        fs::write(&config, "purge_expired = true\n")?;
+        let config = config.to_str().unwrap();
+        let note_cli = |args: &[&str], purge: bool| -> NoteResult<ExitCode> {
+            let mut argv = vec!["note-cli", "--file", path_str];
+            if purge {
+                argv.extend(["--config", config]);
+            }
+            run(Cli::try_parse_from(
+                argv.into_iter().chain(args.iter().copied()),
+            )?)
+        };
+
+        note_cli(&["add", "keep"], false)?;
+        note_cli(&["add", "temp", "--expires", "1h"], false)?;
+        let store = JsonFileStore {
+            path: path_str.to_string(),
+        };
+        let mut temp = store.get(2)?.unwrap();
+        temp.expires_at = Some(Utc::now() - Duration::hours(1));
+        store.upsert(temp)?;
+        note_cli(&["add", "mistake"], false)?;
+
+        note_cli(&["undo"], true)?;
+        let contents = |data: NoteData| -> Vec<String> {
+            data.notes.into_iter().map(|note| note.content).collect()
+        };
+        assert!(!contents(store.load()?).contains(&"mistake".to_string()));
+
+        // a purge before a change doesn't become what undo brings back
+        note_cli(&["add", "mistake"], false)?;
+        note_cli(&["add", "later"], true)?;
+        note_cli(&["undo"], true)?;
+        assert_eq!(contents(store.load()?), vec!["keep", "mistake"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_trash_and_history() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("notes.json");
+        let path_str = path.to_str().unwrap();
+        let store = UndoStore::new(path_str, 0);
+        for content in ["kept", "old trash", "new trash", "expired"] {
+            add_note(&store, content.to_string(), vec![], &AddOptions::default())?;
         }
-        Commands::Edit { id, content } => {
-            edit_note(&cli.file, id, content)?;
+        for content in ["v1", "v2"] {
+            edit_note(&store, 1, content.to_string(), DEFAULT_HISTORY_LIMIT)?;
         }
-        Commands::Search { keyword, method } => {
-            search_note(&cli.file, keyword, method)?;
+        let now = Utc::now();
+        remove_notes(&store, &[2, 3], false)?;
+        let mut data = store.load()?;
+        data.trash[0].deleted_at = Some(now - Duration::days(40));
+        data.trash[1].deleted_at = Some(now - Duration::days(2));
+        data.notes[1].expires_at = Some(now - Duration::hours(1));
+        store.save(&data)?;
+        let before = store.load()?;
+
+        let store = UndoStore::new(path_str, 1);
+        let options = CompactOptions {
+            history_limit: 0,
+            trash_retention: Duration::days(30),
+            now,
+        };
+        compact_notes(&store, path_str, true, &options)?;
+        // a single minified save, made after the backup of the old file
+        assert!(!fs::read_to_string(&path)?.contains('\n'));
+        let backup = sidecar_path(path_str, "1.bak");
+        assert_eq!(load_notes(&backup.to_string_lossy())?, before);
+        let data = store.load()?;
+        assert_eq!(data.notes.len(), 1);
+        assert!(data.notes[0].history.is_empty());
+        let trashed: Vec<&str> = data.trash.iter().map(|n| n.content.as_str()).collect();
+        assert_eq!(trashed, vec!["new trash"]);
+        assert_eq!(data.free_ids, vec![2, 3, 4]);
+
+        // undo brings back what compact dropped
+        undo_last(store.inner.as_ref(), &store.undo_path)?;
+        assert_eq!(store.load()?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_holds_no_notes() -> NoteResult<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let single = JsonFileStore {
+            path: tmpdir
+                .path()
+                .join("single.json")
+                .to_string_lossy()
+                .into_owned(),
+        };
+        let sharded = ShardedStore {
+            index_path: tmpdir.path().join("notes.json"),
+        };
+        let since = Some(parse_date("2030-01-01")?);
+        for store in [&single as &dyn NoteStore, &sharded] {
+            assert!(holds_no_notes(
+                store,
+                &store.load_created_since(since)?,
+                since
+            )?);
+            add_note(store, "old".to_string(), vec![], &AddOptions::default())?;
+            let mut data = store.load()?;
+            data.notes[0].created_at = parse_date("2024-03-01")?;
+            store.save(&data)?;
+
+            // a --since newer than every note still finds the store not empty
+            let pruned = store.load_created_since(since)?;
+            assert!(!holds_no_notes(store, &pruned, since)?);
+            assert!(!holds_no_notes(store, &store.load()?, None)?);
         }
+        assert!(sharded.load_created_since(since)?.notes.is_empty());
+        Ok(())
     }
-    Ok(())
 }
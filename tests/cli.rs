@@ -0,0 +1,348 @@
+use assert_cmd::Command;
+use tempfile::TempDir;
+
+// Runs note-cli on a notes file holding a single note, with its own config
+// directory so that the user's config doesn't apply
+fn note_cli(dir: &TempDir) -> Command {
+    let file = dir.path().join("notes.json");
+    if !file.exists() {
+        Command::cargo_bin("note-cli")
+            .unwrap()
+            .env("XDG_CONFIG_HOME", dir.path())
+            .args(["--file", file.to_str().unwrap(), "add", "first note"])
+            .assert()
+            .success();
+    }
+    let mut command = Command::cargo_bin("note-cli").unwrap();
+    command
+        .env("XDG_CONFIG_HOME", dir.path())
+        .args(["--file", file.to_str().unwrap()]);
+    command
+}
+
+#[test]
+fn test_failures_exit_with_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let cases: [(&[&str], i32, &str); 5] = [
+        (
+            &["edit", "5", "--content", "x"],
+            7,
+            "Error: ID 5 not found\n",
+        ),
+        (
+            &["edit", "1", "--content", ""],
+            1,
+            "Error: no content given\n",
+        ),
+        (
+            &["add-tag", "5", "--tag", "work"],
+            7,
+            "Error: ID 5 not found\n",
+        ),
+        (&["add-tag", "1"], 1, "Error: no tag given\n"),
+        (&["search", ""], 1, "Error: no keyword given\n"),
+    ];
+    for (args, code, stderr) in cases {
+        let output = note_cli(&dir).args(args).output().unwrap();
+        assert_eq!(output.status.code(), Some(code), "{:?}", args);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            stderr,
+            "{:?}",
+            args
+        );
+        assert!(output.stdout.is_empty(), "{:?}", args);
+    }
+}
+
+#[test]
+fn test_data_goes_to_stdout() {
+    let dir = tempfile::tempdir().unwrap();
+    note_cli(&dir)
+        .args(["cat", "1"])
+        .assert()
+        .success()
+        .stdout("first note\n")
+        .stderr("");
+}
+
+#[test]
+fn test_corrupt_file_exit_status() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("notes.json"), "{ not json").unwrap();
+    let output = note_cli(&dir).arg("list").output().unwrap();
+    assert_eq!(output.status.code(), Some(8));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("Error: cannot parse "), "{}", stderr);
+    assert!(stderr.contains("notes.json:1:3"), "{}", stderr);
+}
+
+#[test]
+fn test_doctor_exit_status() {
+    let dir = tempfile::tempdir().unwrap();
+    note_cli(&dir).args(["doctor"]).assert().success();
+    let file = dir.path().join("notes.json");
+    let content = std::fs::read_to_string(&file).unwrap();
+    let content = content.replace("\"free_ids\": []", "\"free_ids\": [1, 1]");
+    std::fs::write(&file, content).unwrap();
+
+    let output = note_cli(&dir).args(["doctor"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(9));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.starts_with("free ID 1 is used by a note\n"),
+        "{}",
+        stdout
+    );
+    note_cli(&dir).args(["doctor", "--fix"]).assert().success();
+    note_cli(&dir)
+        .args(["doctor"])
+        .assert()
+        .success()
+        .stdout("No problem found\n");
+}
+
+#[test]
+fn test_default_file_location() {
+    let dir = tempfile::tempdir().unwrap();
+    let work = dir.path().join("work");
+    std::fs::create_dir(&work).unwrap();
+    let run = |envs: &[(&str, &std::path::Path)], args: &[&str]| {
+        let mut command = Command::cargo_bin("note-cli").unwrap();
+        command
+            .current_dir(&work)
+            .env("XDG_CONFIG_HOME", dir.path())
+            .env_remove("XDG_DATA_HOME")
+            .env_remove("NOTE_CLI_FILE")
+            .envs(envs.iter().copied())
+            .args(args);
+        command.assert().success()
+    };
+
+    let data = dir.path().join("data");
+    run(&[("XDG_DATA_HOME", &data)], &["add", "from xdg"]);
+    let path = data.join("note-cli/notes.json");
+    assert!(path.is_file());
+    run(&[("XDG_DATA_HOME", &data)], &["--verbose", "ids"])
+        .stderr(format!("Notes file: {}\n", path.display()));
+
+    let home = dir.path().join("home");
+    run(&[("HOME", &home)], &["add", "from home"]);
+    assert!(home.join(".local/share/note-cli/notes.json").is_file());
+    assert!(!work.join("notes.json").exists());
+
+    run(
+        &[("XDG_DATA_HOME", &data)],
+        &["--file", "notes.json", "add", "here"],
+    );
+    assert!(work.join("notes.json").is_file());
+}
+
+#[test]
+fn test_file_from_environment() {
+    let dir = tempfile::tempdir().unwrap();
+    let work = dir.path().join("work");
+    std::fs::create_dir(&work).unwrap();
+    let run = |file: &str, args: &[&str]| {
+        let mut command = Command::cargo_bin("note-cli").unwrap();
+        command
+            .current_dir(&work)
+            .env("XDG_CONFIG_HOME", dir.path())
+            .env("XDG_DATA_HOME", dir.path().join("data"))
+            .env("HOME", dir.path())
+            .env("NOTE_CLI_FILE", file)
+            .args(args);
+        command.assert().success()
+    };
+
+    run("~/project.json", &["add", "from the environment"]);
+    run("~/project.json", &["cat", "1"]).stdout("from the environment\n");
+    let listed = run("~/project.json", &["list"]).get_output().stdout.clone();
+    assert!(String::from_utf8_lossy(&listed).contains("from the environment"));
+    assert!(dir.path().join("project.json").is_file());
+    assert!(!work.join("notes.json").exists());
+    assert!(!dir.path().join("data/note-cli/notes.json").exists());
+
+    run(
+        "~/project.json",
+        &["--file", "notes.json", "add", "from the flag"],
+    );
+    assert!(work.join("notes.json").is_file());
+    run("", &["add", "from the default"]);
+    assert!(dir.path().join("data/note-cli/notes.json").is_file());
+}
+
+#[test]
+fn test_flags_override_config() {
+    let dir = tempfile::tempdir().unwrap();
+    note_cli(&dir)
+        .args(["add", "a second note"])
+        .assert()
+        .success();
+    let config = dir.path().join("custom.toml");
+    let configured = dir.path().join("configured.json");
+    std::fs::write(
+        &config,
+        format!(
+            "default_sort = \"content\"\ndate_format = \"%Y!\"\nfile = \"{}\"\n",
+            configured.display()
+        ),
+    )
+    .unwrap();
+    let config = config.to_str().unwrap();
+
+    note_cli(&dir)
+        .args(["--config", config, "list", "--ids-only"])
+        .assert()
+        .success()
+        .stdout("2\n1\n");
+    note_cli(&dir)
+        .args(["--config", config, "list", "--ids-only", "--sort", "id"])
+        .assert()
+        .success()
+        .stdout("1\n2\n");
+    let shown = note_cli(&dir)
+        .args(["--config", config, "show", "1"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&shown.stdout).contains("! +"));
+    let shown = note_cli(&dir)
+        .args(["--config", config, "--date-format", "%Y?", "show", "1"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&shown.stdout).contains("? +"));
+
+    let mut command = Command::cargo_bin("note-cli").unwrap();
+    command
+        .env("XDG_CONFIG_HOME", dir.path())
+        .env_remove("NOTE_CLI_FILE")
+        .args(["--config", config, "add", "in the configured file"])
+        .assert()
+        .success();
+    assert!(configured.is_file());
+
+    let output = note_cli(&dir)
+        .args(["--config", "missing.toml", "list"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_notebooks() {
+    let dir = tempfile::tempdir().unwrap();
+    let run = |args: &[&str]| {
+        let mut command = Command::cargo_bin("note-cli").unwrap();
+        command
+            .env("XDG_CONFIG_HOME", dir.path())
+            .env("XDG_DATA_HOME", dir.path())
+            .env_remove("NOTE_CLI_FILE")
+            .args(args);
+        command.assert().success()
+    };
+
+    run(&["notebooks"]).stdout(format!(
+        "No notebooks in {}\n",
+        dir.path().join("note-cli").display()
+    ));
+    run(&["--notebook", "work", "add", "standup"]).stderr("Created notebook work\n");
+    run(&["--notebook", "work", "add", "review"]).stderr("");
+    run(&["--notebook", "home", "add", "groceries"]);
+    run(&["add", "in the default notebook"]);
+
+    run(&["--notebook", "work", "list", "--ids-only"]).stdout("1\n2\n");
+    run(&["--notebook", "home", "cat", "1"]).stdout("groceries\n");
+    run(&["cat", "1"]).stdout("in the default notebook\n");
+    // backups of a notebook are not notebooks
+    run(&["--notebook", "home", "backup", "create"]);
+    run(&["notebooks"]).stdout("home\t1 notes\nnotes\t1 notes\nwork\t2 notes\n");
+
+    let file = dir.path().join("other.json");
+    Command::cargo_bin("note-cli")
+        .unwrap()
+        .args([
+            "--notebook",
+            "work",
+            "--file",
+            file.to_str().unwrap(),
+            "list",
+        ])
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn test_move_between_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("work.json");
+    note_cli(&dir)
+        .args(["move", "1", "--to", dest.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(format!("Moved note 1 to {} as note 1\n", dest.display()));
+    Command::cargo_bin("note-cli")
+        .unwrap()
+        .args(["--file", dest.to_str().unwrap(), "cat", "1"])
+        .assert()
+        .success()
+        .stdout("first note\n");
+    note_cli(&dir).args(["cat", "1"]).assert().code(7);
+
+    let source = dir.path().join("notes.json");
+    note_cli(&dir).args(["add", "again"]).assert().success();
+    let output = note_cli(&dir)
+        .args(["move", "1", "--to", source.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        format!("Error: note 1 is already in {}\n", source.display())
+    );
+}
+
+#[test]
+fn test_archive_skips_pinned_notes() {
+    let dir = tempfile::tempdir().unwrap();
+    note_cli(&dir).args(["add", "second"]).assert().success();
+    note_cli(&dir).args(["pin", "1"]).assert().success();
+    note_cli(&dir)
+        .args(["--yes", "archive", "--before", "2099-01-01"])
+        .assert()
+        .success()
+        .stdout("Archived 1 notes created before 2099-01-01 (IDs 2-2), skipping 1 pinned note\n");
+    note_cli(&dir)
+        .args(["--yes", "archive", "--before", "2099-01-01"])
+        .assert()
+        .success()
+        .stdout("No note to archive, skipping 1 pinned note\n");
+}
+
+#[test]
+fn test_search_skips_archived_notes() {
+    let dir = tempfile::tempdir().unwrap();
+    note_cli(&dir)
+        .args(["add", "second note"])
+        .assert()
+        .success();
+    note_cli(&dir).args(["archive", "1"]).assert().success();
+    let ids = |args: &[&str]| {
+        let output = note_cli(&dir).args(args).output().unwrap();
+        assert!(output.status.success(), "{:?}", args);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(ids(&["search", "note", "--ids-only"]), "2\n");
+    assert_eq!(ids(&["search", "note", "--all", "--ids-only"]), "1\n2\n");
+    assert_eq!(ids(&["search", "note", "--archived", "--ids-only"]), "1\n");
+    assert_eq!(ids(&["list", "--all", "--ids-only"]), "1\n2\n");
+    // every keyword must match, archived notes included
+    let args = [
+        "search",
+        "first",
+        "note",
+        "--all-keywords",
+        "--all",
+        "--ids-only",
+    ];
+    assert_eq!(ids(&args), "1\n");
+}